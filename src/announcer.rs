@@ -0,0 +1,295 @@
+use std::sync::{Arc, Mutex};
+
+use log::{info, warn};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    client::{PeerRegistry, PieceManager},
+    error::BtError,
+    external_ip::ExternalIp,
+    notify::{Event, Notifier},
+    peer_pool::PeerPool,
+    sources::{PeerSource, PeerSources},
+    tracker::{self, AnnounceEvent, Tracker},
+};
+
+// a torrent's announces, run as an actor: one task owns the tracker
+// and announces on the schedule it asks for, and the rest of the torrent
+// only ever talks to it through AnnounceCommands. stopping and starting
+// again are messages rather than aborting the task partway through an
+// announce, so the tracker always hears started and stopped in the
+// right order and never hears stopped twice.
+//
+// the torrent's disk is an actor too, see disk.rs. its piece state and
+// its peer connections still share their state behind mutexes, and
+// moving them over to actors of their own, each with a command enum like
+// this one, is left for later (see the readme).
+//
+// when our external address changes the announcer doesn't wait for the
+// next interval, peers the tracker hands our old address to can't reach
+// us any more.
+
+#[derive(Debug)]
+pub enum AnnounceCommand {
+    // announce again after a Stop, from event=started
+    Start,
+    // event=stopped, then nothing until Start. the reply, if anyone
+    // wants it, is how that announce went.
+    Stop { reply: Option<oneshot::Sender<Result<(), BtError>>> },
+}
+
+// how the rest of the torrent reaches its announcer. the announcer
+// lives as long as any of these do.
+#[derive(Debug, Clone)]
+pub struct AnnouncerHandle {
+    commands: mpsc::UnboundedSender<AnnounceCommand>,
+}
+
+impl AnnouncerHandle {
+    pub fn start(&self) {
+        let _ = self.commands.send(AnnounceCommand::Start);
+    }
+
+    // doesn't wait for the tracker, the announcer warns if it fails
+    pub fn stop_in_background(&self) {
+        let _ = self.commands.send(AnnounceCommand::Stop { reply: None });
+    }
+
+    pub async fn stop(&self) -> Result<(), BtError> {
+        let (reply, result) = oneshot::channel();
+        if self.commands.send(AnnounceCommand::Stop { reply: Some(reply) }).is_err() {
+            return Ok(());
+        }
+        // an announcer that went away before answering had nothing to say
+        result.await.unwrap_or(Ok(()))
+    }
+}
+
+// what the announcer needs from the torrent to fill in an announce and
+// hand the peers it gets back to the connections
+pub struct Announcer {
+    pub tracker: Arc<Tracker>,
+    pub peers: Arc<Mutex<PeerPool>>,
+    pub piece_manager: Arc<Mutex<PieceManager>>,
+    pub connected: PeerRegistry,
+    pub sources: Arc<Mutex<PeerSources>>,
+    // shared by every torrent, trackers tell it what they see
+    pub external_ip: Arc<ExternalIp>,
+    pub notifier: Notifier,
+    pub max_connections: usize,
+    // overrides the numwant worked out from how many peers we have
+    pub numwant: Option<u32>,
+}
+
+impl Announcer {
+    pub fn spawn(self) -> AnnouncerHandle {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(self.run(receiver));
+        AnnouncerHandle { commands }
+    }
+
+    async fn run(self, mut commands: mpsc::UnboundedReceiver<AnnounceCommand>) {
+        let mut running = true;
+        let mut first = true;
+        let mut next = Instant::now();
+        let mut address_changes = self.external_ip.subscribe();
+
+        loop {
+            tokio::select! {
+                _ = sleep_until(next), if running => {
+                    let (succeeded, wait) = self.announce(first.then_some(AnnounceEvent::Started)).await;
+                    first &= !succeeded;
+                    next = Instant::now() + wait;
+                    // a change found out while that announce was going
+                    // out, maybe by it, is covered by it
+                    address_changes.mark_unchanged();
+                }
+                Ok(()) =  address_changes.changed() => {
+                    if running {
+                        next = Instant::now();
+                    }
+                }
+                command = commands.recv() => match command {
+                    // the torrent is gone
+                    None => return,
+                    Some(AnnounceCommand::Start) => {
+                        if !running {
+                            running = true;
+                            first = true;
+                            next = Instant::now();
+                        }
+                    }
+                    Some(AnnounceCommand::Stop { reply }) => {
+                        let result = if running {
+                            running = false;
+                            let totals = self.piece_manager.lock().unwrap().announce_totals();
+                            self.tracker.stop(totals).await
+                        } else {
+                            Ok(())
+                        };
+                        match reply {
+                            Some(reply) => {
+                                let _ = reply.send(result);
+                            }
+                            None => {
+                                if let Err(e) = result {
+                                    warn!("couldn't tell the tracker we stopped: {}", e);
+                                }
+                            }
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    // returns whether the tracker answered, and how long until the next
+    // announce is due
+    async fn announce(&self, event: Option<AnnounceEvent>) -> (bool, std::time::Duration) {
+        let (totals, complete) = {
+            let pm = self.piece_manager.lock().unwrap();
+            (pm.announce_totals(), pm.complete())
+        };
+        let numwant = self
+            .numwant
+            .unwrap_or_else(|| tracker::numwant(complete, self.connected.lock().unwrap().len(), self.max_connections));
+
+        let (response, wait) = self.tracker.announce(event, totals, numwant).await;
+        match response {
+            Ok(response) => {
+                self.sources.lock().unwrap().record_discovered(PeerSource::Tracker, &response.peers);
+                if let Some(ip) = response.external_ip.filter(|&ip| self.external_ip.report(ip)) {
+                    info!("the tracker sees us at a new address, {}", ip);
+                }
+                let mut peers = self.peers.lock().unwrap();
+                // other torrents' trackers may have found us out first
+                for ip in self.external_ip.get().iter() {
+                    peers.exclude(&(ip.to_string(), self.tracker.port()));
+                }
+                peers.add(PeerSource::Tracker, &response.peers);
+                (true, wait)
+            }
+            Err(e) => {
+                warn!("announce failed, trying again in {}s: {}", wait.as_secs(), e);
+                let state = self.tracker.state();
+                if state.consecutive_failures == 1 {
+                    self.notifier.notify(Event::TrackerError { tracker: state.url, error: e.to_string() });
+                }
+                (false, wait)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{info_hash::InfoHash, torrent::Torrent};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // answers every announce with one peer, passing on the query strings
+    async fn fake_tracker() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut data = vec![0u8; 4096];
+                let n = stream.read(&mut data).await.unwrap();
+                let request = String::from_utf8_lossy(&data[..n]).to_string();
+                let _ = requests.send(request.lines().next().unwrap_or_default().to_string());
+
+                let body = b"d8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe1e";
+                let head = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n", body.len());
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(body).await.unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    #[tokio::test]
+    async fn test_stop_and_start() {
+        let (url, mut requests) = fake_tracker().await;
+        let torrent = Arc::new(Torrent {
+            info_hash: InfoHash::new([1; 20]),
+            announce: url,
+            pieces: vec![[0; 20]],
+            piece_length: 16384,
+            total_size: 16384,
+            output_file: std::env::temp_dir().join("bt-c-announcer").to_string_lossy().to_string(),
+            ..Default::default()
+        });
+        let peers = Arc::new(Mutex::new(PeerPool::new()));
+        let handle = Announcer {
+            tracker: Arc::new(Tracker::new(torrent.clone(), "-BC0001-", 6881)),
+            peers: peers.clone(),
+            piece_manager: Arc::new(Mutex::new(PieceManager::new(torrent.clone()).unwrap())),
+            connected: PeerRegistry::default(),
+            sources: Arc::new(Mutex::new(PeerSources::new())),
+            external_ip: Arc::new(ExternalIp::new()),
+            notifier: Notifier::new(None, torrent),
+            max_connections: 50,
+            numwant: None,
+        }
+        .spawn();
+
+        assert!(requests.recv().await.unwrap().contains("&event=started"));
+        // the stop waits its turn behind the started announce, whose
+        // peers are in the pool by then
+        handle.stop().await.unwrap();
+        assert_eq!(peers.lock().unwrap().sources(&("10.0.0.1".to_string(), 6881)), vec![PeerSource::Tracker]);
+        assert!(requests.recv().await.unwrap().contains("&event=stopped"));
+
+        // the tracker already knows we have gone
+        handle.stop().await.unwrap();
+        handle.start();
+        assert!(requests.recv().await.unwrap().contains("&event=started"));
+    }
+
+    #[tokio::test]
+    async fn test_announces_again_when_our_address_changes() {
+        let (url, mut requests) = fake_tracker().await;
+        let torrent = Arc::new(Torrent {
+            info_hash: InfoHash::new([2; 20]),
+            announce: url,
+            pieces: vec![[0; 20]],
+            piece_length: 16384,
+            total_size: 16384,
+            output_file: std::env::temp_dir().join("bt-c-announcer-ip").to_string_lossy().to_string(),
+            ..Default::default()
+        });
+        let external_ip = Arc::new(ExternalIp::new());
+        let peers = Arc::new(Mutex::new(PeerPool::new()));
+        let _handle = Announcer {
+            tracker: Arc::new(Tracker::new(torrent.clone(), "-BC0001-", 6881)),
+            peers: peers.clone(),
+            piece_manager: Arc::new(Mutex::new(PieceManager::new(torrent.clone()).unwrap())),
+            connected: PeerRegistry::default(),
+            sources: Arc::new(Mutex::new(PeerSources::new())),
+            external_ip: external_ip.clone(),
+            notifier: Notifier::new(None, torrent),
+            max_connections: 50,
+            numwant: None,
+        }
+        .spawn();
+        assert!(requests.recv().await.unwrap().contains("&event=started"));
+        // the first announce is done once its peers are in
+        while peers.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        // the tracker said 1800s, but we've moved
+        external_ip.report("203.0.113.7".parse().unwrap());
+        external_ip.report("198.51.100.1".parse().unwrap());
+        let request = requests.recv().await.unwrap();
+        assert!(!request.contains("&event="));
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::blocklist::Blocklist;
+
+// ip addresses we refuse to connect to. shared by every torrent, so a
+// peer that misbehaves on one torrent doesn't get a second chance on
+// another.
+//
+// peers that send corrupt data are banned for the rest of the session.
+// peers that keep failing the handshake (or send garbage straight
+// away) are only banned for a while, since that is as likely to be a
+// broken nat or an overloaded client as anything malicious. addresses
+// on the blocklist, if there is one, are banned as long as they are on
+// it.
+
+// handshake failures in a row before an address is banned
+const DEFAULT_MAX_FAILURES: u32 = 3;
+
+// how long an address that keeps failing handshakes is banned for
+const DEFAULT_BAN_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+struct Ban {
+    reason: String,
+    // None means banned until the session ends
+    until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct BanList {
+    banned: HashMap<String, Ban>,
+    failures: HashMap<String, u32>,
+    max_failures: u32,
+    ttl: Duration,
+    blocklist: Blocklist,
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        BanList::with_limits(DEFAULT_MAX_FAILURES, DEFAULT_BAN_TTL)
+    }
+}
+
+impl BanList {
+    pub fn new() -> BanList {
+        BanList::default()
+    }
+
+    pub fn with_limits(max_failures: u32, ttl: Duration) -> BanList {
+        BanList {
+            banned: HashMap::new(),
+            failures: HashMap::new(),
+            max_failures,
+            ttl,
+            blocklist: Blocklist::default(),
+        }
+    }
+
+    // bans the address for the rest of the session
+    pub fn ban(&mut self, ip: &str, reason: &str) {
+        self.banned.insert(ip.to_string(), Ban { reason: reason.to_string(), until: None });
+    }
+
+    pub fn is_banned(&self, ip: &str) -> bool {
+        self.is_banned_at(ip, Instant::now())
+    }
+
+    pub fn is_banned_at(&self, ip: &str, now: Instant) -> bool {
+        self.is_blocklisted(ip) || self.banned.get(ip).is_some_and(|ban| ban.until.is_none_or(|until| now < until))
+    }
+
+    pub fn reason(&self, ip: &str) -> Option<&str> {
+        match self.banned.get(ip) {
+            Some(ban) => Some(ban.reason.as_str()),
+            None if self.is_blocklisted(ip) => Some("on the blocklist"),
+            None => None,
+        }
+    }
+
+    // replaces the whole list, Blocklist::default() to have none
+    pub fn set_blocklist(&mut self, blocklist: Blocklist) {
+        self.blocklist = blocklist;
+    }
+
+    fn is_blocklisted(&self, ip: &str) -> bool {
+        !self.blocklist.is_empty() && ip.parse::<IpAddr>().is_ok_and(|ip| self.blocklist.contains(ip))
+    }
+
+    // counts a failed handshake. once an address has failed too many
+    // times in a row it is banned for the ttl. returns true if this
+    // failure got it banned.
+    pub fn record_failure(&mut self, ip: &str, reason: &str, now: Instant) -> bool {
+        self.banned.retain(|_, ban| ban.until.is_none_or(|until| now < until));
+
+        let failures = self.failures.entry(ip.to_string()).or_insert(0);
+        *failures += 1;
+        if *failures < self.max_failures {
+            return false;
+        }
+
+        self.failures.remove(ip);
+        // never shorten a ban that is already in place
+        if !self.banned.contains_key(ip) {
+            self.banned.insert(ip.to_string(), Ban { reason: reason.to_string(), until: Some(now + self.ttl) });
+        }
+        true
+    }
+
+    // the address managed a proper handshake, so forget its failures
+    pub fn record_success(&mut self, ip: &str) {
+        self.failures.remove(ip);
+    }
+
+    pub fn failures(&self, ip: &str) -> u32 {
+        self.failures.get(ip).copied().unwrap_or(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.banned.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.banned.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_ban() {
+        let mut bans = BanList::new();
+        bans.ban("10.0.0.1", "sent too much corrupt data");
+
+        assert!(bans.is_banned("10.0.0.1"));
+        assert!(bans.is_banned_at("10.0.0.1", Instant::now() + Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(bans.reason("10.0.0.1"), Some("sent too much corrupt data"));
+        assert!(!bans.is_banned("10.0.0.2"));
+    }
+
+    #[test]
+    fn test_failures_ban_for_ttl() {
+        let mut bans = BanList::with_limits(3, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(!bans.record_failure("10.0.0.1", "handshake failed", now));
+        assert!(!bans.record_failure("10.0.0.1", "handshake failed", now));
+        assert!(!bans.is_banned_at("10.0.0.1", now));
+
+        assert!(bans.record_failure("10.0.0.1", "handshake failed", now));
+        assert!(bans.is_banned_at("10.0.0.1", now + Duration::from_secs(59)));
+        assert!(!bans.is_banned_at("10.0.0.1", now + Duration::from_secs(60)));
+
+        // expired bans are dropped the next time something fails
+        bans.record_failure("10.0.0.2", "handshake failed", now + Duration::from_secs(61));
+        assert!(bans.is_empty());
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let mut bans = BanList::with_limits(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        bans.record_failure("10.0.0.1", "handshake failed", now);
+        bans.record_success("10.0.0.1");
+        assert_eq!(bans.failures("10.0.0.1"), 0);
+
+        assert!(!bans.record_failure("10.0.0.1", "handshake failed", now));
+        assert!(!bans.is_banned_at("10.0.0.1", now));
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let mut bans = BanList::new();
+        bans.set_blocklist(Blocklist::parse("10.0.0.0/24").unwrap());
+        assert!(bans.is_banned("10.0.0.7"));
+        assert_eq!(bans.reason("10.0.0.7"), Some("on the blocklist"));
+        assert!(!bans.is_banned("10.0.1.7"));
+        // the list isn't counted as bans of our own
+        assert!(bans.is_empty());
+
+        bans.set_blocklist(Blocklist::default());
+        assert!(!bans.is_banned("10.0.0.7"));
+    }
+
+    #[test]
+    fn test_ttl_never_shortens_session_ban() {
+        let mut bans = BanList::with_limits(1, Duration::from_secs(60));
+        let now = Instant::now();
+
+        bans.ban("10.0.0.1", "sent too much corrupt data");
+        bans.record_failure("10.0.0.1", "handshake failed", now);
+        assert!(bans.is_banned_at("10.0.0.1", now + Duration::from_secs(120)));
+        assert_eq!(bans.reason("10.0.0.1"), Some("sent too much corrupt data"));
+    }
+}
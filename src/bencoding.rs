@@ -1,12 +1,12 @@
 
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fmt};
 
 const LIST_INDICATOR: u8 = b'l';
 const INT_INDICATOR: u8 = b'i';
 const DICT_INDICATOR: u8 = b'd';
 const BYTES_INDICATOR: std::ops::RangeInclusive<u8> = b'0'..=b'9';
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Bencode {
     Int(i64),
     List(Vec<Bencode>),
@@ -14,6 +14,97 @@ pub enum Bencode {
     Dict(BTreeMap<Vec<u8>, Bencode>)
 }
 
+// why a value couldn't be had out of a dict with the get_* methods
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldError {
+    NotADict,
+    Missing { key: String },
+    WrongType { key: String, expected: &'static str },
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldError::NotADict => write!(f, "expected a dictionary"),
+            FieldError::Missing { key } => write!(f, "{} is missing", key),
+            FieldError::WrongType { key, expected } => write!(f, "{} is not {}", key, expected),
+        }
+    }
+}
+
+impl std::error::Error for FieldError {}
+
+impl Bencode {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Bencode::Int(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Bencode::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    // only if the bytes are utf-8
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Bencode]> {
+        match self {
+            Bencode::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Bencode>> {
+        match self {
+            Bencode::Dict(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    // the value under key, if this is a dict and has one
+    pub fn get(&self, key: &str) -> Option<&Bencode> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    // the get_* methods are get with an error saying what was wrong
+    pub fn field(&self, key: &str) -> Result<&Bencode, FieldError> {
+        let dict = self.as_dict().ok_or(FieldError::NotADict)?;
+        dict.get(key.as_bytes()).ok_or_else(|| FieldError::Missing { key: key.to_string() })
+    }
+
+    fn typed<'a, T>(&'a self, key: &str, expected: &'static str, f: impl FnOnce(&'a Bencode) -> Option<T>) -> Result<T, FieldError> {
+        f(self.field(key)?).ok_or_else(|| FieldError::WrongType { key: key.to_string(), expected })
+    }
+
+    pub fn get_int(&self, key: &str) -> Result<i64, FieldError> {
+        self.typed(key, "an integer", Bencode::as_int)
+    }
+
+    pub fn get_bytes(&self, key: &str) -> Result<&[u8], FieldError> {
+        self.typed(key, "a string", Bencode::as_bytes)
+    }
+
+    pub fn get_str(&self, key: &str) -> Result<&str, FieldError> {
+        self.typed(key, "utf-8 text", Bencode::as_str)
+    }
+
+    pub fn get_list(&self, key: &str) -> Result<&[Bencode], FieldError> {
+        self.typed(key, "a list", Bencode::as_list)
+    }
+
+    // the dict itself rather than its map, to get fields out of in turn
+    pub fn get_dict(&self, key: &str) -> Result<&Bencode, FieldError> {
+        self.typed(key, "a dictionary", |value| value.as_dict().map(|_| value))
+    }
+}
+
 pub mod encoder {
     use super::Bencode;
 
@@ -45,90 +136,563 @@ pub mod encoder {
 
 pub mod decoder {
     use super::{Bencode, BYTES_INDICATOR, DICT_INDICATOR, INT_INDICATOR, LIST_INDICATOR};
-    use std::{collections::BTreeMap, num::ParseIntError};
-
-    fn parse_int(input: &[u8]) -> Result<(Bencode, &[u8]), String> {
-        let string = String::from_utf8_lossy(input);
-        let end = string.find("e").ok_or("couldn't find end of input string")?;
-        
-        let num_str = &string[..end];
-    
+    use std::{collections::BTreeMap, fmt};
+
+    // lists and dicts nested deeper than this are refused. real torrents
+    // and tracker responses only go a few levels deep.
+    pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+    // what went wrong and where. offsets count bytes from the start of
+    // the input so the bad spot can be found with a hex dump.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum BencodeError {
+        // the input stopped while we were still expecting something
+        UnexpectedEnd { offset: usize, expected: &'static str },
+        UnexpectedByte { offset: usize, expected: &'static str, found: u8 },
+        // the string at offset claims more bytes than are left
+        TruncatedString { offset: usize, length: usize },
+        InvalidInteger { offset: usize, reason: String },
+        // a list or dict at offset went past the nesting limit
+        TooDeep { offset: usize, max: usize },
+        // strict mode only: the key at offset doesn't sort after the one
+        // before it, or is the same key again
+        UnsortedKey { offset: usize },
+        DuplicateKey { offset: usize },
+        // strict mode only: more input after the value, from offset
+        TrailingData { offset: usize },
+    }
+
+    impl BencodeError {
+        pub fn offset(&self) -> usize {
+            match self {
+                BencodeError::UnexpectedEnd { offset, .. }
+                | BencodeError::UnexpectedByte { offset, .. }
+                | BencodeError::TruncatedString { offset, .. }
+                | BencodeError::InvalidInteger { offset, .. }
+                | BencodeError::TooDeep { offset, .. }
+                | BencodeError::UnsortedKey { offset }
+                | BencodeError::DuplicateKey { offset }
+                | BencodeError::TrailingData { offset } => *offset,
+            }
+        }
+    }
+
+    impl fmt::Display for BencodeError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                BencodeError::UnexpectedEnd { offset, expected } => {
+                    write!(f, "bencode ends at byte {}, expected {}", offset, expected)
+                }
+                BencodeError::UnexpectedByte { offset, expected, found } => {
+                    write!(f, "expected {} at byte {}, found {:?} (0x{:02x})", expected, offset, *found as char, found)
+                }
+                BencodeError::TruncatedString { offset, length } => {
+                    write!(f, "string at byte {} is {} bytes long but the input ends first", offset, length)
+                }
+                BencodeError::InvalidInteger { offset, reason } => {
+                    write!(f, "invalid integer at byte {}: {}", offset, reason)
+                }
+                BencodeError::TooDeep { offset, max } => {
+                    write!(f, "bencode at byte {} is nested more than {} levels deep", offset, max)
+                }
+                BencodeError::UnsortedKey { offset } => {
+                    write!(f, "dictionary key at byte {} is out of order", offset)
+                }
+                BencodeError::DuplicateKey { offset } => {
+                    write!(f, "dictionary key at byte {} is there twice", offset)
+                }
+                BencodeError::TrailingData { offset } => {
+                    write!(f, "the value ends at byte {} but the input goes on", offset)
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for BencodeError {}
+
+    impl From<BencodeError> for String {
+        fn from(e: BencodeError) -> String {
+            e.to_string()
+        }
+    }
+
+    // pos is just past the 'i'
+    fn parse_int(data: &[u8], pos: usize, strict: bool) -> Result<(i64, usize), BencodeError> {
+        let end = data[pos..]
+            .iter()
+            .position(|&b| b == b'e')
+            .map(|i| pos + i)
+            .ok_or(BencodeError::UnexpectedEnd { offset: data.len(), expected: "'e' to end the integer" })?;
+
+        let num_str = String::from_utf8_lossy(&data[pos..end]);
+        let invalid = |reason: String| BencodeError::InvalidInteger { offset: pos, reason };
+
         if num_str.starts_with("0") && num_str.len() > 2 || num_str.starts_with("-0") && num_str.len() > 2 {
-            return Err("input string has leading zero".to_string())
-        }
-    
-        let number = num_str.parse::<i64>().map_err(|e| e.to_string())?;
-        let remaining = &input[end + 1..];
-    
-        Ok((Bencode::Int(number), remaining))
-    }
-    
-    fn parse_bytes(input: &[u8]) -> Result<(Bencode, &[u8]), String> {
-        let colon_pos = input.iter().position(|&x| x == b':').ok_or("couldn't find colon in input")?;
-        let len_bytes = &input[..colon_pos];
-        let len_str = String::from_utf8_lossy(len_bytes);
-        let length = len_str.parse::<usize>().map_err(|e: ParseIntError| e.to_string())?;
-    
-        let start = colon_pos + 1;
-        let end = start + length;
-    
-        if input.len() < end {
-            return Err("byte string is shorted than expected".to_string());
-        }
-    
-        let bytes = input[start..end].to_vec();
-        let remaining = &input[end..];
-        
-        Ok((Bencode::Bytes(bytes), remaining))  
-    }   
-    
-    fn parse_list(mut input: &[u8]) -> Result<(Bencode, &[u8]), String> {
-        let mut items = Vec::new();
-    
-        while let Some(&b) = input.first() {
-            if b == b'e' {
-                return Ok((Bencode::List(items), &input[1..]));
+            return Err(invalid("leading zero".to_string()));
+        }
+        // the check above lets "01" and "-0" through, which are out of
+        // spec but kept for the torrents out there that have them
+        if strict && (num_str.starts_with("0") && num_str.len() > 1 || num_str.starts_with("-0")) {
+            return Err(invalid("leading zero".to_string()));
+        }
+
+        let number = num_str.parse::<i64>().map_err(|e| invalid(format!("{:?}: {}", num_str, e)))?;
+        Ok((number, end + 1))
+    }
+
+    // pos is at the first digit of the length
+    fn parse_bytes(data: &[u8], pos: usize, strict: bool) -> Result<(&[u8], usize), BencodeError> {
+        let mut colon = pos;
+        loop {
+            match data.get(colon) {
+                Some(b':') if colon > pos => break,
+                Some(b) if b.is_ascii_digit() => colon += 1,
+                Some(&found) => return Err(BencodeError::UnexpectedByte { offset: colon, expected: "a string length", found }),
+                None => return Err(BencodeError::UnexpectedEnd { offset: colon, expected: "':' after the string length" }),
             }
-    
-            let (item, rest) = decode(input)?;
-            items.push(item);
-            input = rest;
-        }
-    
-        Err("unterminated list (missing e)".to_string())
-    }
-    
-    fn parse_dict(mut input: &[u8]) -> Result<(Bencode, &[u8]), String> {
-        let mut map:BTreeMap<Vec<u8>, Bencode> = BTreeMap::new();
-    
-        while let Some(&b) = input.first() {
-            if b == b'e' {
-                return Ok((Bencode::Dict(map), &input[1..]));
+        }
+
+        let length = std::str::from_utf8(&data[pos..colon])
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or_else(|| BencodeError::InvalidInteger { offset: pos, reason: "string length is too big".to_string() })?;
+        if strict && data[pos] == b'0' && colon > pos + 1 {
+            return Err(BencodeError::InvalidInteger { offset: pos, reason: "string length has a leading zero".to_string() });
+        }
+
+        let start = colon + 1;
+        let end = start
+            .checked_add(length)
+            .filter(|&end| end <= data.len())
+            .ok_or(BencodeError::TruncatedString { offset: pos, length })?;
+
+        Ok((&data[start..end], end))
+    }
+
+    // one step through the input. strings borrow from it, lists and
+    // dicts come as their start, their items (keys and values taking
+    // turns in a dict) and then End.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Token<'a> {
+        Int(i64),
+        Bytes(&'a [u8]),
+        List,
+        Dict,
+        End,
+    }
+
+    // a list or dict the reader is inside of
+    enum Open<'a> {
+        List,
+        // the last key read, and whether its value is next
+        Dict { last: Option<&'a [u8]>, value_next: bool },
+    }
+
+    // a pull decoder that hands out one Token at a time and never copies
+    // a string, for input too big to want as a Bencode tree, like a
+    // metainfo file with piece layers for thousands of files. it stops
+    // after the first value, whatever comes after is in rest(). it is as
+    // fussy as decode, or as decode_strict with strict(), apart from the
+    // trailing data.
+    pub struct Reader<'a> {
+        data: &'a [u8],
+        pos: usize,
+        open: Vec<Open<'a>>,
+        max_depth: usize,
+        strict: bool,
+        done: bool,
+    }
+
+    impl<'a> Reader<'a> {
+        pub fn new(data: &'a [u8]) -> Reader<'a> {
+            Reader::with_depth(data, DEFAULT_MAX_DEPTH)
+        }
+
+        pub fn with_depth(data: &'a [u8], max_depth: usize) -> Reader<'a> {
+            Reader { data, pos: 0, open: Vec::new(), max_depth, strict: false, done: false }
+        }
+
+        pub fn strict(mut self) -> Reader<'a> {
+            self.strict = true;
+            self
+        }
+
+        // where the next token starts
+        pub fn position(&self) -> usize {
+            self.pos
+        }
+
+        pub fn rest(&self) -> &'a [u8] {
+            &self.data[self.pos..]
+        }
+
+        // None once the first value is over. an error ends the reading
+        // too, every call after it gets None.
+        pub fn next_token(&mut self) -> Result<Option<Token<'a>>, BencodeError> {
+            if self.done {
+                return Ok(None);
+            }
+            let token = self.read();
+            if token.is_err() {
+                self.done = true;
             }
-    
-            let (key_bencode, rest) = parse_bytes(input)?;
-            let key = match key_bencode {
-                Bencode::Bytes(k) => k,
-                _ => return Err("dictionary key is not a byte string".to_string()),
+            token.map(Some)
+        }
+
+        fn read(&mut self) -> Result<Token<'a>, BencodeError> {
+            let (data, pos) = (self.data, self.pos);
+            let token = match (self.open.last_mut(), data.get(pos)) {
+                // end of the innermost list or dict
+                (Some(Open::List), Some(b'e')) | (Some(Open::Dict { value_next: false, .. }), Some(b'e')) => {
+                    self.open.pop();
+                    self.pos += 1;
+                    Token::End
+                }
+                (Some(Open::List), None) => {
+                    return Err(BencodeError::UnexpectedEnd { offset: pos, expected: "a list item or 'e'" });
+                }
+                (Some(Open::Dict { value_next: false, .. }), None) => {
+                    return Err(BencodeError::UnexpectedEnd { offset: pos, expected: "a dictionary key or 'e'" });
+                }
+                (Some(Open::Dict { value_next: true, .. }), None) => {
+                    return Err(BencodeError::UnexpectedEnd { offset: pos, expected: "a dictionary value" });
+                }
+                (Some(Open::Dict { last, value_next: value_next @ false }), Some(&found)) => {
+                    if !BYTES_INDICATOR.contains(&found) {
+                        return Err(BencodeError::UnexpectedByte { offset: pos, expected: "a string dictionary key", found });
+                    }
+                    let (key, next) = parse_bytes(data, pos, self.strict)?;
+                    match last {
+                        Some(last) if self.strict && *last == key => return Err(BencodeError::DuplicateKey { offset: pos }),
+                        Some(last) if self.strict && *last > key => return Err(BencodeError::UnsortedKey { offset: pos }),
+                        _ => {}
+                    }
+                    *last = Some(key);
+                    *value_next = true;
+                    self.pos = next;
+                    // a key isn't a whole value, the dict still wants one
+                    return Ok(Token::Bytes(key));
+                }
+                (_, Some(&found @ (LIST_INDICATOR | DICT_INDICATOR))) => {
+                    if self.open.len() >= self.max_depth {
+                        return Err(BencodeError::TooDeep { offset: pos, max: self.max_depth });
+                    }
+                    self.open.push(if found == LIST_INDICATOR { Open::List } else { Open::Dict { last: None, value_next: false } });
+                    self.pos += 1;
+                    return Ok(if found == LIST_INDICATOR { Token::List } else { Token::Dict });
+                }
+                (_, Some(&INT_INDICATOR)) => {
+                    let (number, next) = parse_int(data, pos + 1, self.strict)?;
+                    self.pos = next;
+                    Token::Int(number)
+                }
+                (_, Some(b)) if BYTES_INDICATOR.contains(b) => {
+                    let (bytes, next) = parse_bytes(data, pos, self.strict)?;
+                    self.pos = next;
+                    Token::Bytes(bytes)
+                }
+                (_, Some(&found)) => {
+                    return Err(BencodeError::UnexpectedByte { offset: pos, expected: "'i', 'l', 'd' or a string length", found });
+                }
+                (None, None) => return Err(BencodeError::UnexpectedEnd { offset: pos, expected: "a value" }),
             };
-            input = rest;
-    
-            let (value, rest) = decode(input)?;
-            input = rest;
-    
-            map.insert(key, value);
-        }
-    
-        Err("unterminated dictionary (missing e)".to_string())
-    }
-    
-    pub fn decode(input: &[u8]) -> Result<(Bencode, &[u8]), String> {
-        match input.first() {
-            Some(&INT_INDICATOR) => parse_int(&input[1..]),
-            Some(&LIST_INDICATOR) => parse_list(&input[1..]),
-            Some(&DICT_INDICATOR) => parse_dict(&input[1..]),
-            Some(&b) if BYTES_INDICATOR.contains(&b) => parse_bytes(input),
-            _ => Err("invalid bencode type".to_string())
-        }
-    }
-}
\ No newline at end of file
+
+            // a whole value is done
+            match self.open.last_mut() {
+                None => self.done = true,
+                Some(Open::Dict { value_next, .. }) => *value_next = false,
+                Some(Open::List) => {}
+            }
+            Ok(token)
+        }
+
+        // steps over the next value, a list or dict with everything in
+        // it, and returns the bytes it was written as
+        pub fn skip(&mut self) -> Result<&'a [u8], BencodeError> {
+            let start = self.pos;
+            let mut depth = 0usize;
+            loop {
+                match self.next_token()? {
+                    Some(Token::List | Token::Dict) => depth += 1,
+                    Some(Token::End) if depth == 0 => {
+                        self.done = true;
+                        return Err(BencodeError::UnexpectedByte { offset: start, expected: "a value", found: b'e' });
+                    }
+                    Some(Token::End) => depth -= 1,
+                    Some(Token::Int(_) | Token::Bytes(_)) => {}
+                    None => return Err(BencodeError::UnexpectedEnd { offset: self.pos, expected: "a value" }),
+                }
+                if depth == 0 {
+                    return Ok(&self.data[start..self.pos]);
+                }
+            }
+        }
+    }
+
+    pub fn decode(input: &[u8]) -> Result<(Bencode, &[u8]), BencodeError> {
+        decode_with_depth(input, DEFAULT_MAX_DEPTH)
+    }
+
+    // decode for checking input is bencode to the letter, e.g. torrents
+    // we made ourselves. on top of what decode refuses, so is anything
+    // it lets through for the sake of other clients' sloppy encoders:
+    // dict keys out of order or repeated, integers and string lengths
+    // with leading zeros, "-0", and anything after the value. negative
+    // string lengths are refused either way.
+    pub fn decode_strict(input: &[u8]) -> Result<Bencode, BencodeError> {
+        let (value, rest) = decode_from(Reader::new(input).strict())?;
+        if !rest.is_empty() {
+            return Err(BencodeError::TrailingData { offset: input.len() - rest.len() });
+        }
+        Ok(value)
+    }
+
+    // the bytes of the value under key in the dict data starts with,
+    // exactly as they were written. decoding and encoding again gives
+    // the same bytes only if they were encoded the canonical way, with
+    // sorted keys and no repeats, which not every torrent maker manages.
+    // for a key that is there more than once this is the last one, the
+    // one decode keeps.
+    pub fn raw_value<'a>(data: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>, BencodeError> {
+        match data.first() {
+            Some(&DICT_INDICATOR) => {}
+            Some(&found) => return Err(BencodeError::UnexpectedByte { offset: 0, expected: "a dictionary", found }),
+            None => return Err(BencodeError::UnexpectedEnd { offset: 0, expected: "a dictionary" }),
+        }
+
+        let mut reader = Reader::new(data);
+        reader.next_token()?;
+        let mut found = None;
+        while let Some(Token::Bytes(k)) = reader.next_token()? {
+            let value = reader.skip()?;
+            if k == key {
+                found = Some(value);
+            }
+        }
+        Ok(found)
+    }
+
+    // a list or dict we are still in the middle of
+    enum Frame {
+        List(Vec<Bencode>),
+        // the key is Some once it has been read and its value hasn't
+        Dict(BTreeMap<Vec<u8>, Bencode>, Option<Vec<u8>>),
+    }
+
+    // builds the tree with an explicit stack of the lists and dicts that
+    // are open, so hostile nesting can't blow the call stack
+    pub fn decode_with_depth(data: &[u8], max_depth: usize) -> Result<(Bencode, &[u8]), BencodeError> {
+        decode_from(Reader::with_depth(data, max_depth))
+    }
+
+    fn decode_from<'a>(mut reader: Reader<'a>) -> Result<(Bencode, &'a [u8]), BencodeError> {
+        let mut stack: Vec<Frame> = Vec::new();
+
+        loop {
+            let Some(token) = reader.next_token()? else { unreachable!("the reader ends after a value") };
+            let value = match token {
+                Token::List => {
+                    stack.push(Frame::List(Vec::new()));
+                    continue;
+                }
+                Token::Dict => {
+                    stack.push(Frame::Dict(BTreeMap::new(), None));
+                    continue;
+                }
+                Token::End => match stack.pop() {
+                    Some(Frame::List(items)) => Bencode::List(items),
+                    Some(Frame::Dict(map, _)) => Bencode::Dict(map),
+                    None => unreachable!(),
+                },
+                Token::Bytes(key) if matches!(stack.last(), Some(Frame::Dict(_, None))) => {
+                    if let Some(Frame::Dict(_, k)) = stack.last_mut() {
+                        *k = Some(key.to_vec());
+                    }
+                    continue;
+                }
+                Token::Bytes(bytes) => Bencode::Bytes(bytes.to_vec()),
+                Token::Int(number) => Bencode::Int(number),
+            };
+
+            match stack.last_mut() {
+                None => return Ok((value, reader.rest())),
+                Some(Frame::List(items)) => items.push(value),
+                Some(Frame::Dict(map, key)) => {
+                    if let Some(key) = key.take() {
+                        map.insert(key, value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decoder::*, encoder, Bencode, FieldError};
+
+    #[test]
+    fn test_round_trip() {
+        let input = b"d4:listli1ei-2e3:abce4:nestd1:ad1:bleee3:numi42ee";
+        let (value, rest) = decode(input).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(encoder::encode(&value), input);
+
+        // whatever follows the value is handed back
+        let (value, rest) = decode(b"4:spamtrailing").unwrap();
+        assert!(matches!(value, Bencode::Bytes(b) if b == b"spam"));
+        assert_eq!(rest, b"trailing");
+    }
+
+    #[test]
+    fn test_error_offsets() {
+        let cases: &[(&[u8], usize)] = &[
+            (b"", 0),
+            (b"x", 0),
+            (b"i12", 3),
+            (b"l", 1),
+            (b"li1e", 4),
+            (b"d", 1),
+            (b"d3:key", 6),
+            (b"di1ei2ee", 1),
+            (b"5:abc", 0),
+            (b"iabce", 1),
+            (b"l4x:spame", 2),
+            (b"d3:keyi1e3:fooxe", 14),
+        ];
+
+        for (input, offset) in cases {
+            let e = decode(input).unwrap_err();
+            assert_eq!(e.offset(), *offset, "{:?}: {}", String::from_utf8_lossy(input), e);
+        }
+    }
+
+    #[test]
+    fn test_error_context() {
+        assert_eq!(decode(b"d3:keyi1e3:fooxe").unwrap_err(), BencodeError::UnexpectedByte {
+            offset: 14,
+            expected: "'i', 'l', 'd' or a string length",
+            found: b'x',
+        });
+        assert_eq!(decode(b"l5:abce").unwrap_err(), BencodeError::TruncatedString { offset: 1, length: 5 });
+        assert_eq!(
+            decode(b"di1ei2ee").unwrap_err().to_string(),
+            "expected a string dictionary key at byte 1, found 'i' (0x69)"
+        );
+    }
+
+    #[test]
+    fn test_depth_limit() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+
+        assert!(decode(&nested(DEFAULT_MAX_DEPTH)).is_ok());
+        assert_eq!(
+            decode(&nested(DEFAULT_MAX_DEPTH + 1)).unwrap_err(),
+            BencodeError::TooDeep { offset: DEFAULT_MAX_DEPTH, max: DEFAULT_MAX_DEPTH }
+        );
+        assert_eq!(decode_with_depth(b"ld1:alleee", 2).unwrap_err(), BencodeError::TooDeep { offset: 5, max: 2 });
+
+        // the parse itself doesn't use the call stack however deep it goes
+        assert!(matches!(decode_with_depth(&nested(10_000), usize::MAX), Ok((Bencode::List(_), _))));
+    }
+
+    #[test]
+    fn test_strict() {
+        let canonical = b"d1:ai1e1:bli-3e0:e1:cd1:di0eee";
+        assert_eq!(decode_strict(canonical).unwrap(), decode(canonical).unwrap().0);
+
+        // all of these get past decode
+        let cases: &[(&[u8], BencodeError)] = &[
+            (b"d1:bi1e1:ai2ee", BencodeError::UnsortedKey { offset: 7 }),
+            (b"d1:ai1e1:ai2ee", BencodeError::DuplicateKey { offset: 7 }),
+            (b"i01e", BencodeError::InvalidInteger { offset: 1, reason: "leading zero".to_string() }),
+            (b"i-0e", BencodeError::InvalidInteger { offset: 1, reason: "leading zero".to_string() }),
+            (b"02:ab", BencodeError::InvalidInteger { offset: 0, reason: "string length has a leading zero".to_string() }),
+            (b"i1ei2e", BencodeError::TrailingData { offset: 3 }),
+        ];
+        for (input, error) in cases {
+            assert!(decode(input).is_ok());
+            assert_eq!(&decode_strict(input).unwrap_err(), error, "{:?}", String::from_utf8_lossy(input));
+        }
+
+        // and these don't in either mode
+        assert!(decode(b"l-3:abce").is_err() && decode_strict(b"l-3:abce").is_err());
+        assert!(decode_strict(b"i0e").is_ok() && decode_strict(b"0:").is_ok());
+    }
+
+    fn tokens<'a>(reader: &mut Reader<'a>) -> Result<Vec<Token<'a>>, BencodeError> {
+        let mut tokens = Vec::new();
+        while let Some(token) = reader.next_token()? {
+            tokens.push(token);
+        }
+        Ok(tokens)
+    }
+
+    #[test]
+    fn test_reader() {
+        let input = b"d4:listli1e3:abce3:numi42ee4:more";
+        let mut reader = Reader::new(input);
+        assert_eq!(tokens(&mut reader).unwrap(), [
+            Token::Dict,
+            Token::Bytes(b"list"),
+            Token::List,
+            Token::Int(1),
+            Token::Bytes(b"abc"),
+            Token::End,
+            Token::Bytes(b"num"),
+            Token::Int(42),
+            Token::End,
+        ]);
+        assert_eq!(reader.rest(), b"4:more");
+
+        // strings are the input's own bytes
+        let Ok(Some(Token::Bytes(bytes))) = Reader::new(&input[1..]).next_token() else { panic!("not a string") };
+        assert!(input.as_ptr_range().contains(&bytes.as_ptr()));
+
+        let mut reader = Reader::new(input);
+        reader.next_token().unwrap();
+        reader.next_token().unwrap();
+        assert_eq!(reader.skip().unwrap(), b"li1e3:abce");
+        assert_eq!(reader.next_token().unwrap(), Some(Token::Bytes(b"num")));
+        assert_eq!(reader.position(), 22);
+
+        // the same errors as decode, after which there is nothing more
+        for input in [&b"li1ex"[..], b"d3:key", b"di1ei2ee", b"l4x:spame"] {
+            let mut reader = Reader::new(input);
+            assert_eq!(tokens(&mut reader).unwrap_err(), decode(input).unwrap_err());
+            assert_eq!(reader.next_token(), Ok(None));
+        }
+        assert_eq!(tokens(&mut Reader::new(b"d1:bi1e1:ai2ee").strict()).unwrap_err(), BencodeError::UnsortedKey { offset: 7 });
+        assert!(Reader::new(b"le").skip().is_ok());
+        assert!(Reader::new(b"e").skip().is_err());
+    }
+
+    #[test]
+    fn test_accessors() {
+        let (value, _) = decode(b"d3:agei-3e4:infod4:name3:abce4:listli1ee3:raw2:\xff\xfee").unwrap();
+        assert_eq!(value.get_int("age"), Ok(-3));
+        assert_eq!(value.get_dict("info").and_then(|info| info.get_str("name")), Ok("abc"));
+        assert_eq!(value.get_list("list").unwrap()[0].as_int(), Some(1));
+        assert_eq!(value.get_bytes("raw"), Ok(&b"\xff\xfe"[..]));
+        assert!(value.get("nope").is_none());
+
+        assert_eq!(value.get_str("raw"), Err(FieldError::WrongType { key: "raw".to_string(), expected: "utf-8 text" }));
+        assert_eq!(value.get_int("nope").unwrap_err().to_string(), "nope is missing");
+        assert_eq!(value.get_dict("age").unwrap_err().to_string(), "age is not a dictionary");
+        assert_eq!(Bencode::Int(1).get_int("age"), Err(FieldError::NotADict));
+    }
+
+    #[test]
+    fn test_raw_value() {
+        // keys out of order, which encoding again would sort
+        let data = b"d4:infod1:bi1e1:ai2ee3:numi42e4:infoi7ee";
+        assert_eq!(raw_value(data, b"num").unwrap(), Some(&b"i42e"[..]));
+        assert_eq!(raw_value(data, b"info").unwrap(), Some(&b"i7e"[..]));
+        assert_eq!(raw_value(b"d4:infod1:bi1e1:ai2eee", b"info").unwrap(), Some(&b"d1:bi1e1:ai2ee"[..]));
+        assert_eq!(raw_value(data, b"nope").unwrap(), None);
+
+        assert_eq!(raw_value(b"li1ee", b"info").unwrap_err().offset(), 0);
+        // offsets are into the whole input
+        assert_eq!(raw_value(b"d4:infoi1x", b"info").unwrap_err().offset(), 10);
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::debug;
+use serde::Serialize;
+
+use crate::gzip;
+
+// ranges of addresses we never talk to, downloaded from a list like the
+// ones iblocklist serves and checked by BanList along with its own bans.
+// the list is fetched again every UPDATE_INTERVAL and swapped in whole,
+// a download that fails keeps the list we had.
+//
+// lines can be in either of the usual formats, gzipped or not:
+//
+//   p2p:  some organization:1.2.3.0-1.2.3.255
+//   dat:  001.002.003.000 - 001.002.003.255 , 000 , some organization
+//
+// or a plain address, a range of them, or a cidr block like 10.0.0.0/8.
+// dat entries with an access level above 127 are allowed rather than
+// blocked, as emule has it. lines starting with # are comments.
+
+pub const UPDATE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+// sooner after a download that failed
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+// the big lists are a few tens of megabytes unpacked
+const MAX_SIZE: usize = 256 << 20;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Blocklist {
+    // sorted and with overlaps merged, so a lookup is a binary search
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+    entries: usize,
+}
+
+impl Blocklist {
+    pub fn parse(text: &str) -> Result<Blocklist, String> {
+        let mut list = Blocklist::default();
+        let mut bad = 0;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(None) => {}
+                Some(Some((IpAddr::V4(start), IpAddr::V4(end)))) => list.v4.push((start.into(), end.into())),
+                Some(Some((IpAddr::V6(start), IpAddr::V6(end)))) => list.v6.push((start.into(), end.into())),
+                _ => bad += 1,
+            }
+        }
+        if bad > 0 && list.v4.is_empty() && list.v6.is_empty() {
+            return Err("nothing in it looks like a blocklist entry".to_string());
+        }
+        if bad > 0 {
+            debug!("skipped {} blocklist lines that couldn't be read", bad);
+        }
+
+        list.entries = list.v4.len() + list.v6.len();
+        merge(&mut list.v4);
+        merge(&mut list.v6);
+        Ok(list)
+    }
+
+    // ipv4 addresses mapped into ipv6 are checked as the ipv4 address
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => covers(&self.v4, ip.into()),
+            IpAddr::V6(ip) => covers(&self.v6, ip.into()),
+        }
+    }
+
+    // the entries the list was made from, before overlapping ones were
+    // merged
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+}
+
+// Some(None) for a dat entry that allows its range
+fn parse_line(line: &str) -> Option<Option<(IpAddr, IpAddr)>> {
+    if let Some((range, rest)) = line.split_once(',') {
+        if let Some(range) = parse_range(range.trim()) {
+            let level = rest.split(',').next()?.trim().parse::<u32>().ok()?;
+            return Some((level <= 127).then_some(range));
+        }
+    }
+    // the description in a p2p line can have colons of its own
+    parse_range(line).or_else(|| parse_range(line.rsplit_once(':')?.1.trim())).map(Some)
+}
+
+fn parse_range(range: &str) -> Option<(IpAddr, IpAddr)> {
+    if let Some((start, end)) = range.split_once('-') {
+        let (start, end) = (parse_ip(start.trim())?, parse_ip(end.trim())?);
+        return match (start, end) {
+            (IpAddr::V4(s), IpAddr::V4(e)) if s <= e => Some((start, end)),
+            (IpAddr::V6(s), IpAddr::V6(e)) if s <= e => Some((start, end)),
+            _ => None,
+        };
+    }
+    if let Some((ip, bits)) = range.split_once('/') {
+        let bits = bits.parse::<u32>().ok()?;
+        return match parse_ip(ip)? {
+            IpAddr::V4(ip) if bits <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+                let start = u32::from(ip) & mask;
+                Some((IpAddr::V4(start.into()), IpAddr::V4((start | !mask).into())))
+            }
+            IpAddr::V6(ip) if bits <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+                let start = u128::from(ip) & mask;
+                Some((IpAddr::V6(start.into()), IpAddr::V6((start | !mask).into())))
+            }
+            _ => None,
+        };
+    }
+    let ip = parse_ip(range)?;
+    Some((ip, ip))
+}
+
+// dat files pad ipv4 addresses with zeros, 001.002.003.004, which the
+// standard parser won't take
+fn parse_ip(ip: &str) -> Option<IpAddr> {
+    if ip.contains(':') {
+        return ip.parse().ok();
+    }
+    let mut octets = [0u8; 4];
+    let mut parts = ip.split('.');
+    for octet in &mut octets {
+        let part = parts.next()?;
+        if part.is_empty() || part.len() > 3 || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        *octet = part.parse().ok()?;
+    }
+    parts.next().is_none().then(|| IpAddr::V4(Ipv4Addr::from(octets)))
+}
+
+fn merge<T: Ord + Copy>(ranges: &mut Vec<(T, T)>) {
+    ranges.sort_unstable();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn covers<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let after = ranges.partition_point(|&(start, _)| start <= ip);
+    after > 0 && ip <= ranges[after - 1].1
+}
+
+pub async fn download(client: &reqwest::Client, url: &str) -> Result<Blocklist, String> {
+    let response = client.get(url).timeout(DOWNLOAD_TIMEOUT).send().await.map_err(|e| e.to_string())?;
+    let body = response.error_for_status().map_err(|e| e.to_string())?.bytes().await.map_err(|e| e.to_string())?;
+    if body.len() > MAX_SIZE {
+        return Err(format!("{} is more than {} bytes", url, MAX_SIZE));
+    }
+    let body = if gzip::is_gzip(&body) { gzip::decompress(&body, MAX_SIZE)? } else { body.to_vec() };
+    Blocklist::parse(&String::from_utf8_lossy(&body))
+}
+
+// how keeping the list up to date is going, for the rpc api
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BlocklistStatus {
+    pub url: Option<String>,
+    // unix time of the last download that worked, and how many entries
+    // it had
+    pub updated: Option<u64>,
+    pub entries: usize,
+    // why the last download didn't work, until one does
+    pub error: Option<String>,
+}
+
+impl BlocklistStatus {
+    pub fn record_update(&mut self, list: &Blocklist, now: SystemTime) {
+        self.updated = Some(now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+        self.entries = list.len();
+        self.error = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let list = Blocklist::parse(
+            "# a comment\n\
+             Some Org, Inc: with a colon:1.2.3.0-1.2.3.255\n\
+             001.002.004.000 - 001.002.004.255 , 000 , dat entry\n\
+             010.000.000.000 - 010.255.255.255 , 200 , allowed\n\
+             192.168.0.0/16\n\
+             2001:db8::/32\n\
+             8.8.8.8\n\
+             not an entry\n",
+        )
+        .unwrap();
+        assert_eq!(list.len(), 5);
+
+        for blocked in ["1.2.3.0", "1.2.3.200", "1.2.4.255", "192.168.77.1", "8.8.8.8", "2001:db8::1", "::ffff:1.2.3.4"] {
+            assert!(list.contains(blocked.parse().unwrap()), "{}", blocked);
+        }
+        for allowed in ["1.2.2.255", "1.2.5.0", "10.0.0.1", "8.8.8.9", "2001:db9::1"] {
+            assert!(!list.contains(allowed.parse().unwrap()), "{}", allowed);
+        }
+
+        assert!(Blocklist::parse("<html>not found</html>").is_err());
+        assert!(Blocklist::parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_merge() {
+        let list = Blocklist::parse("a:1.0.0.0-1.0.0.10\nb:1.0.0.5-1.0.0.20\nc:1.0.0.30-1.0.0.40\n1.0.0.12").unwrap();
+        assert_eq!(list.v4, [(0x01000000, 0x01000014), (0x0100001e, 0x01000028)]);
+        assert_eq!(list.len(), 4);
+    }
+}
@@ -0,0 +1,364 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::{
+    bencoding::{decoder, encoder, Bencode},
+    error::BtError,
+    fastresume::{self, FastResume},
+    info_hash::InfoHash,
+    protocol::{expand_bitfield, pack_bitfield},
+    torrent::{parse_torrent, Torrent},
+};
+
+// everything needed to carry a torrent over to another machine: the
+// metadata, which pieces are done and the transfer totals so far. the
+// data itself is copied across separately, into the download directory
+// of the session it is imported into.
+//
+// the bundle is a bencoded dict:
+//   version     FORMAT_VERSION when it was written
+//   info hash   the torrent's real info hash
+//   torrent     the .torrent, see Torrent::to_bytes
+//   have        packed bitfield of the pieces we have
+//   downloaded  bytes downloaded so far
+//   uploaded    bytes uploaded so far
+//   crc         crc-32 of the dict encoded without this key
+//
+// keys we don't know about are kept and written back out, so a bundle
+// from a newer client survives a trip through an older one. bundles
+// from before the version key was added count as version 0 and have
+// no crc to check.
+
+pub const FORMAT_VERSION: i64 = 1;
+
+const KNOWN_KEYS: [&str; 7] = ["version", "info hash", "torrent", "have", "downloaded", "uploaded", "crc"];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bundle {
+    pub info_hash: InfoHash,
+    pub metainfo: Vec<u8>,
+    pub resume: ResumeState,
+    // keys written by some other version that we pass along untouched
+    pub extra: BTreeMap<Vec<u8>, Bencode>,
+}
+
+// how far a torrent had got
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResumeState {
+    // one byte per piece, like a peer bitfield
+    pub have: Vec<u8>,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+impl ResumeState {
+    pub fn is_complete(&self) -> bool {
+        !self.have.is_empty() && self.have.iter().all(|&b| b != 0)
+    }
+
+    pub fn pieces(&self) -> Vec<usize> {
+        (0..self.have.len()).filter(|&i| self.have[i] != 0).collect()
+    }
+
+    // what a session keeps for a torrent between runs: a bundle without
+    // the torrent itself, which the session already has
+    pub fn encode(&self, info_hash: &InfoHash) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"info hash".to_vec(), Bencode::Bytes(info_hash.as_bytes().to_vec()));
+        dict.insert(b"have".to_vec(), Bencode::Bytes(pack_bitfield(&self.have)));
+        dict.insert(b"downloaded".to_vec(), Bencode::Int(self.downloaded as i64));
+        dict.insert(b"uploaded".to_vec(), Bencode::Int(self.uploaded as i64));
+
+        let crc = crc32(&encoder::encode(&Bencode::Dict(dict.clone())));
+        dict.insert(b"crc".to_vec(), Bencode::Int(crc as i64));
+        encoder::encode(&Bencode::Dict(dict))
+    }
+
+    pub fn decode(data: &[u8], info_hash: &InfoHash, num_pieces: usize) -> Result<ResumeState, String> {
+        let mut dict = match decoder::decode(data)? {
+            (Bencode::Dict(dict), _) => dict,
+            _ => return Err("resume data is not a dict".to_string()),
+        };
+
+        let crc = match dict.remove(&b"crc"[..]) {
+            Some(Bencode::Int(crc)) => crc,
+            _ => return Err("resume data is missing its crc".to_string()),
+        };
+        if crc != crc32(&encoder::encode(&Bencode::Dict(dict.clone()))) as i64 {
+            return Err("resume data crc doesn't match, it may be damaged".to_string());
+        }
+
+        match dict.get(&b"info hash"[..]) {
+            Some(Bencode::Bytes(hash)) if hash.as_slice() == info_hash.as_bytes() => {}
+            _ => return Err("resume data is for another torrent".to_string()),
+        }
+        let int = |key: &str| match dict.get(key.as_bytes()) {
+            Some(Bencode::Int(i)) if *i >= 0 => Ok(*i as u64),
+            _ => Err(format!("resume data is missing {}", key)),
+        };
+        let have = match dict.get(&b"have"[..]) {
+            Some(Bencode::Bytes(have)) => expand_bitfield(have, num_pieces).map_err(|e| e.to_string())?,
+            _ => return Err("resume data is missing have".to_string()),
+        };
+
+        Ok(ResumeState { have, downloaded: int("downloaded")?, uploaded: int("uploaded")? })
+    }
+}
+
+// every bundle in the file at path: one of ours, or each torrent of a
+// libtorrent .fastresume with its .torrent found next to it if the info
+// dict isn't in it
+pub fn read(path: &Path) -> Result<Vec<Bundle>, String> {
+    let data = fs::read(path).map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+    if !fastresume::is_fastresume(path) {
+        return Ok(vec![Bundle::decode(&data)?]);
+    }
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    FastResume::decode_all(&data)?
+        .iter()
+        .map(|resume| {
+            let metainfo = fs::read(resume.torrent_path(dir)).or_else(|_| fs::read(path.with_extension("torrent"))).ok();
+            resume.to_bundle(metainfo.as_deref())
+        })
+        .collect()
+}
+
+// crc-32 as used by zip and ethernet (reflected, polynomial 0xEDB88320)
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+impl Bundle {
+    pub fn new(info_hash: InfoHash, metainfo: Vec<u8>, resume: ResumeState) -> Bundle {
+        Bundle { info_hash, metainfo, resume, extra: BTreeMap::new() }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = self.extra.clone();
+        dict.insert(b"version".to_vec(), Bencode::Int(FORMAT_VERSION));
+        dict.insert(b"info hash".to_vec(), Bencode::Bytes(self.info_hash.as_bytes().to_vec()));
+        dict.insert(b"torrent".to_vec(), Bencode::Bytes(self.metainfo.clone()));
+        dict.insert(b"have".to_vec(), Bencode::Bytes(pack_bitfield(&self.resume.have)));
+        dict.insert(b"downloaded".to_vec(), Bencode::Int(self.resume.downloaded as i64));
+        dict.insert(b"uploaded".to_vec(), Bencode::Int(self.resume.uploaded as i64));
+
+        let crc = crc32(&encoder::encode(&Bencode::Dict(dict.clone())));
+        dict.insert(b"crc".to_vec(), Bencode::Int(crc as i64));
+        encoder::encode(&Bencode::Dict(dict))
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Bundle, String> {
+        let mut dict = match decoder::decode(data)? {
+            (Bencode::Dict(dict), _) => dict,
+            _ => return Err("bundle is not a dict".to_string()),
+        };
+
+        let version = match dict.get(&b"version"[..]) {
+            Some(Bencode::Int(v)) => *v,
+            Some(_) => return Err("bundle version is not a number".to_string()),
+            None => 0,
+        };
+        if version > FORMAT_VERSION {
+            return Err(format!("bundle is format version {}, this client only reads up to {}", version, FORMAT_VERSION));
+        }
+
+        // the crc covers everything else, so check it before reading any
+        // of it. dicts always encode with their keys sorted, which makes
+        // the re-encoding match what was written.
+        if version >= 1 {
+            let crc = match dict.remove(&b"crc"[..]) {
+                Some(Bencode::Int(crc)) => crc,
+                _ => return Err("bundle is missing its crc".to_string()),
+            };
+            let actual = crc32(&encoder::encode(&Bencode::Dict(dict.clone())));
+            if crc != actual as i64 {
+                return Err(format!("bundle crc doesn't match (expected {:08x}, got {:08x}), it may be damaged", crc, actual));
+            }
+        }
+
+        let bytes = |key: &str| match dict.get(key.as_bytes()) {
+            Some(Bencode::Bytes(b)) => Ok(b.clone()),
+            _ => Err(format!("bundle is missing {}", key)),
+        };
+        let int = |key: &str| match dict.get(key.as_bytes()) {
+            Some(Bencode::Int(i)) if *i >= 0 => Ok(*i as u64),
+            _ => Err(format!("bundle is missing {}", key)),
+        };
+
+        let mut bundle = Bundle::new(
+            InfoHash::from_bytes(&bytes("info hash")?)?,
+            bytes("torrent")?,
+            ResumeState {
+                have: Vec::new(),
+                downloaded: int("downloaded")?,
+                uploaded: int("uploaded")?,
+            },
+        );
+
+        // the bitfield can only be unpacked once we know the piece count
+        let num_pieces = bundle.torrent()?.pieces.len();
+        bundle.resume.have = expand_bitfield(&bytes("have")?, num_pieces).map_err(|e| e.to_string())?;
+
+        dict.retain(|key, _| !KNOWN_KEYS.iter().any(|k| k.as_bytes() == key.as_slice()));
+        bundle.extra = dict;
+
+        Ok(bundle)
+    }
+
+    // the torrent the bundle is for, with its original info hash
+    pub fn torrent(&self) -> Result<Torrent, BtError> {
+        let mut torrent = parse_torrent(&self.metainfo)?;
+        torrent.info_hash = self.info_hash;
+        Ok(torrent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{encode_metainfo, File};
+
+    fn test_torrent() -> Torrent {
+        Torrent {
+            info_hash: InfoHash::new([0xAB; 20]),
+            announce: "http://tracker.example/announce".to_string(),
+            multi_file: false,
+            piece_length: 16,
+            total_size: 40,
+            pieces: vec![[1; 20], [2; 20], [3; 20]],
+            output_file: "/data/file.bin".to_string(),
+            files: vec![File::new("file.bin".to_string(), 40).with_md5sum(Some("0123456789abcdef0123456789abcdef".to_string()))],
+            comment: Some("a comment".to_string()),
+            creation_date: Some(1700000000),
+            ..Default::default()
+        }
+    }
+
+    fn test_bundle(have: Vec<u8>) -> Bundle {
+        let resume = ResumeState { have, downloaded: 32, uploaded: 1000 };
+        Bundle::new(InfoHash::new([0xAB; 20]), encode_metainfo(&test_torrent()).unwrap(), resume)
+    }
+
+    // decodes an encoded bundle, lets f change it and encodes it again
+    // with a crc that matches, or none if f took the version out
+    fn rewrite(encoded: &[u8], f: impl FnOnce(&mut BTreeMap<Vec<u8>, Bencode>)) -> Vec<u8> {
+        let Ok((Bencode::Dict(mut dict), _)) = decoder::decode(encoded) else { panic!("not a dict") };
+        dict.remove(&b"crc"[..]);
+        f(&mut dict);
+        if dict.contains_key(&b"version"[..]) {
+            let crc = crc32(&encoder::encode(&Bencode::Dict(dict.clone())));
+            dict.insert(b"crc".to_vec(), Bencode::Int(crc as i64));
+        }
+        encoder::encode(&Bencode::Dict(dict))
+    }
+
+    #[test]
+    fn test_resume_data() {
+        let resume = ResumeState { have: vec![1, 0, 1], downloaded: 32, uploaded: 1000 };
+        let hash = InfoHash::new([0xAB; 20]);
+        let encoded = resume.encode(&hash);
+        assert_eq!(ResumeState::decode(&encoded, &hash, 3).unwrap(), resume);
+
+        assert!(ResumeState::decode(&encoded, &InfoHash::new([0xCD; 20]), 3).is_err());
+        let mut damaged = encoded.clone();
+        let at = damaged.len() - 20;
+        damaged[at] ^= 1;
+        assert!(ResumeState::decode(&damaged, &hash, 3).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bundle = test_bundle(vec![1, 0, 1]);
+
+        let decoded = Bundle::decode(&bundle.encode()).unwrap();
+        assert_eq!(decoded, bundle);
+
+        let torrent = decoded.torrent().unwrap();
+        assert_eq!(torrent.info_hash, InfoHash::new([0xAB; 20]));
+        assert_eq!(torrent.output_file, "file.bin");
+        assert_eq!(torrent.pieces, test_torrent().pieces);
+        assert_eq!(torrent.total_size, 40);
+        assert_eq!(torrent.comment.as_deref(), Some("a comment"));
+        assert_eq!(torrent.creation_date, Some(1700000000));
+        assert_eq!(torrent.files[0].md5sum(), Some("0123456789abcdef0123456789abcdef"));
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn test_damage_is_caught() {
+        let encoded = test_bundle(vec![1, 0, 1]).encode();
+
+        // 1000 uploaded becomes 9000
+        let pos = encoded.windows(6).position(|w| w == b"i1000e").unwrap() + 1;
+        let mut damaged = encoded.clone();
+        damaged[pos] = b'9';
+        let e = Bundle::decode(&damaged).unwrap_err();
+        assert!(e.contains("crc"), "{}", e);
+
+        // a versioned bundle always has a crc
+        let Ok((Bencode::Dict(mut dict), _)) = decoder::decode(&encoded) else { panic!("not a dict") };
+        dict.remove(&b"crc"[..]);
+        assert!(Bundle::decode(&encoder::encode(&Bencode::Dict(dict))).is_err());
+    }
+
+    #[test]
+    fn test_versions() {
+        let bundle = test_bundle(vec![0, 1, 1]);
+
+        // written before bundles had a version or crc
+        let old = rewrite(&bundle.encode(), |dict| {
+            dict.remove(&b"version"[..]);
+        });
+        assert_eq!(Bundle::decode(&old).unwrap(), bundle);
+
+        let newer = rewrite(&bundle.encode(), |dict| {
+            dict.insert(b"version".to_vec(), Bencode::Int(FORMAT_VERSION + 1));
+        });
+        assert!(Bundle::decode(&newer).unwrap_err().contains("format version"));
+    }
+
+    #[test]
+    fn test_unknown_keys_are_kept() {
+        let encoded = rewrite(&test_bundle(vec![1, 1, 1]).encode(), |dict| {
+            dict.insert(b"piece priorities".to_vec(), Bencode::List(vec![Bencode::Int(7)]));
+        });
+
+        let decoded = Bundle::decode(&encoded).unwrap();
+        assert_eq!(decoded.extra.len(), 1);
+        assert_eq!(decoded.encode(), encoded);
+    }
+
+    #[test]
+    fn test_resume_state() {
+        let resume = ResumeState { have: vec![1, 0, 1], ..Default::default() };
+        assert_eq!(resume.pieces(), vec![0, 2]);
+        assert!(!resume.is_complete());
+        assert!(ResumeState { have: vec![1, 1], ..Default::default() }.is_complete());
+    }
+
+    #[test]
+    fn test_invalid_bundles() {
+        assert!(Bundle::decode(b"le").is_err());
+        assert!(Bundle::decode(b"d9:info hash3:abce").is_err());
+
+        // a bitfield that doesn't fit the torrent
+        assert!(Bundle::decode(&test_bundle(vec![1; 9]).encode()).is_err());
+
+        let short_hash = rewrite(&test_bundle(vec![1; 3]).encode(), |dict| {
+            dict.insert(b"info hash".to_vec(), Bencode::Bytes(vec![0xAB; 4]));
+        });
+        assert!(Bundle::decode(&short_hash).is_err());
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+// which interested peers we upload to. with no limit every peer that
+// wants something from us is unchoked. with a limit the rest wait in
+// line for a slot, the same way interest.rs queues the peers we
+// download from.
+//
+// a connection's give and take is tracked for as long as it is open.
+// when peers are waiting, a slot held for a whole turn goes to the next
+// in line, the peers that have given us least giving theirs up first.
+// the free rider policy (if there is one) cuts short the unchoke of
+// peers that take a lot and give almost nothing back, halving how long
+// they get each time they are caught so they end up with a short turn
+// rather than none at all.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FreeRiderConfig {
+    // what we have to have sent a peer before its ratio counts
+    pub min_uploaded: u64,
+    // peers that have given back less than this share of what they
+    // took from us count as free riders
+    pub min_ratio: f64,
+    // how long a free rider is first left unchoked while others wait
+    pub unchoke_time: Duration,
+    // the shortest turn it gets, however often it is caught
+    pub min_unchoke_time: Duration,
+}
+
+impl Default for FreeRiderConfig {
+    fn default() -> Self {
+        FreeRiderConfig {
+            min_uploaded: 4 * 1024 * 1024,
+            min_ratio: 0.1,
+            unchoke_time: Duration::from_secs(60),
+            min_unchoke_time: Duration::from_secs(10),
+        }
+    }
+}
+
+// bytes moved over a connection since it opened
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GiveTake {
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl FreeRiderConfig {
+    pub fn is_free_rider(&self, transfer: GiveTake) -> bool {
+        transfer.uploaded >= self.min_uploaded && (transfer.downloaded as f64) < transfer.uploaded as f64 * self.min_ratio
+    }
+}
+
+pub struct UploadChoker {
+    max_unchoked: Option<usize>,
+    free_riders: Option<FreeRiderConfig>,
+    unchoked: HashMap<String, Instant>,
+    waiting: VecDeque<String>,
+    // how many times each connected peer has been choked for free riding
+    strikes: HashMap<String, u32>,
+    // how long a slot is held while others wait. None keeps it for as
+    // long as the peer stays interested.
+    turn: Option<Duration>,
+}
+
+impl UploadChoker {
+    pub fn new(max_unchoked: Option<usize>, free_riders: Option<FreeRiderConfig>) -> UploadChoker {
+        UploadChoker {
+            max_unchoked,
+            free_riders,
+            unchoked: HashMap::new(),
+            waiting: VecDeque::new(),
+            strikes: HashMap::new(),
+            turn: None,
+        }
+    }
+
+    pub fn set_turn(&mut self, turn: Option<Duration>) {
+        self.turn = turn;
+    }
+
+    // returns true if the peer is (or has just been) unchoked.
+    // otherwise it is queued until a slot frees up.
+    pub fn request_unchoke(&mut self, peer_id: &str, now: Instant) -> bool {
+        if self.unchoked.contains_key(peer_id) {
+            return true;
+        }
+
+        let has_room = self.max_unchoked.is_none_or(|max| self.unchoked.len() < max);
+        if has_room && self.waiting.front().is_none_or(|p| p == peer_id) {
+            self.waiting.retain(|p| p != peer_id);
+            self.unchoked.insert(peer_id.to_string(), now);
+            return true;
+        }
+
+        if !self.waiting.iter().any(|p| p == peer_id) {
+            self.waiting.push_back(peer_id.to_string());
+        }
+        false
+    }
+
+    // the peer stopped being interested, its slot goes to the next one
+    // waiting. returns true if someone was given it.
+    pub fn release(&mut self, peer_id: &str, now: Instant) -> bool {
+        self.waiting.retain(|p| p != peer_id);
+        if self.unchoked.remove(peer_id).is_none() {
+            return false;
+        }
+
+        match self.waiting.pop_front() {
+            Some(next) => {
+                self.unchoked.insert(next, now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // the connection closed, so its record goes with it
+    pub fn remove_peer(&mut self, peer_id: &str, now: Instant) -> bool {
+        self.strikes.remove(peer_id);
+        self.release(peer_id, now)
+    }
+
+    pub fn is_unchoked(&self, peer_id: &str) -> bool {
+        self.unchoked.contains_key(peer_id)
+    }
+
+    pub fn strikes(&self, peer_id: &str) -> u32 {
+        self.strikes.get(peer_id).copied().unwrap_or(0)
+    }
+
+    // how long the peer may stay unchoked while others wait, if it is
+    // a free rider
+    fn allowance(&self, config: &FreeRiderConfig, peer_id: &str) -> Duration {
+        let halvings = self.strikes(peer_id).min(16);
+        (config.unchoke_time / 2u32.pow(halvings)).max(config.min_unchoke_time)
+    }
+
+    // chokes peers that have used up their turn, for as long as someone
+    // is waiting for their slot. free riders go first, then whoever has
+    // given us least. not contended, nothing changes. returns the peers
+    // that were choked.
+    pub fn rotate(&mut self, now: Instant, transfers: &HashMap<String, GiveTake>) -> Vec<String> {
+        let mut expired: Vec<(bool, u64, String)> = self
+            .unchoked
+            .iter()
+            .filter_map(|(peer_id, &since)| {
+                let transfer = transfers.get(peer_id).copied().unwrap_or_default();
+                let free_rider = self.free_riders.as_ref().filter(|config| config.is_free_rider(transfer));
+                let limit = [free_rider.map(|config| self.allowance(config, peer_id)), self.turn].into_iter().flatten().min()?;
+                (now.saturating_duration_since(since) >= limit).then(|| (free_rider.is_none(), transfer.downloaded, peer_id.clone()))
+            })
+            .collect();
+        expired.sort();
+        expired.truncate(self.waiting.len());
+
+        for (fair, _, peer_id) in &expired {
+            self.unchoked.remove(peer_id);
+            if !fair {
+                *self.strikes.entry(peer_id.clone()).or_default() += 1;
+            }
+            if let Some(next) = self.waiting.pop_front() {
+                self.unchoked.insert(next, now);
+            }
+            self.waiting.push_back(peer_id.clone());
+        }
+
+        expired.into_iter().map(|(_, _, peer_id)| peer_id).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn transfers(peers: &[(&str, u64, u64)]) -> HashMap<String, GiveTake> {
+        peers
+            .iter()
+            .map(|&(id, uploaded, downloaded)| (id.to_string(), GiveTake { uploaded, downloaded }))
+            .collect()
+    }
+
+    #[test]
+    fn test_slots() {
+        let now = Instant::now();
+        let mut choker = UploadChoker::new(Some(2), None);
+        assert!(choker.request_unchoke("a", now));
+        assert!(choker.request_unchoke("b", now));
+        assert!(!choker.request_unchoke("c", now));
+        assert!(!choker.request_unchoke("d", now));
+
+        // the first in line gets the free slot
+        assert!(choker.release("a", now));
+        assert!(choker.is_unchoked("c"));
+        assert!(!choker.request_unchoke("d", now));
+
+        let mut unlimited = UploadChoker::new(None, None);
+        assert!((0..100).all(|i| unlimited.request_unchoke(&i.to_string(), now)));
+    }
+
+    #[test]
+    fn test_turns() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut choker = UploadChoker::new(Some(2), None);
+        choker.set_turn(Some(Duration::from_secs(30)));
+        choker.request_unchoke("a", start);
+        choker.request_unchoke("b", start);
+        let stats = transfers(&[("a", MIB, 10 * MIB), ("b", MIB, MIB)]);
+        assert!(choker.rotate(at(60), &stats).is_empty());
+
+        choker.request_unchoke("c", at(60));
+        // b has given us less
+        assert_eq!(choker.rotate(at(60), &stats), vec!["b".to_string()]);
+        assert!(choker.is_unchoked("c"));
+        assert!(!choker.request_unchoke("b", at(60)));
+
+        assert_eq!(choker.rotate(at(70), &stats), vec!["a".to_string()]);
+        assert!(choker.is_unchoked("b"));
+        assert!(choker.is_unchoked("c"));
+        assert_eq!(choker.strikes("b"), 0);
+    }
+
+    #[test]
+    fn test_free_riders_lose_their_turn() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let config = FreeRiderConfig::default();
+        let mut choker = UploadChoker::new(Some(2), Some(config));
+        choker.request_unchoke("leech", start);
+        choker.request_unchoke("fair", start);
+
+        let stats = transfers(&[("leech", 50 * MIB, 0), ("fair", 50 * MIB, 40 * MIB), ("waiting", 0, 0)]);
+
+        // nobody is waiting, so even a free rider keeps its slot
+        assert!(choker.rotate(at(120), &stats).is_empty());
+
+        choker.request_unchoke("waiting", at(120));
+        assert_eq!(choker.rotate(at(120), &stats), vec!["leech".to_string()]);
+        assert!(choker.is_unchoked("waiting"));
+        assert!(choker.is_unchoked("fair"));
+        assert_eq!(choker.strikes("leech"), 1);
+
+        // the next turn is half as long
+        choker.release("waiting", at(130));
+        assert!(choker.is_unchoked("leech"));
+        choker.request_unchoke("waiting", at(130));
+        assert!(choker.rotate(at(159), &stats).is_empty());
+        assert_eq!(choker.rotate(at(160), &stats), vec!["leech".to_string()]);
+
+        // and never shorter than the minimum
+        for i in 0..5 {
+            let t = 200 + i * 100;
+            choker.release("waiting", at(t));
+            choker.request_unchoke("waiting", at(t));
+            assert_eq!(choker.rotate(at(t + 60), &stats), vec!["leech".to_string()]);
+        }
+        assert_eq!(choker.strikes("leech"), 7);
+        assert_eq!(choker.allowance(&FreeRiderConfig::default(), "leech"), Duration::from_secs(10));
+
+        choker.remove_peer("leech", at(1000));
+        assert_eq!(choker.strikes("leech"), 0);
+    }
+
+    #[test]
+    fn test_free_rider_threshold() {
+        let config = FreeRiderConfig::default();
+        // too little has been sent to judge
+        assert!(!config.is_free_rider(GiveTake { uploaded: MIB, downloaded: 0 }));
+        assert!(config.is_free_rider(GiveTake { uploaded: 10 * MIB, downloaded: MIB / 2 }));
+        assert!(!config.is_free_rider(GiveTake { uploaded: 10 * MIB, downloaded: MIB }));
+    }
+}
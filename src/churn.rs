@@ -0,0 +1,128 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// every so often, while a torrent is downloading with all its connections
+// taken and fresh addresses waiting in the peer pool, the peers doing
+// least for us are dropped to make room. a peer is worth what it sends
+// us, plus a little for pieces it has that we still need so a peer we
+// haven't got round to asking yet isn't taken for dead weight, halved
+// for every error it makes. peers are only dropped when they are worth
+// well under the average, a swarm of equally good peers is left alone.
+
+// credit for each piece we still need, in bytes a second, up to this
+// many pieces
+const WANTED_CREDIT: f64 = 256.0;
+const MAX_WANTED_CREDIT: usize = 64;
+
+// wasted bytes that count as much against a peer as a failed piece
+const WASTE_PER_ERROR: u64 = 1024 * 1024;
+
+// dropped only when worth less than this much of the average
+const DROP_BELOW: f64 = 0.25;
+
+// peers the churn task wants gone, by peer id. their connections notice
+// the next time they wake up.
+pub type Evictions = Arc<Mutex<HashSet<String>>>;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerScore {
+    pub peer_id: String,
+    // smoothed, bytes a second
+    pub download_rate: f64,
+    // pieces the peer has that we still need
+    pub wanted: usize,
+    pub hash_failures: u64,
+    pub wasted: u64,
+}
+
+impl PeerScore {
+    pub fn value(&self) -> f64 {
+        let credit = self.wanted.min(MAX_WANTED_CREDIT) as f64 * WANTED_CREDIT;
+        let errors = self.hash_failures + self.wasted / WASTE_PER_ERROR;
+        (self.download_rate + credit) / 2f64.powi(errors.min(32) as i32)
+    }
+}
+
+// remembers when each peer was first scored, so one that has only just
+// connected gets the grace period to show what it can do
+pub struct Churn {
+    grace: Duration,
+    first_seen: HashMap<String, Instant>,
+}
+
+impl Churn {
+    pub fn new(grace: Duration) -> Churn {
+        Churn { grace, first_seen: HashMap::new() }
+    }
+
+    // up to max peers to drop, worst first
+    pub fn pick(&mut self, scores: &[PeerScore], now: Instant, max: usize) -> Vec<String> {
+        self.first_seen.retain(|peer_id, _| scores.iter().any(|s| &s.peer_id == peer_id));
+        for score in scores {
+            self.first_seen.entry(score.peer_id.clone()).or_insert(now);
+        }
+        if scores.is_empty() {
+            return vec![];
+        }
+
+        let average = scores.iter().map(PeerScore::value).sum::<f64>() / scores.len() as f64;
+        let mut worst: Vec<(f64, &str)> = scores
+            .iter()
+            .filter(|s| now.saturating_duration_since(self.first_seen[&s.peer_id]) >= self.grace)
+            .map(|s| (s.value(), s.peer_id.as_str()))
+            .filter(|&(value, _)| value <= average * DROP_BELOW)
+            .collect();
+        worst.sort_by(|a, b| a.0.total_cmp(&b.0));
+        worst.into_iter().take(max).map(|(_, peer_id)| peer_id.to_string()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn score(peer_id: &str, download_rate: f64, wanted: usize) -> PeerScore {
+        PeerScore { peer_id: peer_id.to_string(), download_rate, wanted, ..Default::default() }
+    }
+
+    #[test]
+    fn test_value() {
+        assert_eq!(score("a", 1000.0, 0).value(), 1000.0);
+        assert_eq!(score("a", 0.0, 2).value(), 512.0);
+        assert_eq!(score("a", 0.0, 1000).value(), 64.0 * 256.0);
+
+        let failing = PeerScore { hash_failures: 1, wasted: 2 * 1024 * 1024, ..score("a", 8000.0, 0) };
+        assert_eq!(failing.value(), 1000.0);
+    }
+
+    #[test]
+    fn test_picks_dead_weight_after_grace() {
+        let start = Instant::now();
+        let mut churn = Churn::new(Duration::from_secs(60));
+        let scores = [score("fast", 50000.0, 10), score("idle", 0.0, 0), score("slow", 100.0, 0), score("ok", 40000.0, 3)];
+
+        // everyone has only just connected
+        assert!(churn.pick(&scores, start, 2).is_empty());
+        let later = start + Duration::from_secs(60);
+        assert_eq!(churn.pick(&scores, later, 2), vec!["idle", "slow"]);
+        assert_eq!(churn.pick(&scores, later, 1), vec!["idle"]);
+
+        // a peer that reconnects starts its grace over
+        let mut scores = scores.to_vec();
+        scores.retain(|s| s.peer_id != "idle");
+        churn.pick(&scores, later, 2);
+        scores.push(score("idle", 0.0, 0));
+        assert_eq!(churn.pick(&scores, later, 2), vec!["slow"]);
+    }
+
+    #[test]
+    fn test_leaves_an_even_swarm_alone() {
+        let start = Instant::now();
+        let mut churn = Churn::new(Duration::ZERO);
+        let scores = [score("a", 20000.0, 0), score("b", 15000.0, 0), score("c", 18000.0, 0)];
+        assert!(churn.pick(&scores, start, 3).is_empty());
+    }
+}
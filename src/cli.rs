@@ -0,0 +1,790 @@
+use std::path::PathBuf;
+
+use std::time::Duration;
+
+use bt_c::{client::RequestTuning, create::CreateOptions, hooks::Hooks, seeding::SeedLimits, session::{RateLimits, TorrentId}};
+
+pub const USAGE: &str = "usage:
+    bt-c add [--low-memory] [--assume-complete [--sample <n> | --full-check] [--super-seed] | --adopt]
+             [--ratio <r>] [--seed-time <minutes>] [--idle-time <minutes>]
+             [--on-add <cmd>] [--on-complete <cmd>] [--on-error <cmd>] [--webhook <url>]
+             [--completed-dir <dir>] [--no-part-files] [--blocklist <url>]
+             [--geoip <file>] [--block-size <bytes>] [--max-requests <n>]
+             [--request-timeout <s>] [--proxy <url> [--strict-proxy]]
+             [--bind <interface|address>] <torrent> [<dir>]
+    bt-c export <id> <bundle>
+    bt-c import <bundle> [<dir>]
+    bt-c pause [--announce-stopped] <id>
+    bt-c resume <id>
+    bt-c trackers <id>
+    bt-c peers [--watch] <id>
+    bt-c speed [--watch] [<id>]
+    bt-c limit-peer [--download <KiB/s>] [--upload <KiB/s> | --clear] <id> <address>
+    bt-c create [--announce <url>]... [--private] [--comment <text>] [--piece-length <n>] [--output <file>] <path>
+    bt-c info [--magnet] <torrent>
+
+commands:
+    add       add a torrent and start downloading it into <dir> (default: current directory)
+    export    save a torrent of the running client, with its progress, to <bundle>.
+              a <bundle> ending in .fastresume is written for libtorrent based
+              clients (qbittorrent, deluge), with the .torrent next to it
+    import    carry on with an exported torrent whose data has been copied to <dir>,
+              or with those in a .fastresume of qbittorrent or deluge
+    pause     stop downloading and uploading a torrent of the running client
+    resume    carry on with a paused torrent
+    trackers  show how announcing to each of a torrent's trackers is going
+    peers     show a torrent's connections: client, progress, rates, requests
+              in flight and flags. D/d we are downloading/want to but are
+              choked, U/u the same for uploading, K they unchoke us but we
+              aren't interested, ? we unchoke them but they aren't, S snubbed,
+              L held to a per peer limit
+    speed     graph the download and upload rate of the last five minutes, of
+              one torrent or of all of them
+    limit-peer
+              cap what the peer at <address> (ip or ip:port) gets, for as long
+              as the client runs. what isn't given is unlimited, --clear
+              goes back to the torrent's default. L in the peers flags
+    create    make a .torrent of the file or directory at <path> (default: <name>.torrent)
+    info      show what is in a .torrent without adding it, and how announcing
+              to its trackers is going if the running client has it
+
+options:
+    --low-memory         keep few peers and pieces going and buffer nothing,
+                         for machines like a raspberry pi
+    --assume-complete    the data is already in <dir>: map the files, spot check
+                         some pieces and start seeding instead of downloading
+    --adopt              another client was downloading it into <dir>: hash every
+                         piece, keep what matches and download the rest
+    --sample <n>         number of pieces to spot check (default: 16)
+    --full-check         check every piece instead of a sample
+    --super-seed         hand out one piece per peer until it has spread to
+                         others, to get a new torrent going on little upload
+    --ratio <r>          stop seeding once we have uploaded <r> times what we downloaded
+    --seed-time <m>      stop seeding after <m> minutes
+    --idle-time <m>      stop seeding after <m> minutes without uploading anything
+    --on-add <cmd>       shell command to run once the torrent is added, with
+                         BT_TORRENT_NAME, BT_TORRENT_PATH and BT_INFO_HASH set
+    --on-complete <cmd>  the same once it has finished downloading
+    --on-error <cmd>     the same when its data can't be written, with BT_ERROR
+    --webhook <url>      post the torrent's events (added, completed, tracker
+                         errors, failed pieces) to <url> as json
+    --completed-dir <d>  move the data to <d> once the torrent has finished
+    --no-part-files      write to the real file names from the start instead
+                         of <name>.part, renamed once complete
+    --blocklist <url>    never connect to the addresses listed at <url> (p2p
+                         or dat format, can be gzipped), downloaded again daily
+    --geoip <file>       show each peer's country, from a maxmind .mmdb such as
+                         geolite2 country
+    --block-size <bytes> how much to ask peers for in one request, a power of
+                         two from 1 KiB to 128 KiB (default: 16384). most
+                         clients hang up on requests for more than the default
+    --max-requests <n>   the most requests in flight to one peer, up to 250
+                         (default: 64)
+    --request-timeout <s>
+                         seconds before an unanswered request may go to
+                         another peer (default: 300)
+    --proxy <url>        dial peers through a socks5 proxy, socks5://host:port,
+                         or socks5h://host:port to have it look up their names
+    --strict-proxy       never connect to a peer directly, if the proxy is down
+                         nothing gets through instead
+    --bind <interface|address>
+                         only talk to peers over this interface, such as a
+                         vpn's tun0 (linux), or from this address
+    --announce-stopped   also tell the tracker, so it stops handing out our
+                         address until the torrent is resumed
+    --announce <url>     tracker for the new torrent, can be given more than once
+    --private            only get peers from the trackers, never from other peers
+    --comment <text>     free text stored in the torrent
+    --piece-length <n>   bytes per piece, a power of two (default: picked from the size)
+    --output <file>      where to write the torrent
+    --magnet             print a magnet link for the torrent instead
+    --download <KiB/s>   the most a peer sends us a second
+    --upload <KiB/s>     the most we send a peer a second
+    --clear              drop a peer's own limits
+    --watch              keep the view up to date until interrupted";
+
+// pieces hashed by --assume-complete when --sample isn't given
+pub const DEFAULT_SAMPLE: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // boxed, it has far more in it than the others
+    Add(Box<AddArgs>),
+    Export(ExportArgs),
+    Import(ImportArgs),
+    Pause(PauseArgs),
+    Resume(ResumeArgs),
+    Trackers(TrackersArgs),
+    Peers(PeersArgs),
+    Speed(SpeedArgs),
+    LimitPeer(LimitPeerArgs),
+    Create(CreateArgs),
+    Info(InfoArgs),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AddArgs {
+    pub torrent: PathBuf,
+    pub dir: PathBuf,
+    pub assume_complete: bool,
+    pub adopt: bool,
+    // None means every piece gets checked
+    pub sample: Option<usize>,
+    pub super_seed: bool,
+    pub low_memory: bool,
+    pub seed_limits: SeedLimits,
+    pub hooks: Hooks,
+    pub webhook: Option<String>,
+    pub completed_dir: Option<PathBuf>,
+    pub part_files: bool,
+    pub blocklist: Option<String>,
+    pub geoip: Option<PathBuf>,
+    pub requests: RequestTuning,
+    pub proxy: Option<String>,
+    pub strict_proxy: bool,
+    pub bind: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExportArgs {
+    pub id: TorrentId,
+    pub bundle: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ImportArgs {
+    pub bundle: PathBuf,
+    pub dir: PathBuf,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PauseArgs {
+    pub id: TorrentId,
+    pub announce_stopped: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ResumeArgs {
+    pub id: TorrentId,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TrackersArgs {
+    pub id: TorrentId,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PeersArgs {
+    pub id: TorrentId,
+    // redraw every few seconds instead of printing once
+    pub watch: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SpeedArgs {
+    // the whole session's without one
+    pub id: Option<TorrentId>,
+    pub watch: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LimitPeerArgs {
+    pub id: TorrentId,
+    pub address: String,
+    // None for --clear
+    pub limits: Option<RateLimits>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CreateArgs {
+    pub path: PathBuf,
+    pub output: PathBuf,
+    pub options: CreateOptions,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InfoArgs {
+    pub torrent: PathBuf,
+    pub magnet: bool,
+}
+
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut args = args.into_iter();
+
+    match args.next().as_deref() {
+        Some("add") => parse_add(args),
+        Some("export") => parse_export(args),
+        Some("import") => parse_import(args),
+        Some("pause") => parse_pause(args),
+        Some("resume") => parse_resume(args),
+        Some("trackers") => parse_trackers(args),
+        Some("peers") => parse_peers(args),
+        Some("speed") => parse_speed(args),
+        Some("limit-peer") => parse_limit_peer(args),
+        Some("create") => parse_create(args),
+        Some("info") => parse_info(args),
+        Some(other) => Err(format!("unknown command: {}", other)),
+        None => Err("no command given".to_string()),
+    }
+}
+
+fn parse_add<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut positional = Vec::new();
+    let mut assume_complete = false;
+    let mut adopt = false;
+    let mut sample = Some(DEFAULT_SAMPLE);
+    let mut sample_given = false;
+    let mut super_seed = false;
+    let mut low_memory = false;
+    let mut seed_limits = SeedLimits::default();
+    let mut hooks = Hooks::default();
+    let mut webhook = None;
+    let mut completed_dir = None;
+    let mut part_files = true;
+    let mut blocklist = None;
+    let mut geoip = None;
+    let mut requests = RequestTuning::default();
+    let mut proxy = None;
+    let mut strict_proxy = false;
+    let mut bind = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--assume-complete" => assume_complete = true,
+            "--adopt" => adopt = true,
+            "--super-seed" => super_seed = true,
+            "--low-memory" => low_memory = true,
+            "--full-check" => {
+                sample = None;
+                sample_given = true;
+            }
+            "--sample" => {
+                let n = args.next().ok_or("--sample needs a value")?;
+                sample = Some(n.parse().map_err(|_| format!("invalid sample size: {}", n))?);
+                sample_given = true;
+            }
+            "--ratio" => {
+                let r = args.next().ok_or("--ratio needs a value")?;
+                match r.parse::<f64>() {
+                    Ok(ratio) if ratio > 0.0 => seed_limits.ratio = Some(ratio),
+                    _ => return Err(format!("invalid ratio: {}", r)),
+                }
+            }
+            "--seed-time" => seed_limits.seed_time = Some(parse_minutes(&arg, args.next())?),
+            "--idle-time" => seed_limits.idle_timeout = Some(parse_minutes(&arg, args.next())?),
+            "--on-add" => hooks.on_add = Some(args.next().ok_or("--on-add needs a command")?),
+            "--on-complete" => hooks.on_complete = Some(args.next().ok_or("--on-complete needs a command")?),
+            "--on-error" => hooks.on_error = Some(args.next().ok_or("--on-error needs a command")?),
+            "--webhook" => webhook = Some(args.next().ok_or("--webhook needs a url")?),
+            "--completed-dir" => completed_dir = Some(PathBuf::from(args.next().ok_or("--completed-dir needs a directory")?)),
+            "--no-part-files" => part_files = false,
+            "--blocklist" => blocklist = Some(args.next().ok_or("--blocklist needs a url")?),
+            "--geoip" => geoip = Some(PathBuf::from(args.next().ok_or("--geoip needs a file")?)),
+            "--block-size" => requests.block_size = parse_number(&arg, args.next())?,
+            "--max-requests" => requests.max_requests_per_peer = parse_number(&arg, args.next())?,
+            "--request-timeout" => requests.timeout = Duration::from_secs(parse_number(&arg, args.next())?),
+            "--proxy" => proxy = Some(args.next().ok_or("--proxy needs a url")?),
+            "--strict-proxy" => strict_proxy = true,
+            "--bind" => bind = Some(args.next().ok_or("--bind needs an interface or address")?),
+            flag if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    if sample_given && !assume_complete {
+        return Err("--sample and --full-check only make sense with --assume-complete".to_string());
+    }
+
+    if adopt && assume_complete {
+        return Err("--adopt checks what is there, --assume-complete trusts it, pick one".to_string());
+    }
+
+    if strict_proxy && proxy.is_none() {
+        return Err("--strict-proxy needs a --proxy to send peer connections through".to_string());
+    }
+
+    if super_seed && !assume_complete {
+        return Err("--super-seed needs --assume-complete, only a complete torrent can be super seeded".to_string());
+    }
+
+    let mut positional = positional.into_iter();
+    let torrent = positional.next().ok_or("missing <torrent>")?;
+    let dir = positional.next().unwrap_or_else(|| ".".to_string());
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument: {}", extra));
+    }
+
+    Ok(Command::Add(Box::new(AddArgs {
+        torrent: PathBuf::from(torrent),
+        dir: PathBuf::from(dir),
+        assume_complete,
+        adopt,
+        sample,
+        super_seed,
+        low_memory,
+        seed_limits,
+        hooks,
+        webhook,
+        completed_dir,
+        part_files,
+        blocklist,
+        geoip,
+        requests,
+        proxy,
+        strict_proxy,
+        bind,
+    })))
+}
+
+// checked against the limits when the session is built
+fn parse_number<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value.parse().map_err(|_| format!("invalid number for {}: {}", flag, value))
+}
+
+fn parse_minutes(flag: &str, value: Option<String>) -> Result<Duration, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    let minutes: u64 = value.parse().map_err(|_| format!("invalid number of minutes: {}", value))?;
+    Ok(Duration::from_secs(minutes * 60))
+}
+
+fn parse_export<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let id = parse_id(args.next())?;
+    let bundle = args.next().ok_or("missing <bundle>")?;
+    if let Some(extra) = args.next() {
+        return Err(format!("unexpected argument: {}", extra));
+    }
+
+    Ok(Command::Export(ExportArgs { id, bundle: PathBuf::from(bundle) }))
+}
+
+fn parse_import<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let bundle = args.next().ok_or("missing <bundle>")?;
+    let dir = args.next().unwrap_or_else(|| ".".to_string());
+    if let Some(extra) = args.next() {
+        return Err(format!("unexpected argument: {}", extra));
+    }
+
+    Ok(Command::Import(ImportArgs { bundle: PathBuf::from(bundle), dir: PathBuf::from(dir) }))
+}
+
+fn parse_id(id: Option<String>) -> Result<TorrentId, String> {
+    let id = id.ok_or("missing <id>")?;
+    id.parse().map_err(|_| format!("invalid torrent id: {}", id))
+}
+
+fn parse_pause<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut announce_stopped = false;
+    let mut positional = Vec::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "--announce-stopped" => announce_stopped = true,
+            flag if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let id = parse_id(positional.next())?;
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument: {}", extra));
+    }
+
+    Ok(Command::Pause(PauseArgs { id, announce_stopped }))
+}
+
+fn parse_resume<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let id = parse_id(args.next())?;
+    if let Some(extra) = args.next() {
+        return Err(format!("unexpected argument: {}", extra));
+    }
+
+    Ok(Command::Resume(ResumeArgs { id }))
+}
+
+fn parse_trackers<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let id = parse_id(args.next())?;
+    if let Some(extra) = args.next() {
+        return Err(format!("unexpected argument: {}", extra));
+    }
+
+    Ok(Command::Trackers(TrackersArgs { id }))
+}
+
+fn parse_peers<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut watch = false;
+    let mut positional = Vec::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "--watch" => watch = true,
+            flag if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let id = parse_id(positional.next())?;
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument: {}", extra));
+    }
+
+    Ok(Command::Peers(PeersArgs { id, watch }))
+}
+
+fn parse_speed<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut watch = false;
+    let mut positional = Vec::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "--watch" => watch = true,
+            flag if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let id = match positional.next() {
+        Some(id) => Some(parse_id(Some(id))?),
+        None => None,
+    };
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument: {}", extra));
+    }
+
+    Ok(Command::Speed(SpeedArgs { id, watch }))
+}
+
+fn parse_limit_peer<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut limits = RateLimits::default();
+    let mut clear = false;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--download" => limits.download = Some(parse_number::<u64>(&arg, args.next())?.saturating_mul(1024)),
+            "--upload" => limits.upload = Some(parse_number::<u64>(&arg, args.next())?.saturating_mul(1024)),
+            "--clear" => clear = true,
+            flag if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let limits = match (clear, limits == RateLimits::default()) {
+        (true, true) => None,
+        (true, false) => return Err("--clear drops the peer's limits, it can't also set them".to_string()),
+        (false, true) => return Err("give --download, --upload or --clear".to_string()),
+        (false, false) => Some(limits),
+    };
+    let mut positional = positional.into_iter();
+    let id = parse_id(positional.next())?;
+    let address = positional.next().ok_or("missing <address>")?;
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument: {}", extra));
+    }
+
+    Ok(Command::LimitPeer(LimitPeerArgs { id, address, limits }))
+}
+
+fn parse_create<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut options = CreateOptions::default();
+    let mut output = None;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--announce" => options.announce.push(args.next().ok_or("--announce needs a url")?),
+            "--private" => options.private = true,
+            "--comment" => options.comment = Some(args.next().ok_or("--comment needs a value")?),
+            "--piece-length" => {
+                let n = args.next().ok_or("--piece-length needs a value")?;
+                options.piece_length = Some(n.parse().map_err(|_| format!("invalid piece length: {}", n))?);
+            }
+            "--output" => output = Some(PathBuf::from(args.next().ok_or("--output needs a file")?)),
+            flag if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let path = PathBuf::from(positional.next().ok_or("missing <path>")?);
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument: {}", extra));
+    }
+
+    let output = match output {
+        Some(output) => output,
+        None => {
+            let name = path.file_name().ok_or_else(|| format!("{} has no name", path.display()))?;
+            PathBuf::from(format!("{}.torrent", name.to_string_lossy()))
+        }
+    };
+
+    Ok(Command::Create(CreateArgs { path, output, options }))
+}
+
+fn parse_info<I: Iterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut magnet = false;
+    let mut positional = Vec::new();
+
+    for arg in args {
+        match arg.as_str() {
+            "--magnet" => magnet = true,
+            flag if flag.starts_with("--") => return Err(format!("unknown option: {}", flag)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let torrent = positional.next().ok_or("missing <torrent>")?;
+    if let Some(extra) = positional.next() {
+        return Err(format!("unexpected argument: {}", extra));
+    }
+
+    Ok(Command::Info(InfoArgs { torrent: PathBuf::from(torrent), magnet }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_add_defaults() {
+        let cmd = parse(args("add foo.torrent")).unwrap();
+        assert_eq!(cmd, Command::Add(Box::new(AddArgs {
+            torrent: PathBuf::from("foo.torrent"),
+            dir: PathBuf::from("."),
+            assume_complete: false,
+            adopt: false,
+            sample: Some(DEFAULT_SAMPLE),
+            super_seed: false,
+            low_memory: false,
+            seed_limits: SeedLimits::default(),
+            hooks: Hooks::default(),
+            webhook: None,
+            completed_dir: None,
+            part_files: true,
+            blocklist: None,
+            geoip: None,
+            requests: RequestTuning::default(),
+            proxy: None,
+            strict_proxy: false,
+            bind: None,
+        })));
+    }
+
+    #[test]
+    fn test_add_assume_complete() {
+        let cmd = parse(args("add --assume-complete foo.torrent /data --sample 4")).unwrap();
+        assert_eq!(cmd, Command::Add(Box::new(AddArgs {
+            torrent: PathBuf::from("foo.torrent"),
+            dir: PathBuf::from("/data"),
+            assume_complete: true,
+            adopt: false,
+            sample: Some(4),
+            super_seed: false,
+            low_memory: false,
+            seed_limits: SeedLimits::default(),
+            hooks: Hooks::default(),
+            webhook: None,
+            completed_dir: None,
+            part_files: true,
+            blocklist: None,
+            geoip: None,
+            requests: RequestTuning::default(),
+            proxy: None,
+            strict_proxy: false,
+            bind: None,
+        })));
+
+        let Ok(Command::Add(add)) = parse(args("add --assume-complete --full-check foo.torrent /data")) else { panic!() };
+        assert_eq!(add.sample, None);
+
+        let Ok(Command::Add(add)) = parse(args("add --assume-complete --super-seed foo.torrent")) else { panic!() };
+        assert!(add.super_seed);
+
+        let Ok(Command::Add(add)) = parse(args("add --low-memory foo.torrent")) else { panic!() };
+        assert!(add.low_memory);
+
+        let Ok(Command::Add(add)) = parse(args("add --adopt foo.torrent /data")) else { panic!() };
+        assert!(add.adopt && !add.assume_complete);
+        assert!(parse(args("add --adopt --assume-complete foo.torrent")).is_err());
+    }
+
+    #[test]
+    fn test_add_seed_limits() {
+        let Ok(Command::Add(add)) = parse(args("add --ratio 1.5 --idle-time 30 foo.torrent")) else { panic!() };
+        assert_eq!(add.seed_limits, SeedLimits {
+            ratio: Some(1.5),
+            seed_time: None,
+            idle_timeout: Some(Duration::from_secs(1800)),
+        });
+
+        let Ok(Command::Add(add)) = parse(args("add --seed-time 120 foo.torrent")) else { panic!() };
+        assert_eq!(add.seed_limits.seed_time, Some(Duration::from_secs(7200)));
+
+        assert!(parse(args("add --ratio 0 foo.torrent")).is_err());
+        assert!(parse(args("add --ratio x foo.torrent")).is_err());
+        assert!(parse(args("add --seed-time 1.5 foo.torrent")).is_err());
+        assert!(parse(args("add foo.torrent --idle-time")).is_err());
+    }
+
+    #[test]
+    fn test_add_requests() {
+        let Ok(Command::Add(add)) = parse(args("add --block-size 32768 --max-requests 200 --request-timeout 60 foo.torrent")) else { panic!() };
+        assert_eq!(add.requests, RequestTuning { block_size: 32768, max_requests_per_peer: 200, timeout: Duration::from_secs(60) });
+        assert!(parse(args("add --block-size 16k foo.torrent")).is_err());
+        assert!(parse(args("add --max-requests -1 foo.torrent")).is_err());
+        assert!(parse(args("add foo.torrent --request-timeout")).is_err());
+    }
+
+    #[test]
+    fn test_add_hooks() {
+        let Ok(Command::Add(add)) = parse(args("add --on-complete ./unpack.sh --on-error ./alert.sh foo.torrent")) else { panic!() };
+        assert_eq!(add.hooks, Hooks {
+            on_add: None,
+            on_complete: Some("./unpack.sh".to_string()),
+            on_error: Some("./alert.sh".to_string()),
+        });
+        assert_eq!(add.webhook, None);
+        assert!(parse(args("add foo.torrent --on-add")).is_err());
+
+        let Ok(Command::Add(add)) = parse(args("add --blocklist http://lists.example/level1.gz foo.torrent")) else { panic!() };
+        assert_eq!(add.blocklist.as_deref(), Some("http://lists.example/level1.gz"));
+
+        let Ok(Command::Add(add)) = parse(args("add --geoip /usr/share/GeoIP/GeoLite2-Country.mmdb foo.torrent")) else { panic!() };
+        assert_eq!(add.geoip, Some(PathBuf::from("/usr/share/GeoIP/GeoLite2-Country.mmdb")));
+
+        let Ok(Command::Add(add)) = parse(args("add --webhook http://localhost:8123/hook foo.torrent")) else { panic!() };
+        assert_eq!(add.webhook.as_deref(), Some("http://localhost:8123/hook"));
+    }
+
+    #[test]
+    fn test_add_proxy() {
+        let Ok(Command::Add(add)) = parse(args("add --proxy socks5h://127.0.0.1:9050 --strict-proxy foo.torrent")) else { panic!() };
+        assert_eq!(add.proxy.as_deref(), Some("socks5h://127.0.0.1:9050"));
+        assert!(add.strict_proxy);
+        assert!(parse(args("add --strict-proxy foo.torrent")).is_err());
+        assert!(parse(args("add foo.torrent --proxy")).is_err());
+
+        let Ok(Command::Add(add)) = parse(args("add --bind tun0 foo.torrent")) else { panic!() };
+        assert_eq!(add.bind.as_deref(), Some("tun0"));
+        assert!(parse(args("add foo.torrent --bind")).is_err());
+    }
+
+    #[test]
+    fn test_add_completed_dir() {
+        let Ok(Command::Add(add)) = parse(args("add --completed-dir /data/done --no-part-files foo.torrent /data/incoming")) else { panic!() };
+        assert_eq!(add.completed_dir, Some(PathBuf::from("/data/done")));
+        assert!(!add.part_files);
+        assert!(parse(args("add foo.torrent --completed-dir")).is_err());
+    }
+
+    #[test]
+    fn test_limit_peer() {
+        assert_eq!(parse(args("limit-peer --upload 64 3 10.0.0.1:6881")).unwrap(), Command::LimitPeer(LimitPeerArgs {
+            id: 3,
+            address: "10.0.0.1:6881".to_string(),
+            limits: Some(RateLimits { download: None, upload: Some(65536) }),
+        }));
+        let Ok(Command::LimitPeer(limit)) = parse(args("limit-peer --clear 3 10.0.0.1")) else { panic!() };
+        assert_eq!(limit.limits, None);
+        assert!(parse(args("limit-peer 3 10.0.0.1")).is_err());
+        assert!(parse(args("limit-peer --clear --download 8 3 10.0.0.1")).is_err());
+        assert!(parse(args("limit-peer --upload 64 3")).is_err());
+    }
+
+    #[test]
+    fn test_export_import() {
+        assert_eq!(parse(args("export 3 out.bundle")).unwrap(), Command::Export(ExportArgs {
+            id: 3,
+            bundle: PathBuf::from("out.bundle"),
+        }));
+        assert_eq!(parse(args("import out.bundle")).unwrap(), Command::Import(ImportArgs {
+            bundle: PathBuf::from("out.bundle"),
+            dir: PathBuf::from("."),
+        }));
+
+        assert!(parse(args("export x out.bundle")).is_err());
+        assert!(parse(args("export 3")).is_err());
+        assert!(parse(args("import")).is_err());
+        assert!(parse(args("import a b c")).is_err());
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        assert_eq!(parse(args("pause 2")).unwrap(), Command::Pause(PauseArgs { id: 2, announce_stopped: false }));
+        assert_eq!(parse(args("pause --announce-stopped 2")).unwrap(), Command::Pause(PauseArgs { id: 2, announce_stopped: true }));
+        assert_eq!(parse(args("resume 2")).unwrap(), Command::Resume(ResumeArgs { id: 2 }));
+
+        assert!(parse(args("pause")).is_err());
+        assert!(parse(args("pause x")).is_err());
+        assert!(parse(args("pause --bogus 2")).is_err());
+        assert!(parse(args("resume 2 3")).is_err());
+
+        assert_eq!(parse(args("trackers 2")).unwrap(), Command::Trackers(TrackersArgs { id: 2 }));
+        assert!(parse(args("trackers")).is_err());
+        assert!(parse(args("trackers 2 3")).is_err());
+
+        assert_eq!(parse(args("peers 2")).unwrap(), Command::Peers(PeersArgs { id: 2, watch: false }));
+        assert_eq!(parse(args("peers --watch 2")).unwrap(), Command::Peers(PeersArgs { id: 2, watch: true }));
+        assert!(parse(args("peers --bogus 2")).is_err());
+
+        assert_eq!(parse(args("speed")).unwrap(), Command::Speed(SpeedArgs { id: None, watch: false }));
+        assert_eq!(parse(args("speed --watch 2")).unwrap(), Command::Speed(SpeedArgs { id: Some(2), watch: true }));
+        assert!(parse(args("speed x")).is_err());
+        assert!(parse(args("speed 2 3")).is_err());
+    }
+
+    #[test]
+    fn test_info() {
+        assert_eq!(parse(args("info foo.torrent")).unwrap(), Command::Info(InfoArgs { torrent: PathBuf::from("foo.torrent"), magnet: false }));
+        let Ok(Command::Info(info)) = parse(args("info --magnet foo.torrent")) else { panic!() };
+        assert!(info.magnet);
+        assert!(parse(args("info --bogus foo.torrent")).is_err());
+        assert!(parse(args("info")).is_err());
+        assert!(parse(args("info a b")).is_err());
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        assert!(parse(args("")).is_err());
+        assert!(parse(args("frobnicate")).is_err());
+        assert!(parse(args("add")).is_err());
+        assert!(parse(args("add a b c")).is_err());
+        assert!(parse(args("add --sample 3 foo.torrent")).is_err());
+        assert!(parse(args("add --assume-complete --sample x foo.torrent")).is_err());
+        assert!(parse(args("add --bogus foo.torrent")).is_err());
+        assert!(parse(args("add --super-seed foo.torrent")).is_err());
+    }
+
+    #[test]
+    fn test_create() {
+        let cmd = parse(args("create --announce http://a/announce --private --announce http://b/announce /data/album")).unwrap();
+        assert_eq!(cmd, Command::Create(CreateArgs {
+            path: PathBuf::from("/data/album"),
+            output: PathBuf::from("album.torrent"),
+            options: CreateOptions {
+                announce: vec!["http://a/announce".to_string(), "http://b/announce".to_string()],
+                private: true,
+                comment: None,
+                piece_length: None,
+            },
+        }));
+
+        let Ok(Command::Create(create)) = parse(args("create --piece-length 65536 --output x.torrent --comment hi file.iso")) else { panic!() };
+        assert_eq!(create.output, PathBuf::from("x.torrent"));
+        assert_eq!(create.options.piece_length, Some(65536));
+        assert_eq!(create.options.comment.as_deref(), Some("hi"));
+
+        assert!(parse(args("create")).is_err());
+        assert!(parse(args("create --announce")).is_err());
+        assert!(parse(args("create --piece-length big file.iso")).is_err());
+    }
+}
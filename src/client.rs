@@ -1,13 +1,85 @@
-use std::{collections::{BTreeMap, HashMap, VecDeque}, error::Error, fs::{File, OpenOptions}, future::Pending, hash::Hash, io::{self, ErrorKind}, os::unix::fs::FileExt as _, path::Path, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, io, net::{IpAddr, Ipv4Addr, Ipv6Addr}, ops::Range, path::PathBuf, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 use std::io::{Result as IoResult};
 
+use bytes::Bytes;
 use log::{info, warn};
 use sha1::{Sha1, Digest};
-use tokio::sync::Mutex;
-
-use crate::{protocol::PeerConnection, torrent::Torrent, tracker::Tracker};
-
-const REQUEST_SIZE: u32 = 2_u32.pow(14);
+use tokio::{sync::{broadcast, mpsc::UnboundedReceiver, watch}, task::JoinHandle, time::interval};
+
+use crate::{
+    banlist::BanList,
+    announcer::{Announcer, AnnouncerHandle},
+    bundle::ResumeState,
+    dht::DhtState,
+    disk::{Disk, DiskHandle},
+    error::BtError,
+    choker::{FreeRiderConfig, UploadChoker},
+    churn::{Churn, Evictions, PeerScore},
+    external_ip::ExternalIp,
+    hooks::{HookEvent, Hooks},
+    notify::{Event, Notifier},
+    disk_space::DiskSpaceCheck,
+    filemap::Storage,
+    hashing::{BlockWrite, BlockWritten, HashJob, HashResult, PendingBlock, PieceHash, Verifier},
+    interest::InterestManager,
+    latency::LatencyTracker,
+    listener::IncomingQueue,
+    peer_id::PEER_ID_PREFIX,
+    peer_pool::PeerPool,
+    picker::{Candidate, PickerKind, PiecePicker},
+    piece_map::PieceMap,
+    pipeline,
+    progress::{Progress, ProgressReporter},
+    protocol::PeerConnection,
+    seeding::{self, SeedClock, SeedLimits, StopReason},
+    session::{PeerInfo, RateLimits, TorrentId, TorrentState, TorrentStatus},
+    shaper::{PeerLimits, SessionShaper},
+    sources::PeerSources,
+    stats::{RateSeries, StatsTracker},
+    superseed::SuperSeeder,
+    torrent::Torrent,
+    tracker::{Totals, Tracker, TrackerState},
+    transport::PeerTransport,
+    warnings,
+};
+
+// the block size everyone uses, and the default for ours
+pub const REQUEST_SIZE: u32 = 2_u32.pow(14);
+// what a block size can be set to. bep 3 puts the limit at 128 KiB,
+// though most clients hang up on requests for more than REQUEST_SIZE.
+pub const MIN_BLOCK_SIZE: u32 = 2_u32.pow(10);
+pub const MAX_BLOCK_SIZE: u32 = 2_u32.pow(17);
+// other clients queue a few hundred requests from a peer at most (250
+// is what libtorrent and most others advertise as reqq) and drop the rest
+pub const MAX_REQUESTS_PER_PEER: usize = 250;
+
+// pieces a peer can send us that fail verification before it is banned
+const DEFAULT_MAX_HASH_FAILURES: u32 = 3;
+
+pub const DEFAULT_LISTEN_PORT: u16 = 6881;
+
+// how often upload slots are checked for having been held a whole turn
+const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+// how often progress makes it into the log while downloading
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+// verified pieces waiting to be announced to each connection. a
+// connection that falls this far behind skips the ones it missed.
+const HAVE_BACKLOG: usize = 256;
+
+// how often requests are checked for having gone unanswered past the
+// request timeout, and how many expiries can wait for their connections
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+const EXPIRY_BACKLOG: usize = 256;
+
+// the blocks of a piece due this soon (or overdue) are asked of a
+// second peer as well, in case the first is slow to answer
+const DEADLINE_DUPLICATE_WINDOW: Duration = Duration::from_secs(2);
+
+// bytes a torrent can have asked for and not yet checked, see
+// PieceManager::in_flight
+pub const DEFAULT_MAX_IN_FLIGHT: u64 = 64 << 20;
 
 // **** ENUMS **** //
 
@@ -17,6 +89,8 @@ enum Status {
     Missing = 0,
     Pending = 1,
     Retrieved = 2,
+    // arrived, and on its way to disk
+    Writing = 3,
 }
 
 // **** STRUCTS **** // 
@@ -31,7 +105,8 @@ pub struct Block {
     offset: u64,
     length: u64,
     status: Status,
-    data: Option<Vec<u8>>,
+    // the peer that sent us this block, so bad data can be blamed on someone
+    source: Option<String>,
 }
 
 // torrents are composed of pieces. each of a particular size.
@@ -42,86 +117,1136 @@ pub struct Block {
 pub struct Piece {
     index: u32,
     blocks: Vec<Block>,
-    hash_value: String,
+    hash_value: [u8; 20],
+    // blocks are hashed in order as they arrive, up to the first one
+    // that is still missing. hashed is how many bytes that covers. the
+    // hasher is None while a block write has it.
+    hasher: Option<Sha1>,
+    hashed: u64,
+}
+
+// the read that goes with PieceManager::upload_location
+pub fn read_block_at(storage: &Storage, offset: u64, length: u32) -> io::Result<Bytes> {
+    let mut data = vec![0u8; length as usize];
+    storage.read_at(offset, &mut data)?;
+    Ok(data.into())
+}
+
+// a piece's data once we have it, see TorrentClient::read_piece. haves
+// has to be subscribed to before we look, or the piece could come in
+// between looking and waiting.
+pub async fn wait_for_piece(pm: Arc<Mutex<PieceManager>>, mut haves: broadcast::Receiver<u32>, index: u32) -> Result<Bytes, BtError> {
+    let expected = pm.lock().unwrap().torrent().pieces.get(index as usize).copied();
+    let Some(expected) = expected else {
+        return Err(BtError::Session(format!("piece {} is out of range, the torrent has {} pieces", index, pm.lock().unwrap().total_pieces())));
+    };
+
+    let (storage, range) = loop {
+        let location = pm.lock().unwrap().piece_location(index);
+        if let Some(location) = location {
+            break location;
+        }
+        match haves.recv().await {
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return Err(BtError::Session(format!("the torrent was removed before piece {} came in", index))),
+        }
+    };
+
+    // checked again, the caller gets the data itself rather than a file
+    // that could have been changed behind our back
+    tokio::task::spawn_blocking(move || {
+        let mut data = vec![0u8; (range.end - range.start) as usize];
+        storage.read_at(range.start, &mut data).map_err(|e| io::Error::new(e.kind(), format!("couldn't read piece {}: {}", index, e)))?;
+        if Sha1::digest(&data)[..] != expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("piece {} has changed on disk since it was checked", index)).into());
+        }
+        Ok(data.into())
+    })
+    .await
+    .map_err(|e| io::Error::other(format!("couldn't read piece {}: {}", index, e)))?
 }
 
+// a block by its piece and offset
+type BlockKey = (u64, u64);
+
 #[derive(Debug)]
 pub struct PendingRequest {
     block: Block,
     added: u128,
+    // who it was sent to
+    peer: String,
+    // gone unanswered too long. the next peer that has the piece, other
+    // than the one that sat on it, gets it instead.
+    expired: bool,
+    // the second peer it was sent to, for a piece close to its deadline
+    duplicate: Option<String>,
+}
+
+// a request a peer sat on for too long, for its connection to cancel
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpiredRequest {
+    pub peer_id: String,
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
 }
 
+// one per torrent, shared by all of its peer connections behind an
+// Arc<Mutex<..>> (see PeerContext). every call is quick bookkeeping so
+// the lock is never held for long, and nothing touches the disk while it
+// is held. blocks that arrive are written (and hashed) after the lock is
+// let go, see block_received, and so are uploads, see upload_location,
+// and the move of a finished torrent, see storage.
 pub struct PieceManager {
     torrent: Arc<Torrent>,
     peers: HashMap<String, Vec<u8>>,
-    pending_blocks: Vec<PendingRequest>,
-    missing_pieces: Vec<Piece>,
-    ongoing_pieces: Vec<Piece>,
-    have_pieces: Vec<Piece>,
+    // requests we are waiting on, by piece and offset. the pieces are
+    // kept by index. a torrent can have tens of thousands of blocks, so
+    // nothing that happens per block scans these.
+    pending_blocks: BTreeMap<BlockKey, PendingRequest>,
+    // the same requests by when they were last sent, oldest first, which
+    // is the order they time out in. the ones that have are taken off the
+    // front.
+    sent_at: BTreeSet<(u128, BlockKey)>,
+    // the ones marked expired, for any peer but the one that sat on them
+    expired_blocks: BTreeSet<BlockKey>,
+    missing_pieces: BTreeMap<u32, Piece>,
+    ongoing_pieces: BTreeMap<u32, Piece>,
+    // complete pieces whose hash is being checked by the verifier
+    verifying: BTreeMap<u32, Piece>,
+    have_pieces: BTreeMap<u32, Piece>,
     max_pending_time: u32,
-    total_pieces: u16,
-    fd: File,
+    block_size: u32,
+    // where each piece is in the torrent's data
+    piece_map: PieceMap,
+    stats: StatsTracker,
+    hash_failures: HashMap<String, u32>,
+    max_hash_failures: u32,
+    // the most a peer may ask us for in one request
+    max_request_length: u32,
+    storage: Arc<Storage>,
+    // Some while super seeding
+    super_seeder: Option<SuperSeeder>,
+    max_ongoing_pieces: Option<usize>,
+    // what pending_blocks adds up to, and the most in_flight may reach
+    // before we stop sending requests
+    requested: u64,
+    max_in_flight: Option<u64>,
+    latency: LatencyTracker,
+    // pieces something is waiting on, which go to the fastest peers
+    time_critical: BTreeSet<u32>,
+    // when some of those are needed by. the soonest is picked first.
+    deadlines: HashMap<u32, Instant>,
+    // the same, soonest first
+    by_deadline: BTreeSet<(Instant, u32)>,
+    // peers that have stopped answering our requests, see pipeline.rs
+    snubbed: HashSet<String>,
+    // chooses the pieces nothing more urgent has a claim on
+    picker: Box<dyn PiecePicker>,
+    // nothing is requested or served while this is set
+    paused: bool,
+    // why the torrent stopped, when the disk filled up under it. it
+    // stays paused until resumed, with room made by then hopefully.
+    disk_error: Option<String>,
+}
+
+// how we ask peers for blocks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestTuning {
+    // bytes asked for in one request, a power of two
+    pub block_size: u32,
+    // the most requests kept in flight to one peer. fewer are sent to
+    // slow peers, see pipeline.rs.
+    pub max_requests_per_peer: usize,
+    // how long a block can go unanswered before another peer may be
+    // asked for it
+    pub timeout: Duration,
+}
+
+// settings for a single running torrent
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    // number of peer connections kept open at once
+    pub max_peer_connections: usize,
+    // number of peers we are interested in (and so download from) at once
+    pub max_interested_peers: usize,
+    // how often the slowest interested peer is swapped for a waiting one
+    pub interest_rotation_interval: Duration,
+    // how often the worst peers are dropped for fresh ones from the peer
+    // pool, see churn.rs. None keeps every peer for as long as it stays.
+    pub churn_interval: Option<Duration>,
+    // pieces a peer can send us that fail verification before it is banned
+    pub max_hash_failures: u32,
+    // the biggest block a peer may request. peers asking for more are
+    // disconnected, everyone uses REQUEST_SIZE.
+    pub max_request_length: u32,
+    // block size, requests in flight and how long to wait for them
+    pub requests: RequestTuning,
+    // the start of our peer id, identifying the client to other peers
+    pub peer_id_prefix: String,
+    // the port we announce to trackers
+    pub listen_port: u16,
+    // the one port every kind of udp traffic shares: the dht for now,
+    // utp and udp trackers once we have them. None uses listen_port, so
+    // a single port number needs opening for both tcp and udp and what
+    // a nat maps for one works for the other.
+    pub udp_port: Option<u16>,
+    // peers to ask trackers for on every announce. None picks a number
+    // from how many connections there are left to fill.
+    pub numwant: Option<u32>,
+    // advertise dht support to peers. ignored for private torrents.
+    pub dht: bool,
+    // super seed a torrent when it starts out complete
+    pub super_seeding: bool,
+    // pieces being downloaded at once. None means no limit.
+    pub max_ongoing_pieces: Option<usize>,
+    // bytes we can be waiting on, from peers or the disk and verifier,
+    // before new requests stop going out. None means no limit.
+    pub max_in_flight_bytes: Option<u64>,
+    // peers we upload to at once, the rest wait for a slot. None means
+    // everyone interested is unchoked.
+    pub max_upload_slots: Option<usize>,
+    // how long a peer keeps its upload slot while others wait for one.
+    // None keeps it for as long as the peer stays interested.
+    pub upload_slot_turn: Option<Duration>,
+    // cut short the turns of peers that only take while others are
+    // waiting for an upload slot. None leaves them alone.
+    pub free_riders: Option<FreeRiderConfig>,
+    // time allowed to reach a peer, and then to swap handshakes with it.
+    // peers that can't manage it are usually behind a broken nat or not
+    // running bittorrent at all.
+    pub connect_timeout: Duration,
+    pub handshake_timeout: Duration,
+    // the order new pieces are started in
+    pub piece_picker: PickerKind,
+    // addresses for trackers to hand out instead of, or as well as,
+    // the one our announces come from
+    pub announce_ip: Option<IpAddr>,
+    pub announce_ipv6: Option<Ipv6Addr>,
+    // when a complete torrent stops seeding on its own
+    pub seed_limits: SeedLimits,
+    // commands to run when the torrent is added, completes or fails,
+    // see hooks.rs
+    pub hooks: Hooks,
+    // where to post the torrent's events as json, see notify.rs
+    pub webhook: Option<String>,
+    // what to do about a torrent that won't fit on the disk
+    pub disk_space_check: DiskSpaceCheck,
+    // download into part files and rename them once complete, see
+    // filemap.rs
+    pub part_files: bool,
+    // where complete torrents are moved to, if not left where they
+    // were downloaded
+    pub completed_dir: Option<PathBuf>,
+    // what each peer may take, until it is given caps of its own, see
+    // shaper.rs
+    pub peer_rate_limits: RateLimits,
+}
+
+// peers with an open connection, keyed by their peer id in hex
+pub type PeerRegistry = Arc<Mutex<HashMap<String, PeerInfo>>>;
+
+// everything a peer connection shares with the rest of its torrent
+#[derive(Clone)]
+pub struct PeerContext {
+    // every peer address the torrent knows of
+    pub peers: Arc<Mutex<PeerPool>>,
+    // peers to hang up on for doing too little
+    pub evictions: Evictions,
+    // peers that dialed us, waiting for a connection to answer them
+    pub incoming: IncomingQueue,
+    pub piece_manager: Arc<Mutex<PieceManager>>,
+    pub interest: Arc<Mutex<InterestManager>>,
+    pub uploads: Arc<Mutex<UploadChoker>>,
+    pub connected: PeerRegistry,
+    pub bans: Arc<Mutex<BanList>>,
+    // where the session's peers came from, shared by every torrent
+    pub sources: Arc<Mutex<PeerSources>>,
+    // index of every piece we verify, so all peers can be sent a Have
+    pub haves: broadcast::Sender<u32>,
+    // requests that have expired, each for the connection that sent it
+    pub expired: broadcast::Sender<ExpiredRequest>,
+    pub abort: Arc<AtomicBool>,
+    // whether to set the dht bit in our handshake and send Port messages
+    pub dht: Arc<AtomicBool>,
+    // where the dht nodes peers tell us about go
+    pub dht_state: Arc<Mutex<DhtState>>,
+    // where our (future) dht node listens, sent in Port messages
+    pub dht_port: u16,
+    pub connect_timeout: Duration,
+    pub handshake_timeout: Duration,
+    // where pieces go to be checked once their last block arrives
+    pub verifier: Verifier,
+    // where blocks go to be written, and uploads are read from
+    pub disk: DiskHandle,
+    pub max_requests_per_peer: usize,
+    pub peer_limits: watch::Receiver<PeerLimits>,
+    // the session's caps, taken from by every torrent's connections
+    pub session_limits: SessionShaper,
 }
 
 pub struct TorrentClient {
-    tracker: Tracker,
-    available_peers: Arc<Mutex<VecDeque<PeerConnection>>>,
-    peers: Vec<PeerConnection>,
-    piece_manager: PieceManager,
-    abort: bool,
+    torrent: Arc<Torrent>,
+    tracker: Arc<Tracker>,
+    peers: Arc<Mutex<PeerPool>>,
+    evictions: Evictions,
+    incoming: IncomingQueue,
+    tasks: Vec<JoinHandle<()>>,
+    // the task that talks to the tracker, see announcer.rs
+    announcer: Option<AnnouncerHandle>,
+    connected: PeerRegistry,
+    piece_manager: Arc<Mutex<PieceManager>>,
+    interest: Arc<Mutex<InterestManager>>,
+    uploads: Arc<Mutex<UploadChoker>>,
+    bans: Arc<Mutex<BanList>>,
+    sources: Arc<Mutex<PeerSources>>,
+    dht_state: Arc<Mutex<DhtState>>,
+    external_ip: Arc<ExternalIp>,
+    notifier: Notifier,
+    haves: broadcast::Sender<u32>,
+    expired: broadcast::Sender<ExpiredRequest>,
+    verifier: Verifier,
+    // taken by start, which applies the results as they come in
+    verified: Option<UnboundedReceiver<HashResult>>,
+    // the thread that writes and reads the torrent's data, see disk.rs
+    disk: DiskHandle,
+    transport: Arc<dyn PeerTransport>,
+    config: ClientConfig,
+    state: TorrentState,
+    abort: Arc<AtomicBool>,
+    // what connections advertise, from the session setting, this
+    // torrent's override and whether it is private
+    dht: Arc<AtomicBool>,
+    dht_override: Option<bool>,
+    // every connection watches these for its own caps
+    peer_limits: watch::Sender<PeerLimits>,
+    // unlimited until the session shares its own, see share_rate_limits
+    session_limits: SessionShaper,
+    // set once a seed limit is reached
+    finished: Arc<Mutex<Option<StopReason>>>,
 }
 
 // **** IMPLEMENTATIONS **** // 
 
+impl Default for RequestTuning {
+    fn default() -> Self {
+        RequestTuning {
+            block_size: REQUEST_SIZE,
+            max_requests_per_peer: pipeline::DEFAULT_MAX_DEPTH,
+            timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+impl RequestTuning {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size) {
+            return Err(format!(
+                "block size must be a power of two from {} to {} bytes: {}",
+                MIN_BLOCK_SIZE, MAX_BLOCK_SIZE, self.block_size
+            ));
+        }
+        if !(1..=MAX_REQUESTS_PER_PEER).contains(&self.max_requests_per_peer) {
+            return Err(format!("requests per peer must be from 1 to {}: {}", MAX_REQUESTS_PER_PEER, self.max_requests_per_peer));
+        }
+        // kept in milliseconds as a u32
+        if self.timeout < Duration::from_secs(1) || self.timeout.as_millis() > u32::MAX as u128 {
+            return Err(format!("request timeout must be from 1 second to {} days: {:?}", u32::MAX / 86_400_000, self.timeout));
+        }
+        Ok(())
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            max_peer_connections: 40,
+            max_interested_peers: 16,
+            interest_rotation_interval: Duration::from_secs(30),
+            churn_interval: Some(Duration::from_secs(120)),
+            max_hash_failures: DEFAULT_MAX_HASH_FAILURES,
+            max_request_length: REQUEST_SIZE,
+            requests: RequestTuning::default(),
+            peer_id_prefix: PEER_ID_PREFIX.to_string(),
+            listen_port: DEFAULT_LISTEN_PORT,
+            udp_port: None,
+            numwant: None,
+            dht: false,
+            super_seeding: false,
+            max_ongoing_pieces: None,
+            max_in_flight_bytes: Some(DEFAULT_MAX_IN_FLIGHT),
+            max_upload_slots: Some(8),
+            upload_slot_turn: Some(Duration::from_secs(60)),
+            free_riders: None,
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            piece_picker: PickerKind::RarestFirst,
+            announce_ip: None,
+            announce_ipv6: None,
+            seed_limits: SeedLimits::default(),
+            hooks: Hooks::default(),
+            webhook: None,
+            disk_space_check: DiskSpaceCheck::default(),
+            part_files: true,
+            completed_dir: None,
+            peer_rate_limits: RateLimits::default(),
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn udp_port(&self) -> u16 {
+        self.udp_port.unwrap_or(self.listen_port)
+    }
+
+    // for raspberry pi class machines: a handful of peers and pieces at
+    // a time. there are no piece buffers or disk cache to shrink, every
+    // block already goes straight to the file.
+    pub fn low_memory() -> ClientConfig {
+        ClientConfig {
+            max_peer_connections: 8,
+            max_interested_peers: 4,
+            max_ongoing_pieces: Some(4),
+            max_in_flight_bytes: Some(8 << 20),
+            ..ClientConfig::default()
+        }
+    }
+}
+
 impl TorrentClient {
-    pub async fn new(torrent: Torrent) -> Result<Self, Box<dyn Error>> {
+    pub async fn new(
+        torrent: Torrent,
+        config: ClientConfig,
+        transport: Arc<dyn PeerTransport>,
+        bans: Arc<Mutex<BanList>>,
+        sources: Arc<Mutex<PeerSources>>,
+        dht_state: Arc<Mutex<DhtState>>,
+        external_ip: Arc<ExternalIp>,
+    ) -> Result<Self, BtError> {
         let torrent = Arc::new(torrent);
         
-        let tracker = Tracker::new(torrent.clone());
-        let piece_manager = PieceManager::new(torrent.clone())?;
-        let available_peers = Arc::new(Mutex::new(VecDeque::new()));
+        let tracker = Tracker::new(torrent.clone(), &config.peer_id_prefix, config.listen_port)
+            .with_addresses(config.announce_ip, config.announce_ipv6);
+        let tracker = Arc::new(tracker);
+        let mut piece_manager = PieceManager::open(torrent.clone(), config.part_files, config.requests.block_size)?;
+        piece_manager.max_pending_time = config.requests.timeout.as_millis() as u32;
+        piece_manager.max_hash_failures = config.max_hash_failures;
+        piece_manager.max_request_length = config.max_request_length;
+        piece_manager.max_ongoing_pieces = config.max_ongoing_pieces;
+        piece_manager.max_in_flight = config.max_in_flight_bytes;
+        piece_manager.set_picker(config.piece_picker.build());
+        let piece_manager = Arc::new(Mutex::new(piece_manager));
+        let interest = Arc::new(Mutex::new(InterestManager::new(config.max_interested_peers)));
+        // our own addresses, which trackers like to hand back to us
+        let mut peers = PeerPool::new();
+        let own_ips = [IpAddr::from(Ipv4Addr::LOCALHOST), IpAddr::from(Ipv6Addr::LOCALHOST)];
+        let configured = config.announce_ip.into_iter().chain(config.announce_ipv6.map(IpAddr::from));
+        for ip in own_ips.into_iter().chain(configured).chain(external_ip.get().iter()) {
+            peers.exclude(&(ip.to_string(), config.listen_port));
+        }
+        let mut uploads = UploadChoker::new(config.max_upload_slots, config.free_riders.clone());
+        uploads.set_turn(config.upload_slot_turn);
+        let uploads = Arc::new(Mutex::new(uploads));
+        let dht = Arc::new(AtomicBool::new(config.dht && !torrent.private));
+        // a trackerless torrent's nodes are the first ones to ask. like
+        // those from Port messages, they have no ids until they answer.
+        if !torrent.private {
+            let mut dht_state = dht_state.lock().unwrap();
+            for node in &torrent.nodes {
+                dht_state.add_candidate(node.clone());
+            }
+        }
+        let (verifier, verified) = Verifier::new();
+        let disk = Disk { storage: piece_manager.lock().unwrap().storage() }.spawn()?;
+        let notifier = Notifier::new(config.webhook.clone(), torrent.clone());
 
         Ok(TorrentClient {
+            torrent,
             tracker,
-            available_peers,
-            peers: vec![],
+            peers: Arc::new(Mutex::new(peers)),
+            evictions: Evictions::default(),
+            incoming: IncomingQueue::default(),
+            tasks: vec![],
+            announcer: None,
+            connected: Arc::new(Mutex::new(HashMap::new())),
             piece_manager,
-            abort: false,
+            interest,
+            uploads,
+            bans,
+            sources,
+            dht_state,
+            external_ip,
+            notifier,
+            haves: broadcast::Sender::new(HAVE_BACKLOG),
+            expired: broadcast::Sender::new(EXPIRY_BACKLOG),
+            verifier,
+            verified: Some(verified),
+            disk,
+            transport,
+            state: TorrentState::Downloading,
+            abort: Arc::new(AtomicBool::new(false)),
+            dht,
+            dht_override: None,
+            peer_limits: watch::channel(PeerLimits::new(config.peer_rate_limits)).0,
+            session_limits: SessionShaper::default(),
+            finished: Arc::new(Mutex::new(None)),
+            config,
         })
     }
 
-    
+    fn context(&self) -> PeerContext {
+        PeerContext {
+            peers: self.peers.clone(),
+            evictions: self.evictions.clone(),
+            incoming: self.incoming.clone(),
+            piece_manager: self.piece_manager.clone(),
+            interest: self.interest.clone(),
+            uploads: self.uploads.clone(),
+            connected: self.connected.clone(),
+            bans: self.bans.clone(),
+            sources: self.sources.clone(),
+            haves: self.haves.clone(),
+            expired: self.expired.clone(),
+            abort: self.abort.clone(),
+            dht: self.dht.clone(),
+            dht_state: self.dht_state.clone(),
+            dht_port: self.config.udp_port(),
+            connect_timeout: self.config.connect_timeout,
+            handshake_timeout: self.config.handshake_timeout,
+            verifier: self.verifier.clone(),
+            disk: self.disk.clone(),
+            max_requests_per_peer: self.config.requests.max_requests_per_peer,
+            peer_limits: self.peer_limits.subscribe(),
+            session_limits: self.session_limits.clone(),
+        }
+    }
+
+    // spawns the peer connections along with the announce and interest
+    // rotation loops. everything runs on background tasks so this
+    // returns straight away.
+    pub fn start(&mut self) {
+        for _ in 0..self.config.max_peer_connections {
+            let mut conn = PeerConnection::new(self.tracker.peer_id().to_string(), self.transport.clone(), self.context());
+            self.tasks.push(tokio::spawn(async move { conn.start().await }));
+        }
+
+        // tell every peer about a piece once it checks out. other peers
+        // may also have nothing left that we want. the last piece, or
+        // the first one that can't be written, runs the hooks for it.
+        if let Some(mut verified) = self.verified.take() {
+            let pm = self.piece_manager.clone();
+            let interest = self.interest.clone();
+            let haves = self.haves.clone();
+            let torrent = self.torrent.clone();
+            let hooks = self.config.hooks.clone();
+            let mut notifier = self.notifier.clone();
+            let completed_dir = self.config.completed_dir.clone();
+            self.tasks.push(tokio::spawn(async move {
+                let mut failed = false;
+                while let Some(result) = verified.recv().await {
+                    let index = result.index;
+                    let error = result.outcome.as_ref().err().map(|e| format!("failed to write piece {}: {}", index, e));
+                    let corrupt = matches!(result.outcome, Ok(false));
+                    let finished = {
+                        let mut pm = pm.lock().unwrap();
+                        if pm.piece_checked(result) {
+                            let _ = haves.send(index);
+                            pm.complete().then(|| pm.storage())
+                        } else {
+                            if corrupt {
+                                notifier.notify(Event::HashFailure { piece: index });
+                            }
+                            None
+                        }
+                    };
+
+                    // moving to another filesystem copies every file, so
+                    // it happens off the lock and off the runtime
+                    if let Some(storage) = finished {
+                        let to = completed_dir.clone();
+                        let moved = tokio::task::spawn_blocking(move || storage.finalize(to.as_deref()))
+                            .await
+                            .unwrap_or_else(|e| Err(io::Error::other(e)));
+                        let torrent = match moved {
+                            Ok(location) => Arc::new(Torrent { output_file: location.to_string_lossy().to_string(), ..(*torrent).clone() }),
+                            Err(e) => {
+                                warn!("couldn't move {} into place, it stays where it is: {}", torrent.output_file, e);
+                                torrent.clone()
+                            }
+                        };
+                        hooks.run(HookEvent::Completed, &torrent, None);
+                        notifier.set_torrent(torrent);
+                        notifier.notify(Event::Completed);
+                    }
+                    if let Some(error) = error.filter(|_| !failed) {
+                        failed = true;
+                        hooks.run(HookEvent::Error, &torrent, Some(&error));
+                    }
+                    interest.lock().unwrap().notify_changed();
+                }
+            }));
+        }
+
+        // a trackerless torrent finds its peers over the dht alone
+        if self.announcer.is_none() && !self.torrent.trackers().is_empty() {
+            self.spawn_announcer();
+        }
+
+        let interest = self.interest.clone();
+        let pm = self.piece_manager.clone();
+        let rotation = self.config.interest_rotation_interval;
+        self.tasks.push(tokio::spawn(async move {
+            let mut ticker = interval(rotation);
+            loop {
+                ticker.tick().await;
+                let now = Instant::now();
+                let rates = pm.lock().unwrap().stats().download_rates(now);
+                if let Some(peer_id) = interest.lock().unwrap().rotate(now, &rates) {
+                    info!("rotated out slow peer {}", peer_id);
+                }
+            }
+        }));
+
+        self.spawn_request_expiry();
+
+        if let Some(every) = self.config.churn_interval {
+            self.spawn_churn(every);
+        }
+
+        if self.config.max_upload_slots.is_some() && (self.config.upload_slot_turn.is_some() || self.config.free_riders.is_some()) {
+            let interest = self.interest.clone();
+            let uploads = self.uploads.clone();
+            let pm = self.piece_manager.clone();
+            self.tasks.push(tokio::spawn(async move {
+                let mut ticker = interval(CHOKE_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    let transfers = pm.lock().unwrap().stats().give_take();
+                    let choked = uploads.lock().unwrap().rotate(Instant::now(), &transfers);
+                    if !choked.is_empty() {
+                        info!("passed on the upload slots of {}", choked.join(", "));
+                        interest.lock().unwrap().notify_changed();
+                    }
+                }
+            }));
+        }
+
+        if self.config.seed_limits.is_set() {
+            self.spawn_seed_limits();
+        }
+
+        let pm = self.piece_manager.clone();
+        let name = self.torrent.output_file.clone();
+        self.tasks.push(tokio::spawn(async move {
+            let mut reporter = ProgressReporter::new(PROGRESS_LOG_INTERVAL);
+            let mut ticker = interval(Duration::from_secs(1));
+            loop {
+                ticker.tick().await;
+                let progress = pm.lock().unwrap().progress();
+                if let Some(progress) = reporter.update(progress, Instant::now()) {
+                    info!("{}: {}", name, progress);
+                }
+            }
+        }));
+    }
+
+    // requests only used to be looked at again when a peer happened to
+    // ask for more blocks, so a download stuck on a few unanswered ones
+    // stayed stuck. this goes over them on a timer: the connections that
+    // sent them cancel them and count the peer as snubbing us, and the
+    // wakeup gets the blocks asked of someone else.
+    fn spawn_request_expiry(&mut self) {
+        let pm = self.piece_manager.clone();
+        let expired = self.expired.clone();
+        let interest = self.interest.clone();
+        self.tasks.push(tokio::spawn(async move {
+            let mut ticker = interval(EXPIRY_INTERVAL);
+            loop {
+                ticker.tick().await;
+                let requests = pm.lock().unwrap().expire_requests();
+                if requests.is_empty() {
+                    continue;
+                }
+                info!("{} requests went unanswered for too long, asking other peers", requests.len());
+                for request in requests {
+                    let _ = expired.send(request);
+                }
+                interest.lock().unwrap().notify_changed();
+            }
+        }));
+    }
+
+    // drops the peers doing least for us, but only while every
+    // connection is taken and the pool has someone to replace them with.
+    // a complete torrent keeps whoever it is uploading to.
+    fn spawn_churn(&mut self, every: Duration) {
+        let pm = self.piece_manager.clone();
+        let peers = self.peers.clone();
+        let connected = self.connected.clone();
+        let evictions = self.evictions.clone();
+        let interest = self.interest.clone();
+        let max_connections = self.config.max_peer_connections;
+        self.tasks.push(tokio::spawn(async move {
+            let mut churn = Churn::new(every);
+            let mut ticker = interval(every);
+            loop {
+                ticker.tick().await;
+                let now = Instant::now();
+                let due = peers.lock().unwrap().due(now);
+                let open = connected.lock().unwrap().len();
+                if due == 0 || open < max_connections {
+                    continue;
+                }
+                let scores = {
+                    let mut pm = pm.lock().unwrap();
+                    if pm.complete() {
+                        continue;
+                    }
+                    pm.peer_scores(now)
+                };
+
+                // a tenth of the connections at most, so a bad round
+                // can't empty the torrent
+                let worst = churn.pick(&scores, now, due.min((open / 10).max(1)));
+                if !worst.is_empty() {
+                    info!("dropping the worst peers {}", worst.join(", "));
+                    evictions.lock().unwrap().extend(worst);
+                    interest.lock().unwrap().notify_changed();
+                }
+            }
+        }));
+    }
+
+    // stops the torrent once it has seeded enough. the task is done
+    // after that, so a finished torrent that is resumed has no limits.
+    fn spawn_seed_limits(&mut self) {
+        let pm = self.piece_manager.clone();
+        let interest = self.interest.clone();
+        let announcer = self.announcer.clone();
+        let finished = self.finished.clone();
+        let limits = self.config.seed_limits;
+        let total_size = self.torrent.total_size;
+        let name = self.torrent.output_file.clone();
+        self.tasks.push(tokio::spawn(async move {
+            let mut clock = SeedClock::new(limits, Instant::now());
+            let mut ticker = interval(seeding::CHECK_INTERVAL);
+            let reason = loop {
+                ticker.tick().await;
+                let mut pm = pm.lock().unwrap();
+                let totals = pm.announce_totals();
+                let seeding = pm.complete() && !pm.is_paused();
+                let ratio = seeding::share_ratio(totals.uploaded, totals.downloaded, total_size);
+                if let Some(reason) = clock.tick(Instant::now(), seeding, totals.uploaded, ratio) {
+                    pm.set_paused(true);
+                    *finished.lock().unwrap() = Some(reason);
+                    break reason;
+                }
+            };
+
+            interest.lock().unwrap().notify_changed();
+            info!("{}: done seeding ({:?} limit reached)", name, reason);
+            if let Some(announcer) = announcer {
+                if let Err(e) = announcer.stop().await {
+                    warn!("couldn't tell the tracker we stopped: {}", e);
+                }
+            }
+        }));
+    }
+
+    // announces every interval the tracker asks for, starting with
+    // event=started
+    fn spawn_announcer(&mut self) {
+        let announcer = Announcer {
+            tracker: self.tracker.clone(),
+            peers: self.peers.clone(),
+            piece_manager: self.piece_manager.clone(),
+            connected: self.connected.clone(),
+            sources: self.sources.clone(),
+            external_ip: self.external_ip.clone(),
+            notifier: self.notifier.clone(),
+            max_connections: self.config.max_peer_connections,
+            numwant: self.config.numwant,
+        };
+        self.announcer = Some(announcer.spawn());
+    }
+
+    // tears down every background task belonging to this torrent
+    pub fn stop(&mut self) {
+        self.abort.store(true, Ordering::Relaxed);
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        // the announcer goes once nothing is left to talk to it, which
+        // may be after a last announce_stopped
+        self.announcer = None;
+    }
+
+    // stop() for when the process is about to exit: once the tasks are
+    // gone (dropping every peer connection with them) the disk is closed
+    // behind the block writes they left it, nothing else gets written, so
+    // what is on disk can be flushed and trusted by the resume data. the
+    // tracker is told separately, see announce_stopped.
+    pub async fn shutdown(&mut self) -> Result<(), BtError> {
+        self.stop();
+        self.disk.close().await.map_err(|e| {
+            BtError::Storage(io::Error::new(e.kind(), format!("couldn't flush {} to disk: {}", self.torrent.output_file, e)))
+        })
+    }
+
+    // the event=stopped announce, to be awaited after shutdown. it
+    // doesn't borrow the client so every torrent can say goodbye at once,
+    // and has to be made before shutdown lets go of the announcer.
+    pub fn announce_stopped(&self) -> impl std::future::Future<Output = Result<(), BtError>> + Send + 'static {
+        let announcer = self.announcer.clone();
+        async move {
+            match announcer {
+                Some(announcer) => announcer.stop().await,
+                None => Ok(()),
+            }
+        }
+    }
+
+    pub fn torrent(&self) -> &Arc<Torrent> {
+        &self.torrent
+    }
+
+    // where the session's listener leaves peers that dialed us for this
+    // torrent
+    pub fn incoming(&self) -> IncomingQueue {
+        self.incoming.clone()
+    }
+
+    pub fn state(&self) -> TorrentState {
+        if self.finished.lock().unwrap().is_some() {
+            return TorrentState::Finished;
+        }
+        if self.piece_manager.lock().unwrap().disk_error().is_some() {
+            return TorrentState::Error;
+        }
+        match self.state {
+            TorrentState::Downloading if self.is_complete() => TorrentState::Seeding,
+            state => state,
+        }
+    }
+
+    // the torrent's files, wherever they are now
+    pub fn storage(&self) -> Arc<Storage> {
+        self.piece_manager.lock().unwrap().storage()
+    }
+
+    // true once every piece is on disk
+    pub fn is_complete(&self) -> bool {
+        self.piece_manager.lock().unwrap().complete()
+    }
+
+    // skips downloading entirely and seeds the data already on disk.
+    // the caller is responsible for having checked the data first.
+    pub async fn assume_complete(&mut self) {
+        let storage = {
+            let mut pm = self.piece_manager.lock().unwrap();
+            pm.mark_complete();
+            if self.config.super_seeding {
+                pm.start_super_seeding();
+            }
+            pm.storage()
+        };
+        self.state = TorrentState::Seeding;
+
+        // part files left by a run that stopped between the last piece
+        // and renaming them
+        let renamed = tokio::task::spawn_blocking(move || storage.finalize(None))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        if let Err(e) = renamed {
+            warn!("couldn't rename the part files of {}: {}", self.torrent.output_file, e);
+        }
+    }
+
+    // picks up from an earlier session before the torrent is started.
+    // as with assume_complete, the data must already have been checked.
+    pub async fn restore(&mut self, resume: &ResumeState) {
+        {
+            let mut pm = self.piece_manager.lock().unwrap();
+            pm.mark_have(&resume.have);
+            pm.stats().carry_over(resume.downloaded, resume.uploaded);
+        }
+
+        if resume.is_complete() {
+            self.assume_complete().await;
+        }
+    }
+
+    // what restore needs to carry on elsewhere
+    pub fn resume_state(&self) -> ResumeState {
+        let pm = self.piece_manager.lock().unwrap();
+        ResumeState {
+            have: pm.bitfield(self.torrent.pieces.len()),
+            downloaded: pm.bytes_downloaded(),
+            uploaded: pm.bytes_uploaded(),
+        }
+    }
+
+    // dht support as the session has it, applied unless the torrent
+    // has an override. only connections made after this see the change.
+    pub fn set_session_dht(&mut self, enabled: bool) {
+        self.config.dht = enabled;
+        self.update_dht();
+    }
+
+    // Some forces dht on or off for this torrent, None goes back to the
+    // session setting. private torrents never use the dht either way.
+    pub fn set_dht_override(&mut self, enabled: Option<bool>) {
+        self.dht_override = enabled;
+        self.update_dht();
+    }
+
+    fn update_dht(&self) {
+        let enabled = !self.torrent.private && self.dht_override.unwrap_or(self.config.dht);
+        self.dht.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn dht_enabled(&self) -> bool {
+        self.dht.load(Ordering::Relaxed)
+    }
+
+    // asks for the piece to be downloaded within the given time, e.g.
+    // by a media player that is about to reach it. it goes to the
+    // lowest latency peers ahead of everything else, and once it is
+    // nearly due its blocks are asked of a second peer too. pairs with
+    // read_piece.
+    pub fn set_piece_deadline(&self, piece: u32, deadline: Duration) -> Result<(), BtError> {
+        let num_pieces = self.torrent.pieces.len();
+        if piece as usize >= num_pieces {
+            return Err(BtError::Session(format!("piece {} is out of range, the torrent has {} pieces", piece, num_pieces)));
+        }
+        self.piece_manager.lock().unwrap().set_piece_deadline(piece, Instant::now() + deadline);
+        self.interest.lock().unwrap().notify_changed();
+        Ok(())
+    }
+
+    pub fn clear_deadline(&self, piece: u32) {
+        self.piece_manager.lock().unwrap().clear_deadline(piece);
+    }
+
+    // the piece's data, checked against its hash, for a program that
+    // uses the data itself instead of reading the files. waits for the
+    // piece to download if we don't have it yet, which takes as long as
+    // it takes: a deadline gets it sooner, and a torrent that is paused
+    // or queued doesn't get it at all until it starts again. it doesn't
+    // borrow the client, like announce_stopped.
+    pub fn read_piece(&self, index: u32) -> impl std::future::Future<Output = Result<Bytes, BtError>> + Send + 'static {
+        wait_for_piece(self.piece_manager.clone(), self.haves.subscribe(), index)
+    }
+
+    // pieces already under way are finished whatever the new strategy
+    pub fn set_piece_picker(&mut self, kind: PickerKind) {
+        self.config.piece_picker = kind;
+        self.piece_manager.lock().unwrap().set_picker(kind.build());
+    }
+
+    // a torrent waiting its turn in the session's queue. one that was
+    // never started stays that way, one that was running stops talking
+    // to peers and the tracker until dequeue, like a pause.
+    pub fn enqueue(&mut self) {
+        if self.is_started() {
+            self.pause(true);
+        }
+        self.state = TorrentState::Queued;
+    }
+
+    // lets a queued torrent run, starting it the first time
+    pub fn dequeue(&mut self) {
+        if self.is_started() {
+            self.resume();
+        } else {
+            self.state = TorrentState::Downloading;
+            self.start();
+        }
+    }
+
+    pub fn is_started(&self) -> bool {
+        !self.tasks.is_empty()
+    }
+
+    // stops requesting and uploading until resumed. connections stay
+    // open and all of the piece state is kept, so nothing has to be
+    // rechecked. with announce_stopped the tracker is told we have gone,
+    // and isn't announced to again until resume.
+    pub fn pause(&mut self, announce_stopped: bool) {
+        self.state = TorrentState::Paused;
+        self.piece_manager.lock().unwrap().set_paused(true);
+        self.interest.lock().unwrap().notify_changed();
+
+        if announce_stopped {
+            if let Some(announcer) = &self.announcer {
+                announcer.stop_in_background();
+            }
+        }
+    }
+
+    pub fn resume(&mut self) {
+        self.finished.lock().unwrap().take();
+        self.piece_manager.lock().unwrap().set_paused(false);
+        self.interest.lock().unwrap().notify_changed();
+        // back to event=started if the tracker was told we stopped
+        if let Some(announcer) = &self.announcer {
+            announcer.start();
+        }
+
+        self.state = if self.piece_manager.lock().unwrap().complete() {
+            TorrentState::Seeding
+        } else {
+            TorrentState::Downloading
+        };
+    }
+
+    // snapshot of the torrent's progress, used by the rpc server
+    pub fn status(&self, id: TorrentId) -> TorrentStatus {
+        let state = self.state();
+        let stop_reason = *self.finished.lock().unwrap();
+        let mut pm = self.piece_manager.lock().unwrap();
+        let progress = pm.progress();
+        let totals = pm.announce_totals();
+
+        TorrentStatus {
+            id,
+            name: self.torrent.output_file.clone(),
+            info_hash: self.torrent.info_hash.to_string(),
+            state,
+            error: pm.disk_error().map(str::to_string),
+            path: pm.location().to_string_lossy().to_string(),
+            pieces_have: progress.have,
+            pieces_total: progress.wanted,
+            progress: progress.fraction(),
+            // the queue belongs to the session, which fills this in
+            queue_position: 0,
+            downloaded: totals.downloaded,
+            uploaded: totals.uploaded,
+            ratio: seeding::share_ratio(totals.uploaded, totals.downloaded, self.torrent.total_size),
+            stop_reason,
+            total_size: self.torrent.total_size,
+            dht: self.dht_enabled(),
+            tracker: self.tracker.state(),
+            stats: pm.stats().torrent(Instant::now()),
+            accounting: pm.stats().accounting(),
+        }
+    }
+
+    pub fn notify(&self, event: Event) {
+        self.notifier.notify(event);
+    }
+
+    // every tracker in the torrent, in the order they are tried
+    pub fn trackers(&self) -> Vec<TrackerState> {
+        self.tracker.states()
+    }
+
+    // the download and upload rate each second for the last few minutes
+    pub fn rate_history(&self) -> RateSeries {
+        self.piece_manager.lock().unwrap().stats().history(Instant::now())
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let mut pm = self.piece_manager.lock().unwrap();
+        let limits = self.peer_limits.borrow();
+        let now = Instant::now();
+        self.connected.lock().unwrap()
+            .iter()
+            .map(|(id, peer)| PeerInfo {
+                pieces: pm.peers.get(id).map_or(0, |bf| bf.iter().filter(|&&b| b != 0).count()),
+                stats: pm.stats().peer_snapshot(id, now).unwrap_or_default(),
+                snubbed: pm.is_snubbed(id),
+                limits: limits.for_peer(&peer.address),
+                ..peer.clone()
+            })
+            .collect()
+    }
+
+    // holds the torrent's connections to the session's caps as well as
+    // their own. only connections started after this take any notice.
+    pub fn share_rate_limits(&mut self, limits: SessionShaper) {
+        self.session_limits = limits;
+    }
+
+    // caps for the peer at address (ip or ip:port) in place of the
+    // torrent's default, or with None back to the default. connections
+    // to it pick them up straight away.
+    pub fn set_peer_rate_limits(&self, address: &str, limits: Option<RateLimits>) -> Result<(), BtError> {
+        let mut peer_limits = self.peer_limits.borrow().clone();
+        peer_limits.set(address, limits).map_err(BtError::Config)?;
+        self.peer_limits.send_replace(peer_limits);
+        Ok(())
+    }
+}
+
+impl Drop for TorrentClient {
+    fn drop(&mut self) {
+        self.stop();
+    }
 }
 
 impl PieceManager {
     // create new piece manager from torrent
     pub fn new(torrent: Arc<Torrent>) -> IoResult<PieceManager> {
-        let total_pieces = torrent.pieces.len() as u16;
+        PieceManager::open(torrent, false, REQUEST_SIZE)
+    }
 
-        // output directory for torrent.
-        let fd = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(Path::new(&torrent.output_file))?;
+    // see Storage::open_with. the torrent is split into blocks of
+    // block_size up front, so it can't change afterwards.
+    pub fn open(torrent: Arc<Torrent>, part_files: bool, block_size: u32) -> IoResult<PieceManager> {
+        // pieces are numbered with a u32 on the wire
+        if u32::try_from(torrent.pieces.len()).is_err() {
+            return Err(io::Error::other("the torrent has too many pieces"));
+        }
+        let piece_map = torrent.piece_map();
+
+        let storage = Arc::new(Storage::open_with(&torrent, part_files)?);
 
         let mut pm = PieceManager {
             torrent,
             peers: HashMap::new(),
-            pending_blocks: Vec::new(),
-            missing_pieces: Vec::new(),
-            ongoing_pieces: Vec::new(),
-            have_pieces: Vec::new(),
+            pending_blocks: BTreeMap::new(),
+            sent_at: BTreeSet::new(),
+            expired_blocks: BTreeSet::new(),
+            missing_pieces: BTreeMap::new(),
+            ongoing_pieces: BTreeMap::new(),
+            verifying: BTreeMap::new(),
+            have_pieces: BTreeMap::new(),
             max_pending_time: 300_000,
-            total_pieces,
-            fd,
+            block_size,
+            piece_map,
+            stats: StatsTracker::new(),
+            hash_failures: HashMap::new(),
+            max_hash_failures: DEFAULT_MAX_HASH_FAILURES,
+            max_request_length: REQUEST_SIZE,
+            storage,
+            super_seeder: None,
+            max_ongoing_pieces: None,
+            requested: 0,
+            max_in_flight: None,
+            latency: LatencyTracker::new(),
+            time_critical: BTreeSet::new(),
+            deadlines: HashMap::new(),
+            by_deadline: BTreeSet::new(),
+            snubbed: HashSet::new(),
+            picker: PickerKind::default().build(),
+            paused: false,
+            disk_error: None,
         };
 
-        pm.missing_pieces = pm.initiate_pieces();
+        pm.missing_pieces = pm.initiate_pieces().into_iter().map(|p| (p.index, p)).collect();
 
         Ok(pm)
     }
 
+    pub fn torrent(&self) -> &Arc<Torrent> {
+        &self.torrent
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
     pub fn print(&self) {
         println!("Torrent: {:#?}\n Peers: {:#?}\n Pending Blocks: {:#?}\n", self.torrent, self.peers, self.pending_blocks);
     }
@@ -130,159 +1255,577 @@ impl PieceManager {
     pub fn initiate_pieces(&self) -> Vec<Piece> {
         let torrent = &self.torrent;
         let mut pieces: Vec<Piece> = Vec::new();
-        let total_pieces = torrent.pieces.len();
-        // the std number of blocks is the length of all of the blocks
-        // for all pieces except for (in most, but perhaps not all, cases) the last piece.
-        let std_piece_blocks = torrent.piece_length.div_ceil(REQUEST_SIZE);
 
         for (i, hash_value) in torrent.pieces.iter().enumerate() {
-            let mut blocks: Vec<Block> = Vec::new(); 
-            // check if the current piece is not the last piece
-            if i < (total_pieces - 1) {
-                for offset in 0..std_piece_blocks {
-                    let block: Block = Block::new(i as u64, (offset * REQUEST_SIZE )as u64, REQUEST_SIZE as u64);
-                    blocks.push(block);
+            let blocks: Vec<Block> = self
+                .piece_map
+                .blocks(i as u32, self.block_size as u64)
+                .map(|(begin, length)| Block::new(i as u64, begin, length))
+                .collect();
+
+            // push piece
+            pieces.push(Piece 
+                { index: i as u32, blocks, hash_value: *hash_value, hasher: Some(Sha1::new()), hashed: 0 }
+            )
+        }
+        pieces
+    }
+
+
+    // a block a peer sent. one we asked for is handed back to be written
+    // off the lock, with the piece's hash when there is a block for it,
+    // and block_written takes what came of that. until then the block is
+    // being written, so a second copy of it is thrown away and the piece
+    // can't be finished under it.
+    pub fn block_received(&mut self, peer_id: String, piece_index: u64, block_offset: u64, data: Bytes) -> Option<BlockWrite> {
+        let length = data.len() as u64;
+        self.stats.record_download(&peer_id, length, Instant::now());
+    
+        let index = piece_index as u32;
+        // the other copy of a block asked of two peers got here first
+        if self.ongoing_pieces.get(&index).is_some_and(|piece| piece.is_taken(block_offset))
+            || self.verifying.contains_key(&index)
+            || self.has_piece(index)
+        {
+            self.forget_request(piece_index, block_offset);
+            self.stats.record_wasted(&peer_id, length, Instant::now());
+            return None;
+        }
+
+        // only a block we asked someone for, exactly as we asked for it.
+        // anything else would be written over whatever is next to it,
+        // pieces we have already checked included.
+        let requested = self.pending_blocks.contains_key(&(piece_index, block_offset));
+        let expected = self.ongoing_pieces.get(&index).and_then(|piece| piece.block_at(block_offset));
+        if !expected.is_some_and(|block| block.length == length && (requested || block.status == Status::Pending)) {
+            warnings::warn("unexpected block", || format!("{} sent {} bytes at {} of piece {}, which we didn't ask for", peer_id, length, block_offset, index));
+            self.stats.record_wasted(&peer_id, length, Instant::now());
+            return None;
+        }
+
+        self.forget_request(piece_index, block_offset);
+        let offset = self.piece_offset(index);
+        let piece = self.ongoing_pieces.get_mut(&index)?;
+        let hash = piece.take_hash(offset, block_offset);
+        piece.block_writing(block_offset, &peer_id);
+        Some(BlockWrite { peer_id, index, offset: block_offset, position: offset + block_offset, data, hash })
+    }
+
+    // returns the job that checks the piece if the block finished it
+    // off. piece_checked takes the result. a block that couldn't be
+    // written is asked for again, once the disk has room for it.
+    pub fn block_written(&mut self, written: BlockWritten) -> Option<HashJob> {
+        let BlockWritten { peer_id, index, offset, outcome, hash } = written;
+        // the torrent was paused or checked under it
+        let mut piece = self.ongoing_pieces.remove(&index)?;
+
+        // a piece that was started over while its hasher was out has a
+        // new one by now
+        match hash {
+            Some((hasher, Ok(covered))) if piece.hasher.is_none() => {
+                piece.hasher = Some(hasher);
+                piece.hashed += covered;
+            }
+            Some((_, Err(e))) => {
+                warnings::warn("piece write failed", || format!("failed to read back a block of piece {}: {}", index, e));
+                piece.reset();
+            }
+            _ => {}
+        }
+
+        let writing = piece.block_at(offset).is_some_and(|block| block.status == Status::Writing);
+        match outcome {
+            Err(e) => {
+                warnings::warn("piece write failed", || format!("failed to write block of piece {} to file: {}", index, e));
+                self.write_failed(&e);
+                if writing {
+                    piece.block_missing(offset);
                 }
-            // if the current piece is not the last piece, then it might be the case
-            // that the length of this piece is not the same as the rest of the pieces
-            // and we need to account for that
+            }
+            Ok(()) if writing => piece.block_received(offset as u32, &peer_id),
+            Ok(()) => {}
+        }
+
+        if piece.is_complete() && piece.hasher.is_some() {
+            let job = piece.hash_job(self.piece_offset(index), self.storage.clone());
+            self.verifying.insert(index, piece);
+            return Some(job);
+        }
+        self.ongoing_pieces.insert(index, piece);
+        None
+    }
+
+    fn forget_request(&mut self, piece_index: u64, block_offset: u64) {
+        let key = (piece_index, block_offset);
+        if let Some(request) = self.pending_blocks.remove(&key) {
+            self.requested -= request.block.length;
+            self.sent_at.remove(&(request.added, key));
+            self.expired_blocks.remove(&key);
+        }
+    }
+
+    // a piece that is started over can ask for a block again while the
+    // old request is still around, which this replaces
+    fn add_request(&mut self, request: PendingRequest) {
+        let key = (request.block.piece, request.block.offset);
+        self.forget_request(key.0, key.1);
+        self.requested += request.block.length;
+        self.sent_at.insert((request.added, key));
+        self.pending_blocks.insert(key, request);
+    }
+
+    // moves a request to the back of sent_at, as sent again at current
+    fn resend(&mut self, key: BlockKey, current: u128, expired: bool) -> Option<&mut PendingRequest> {
+        let request = self.pending_blocks.get_mut(&key)?;
+        self.sent_at.remove(&(request.added, key));
+        self.sent_at.insert((current, key));
+        request.added = current;
+        request.expired = expired;
+        if expired {
+            self.expired_blocks.insert(key);
+        } else {
+            self.expired_blocks.remove(&key);
+        }
+        Some(request)
+    }
+
+    // whether a block a peer sent is one the torrent is made of, at all.
+    // a peer sending one that isn't is broken and gets disconnected.
+    pub fn is_block(&self, index: u32, begin: u32, length: u32) -> bool {
+        self.piece_map.blocks(index, self.block_size as u64).any(|block| block == (begin as u64, length as u64))
+    }
+
+    // returns true if the piece passed its hash check and is ours now.
+    // one that didn't goes back to being downloaded.
+    pub fn piece_checked(&mut self, result: HashResult) -> bool {
+        let Some(mut piece) = self.verifying.remove(&result.index) else {
+            return false;
+        };
+
+        match result.outcome {
+            Ok(true) => {
+                self.forget_deadline(piece.index);
+                self.stats.record_verified(piece.length());
+                self.have_pieces.insert(piece.index, piece);
+                return true;
+            }
+            Ok(false) => {
+                warnings::warn("corrupt piece", || format!("discarding corrupt piece {}", piece.index));
+                self.blame(&piece);
+            }
+            Err(e) => {
+                warnings::warn("piece write failed", || format!("failed to write piece {} to file: {}", piece.index, e));
+                self.write_failed(&e);
+            }
+        }
+        piece.reset();
+        self.ongoing_pieces.insert(piece.index, piece);
+        false
+    }
+
+    // every peer that contributed to a piece that failed its hash check
+    // gets a strike. we can't tell which block was bad, so when several
+    // peers shared a piece they are all blamed.
+    fn blame(&mut self, piece: &Piece) {
+        let contributors = piece.contributors();
+        self.stats.record_hash_failure(&contributors, piece.length(), Instant::now());
+
+        for peer_id in contributors {
+            *self.hash_failures.entry(peer_id).or_insert(0) += 1;
+        }
+    }
+
+    // true once the peer has sent us too many pieces that failed verification
+    pub fn is_banned(&self, peer_id: &str) -> bool {
+        self.hash_failures.get(peer_id).is_some_and(|&n| n >= self.max_hash_failures)
+    }
+
+    pub fn have_count(&self) -> usize {
+        self.have_pieces.len()
+    }
+
+    pub fn total_pieces(&self) -> usize {
+        self.piece_map.num_pieces() as usize
+    }
+
+    pub fn progress(&self) -> Progress {
+        Progress { have: self.have_count(), wanted: self.total_pieces() }
+    }
+
+    pub fn complete(&self) -> bool {
+        // returns true if we have downloaded all of the pieces for this torrent
+        self.have_pieces.len() == self.total_pieces()
+    }
+
+    // payload from pieces that passed their hash check. pieces that were
+    // on disk already don't count, we never downloaded them.
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.stats.verified()
+    }
+
+    // what is still missing, going by the actual size of each piece
+    pub fn bytes_left(&self) -> u64 {
+        let have: u64 = self.have_pieces.values().map(Piece::length).sum();
+        self.torrent.total_size.saturating_sub(have)
+    }
+
+    pub fn announce_totals(&self) -> Totals {
+        Totals { uploaded: self.bytes_uploaded(), downloaded: self.bytes_downloaded(), left: self.bytes_left() }
+    }
+
+    pub fn bytes_uploaded(&self) -> u64 {
+        self.stats.uploaded()
+    }
+
+    pub fn block_uploaded(&mut self, peer_id: &str, length: u64) {
+        self.stats.record_upload(peer_id, length, Instant::now());
+    }
+
+    pub fn stats(&mut self) -> &mut StatsTracker {
+        &mut self.stats
+    }
+
+    // treat every piece as downloaded, for data that is already on disk
+    pub fn mark_complete(&mut self) {
+        self.pending_blocks.clear();
+        self.sent_at.clear();
+        self.expired_blocks.clear();
+        self.requested = 0;
+        self.have_pieces.append(&mut self.ongoing_pieces);
+        self.have_pieces.append(&mut self.verifying);
+        self.have_pieces.append(&mut self.missing_pieces);
+    }
+
+    // only makes sense once we have every piece, see superseed.rs
+    pub fn start_super_seeding(&mut self) {
+        if self.complete() {
+            self.super_seeder = Some(SuperSeeder::new(self.torrent.pieces.len()));
+        }
+    }
+
+    pub fn is_super_seeding(&self) -> bool {
+        self.super_seeder.is_some()
+    }
+
+    // the next piece to announce to a peer while super seeding, if it is
+    // due one
+    pub fn next_super_seed_offer(&mut self, peer_id: &str) -> Option<u32> {
+        let seeder = self.super_seeder.as_mut().filter(|s| !s.is_waiting(peer_id))?;
+        let peer_pieces = self.peers.get(peer_id).map(Vec::as_slice).unwrap_or(&[]);
+
+        let mut availability = vec![0u32; self.torrent.pieces.len()];
+        for bitfield in self.peers.values() {
+            for (count, &b) in availability.iter_mut().zip(bitfield) {
+                *count += (b != 0) as u32;
+            }
+        }
+
+        seeder.next_offer(peer_id, peer_pieces, &availability)
+    }
+
+    // treat the given pieces (one byte each, as in a bitfield) as
+    // downloaded, for resuming where an earlier session left off
+    pub fn mark_have(&mut self, have: &[u8]) {
+        for (index, &b) in have.iter().enumerate() {
+            if b == 0 {
+                continue;
+            }
+            if let Some(piece) = self.missing_pieces.remove(&(index as u32)) {
+                self.have_pieces.insert(piece.index, piece);
+            }
+        }
+    }
+
+    pub fn has_piece(&self, index: u32) -> bool {
+        self.have_pieces.contains_key(&index)
+    }
+
+    // our pieces in the one-byte-per-piece layout used for peer bitfields
+    pub fn bitfield(&self, num_pieces: usize) -> Vec<u8> {
+        let mut bitfield = vec![0u8; num_pieces];
+        for &index in self.have_pieces.keys() {
+            if let Some(bit) = bitfield.get_mut(index as usize) {
+                *bit = 1;
+            }
+        }
+        bitfield
+    }
+
+    // reads a block of a piece we have back off disk to send to a peer
+    pub fn read_block(&self, peer_id: &str, index: u32, begin: u32, length: u32) -> io::Result<Bytes> {
+        let offset = self.upload_location(peer_id, index, begin)?;
+        read_block_at(&self.storage, offset, length)
+    }
+
+    // whether a request is for a block that exists at all. a peer that
+    // asks for anything else is broken or up to something, and gets
+    // disconnected rather than having us read whatever it points at.
+    pub fn check_request(&self, index: u32, begin: u32, length: u32) -> Result<(), String> {
+        let Some(piece_length) = self.piece_map.piece_size(index) else {
+            return Err(format!("requested piece {} of {}", index, self.torrent.pieces.len()));
+        };
+        if length == 0 || length > self.max_request_length {
+            return Err(format!("requested {} bytes, the limit is {}", length, self.max_request_length));
+        }
+        if self.piece_map.block(index, begin as u64, length as u64).is_none() {
+            return Err(format!("requested {} bytes at {} of piece {}, which is {} bytes", length, begin, index, piece_length));
+        }
+        Ok(())
+    }
+
+    // where a block we may send the peer is, so a connection can have the
+    // disk read it after letting go of the lock instead of holding up
+    // every other connection while it waits. a piece we have never
+    // changes on disk, so there's nothing to race with.
+    pub fn upload_location(&self, peer_id: &str, index: u32, begin: u32) -> io::Result<u64> {
+        if self.paused {
+            return Err(io::Error::other("the torrent is paused"));
+        }
+        if !self.has_piece(index) {
+            return Err(io::Error::other(format!("we don't have piece {}", index)));
+        }
+
+        if self.super_seeder.as_ref().is_some_and(|s| !s.may_serve(peer_id, index)) {
+            return Err(io::Error::other(format!("piece {} wasn't offered to {}", index, peer_id)));
+        }
+
+        Ok(self.piece_offset(index) + begin as u64)
+    }
+
+    // where a piece we have is, to read back whole. unlike
+    // upload_location this is for us, pausing and super seeding don't
+    // come into it.
+    pub fn piece_location(&self, index: u32) -> Option<(Arc<Storage>, Range<u64>)> {
+        let range = self.piece_map.piece(index).filter(|_| self.has_piece(index))?;
+        Some((self.storage.clone(), range))
+    }
+
+    // where a piece starts in the torrent's data
+    fn piece_offset(&self, index: u32) -> u64 {
+        self.piece_map.piece(index).map_or(self.piece_map.total_size(), |range| range.start)
+    }
+
+    // adds a peer and its corresponding bitfield
+    pub fn add_peer(&mut self, peer_id: String, bitfield: Vec<u8>) {
+        if let Some(seeder) = &mut self.super_seeder {
+            seeder.peer_bitfield(&peer_id, &bitfield);
+        }
+        self.peers.insert(peer_id, bitfield);
+    }
+
+    // returns true if this frees up a super seeding peer for a new piece
+    pub fn update_peer(&mut self, peer_id: String, index: u32) -> bool {
+        let freed = self.super_seeder.as_mut().is_some_and(|s| s.peer_has(&peer_id, index));
+
+        if let Some(bitfield) = self.peers.get_mut(&peer_id) {
+            if let Some(byte) = bitfield.get_mut(index as usize) {
+                *byte = 1
             } else {
-                // get the length of the last piece and number of blocks for that piece
-                let last_length = torrent.total_size % torrent.piece_length as u64;
-                let num_blocks = last_length.div_ceil(REQUEST_SIZE as u64);
-
-                // make new blocks 
-                for offset in 0..num_blocks {
-                    let start = offset * REQUEST_SIZE as u64;
-                    let length = std::cmp::min(REQUEST_SIZE as u64, last_length - start);
-                    blocks.push(Block::new(i as u64, start, length));
-                }
+                warnings::warn("have out of range", || format!("index {} out of range for peer {}", index, peer_id))
+            }
+        } else {
+            warnings::warn("peer not found", || format!("peer {} not found", peer_id))
+        }
+
+        freed
+    }
+
+    // what each connected peer is doing for us, for the churn task
+    pub fn peer_scores(&mut self, now: Instant) -> Vec<PeerScore> {
+        let mut scores = Vec::new();
+        for (peer_id, bitfield) in &self.peers {
+            let wanted = self
+                .missing_pieces
+                .keys()
+                .chain(self.ongoing_pieces.keys())
+                .filter(|&&index| bitfield.get(index as usize).is_some_and(|&b| b != 0))
+                .count();
+            let stats = self.stats.peer_snapshot(peer_id, now).unwrap_or_default();
+            scores.push(PeerScore {
+                peer_id: peer_id.clone(),
+                download_rate: stats.download_rate_avg,
+                wanted,
+                hash_failures: stats.hash_failures,
+                wasted: stats.wasted,
+            });
+        }
+        scores
+    }
+
+    // true if the peer has at least one piece we still need. nobody is
+    // while we are paused.
+    pub fn is_interesting(&self, peer_id: &str) -> bool {
+        if self.paused {
+            return false;
+        }
+        if let Some(bitfield) = self.peers.get(peer_id) {
+            self.missing_pieces
+                .keys()
+                .chain(self.ongoing_pieces.keys())
+                .any(|&index| bitfield.get(index as usize).is_some_and(|&b| b != 0))
+        } else {
+            false
+        }
+    }
+
+    pub fn delete_peer(&mut self, peer_id: String) {
+        self.stats.remove_peer(&peer_id);
+        self.snubbed.remove(&peer_id);
+        self.latency.remove_peer(&peer_id);
+        if let Some(seeder) = &mut self.super_seeder {
+            seeder.remove_peer(&peer_id);
+        }
+        if self.peers.remove(&peer_id).is_none() {
+            warnings::warn("peer not found", || "couldn't remove peer because it doesn't exist".to_string())
+        }
+    }
+
+    // how long the peer took to send a block we asked it for
+    pub fn record_latency(&mut self, peer_id: &str, latency: Duration) {
+        self.latency.record(peer_id, latency);
+    }
+
+    pub fn set_picker(&mut self, picker: Box<dyn PiecePicker>) {
+        self.picker = picker;
+    }
+
+    // connections notice once they are woken up, see TorrentClient::pause
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        if !paused {
+            self.disk_error = None;
+        }
+    }
+
+    pub fn disk_error(&self) -> Option<&str> {
+        self.disk_error.as_deref()
+    }
+
+    // for what can't be done under the lock, such as Storage::finalize,
+    // which may copy the whole torrent to another filesystem
+    pub fn storage(&self) -> Arc<Storage> {
+        self.storage.clone()
+    }
 
-                // change the length of the last block if its length
-                // is less than the block length
-                if last_length % REQUEST_SIZE as u64 > 0 {
-                    if let Some(last_block) = blocks.last_mut() {
-                        last_block.length = last_length % REQUEST_SIZE as u64;
-                    }
-                }
-            }
+    pub fn location(&self) -> PathBuf {
+        self.storage.location()
+    }
 
-            // push piece
-            pieces.push(Piece 
-                { index: i as u32, blocks, hash_value: hash_value.to_string(),  }
-            )
+    // a full disk fails every write after the first, so rather than
+    // warn about each piece the torrent stops
+    fn write_failed(&mut self, e: &io::Error) {
+        if e.kind() == io::ErrorKind::StorageFull && self.disk_error.is_none() {
+            warn!("the disk is full, stopping {} until it is resumed", self.torrent.output_file);
+            self.disk_error = Some(format!("out of disk space: {}", e));
+            self.paused = true;
         }
-        pieces
     }
 
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
 
-    pub fn block_received(&mut self, peer_id: String, piece_index: u64, block_offset: u64, data: Vec<u8>) {
-        if let Some(pos) = self.pending_blocks.iter().position(|r| {
-            r.block.piece == piece_index && r.block.offset == block_offset
-        }) {
-            self.pending_blocks.remove(pos);
-        }
-    
-        let index = piece_index as u32;
-        if let Some(pos) = self.ongoing_pieces.iter().position(|p| p.index == index) {
-            let mut piece = self.ongoing_pieces.remove(pos);
-    
-            piece.block_received(block_offset as u32, data);
-    
-            if piece.is_complete() {
-                if piece.is_hash_matching() {
-                    let offset = piece.index as u64 * self.torrent.piece_length as u64;
-                    if let Err(e) = self.write_piece(offset, &piece.blocks) {
-                        eprintln!("failed to write piece {} to file: {}", piece.index, e);
-                        return;
-                    }
-    
-                    self.have_pieces.push(piece);
-    
-                    let complete = self.have_pieces.len();
-                    let total = self.total_pieces as usize;
-                    let percentage = (complete as f64 / total as f64) * 100.0;
-                    info!("{}/{} pieces downloaded ({:.2}%)", complete, total, percentage);
-                } else {
-                    warn!("discarding corrupt piece {}", piece.index);
-                    piece.reset();
-                    self.ongoing_pieces.push(piece);
-                }
-            } else {
-                self.ongoing_pieces.push(piece);
-            }
+    pub fn set_time_critical(&mut self, index: u32, critical: bool) {
+        if critical {
+            self.time_critical.insert(index);
         } else {
-            warn!("trying to update piece {} that is not ongoing!", piece_index);
+            self.time_critical.remove(&index);
         }
     }
-    
 
-    pub fn write_piece(&mut self, offset: u64, blocks: &[Block]) -> io::Result<()> {
-        let mut buffer = Vec::new();
+    // the piece is time critical until it is verified, and is picked
+    // ahead of critical pieces with a later deadline or none at all
+    pub fn set_piece_deadline(&mut self, index: u32, deadline: Instant) {
+        self.time_critical.insert(index);
+        self.forget_deadline(index);
+        self.deadlines.insert(index, deadline);
+        self.by_deadline.insert((deadline, index));
+    }
 
-        for block in blocks {
-            if let Some(ref data) = block.data {
-                buffer.extend_from_slice(data);
-            } else {
-                return Err(io::Error::new(ErrorKind::Other, "missing block data"));
-            }
-        }
+    pub fn clear_deadline(&mut self, index: u32) {
+        self.time_critical.remove(&index);
+        self.forget_deadline(index);
+    }
 
-        self.fd.write_all_at(&buffer, offset)?;
-        Ok(())
+    fn forget_deadline(&mut self, index: u32) {
+        if let Some(deadline) = self.deadlines.remove(&index) {
+            self.by_deadline.remove(&(deadline, index));
+        }
     }
 
-    pub fn complete(&self) -> bool {
-        // returns true if we have downloaded all of the pieces for this torrent
-        self.have_pieces.len() == self.total_pieces as usize
+    // sorts pieces with a deadline first, soonest first
+    fn deadline_key(&self, index: u32) -> (bool, Option<Instant>) {
+        match self.deadlines.get(&index) {
+            Some(&deadline) => (false, Some(deadline)),
+            None => (true, None),
+        }
     }
 
-    pub fn bytes_downloaded(&self) -> u64 {
-        // gets the number of bytes downloaded
-        (self.have_pieces.len() * self.torrent.piece_length as usize) as u64
+    // every piece has been started, so the last few pieces are all
+    // that stand between us and a finished torrent
+    fn is_endgame(&self) -> bool {
+        self.missing_pieces.is_empty() && !self.ongoing_pieces.is_empty()
     }
 
-    pub fn bytes_uploaded(&self) -> u64 {
-        // TODO: seeding not implemented
-        0
+    fn is_critical(&self, index: u32) -> bool {
+        self.time_critical.contains(&index) || self.is_endgame()
     }
 
-    // adds a peer and its corresponding bitfield
-    pub fn add_peer(&mut self, peer_id: String, bitfield: Vec<u8>) {
-        self.peers.insert(peer_id, bitfield);
+    // a time critical piece is left to the fast peers when one of them
+    // has it. a slow peer only gets it when none of them do.
+    fn reserved_for_fast(&self, index: u32, peer_id: &str, fast: &Option<HashSet<String>>) -> bool {
+        let Some(fast) = fast else { return false };
+        !fast.contains(peer_id)
+            && self.is_critical(index)
+            && fast.iter().any(|id| self.peers.get(id).is_some_and(|bf| bf.get(index as usize).is_some_and(|&b| b != 0)))
     }
 
-    pub fn update_peer(&mut self, peer_id: String, index: u32) {
-        if let Some(bitfield) = self.peers.get_mut(&peer_id) {
-            if let Some(byte) = bitfield.get_mut(index as usize) {
-                *byte = 1
-            } else {
-                eprintln!("index {} out of range for peer {}", index, peer_id)
-            }
+    pub fn set_snubbed(&mut self, peer_id: &str, snubbed: bool) {
+        if snubbed {
+            self.snubbed.insert(peer_id.to_string());
         } else {
-            eprintln!("peer {} not found", peer_id)
+            self.snubbed.remove(peer_id);
         }
     }
 
-    pub fn delete_peer(&mut self, peer_id: String) {
-        if self.peers.remove(&peer_id).is_none() {
-            eprintln!("couldn't remove peer because it doesn't exist")
-        }
+    pub fn is_snubbed(&self, peer_id: &str) -> bool {
+        self.snubbed.contains(peer_id)
+    }
+
+    // a snubbing peer only gets the pieces nobody better has
+    fn left_to_others(&self, index: u32, peer_id: &str) -> bool {
+        self.snubbed.contains(peer_id)
+            && self.peers.iter().any(|(id, bf)| !self.snubbed.contains(id) && bf.get(index as usize).is_some_and(|&b| b != 0))
     }
 
     pub fn next_request(&mut self, peer_id: &String) -> Option<Block> {
-        
+        if self.paused {
+            return None;
+        }
+
         if let Some(block) = self.expired_requests(peer_id) {
             return Some(block);
         }
 
+        // nothing more until the data we are waiting on is in and
+        // checked. expired requests are already counted, so they still
+        // go out above.
+        if !self.has_room_in_flight() {
+            return None;
+        }
+
+        if let Some(block) = self.deadline_duplicate(peer_id, Instant::now()) {
+            return Some(block);
+        }
+
         if let Some(block) = self.next_ongoing(peer_id) {
             return Some(block);
         }
 
-        if let Some(mut block) = self.get_rarest_piece(peer_id) {
-            let next_block = block.next_request()?;
-            return Some(next_block);
+        // a new piece is moved to the ongoing list, where its first
+        // block gets handed out like any other
+        if self.start_piece(peer_id).is_some() {
+            return self.next_ongoing(peer_id);
         }
 
         None
@@ -293,114 +1836,197 @@ impl PieceManager {
         let current = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("Time went backwards")
-            .as_millis() as u128;
-
-        for request in self.pending_blocks.iter_mut() {
-            if let Some(bitfield) = self.peers.get(peer_id) {
-                if let Some(&has_piece) = bitfield.get(request.block.piece as usize) {
-                    if has_piece != 0 && request.added + (self.max_pending_time as u128) < current {
-                        info!(
-                            "re-requesting block {} for piece {}",
-                            request.block.offset, request.block.piece
-                        );
-                        request.added = current;
-                        return Some(request.block.clone());
-                    }
-                }
-            }
+            .as_millis();
+
+        let timeout = self.max_pending_time as u128;
+        let bitfield = self.peers.get(peer_id)?;
+
+        // the ones some other peer sat on, then any that have timed out
+        // again since. only those are looked at, not every request.
+        let key = self
+            .expired_blocks
+            .iter()
+            .copied()
+            .filter(|key| self.pending_blocks.get(key).is_some_and(|request| request.peer != peer_id))
+            .chain(self.sent_at.iter().take_while(|(added, _)| added + timeout < current).map(|&(_, key)| key))
+            .find(|&(piece, _)| bitfield.get(piece as usize).is_some_and(|&b| b != 0))?;
+
+        let request = self.resend(key, current, false)?;
+        info!(
+            "re-requesting block {} for piece {}",
+            request.block.offset, request.block.piece
+        );
+        request.peer = peer_id.to_string();
+        Some(request.block.clone())
+    }
+
+    // a block of a piece that is due within DEADLINE_DUPLICATE_WINDOW,
+    // already asked of some other peer, soonest deadline first. each
+    // block is only sent to one more peer, and whichever copy comes in
+    // second is wasted. snubbing peers are no help with a deadline.
+    pub fn deadline_duplicate(&mut self, peer_id: &str, now: Instant) -> Option<Block> {
+        if self.snubbed.contains(peer_id) {
+            return None;
         }
-        None
+        let bitfield = self.peers.get(peer_id)?;
+        let key = self
+            .by_deadline
+            .iter()
+            .take_while(|(deadline, _)| deadline.saturating_duration_since(now) <= DEADLINE_DUPLICATE_WINDOW)
+            .filter(|(_, index)| bitfield.get(*index as usize).is_some_and(|&b| b != 0))
+            .flat_map(|&(_, index)| self.pending_blocks.range((index as u64, 0)..(index as u64 + 1, 0)))
+            .find(|(_, request)| request.peer != peer_id && request.duplicate.is_none())
+            .map(|(&key, _)| key)?;
+        let request = self.pending_blocks.get_mut(&key)?;
+        info!("asking {} for block {} of piece {} as well, it is due soon", peer_id, request.block.offset, request.block.piece);
+        request.duplicate = Some(peer_id.to_string());
+        Some(request.block.clone())
+    }
+
+    // marks the requests older than the request timeout as expired, so
+    // another peer is asked for them, and returns them for the peers
+    // they were sent to to cancel. each is only returned once. with
+    // nobody else to ask, the peer that sat on it gets it back after
+    // another timeout.
+    pub fn expire_requests(&mut self) -> Vec<ExpiredRequest> {
+        let current = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_millis();
+        self.expire_requests_at(current)
+    }
+
+    pub fn expire_requests_at(&mut self, current: u128) -> Vec<ExpiredRequest> {
+        let timeout = self.max_pending_time as u128;
+        let due: Vec<BlockKey> = self
+            .sent_at
+            .iter()
+            .take_while(|(added, _)| added + timeout < current)
+            .map(|&(_, key)| key)
+            .filter(|key| !self.expired_blocks.contains(key))
+            .collect();
+        due.into_iter()
+            .filter_map(|key| {
+                let request = self.resend(key, current, true)?;
+                Some(ExpiredRequest {
+                    peer_id: request.peer.clone(),
+                    index: request.block.piece as u32,
+                    begin: request.block.offset as u32,
+                    length: request.block.length as u32,
+                })
+            })
+            .collect()
     }
 
     pub fn next_ongoing(&mut self, peer_id: &str) -> Option<Block> {
-        for piece_idx in 0..self.ongoing_pieces.len() {
-            let piece = &mut self.ongoing_pieces[piece_idx];
-            
-            if let Some(bitfield) = self.peers.get(peer_id) {
-                if piece.index as usize >= bitfield.len() || bitfield[piece.index as usize] == 0 {
-                    continue;
-                }
-                
-                if let Some(block) = piece.next_request() {
-                    let current_time = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .expect("Time went backwards")
-                        .as_millis();
-                    
-                    self.pending_blocks.push(PendingRequest {
-                        block: block.clone(),
-                        added: current_time,
-                    });
-                    
-                    return Some(block);
-                }
+        let bitfield = self.peers.get(peer_id)?;
+        let fast = self.latency.fast_peers();
+
+        // fast peers take time critical pieces first, the rest leave
+        // those to the fast peers
+        let mut order: Vec<u32> = self
+            .ongoing_pieces
+            .keys()
+            .copied()
+            .filter(|&index| bitfield.get(index as usize).is_some_and(|&b| b != 0))
+            .filter(|&index| !self.reserved_for_fast(index, peer_id, &fast))
+            .filter(|&index| !self.left_to_others(index, peer_id))
+            .collect();
+        if fast.as_ref().is_none_or(|fast| fast.contains(peer_id)) {
+            order.sort_by_key(|&index| (!self.is_critical(index), self.deadline_key(index)));
+        }
+
+        for index in order {
+            let Some(piece) = self.ongoing_pieces.get_mut(&index) else { continue };
+            if let Some(block) = piece.next_request() {
+                let current_time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_millis();
+
+                let request = PendingRequest {
+                    block: block.clone(),
+                    added: current_time,
+                    peer: peer_id.to_string(),
+                    expired: false,
+                    duplicate: None,
+                };
+                self.add_request(request);
+                return Some(block);
             }
         }
-        
+
         None
     }
 
-    pub fn get_rarest_piece(&mut self, peer_id: &String) -> Option<Piece> {
-        let mut piece_count: HashMap<u32, u32> = HashMap::new();
+    // bytes asked of peers that haven't come in, and bytes of pieces that
+    // are written but still waiting on the verifier, which reads each
+    // one back whole. on a fast connection with a slow disk both pile
+    // up, and what is still to arrive sits in socket and read buffers.
+    pub fn in_flight(&self) -> u64 {
+        self.requested + self.verifying.values().map(Piece::length).sum::<u64>()
+    }
+
+    // with nothing in flight a request always goes out, however small
+    // the budget, or nothing would ever arrive to make room
+    fn has_room_in_flight(&self) -> bool {
+        self.max_in_flight.is_none_or(|max| self.in_flight() == 0 || self.in_flight() + self.block_size as u64 <= max)
+    }
+
+    // false once as many pieces are under way as we have memory for.
+    // pieces waiting on the verifier may still hold their blocks.
+    fn can_start_piece(&self) -> bool {
+        self.max_ongoing_pieces.is_none_or(|max| self.ongoing_pieces.len() + self.verifying.len() < max)
+    }
+
+    // starts on a piece the peer has and nobody else is downloading
+    pub fn start_piece(&mut self, peer_id: &String) -> Option<Piece> {
+        if !self.can_start_piece() {
+            return None;
+        }
+
+        let mut candidates = Vec::new();
 
         let peer_bitfield = match self.peers.get(peer_id) {
             Some(bf) => bf,
             None => {
-                eprintln!("peer not found: {}", peer_id);
+                warnings::warn("peer not found", || format!("peer not found: {}", peer_id));
                 return None;
             }
         };
 
-        for piece in &self.missing_pieces {
-            if !peer_bitfield[piece.index as usize] == 0 {
+        let fast = self.latency.fast_peers();
+        let is_fast = fast.as_ref().is_none_or(|fast| fast.contains(peer_id.as_str()));
+
+        for &index in self.missing_pieces.keys() {
+            if peer_bitfield.get(index as usize).is_none_or(|&b| b == 0) {
+                continue;
+            }
+            if self.reserved_for_fast(index, peer_id, &fast) || self.left_to_others(index, peer_id) {
                 continue;
             }
 
             let mut count = 0;
             for other_bitfield in self.peers.values() {
-                if other_bitfield[piece.index as usize] > 0 {
+                if other_bitfield.get(index as usize).is_some_and(|&b| b > 0) {
                     count += 1
                 }
             }
 
-            piece_count.insert(piece.index, count);
+            candidates.push(Candidate { index, availability: count });
         }
 
-        let rarest_index = piece_count
+        // fast peers start on time critical pieces before anything the
+        // picker would choose, the ones due soonest (then rarest) first
+        let critical = candidates
             .iter()
-            .min_by_key(|(_, &count)| count)
-            .map(|(&index, _)| index)?;
-
-        if let Some(pos) = self.missing_pieces.iter().position(|p| p.index == rarest_index) {
-            let piece = self.missing_pieces.remove(pos);
-            self.ongoing_pieces.push(piece.clone());
-            return Some(piece);
-        }
-
-        None
-    }
-
-    pub fn next_missing(&mut self, peer_id: &str) -> Option<Block> {
-        if let Some(bitfield) = self.peers.get(peer_id) {
-            for i in 0..self.missing_pieces.len() {
-                let index = self.missing_pieces[i].index as usize;
-    
-                if let Some(&bit) = bitfield.get(index) {
-                    if bit != 0 {
-                        let mut piece = self.missing_pieces.remove(i);
-                        self.ongoing_pieces.push(piece.clone());
-                        return piece.next_request();
-                    }
-                }
-            }
-        } else {
-            eprintln!("peer not found: {}", peer_id);
-        }
-    
-        None
+            .filter(|c| is_fast && self.time_critical.contains(&c.index))
+            .min_by_key(|c| (self.deadline_key(c.index), c.availability))
+            .map(|c| c.index);
+        let index = critical.or_else(|| self.picker.pick(&candidates, self.have_count()))?;
+
+        let piece = self.missing_pieces.remove(&index)?;
+        self.ongoing_pieces.insert(index, piece.clone());
+        Some(piece)
     }
 
-    
 }
 
 impl Block {
@@ -410,18 +2036,32 @@ impl Block {
             offset,
             length,
             status: Status::Missing,
-            data: None,
+            source: None,
         }
     } 
+
+    pub fn piece(&self) -> u64 {
+        self.piece
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
 }
 
 impl Piece {
     // create new piece object
-    pub fn new(index: u32, blocks: Vec<Block>, hash_value: String) -> Piece {
+    pub fn new(index: u32, blocks: Vec<Block>, hash_value: [u8; 20]) -> Piece {
         Piece {
             index,
             blocks,
-            hash_value
+            hash_value,
+            hasher: Some(Sha1::new()),
+            hashed: 0,
         }
     }
 
@@ -429,7 +2069,18 @@ impl Piece {
     pub fn reset(&mut self) {
         for block in &mut self.blocks {
             block.status = Status::Missing;
+            block.source = None;
         }
+        self.hasher = Some(Sha1::new());
+        self.hashed = 0;
+    }
+
+    // every peer that sent us at least one block of this piece
+    pub fn contributors(&self) -> Vec<String> {
+        let mut peers: Vec<String> = self.blocks.iter().filter_map(|b| b.source.clone()).collect();
+        peers.sort();
+        peers.dedup();
+        peers
     }
 
     // get next block to be requested by the client.
@@ -444,13 +2095,41 @@ impl Piece {
         }
     }
     
-    // update the block information if the block has now been received by the client
-    pub fn block_received(&mut self, offset: u32, data: Vec<u8>) {
+    // the block is on disk, so only remember that it came and who from
+    pub fn block_received(&mut self, offset: u32, peer_id: &str) {
         if let Some(block) = self.blocks.iter_mut().find(|b| b.offset == offset as u64) {
             block.status = Status::Retrieved;
-            block.data = Some(data);
+            block.source = Some(peer_id.to_string());
         } else {
-            eprintln!("trying to finish a non-existing block: {}", offset)
+            warnings::warn("unknown block", || format!("trying to finish a non-existing block: {}", offset))
+        }
+    }
+
+    fn block_at(&self, offset: u64) -> Option<&Block> {
+        self.blocks.iter().find(|b| b.offset == offset)
+    }
+
+    // the block goes back to being handed out, its request is gone
+    fn block_missing(&mut self, offset: u64) {
+        if let Some(block) = self.blocks.iter_mut().find(|b| b.offset == offset) {
+            block.status = Status::Missing;
+            block.source = None;
+        }
+    }
+
+    pub fn is_retrieved(&self, offset: u64) -> bool {
+        self.blocks.iter().any(|b| b.offset == offset && b.status == Status::Retrieved)
+    }
+
+    // the block is on its way to disk, or already there
+    fn is_taken(&self, offset: u64) -> bool {
+        self.blocks.iter().any(|b| b.offset == offset && matches!(b.status, Status::Writing | Status::Retrieved))
+    }
+
+    fn block_writing(&mut self, offset: u64, peer_id: &str) {
+        if let Some(block) = self.blocks.iter_mut().find(|b| b.offset == offset) {
+            block.status = Status::Writing;
+            block.source = Some(peer_id.to_string());
         }
     }
 
@@ -461,30 +2140,78 @@ impl Piece {
             .filter(|b| b.status != Status::Retrieved)
             .cloned()
             .collect();
-        blocks.len() == 0
+        blocks.is_empty()
     }
 
     
-    pub fn is_hash_matching(&self) -> bool {
-        let mut hasher = Sha1::new();
+    // the block the hash is waiting on, if it has arrived
+    fn next_unhashed(&self) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|b| b.offset == self.hashed && b.status == Status::Retrieved)
+            .filter(|_| self.hashed < self.length())
+    }
 
-        for block in &self.blocks {
-            if let Some(ref data) = block.data {
-                hasher.update(data);
-            } else {
-               return false;
-            }
-        }
+    // the hasher goes along with the write of the block that is next in
+    // line, when that is the one just received, as it is when blocks come
+    // in order. otherwise it goes to read back the one that is next in
+    // line, if it came early. either way it is at most one block per block
+    // received, so the cost of a message stays the same whatever order
+    // blocks come in. anything still unhashed when the piece is complete
+    // is left to the verifier.
+    fn take_hash(&mut self, offset: u64, received: u64) -> Option<PieceHash> {
+        let early = if received == self.hashed && self.hashed < self.length() {
+            None
+        } else {
+            let block = &self.blocks[self.next_unhashed()?];
+            Some(PendingBlock { position: offset + block.offset, length: block.length })
+        };
+        Some(PieceHash { hasher: self.hasher.take()?, early })
+    }
 
-        let calculated_hash = hasher.finalize();
+    pub fn length(&self) -> u64 {
+        self.blocks.iter().map(|b| b.length).sum()
+    }
 
-        let hex_hash = hex::encode(calculated_hash);
-        self.hash_value == hex_hash
+    // everything the verifier needs to finish the piece's hash
+    fn hash_job(&self, offset: u64, storage: Arc<Storage>) -> HashJob {
+        let mut blocks: Vec<&Block> = self.blocks.iter().filter(|b| b.offset >= self.hashed).collect();
+        blocks.sort_by_key(|b| b.offset);
+
+        HashJob {
+            index: self.index,
+            hasher: self.hasher.clone().unwrap_or_default(),
+            blocks: blocks.into_iter().map(|b| PendingBlock { position: offset + b.offset, length: b.length }).collect(),
+            expected: self.hash_value,
+            storage,
+        }
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::info_hash::InfoHash;
+
+    // a block written and recorded the way a connection does it, off
+    // the lock in between
+    fn receive(pm: &mut PieceManager, peer_id: String, index: u64, offset: u64, data: &[u8]) -> Option<HashJob> {
+        let write = pm.block_received(peer_id, index, offset, Bytes::copy_from_slice(data))?;
+        let written = write.run(&pm.storage);
+        pm.block_written(written)
+    }
+
+    fn by_index(pieces: Vec<Piece>) -> BTreeMap<u32, Piece> {
+        pieces.into_iter().map(|p| (p.index, p)).collect()
+    }
+
+    // as if every block had been requested
+    fn asked(mut piece: Piece) -> Piece {
+        for block in &mut piece.blocks {
+            block.status = Status::Pending;
+        }
+        piece
+    }
 
     fn create_test_blocks() -> Vec<Block> {
         (0..10).map(|offset| Block::new(0, offset * 10, 10)).collect()
@@ -492,14 +2219,14 @@ mod tests {
 
     #[test]
     fn test_empty_piece() {
-        let mut p = Piece::new(0, vec![], "".to_string());
+        let mut p = Piece::new(0, vec![], [0; 20]);
         assert_eq!(p.next_request(), None); 
     }
 
     #[test]
     fn test_request_ok() {
         let blocks = create_test_blocks();
-        let mut p = Piece::new(0, blocks, "".to_string());
+        let mut p = Piece::new(0, blocks, [0; 20]);
 
         let block = p.next_request().expect("should return a block");
         let missing = p.blocks.iter().filter(|b| b.status == Status::Missing).count();
@@ -512,16 +2239,16 @@ mod tests {
 
     #[test]
     fn test_reset_missing_block() {
-        let mut p = Piece::new(0, vec![], "".to_string());
-        p.block_received(123, b"hello".to_vec());
+        let mut p = Piece::new(0, vec![], [0; 20]);
+        p.block_received(123, "peer");
     }
 
     #[test]
     fn test_reset_block() {
         let blocks = create_test_blocks();
-        let mut p = Piece::new(0, blocks, "".to_string());
+        let mut p = Piece::new(0, blocks, [0; 20]);
 
-        p.block_received(10, b"hello".to_vec());
+        p.block_received(10, "peer");
 
         let retrieved = p.blocks.iter().filter(|b| b.status == Status::Retrieved).count();
         let missing = p.blocks.iter().filter(|b| b.status == Status::Missing).count();
@@ -529,4 +2256,748 @@ mod tests {
         assert_eq!(retrieved, 1);
         assert_eq!(missing, 9);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_contributors() {
+        let blocks = create_test_blocks();
+        let mut p = Piece::new(0, blocks, [0; 20]);
+
+        p.block_received(0, "b");
+        p.block_received(10, "a");
+        p.block_received(20, "b");
+        assert_eq!(p.contributors(), vec!["a".to_string(), "b".to_string()]);
+
+        p.reset();
+        assert!(p.contributors().is_empty());
+    }
+
+    #[test]
+    fn test_ban_after_repeated_hash_failures() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            announce: String::new(),
+            multi_file: false,
+            piece_length: 16384,
+            total_size: 16384,
+            pieces: vec![[0; 20]],
+            output_file: std::env::temp_dir().join("bt-c-client-ban").to_string_lossy().to_string(),
+            files: vec![],
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+
+        let mut piece = Piece::new(0, create_test_blocks(), [0; 20]);
+        piece.block_received(0, "bad");
+        piece.block_received(10, "shared");
+
+        for _ in 0..DEFAULT_MAX_HASH_FAILURES - 1 {
+            pm.blame(&piece);
+        }
+        assert!(!pm.is_banned("bad"));
+
+        pm.blame(&piece);
+        assert!(pm.is_banned("bad"));
+        assert!(pm.is_banned("shared"));
+        assert!(!pm.is_banned("innocent"));
+    }
+
+    #[test]
+    fn test_blocks_go_straight_to_disk() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            announce: String::new(),
+            multi_file: false,
+            piece_length: 16384,
+            total_size: 16384,
+            pieces: vec![[0; 20]],
+            output_file: std::env::temp_dir().join("bt-c-client-straight-to-disk").to_string_lossy().to_string(),
+            files: vec![],
+            ..Default::default()
+        };
+        let output = torrent.output_file.clone();
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        pm.max_ongoing_pieces = Some(1);
+
+        let data: Vec<u8> = (0..16384u32).map(|i| i as u8).collect();
+        let blocks = (0..4).map(|i| Block::new(0, i * 4096, 4096)).collect();
+        pm.ongoing_pieces = by_index(vec![asked(Piece::new(0, blocks, Sha1::digest(&data).into()))]);
+
+        // the one ongoing piece is all we are allowed
+        pm.add_peer("peer".to_string(), vec![1; 20]);
+        assert!(pm.start_piece(&"peer".to_string()).is_none());
+
+        // out of order, each block is written where it belongs
+        for i in [2, 0, 3] {
+            assert!(receive(&mut pm, "peer".to_string(), 0, i * 4096, &data[i as usize * 4096..][..4096]).is_none());
+            let written = std::fs::read(&output).unwrap();
+            assert_eq!(written[i as usize * 4096..][..4096], data[i as usize * 4096..][..4096]);
+        }
+
+        // the verifier reads back whatever wasn't hashed on arrival
+        let job = receive(&mut pm, "peer".to_string(), 0, 4096, &data[4096..8192]).unwrap();
+        assert_eq!(job.blocks.len(), 2);
+        // still taking up the one slot while it is checked
+        assert!(pm.start_piece(&"peer".to_string()).is_none());
+        assert!(pm.piece_checked(job.run()));
+        assert!(pm.has_piece(0));
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_piece() {
+        let data: Vec<u8> = (0..16384u32).map(|i| (i * 5) as u8).collect();
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            announce: String::new(),
+            multi_file: false,
+            piece_length: 16384,
+            total_size: 16384,
+            pieces: vec![Sha1::digest(&data).into()],
+            output_file: std::env::temp_dir().join("bt-c-client-wait-for-piece").to_string_lossy().to_string(),
+            files: vec![],
+            ..Default::default()
+        };
+        let output = torrent.output_file.clone();
+        let pm = Arc::new(Mutex::new(PieceManager::new(Arc::new(torrent)).unwrap()));
+        let haves = broadcast::Sender::new(4);
+
+        let read = tokio::spawn(wait_for_piece(pm.clone(), haves.subscribe(), 0));
+        tokio::task::yield_now().await;
+        assert!(!read.is_finished());
+
+        // the piece comes in the way the verifier task has it
+        {
+            let mut pm = pm.lock().unwrap();
+            pm.ongoing_pieces = by_index(vec![asked(Piece::new(0, vec![Block::new(0, 0, 16384)], Sha1::digest(&data).into()))]);
+            let job = receive(&mut pm, "peer".to_string(), 0, 0, &data).unwrap();
+            assert!(pm.piece_checked(job.run()));
+        }
+        haves.send(0).unwrap();
+        assert_eq!(read.await.unwrap().unwrap(), data);
+
+        // data that went bad after it was checked isn't handed out
+        std::fs::write(&output, vec![0u8; 16384]).unwrap();
+        assert!(wait_for_piece(pm.clone(), haves.subscribe(), 0).await.is_err());
+        assert!(wait_for_piece(pm.clone(), haves.subscribe(), 1).await.is_err());
+
+        // nor does a torrent that goes away leave the caller waiting
+        pm.lock().unwrap().have_pieces.clear();
+        let waiting = wait_for_piece(pm.clone(), haves.subscribe(), 0);
+        drop(haves);
+        assert!(waiting.await.is_err());
+    }
+
+    #[test]
+    fn test_hash_on_arrival() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            announce: String::new(),
+            multi_file: false,
+            piece_length: 16384,
+            total_size: 16384,
+            pieces: vec![[0; 20]],
+            output_file: std::env::temp_dir().join("bt-c-client-hash-on-arrival").to_string_lossy().to_string(),
+            files: vec![],
+            ..Default::default()
+        };
+        let output = torrent.output_file.clone();
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+
+        let data: Vec<u8> = (0..16384u32).map(|i| (i * 7) as u8).collect();
+        let blocks = (0..4).map(|i| Block::new(0, i * 4096, 4096)).collect();
+        pm.ongoing_pieces = by_index(vec![asked(Piece::new(0, blocks, Sha1::digest(&data).into()))]);
+        let block = |i: usize| &data[i * 4096..][..4096];
+
+        // in order blocks are hashed right away
+        receive(&mut pm, "peer".to_string(), 0, 0, block(0));
+        assert_eq!(pm.ongoing_pieces[&0].hashed, 4096);
+
+        // an early block waits its turn, which comes one block at a time
+        receive(&mut pm, "peer".to_string(), 0, 8192, block(2));
+        assert_eq!(pm.ongoing_pieces[&0].hashed, 4096);
+        receive(&mut pm, "peer".to_string(), 0, 4096, block(1));
+        assert_eq!(pm.ongoing_pieces[&0].hashed, 8192);
+
+        // the last block arriving hashes the one it was waiting on, and
+        // leaves itself to the verifier
+        let job = receive(&mut pm, "peer".to_string(), 0, 12288, block(3)).unwrap();
+        assert_eq!(job.blocks.len(), 1);
+        assert!(pm.piece_checked(job.run()));
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[test]
+    fn test_unrequested_blocks_are_dropped() {
+        let data = [3u8; 16384];
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            piece_length: 16384,
+            total_size: 2 * 16384,
+            pieces: vec![[0; 20], Sha1::digest(data).into()],
+            output_file: std::env::temp_dir().join("bt-c-client-unrequested").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let output = torrent.output_file.clone();
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        pm.ongoing_pieces = by_index(vec![asked(Piece::new(1, vec![Block::new(1, 0, 16384)], Sha1::digest(data).into()))]);
+        let job = receive(&mut pm, "peer".to_string(), 1, 0, &data).unwrap();
+        assert!(pm.piece_checked(job.run()));
+
+        // piece 0 is under way but none of its blocks were asked for, and
+        // an offset past its end would land on piece 1
+        pm.ongoing_pieces = by_index(vec![Piece::new(0, vec![Block::new(0, 0, 16384)], [0; 20])]);
+        assert!(receive(&mut pm, "bad".to_string(), 0, 0, &[0xAA; 16384]).is_none());
+        assert!(receive(&mut pm, "bad".to_string(), 0, 16384, &[0xAA; 16384]).is_none());
+        // nor the right offset with the wrong length
+        pm.ongoing_pieces = by_index(vec![asked(Piece::new(0, vec![Block::new(0, 0, 16384)], [0; 20]))]);
+        assert!(receive(&mut pm, "bad".to_string(), 0, 0, &[0xAA; 2 * 16384]).is_none());
+
+        assert_eq!(std::fs::read(&output).unwrap()[16384..], data);
+        assert!(pm.has_piece(1));
+        assert_eq!(pm.stats().peer_snapshot("bad", Instant::now()).unwrap().wasted, 4 * 16384);
+        assert!(!pm.is_block(0, 16384, 16384));
+        assert!(pm.is_block(1, 0, 16384));
+    }
+
+    #[test]
+    fn test_failed_write_is_requested_again() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            piece_length: 16384,
+            total_size: 16384,
+            pieces: vec![[0; 20]],
+            output_file: "/dev/full".to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        let peer = "peer".to_string();
+        pm.add_peer(peer.clone(), vec![1]);
+        let block = pm.next_request(&peer).unwrap();
+        assert!(receive(&mut pm, peer.clone(), 0, 0, &[1; 16384]).is_none());
+        assert_eq!(pm.in_flight(), 0);
+
+        // the disk filling up paused the torrent, resuming gets the block
+        // asked for again
+        assert!(pm.disk_error().is_some());
+        pm.set_paused(false);
+        assert_eq!(pm.next_request(&peer).map(|b| (b.piece(), b.offset())), Some((block.piece(), block.offset())));
+    }
+
+    #[test]
+    fn test_failed_check_is_downloaded_again() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            piece_length: 16384,
+            total_size: 16384,
+            pieces: vec![[0; 20]],
+            output_file: std::env::temp_dir().join("bt-c-client-failed-check").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        let blocks = (0..2).map(|i| Block::new(0, i * 8192, 8192)).collect();
+        pm.ongoing_pieces = by_index(vec![asked(Piece::new(0, blocks, Sha1::digest([1u8; 16384]).into()))]);
+
+        receive(&mut pm, "bad".to_string(), 0, 8192, &[2; 8192]);
+        let job = receive(&mut pm, "bad".to_string(), 0, 0, &[1; 8192]).unwrap();
+        assert!(pm.ongoing_pieces.is_empty());
+
+        assert!(!pm.piece_checked(job.run()));
+        assert!(!pm.has_piece(0));
+        assert_eq!(pm.hash_failures["bad"], 1);
+        assert_eq!(pm.ongoing_pieces.get_mut(&0).unwrap().next_request().map(|b| b.offset()), Some(0));
+    }
+
+    #[test]
+    fn test_announce_totals() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            piece_length: 16384,
+            total_size: 16384 + 100,
+            pieces: vec![[0; 20], Sha1::digest([5u8; 100]).into()],
+            output_file: std::env::temp_dir().join("bt-c-client-totals").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        pm.ongoing_pieces = by_index(vec![asked(Piece::new(1, vec![Block::new(1, 0, 100)], Sha1::digest([5u8; 100]).into()))]);
+        assert_eq!(pm.announce_totals(), Totals { uploaded: 0, downloaded: 0, left: 16484 });
+
+        let job = receive(&mut pm, "peer".to_string(), 1, 0, &[5; 100]).unwrap();
+        // a second copy of the block is wasted, not downloaded
+        receive(&mut pm, "peer".to_string(), 1, 0, &[5; 100]);
+        assert!(pm.piece_checked(job.run()));
+        pm.block_uploaded("peer", 16384);
+
+        // the last piece only counts for what it really holds
+        assert_eq!(pm.announce_totals(), Totals { uploaded: 16384, downloaded: 100, left: 16384 });
+        let accounting = pm.stats().accounting();
+        assert_eq!(accounting.payload_downloaded, 100);
+        assert_eq!(accounting.wasted, 100);
+    }
+
+    #[test]
+    fn test_corrupt_piece_is_reset() {
+        let mut piece = Piece::new(0, create_test_blocks(), Sha1::digest([0u8; 100]).into());
+        for i in 0..10 {
+            piece.block_received(i * 10, "peer");
+            piece.hasher.as_mut().unwrap().update([1; 10]);
+            piece.hashed += 10;
+        }
+        assert!(piece.is_complete());
+        assert_ne!(<[u8; 20]>::from(piece.hasher.clone().unwrap().finalize()), piece.hash_value);
+
+        piece.reset();
+        assert_eq!(piece.hashed, 0);
+        assert_eq!(piece.next_unhashed(), None);
+    }
+
+    #[test]
+    fn test_latency_tiers() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![[0; 20]],
+            piece_length: 16384,
+            total_size: 16384 * 20,
+            output_file: std::env::temp_dir().join("bt-c-client-tiers").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        let piece = |i: u32| Piece::new(i, vec![Block::new(i as u64, 0, 16384)], [0; 20]);
+
+        for (peer, ms) in [("fast", 10), ("b", 200), ("c", 300), ("d", 400)] {
+            pm.add_peer(peer.to_string(), vec![1; 20]);
+            pm.record_latency(peer, Duration::from_millis(ms));
+        }
+
+        // a time critical piece goes to the fast peer before rarer ones,
+        // and slow peers are given something else
+        pm.missing_pieces = by_index(vec![piece(2), piece(3)]);
+        pm.set_time_critical(3, true);
+        assert_eq!(pm.start_piece(&"b".to_string()).map(|p| p.index), Some(2));
+        pm.missing_pieces.insert(2, piece(2));
+        pm.ongoing_pieces.clear();
+        assert_eq!(pm.start_piece(&"fast".to_string()).map(|p| p.index), Some(3));
+
+        // in the endgame every piece left is held back for the fast peer
+        pm.missing_pieces.clear();
+        pm.ongoing_pieces = by_index(vec![piece(0), piece(1)]);
+        pm.set_time_critical(3, false);
+        assert!(pm.next_ongoing("b").is_none());
+        assert!(pm.next_ongoing("fast").is_some());
+
+        // unless it doesn't have the piece
+        pm.add_peer("fast".to_string(), vec![1, 0]);
+        assert_eq!(pm.next_ongoing("b").map(|b| b.piece()), Some(1));
+    }
+
+    #[test]
+    fn test_snubbed_peers_get_what_nobody_else_has() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![[0; 20]],
+            piece_length: 16384,
+            total_size: 16384 * 4,
+            output_file: std::env::temp_dir().join("bt-c-client-snubbed").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        let piece = |i: u32| Piece::new(i, vec![Block::new(i as u64, 0, 16384)], [0; 20]);
+        let snub = "snub".to_string();
+        pm.add_peer(snub.clone(), vec![1, 1, 1, 1]);
+        pm.add_peer("good".to_string(), vec![1, 1, 0, 1]);
+        pm.missing_pieces = by_index(vec![piece(0), piece(1), piece(2)]);
+        pm.ongoing_pieces = by_index(vec![piece(3)]);
+
+        pm.set_snubbed(&snub, true);
+        assert!(pm.next_ongoing(&snub).is_none());
+        assert_eq!(pm.start_piece(&snub).map(|p| p.index), Some(2));
+        assert!(pm.start_piece(&snub).is_none());
+
+        // nobody better is left
+        pm.set_snubbed("good", true);
+        assert_eq!(pm.start_piece(&snub).map(|p| p.index), Some(0));
+        pm.delete_peer("good".to_string());
+        pm.set_snubbed(&snub, false);
+        assert_eq!(pm.start_piece(&snub).map(|p| p.index), Some(1));
+    }
+
+    #[test]
+    fn test_deadlines() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![[0; 20]],
+            piece_length: 16384,
+            total_size: 16384 * 20,
+            output_file: std::env::temp_dir().join("bt-c-client-deadlines").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        let piece = |i: u32| Piece::new(i, vec![Block::new(i as u64, 0, 16384)], [0; 20]);
+        let peer = "peer".to_string();
+        pm.add_peer(peer.clone(), vec![1; 20]);
+
+        let now = Instant::now();
+        pm.missing_pieces = by_index(vec![piece(1), piece(2), piece(3), piece(4)]);
+        pm.set_time_critical(1, true);
+        pm.set_piece_deadline(3, now + Duration::from_secs(10));
+        pm.set_piece_deadline(4, now + Duration::from_secs(5));
+
+        // soonest deadline first, then critical pieces without one
+        let picked: Vec<u32> = (0..4).filter_map(|_| pm.start_piece(&peer)).map(|p| p.index).collect();
+        assert_eq!(picked, vec![4, 3, 1, 2]);
+
+        // started pieces are worked on in the same order, as long as it
+        // isn't the endgame, where every piece is critical. the rest go
+        // lowest index first.
+        pm.missing_pieces.insert(5, piece(5));
+        pm.clear_deadline(4);
+        assert_eq!(pm.next_ongoing("peer").map(|b| b.piece()), Some(3));
+        assert_eq!(pm.next_ongoing("peer").map(|b| b.piece()), Some(1));
+        assert_eq!(pm.next_ongoing("peer").map(|b| b.piece()), Some(2));
+        assert_eq!(pm.next_ongoing("peer").map(|b| b.piece()), Some(4));
+    }
+
+    #[test]
+    fn test_deadline_duplicates() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![[0; 20]; 4],
+            piece_length: 16384,
+            total_size: 16384 * 4,
+            output_file: std::env::temp_dir().join("bt-c-client-deadline-duplicates").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        let blocks = |i: u32| (0..2).map(|b| Block::new(i as u64, b * 8192, 8192)).collect();
+        pm.missing_pieces.clear();
+        pm.ongoing_pieces = by_index(vec![Piece::new(1, blocks(1), [0; 20]), Piece::new(2, blocks(2), [0; 20])]);
+        for peer in ["a", "b", "c"] {
+            pm.add_peer(peer.to_string(), vec![1; 4]);
+        }
+        let now = Instant::now();
+        pm.set_piece_deadline(1, now + Duration::from_secs(60));
+        pm.set_piece_deadline(2, now + Duration::from_secs(1));
+        for _ in 0..4 {
+            pm.next_ongoing("a").unwrap();
+        }
+
+        // only blocks that are due soon, and only once each
+        assert_eq!(pm.deadline_duplicate("a", now), None);
+        let first = pm.deadline_duplicate("b", now).unwrap();
+        assert_eq!((first.piece(), first.offset()), (2, 0));
+        assert_eq!(pm.deadline_duplicate("c", now).map(|b| b.offset()), Some(8192));
+        assert_eq!(pm.deadline_duplicate("c", now), None);
+        // piece 1 is close enough a minute later
+        assert_eq!(pm.deadline_duplicate("b", now + Duration::from_secs(59)).map(|b| b.piece()), Some(1));
+
+        // whichever copy comes in second is thrown away
+        receive(&mut pm, "b".to_string(), 2, 0, &[7; 8192]);
+        receive(&mut pm, "a".to_string(), 2, 0, &[9; 8192]);
+        assert_eq!(pm.stats().peer_snapshot("a", Instant::now()).unwrap().wasted, 8192);
+
+        // a deadline moved back is no longer due soon
+        pm.set_piece_deadline(2, now + Duration::from_secs(60));
+        pm.clear_deadline(1);
+        assert_eq!(pm.by_deadline.len(), 1);
+        assert_eq!(pm.deadline_duplicate("c", now), None);
+    }
+
+    #[test]
+    fn test_in_flight_budget() {
+        let data = [0u8; 16384];
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![Sha1::digest(data).into(); 4],
+            piece_length: 16384,
+            total_size: 16384 * 4,
+            output_file: std::env::temp_dir().join("bt-c-client-in-flight").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        pm.max_in_flight = Some(2 * 16384);
+        let peer = "peer".to_string();
+        pm.add_peer(peer.clone(), vec![1; 4]);
+
+        let first = pm.next_request(&peer).unwrap();
+        pm.next_request(&peer).unwrap();
+        assert_eq!(pm.in_flight(), 2 * 16384);
+        assert!(pm.next_request(&peer).is_none());
+
+        // a piece that is in but not yet checked still counts
+        let job = receive(&mut pm, peer.clone(), first.piece(), 0, &data).unwrap();
+        assert_eq!(pm.in_flight(), 2 * 16384);
+        assert!(pm.next_request(&peer).is_none());
+        assert!(pm.piece_checked(job.run()));
+        assert_eq!(pm.in_flight(), 16384);
+        assert!(pm.next_request(&peer).is_some());
+    }
+
+    #[test]
+    fn test_shared_between_peers() {
+        const PIECES: usize = 16;
+        const PEERS: usize = 24;
+        let piece_length = 2 * REQUEST_SIZE;
+        let data: Vec<u8> = (0..PIECES as u32 * piece_length).map(|i| (i % 251) as u8).collect();
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: data.chunks(piece_length as usize).map(|piece| Sha1::digest(piece).into()).collect(),
+            piece_length,
+            total_size: data.len() as u64,
+            output_file: std::env::temp_dir().join("bt-c-client-shared").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let output = torrent.output_file.clone();
+        let pm = Arc::new(Mutex::new(PieceManager::new(Arc::new(torrent)).unwrap()));
+        let handed_out = Arc::new(Mutex::new(HashSet::new()));
+
+        // every peer has everything and they all go at it at once, the
+        // way peer connections do, taking the lock for each call
+        let peers: Vec<_> = (0..PEERS)
+            .map(|i| {
+                let (pm, handed_out, data) = (pm.clone(), handed_out.clone(), data.clone());
+                std::thread::spawn(move || {
+                    let peer = format!("peer {}", i);
+                    pm.lock().unwrap().add_peer(peer.clone(), vec![1; PIECES]);
+                    loop {
+                        let next = pm.lock().unwrap().next_request(&peer);
+                        let Some(block) = next else {
+                            if pm.lock().unwrap().complete() {
+                                return;
+                            }
+                            std::thread::yield_now();
+                            continue;
+                        };
+                        assert!(handed_out.lock().unwrap().insert((block.piece(), block.offset())), "{:?} was handed out twice", block);
+
+                        let start = (block.piece() * piece_length as u64 + block.offset()) as usize;
+                        let job = receive(&mut pm.lock().unwrap(), peer.clone(), block.piece(), block.offset(), &data[start..][..block.length() as usize]);
+                        if let Some(job) = job {
+                            let result = job.run();
+                            assert!(pm.lock().unwrap().piece_checked(result));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for peer in peers {
+            peer.join().unwrap();
+        }
+
+        assert_eq!(handed_out.lock().unwrap().len(), PIECES * 2);
+        assert_eq!(pm.lock().unwrap().have_count(), PIECES);
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[test]
+    fn test_check_request() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![[0; 20]; 3],
+            piece_length: 32768,
+            total_size: 32768 * 2 + 1000,
+            output_file: std::env::temp_dir().join("bt-c-client-check-request").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        assert!(pm.check_request(0, 0, REQUEST_SIZE).is_ok());
+        assert!(pm.check_request(1, 16384, REQUEST_SIZE).is_ok());
+        assert!(pm.check_request(2, 0, 1000).is_ok());
+
+        assert!(pm.check_request(3, 0, REQUEST_SIZE).is_err());
+        assert!(pm.check_request(0, 0, 0).is_err());
+        assert!(pm.check_request(0, 0, REQUEST_SIZE + 1).is_err());
+        assert!(pm.check_request(0, 16385, REQUEST_SIZE).is_err());
+        // past the end of the short last piece
+        assert!(pm.check_request(2, 0, 1001).is_err());
+        assert!(pm.check_request(2, u32::MAX, 1).is_err());
+
+        pm.max_request_length = 32768;
+        assert!(pm.check_request(0, 0, 32768).is_ok());
+    }
+
+    #[test]
+    fn test_expire_requests() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![[0; 20]],
+            piece_length: 16384,
+            total_size: 16384,
+            output_file: std::env::temp_dir().join("bt-c-client-expire").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        let (slow, other) = ("slow".to_string(), "other".to_string());
+        pm.add_peer(slow.clone(), vec![1]);
+        pm.add_peer(other.clone(), vec![1]);
+
+        let block = pm.next_request(&slow).unwrap();
+        assert!(pm.next_request(&other).is_none());
+        let sent = pm.pending_blocks[&(0, 0)].added;
+        assert!(pm.expire_requests_at(sent + 1000).is_empty());
+
+        let later = sent + pm.max_pending_time as u128 + 1;
+        assert_eq!(pm.expire_requests_at(later), [ExpiredRequest { peer_id: slow.clone(), index: 0, begin: 0, length: 16384 }]);
+        assert!(pm.expire_requests_at(later).is_empty());
+
+        // handed to the other peer straight away, not back to the slow one
+        assert!(pm.next_request(&slow).is_none());
+        assert_eq!(pm.next_request(&other).map(|b| (b.piece(), b.offset())), Some((block.piece(), block.offset())));
+        assert_eq!(pm.pending_blocks[&(0, 0)].peer, other);
+        assert!(pm.next_request(&other).is_none());
+        assert!(pm.expired_blocks.is_empty());
+
+        // each request is in sent_at once, and leaves with it
+        assert_eq!(pm.sent_at.len(), 1);
+        receive(&mut pm, other.clone(), 0, 0, &[0; 16384]);
+        assert!(pm.sent_at.is_empty() && pm.pending_blocks.is_empty());
+    }
+
+    #[test]
+    fn test_more_pieces_than_a_u16() {
+        const PIECES: usize = 70_000;
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![[0; 20]; PIECES],
+            piece_length: 16384,
+            total_size: 16384 * PIECES as u64 - 100,
+            output_file: std::env::temp_dir().join("bt-c-client-many-pieces").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        assert_eq!(pm.total_pieces(), PIECES);
+        assert_eq!(pm.progress().wanted, PIECES);
+        assert_eq!(pm.missing_pieces.len(), PIECES);
+
+        let mut have = vec![1u8; PIECES];
+        have[PIECES - 1] = 0;
+        pm.mark_have(&have);
+        assert_eq!(pm.have_count(), PIECES - 1);
+        assert!(!pm.complete());
+        assert_eq!(pm.bitfield(PIECES)[65_536], 1);
+        assert_eq!(pm.upload_location("peer", 69_998, 100).unwrap(), 69_998 * 16384 + 100);
+
+        pm.add_peer("peer".to_string(), vec![1; PIECES]);
+        let block = pm.next_request(&"peer".to_string()).unwrap();
+        assert_eq!((block.piece(), block.length()), (PIECES as u64 - 1, 16384 - 100));
+        pm.update_peer("peer".to_string(), PIECES as u32 - 1);
+        assert!(pm.check_request(PIECES as u32 - 1, 0, 16384 - 100).is_ok());
+        assert!(pm.check_request(PIECES as u32, 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_pieces_past_4_gib() {
+        // offsets within the torrent go past what a u32 holds
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![[0; 20]; 3],
+            piece_length: 1 << 31,
+            total_size: (1 << 32) + 5,
+            output_file: std::env::temp_dir().join("bt-c-client-4gib").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::open(Arc::new(torrent), false, MAX_BLOCK_SIZE).unwrap();
+        assert_eq!(pm.missing_pieces[&2].blocks.iter().map(|b| b.length()).collect::<Vec<_>>(), [5]);
+        assert!(pm.check_request(2, 0, 5).is_ok());
+        assert!(pm.check_request(2, 0, 6).is_err());
+        assert!(pm.check_request(1, (1 << 31) - 16384, 16384).is_ok());
+        pm.mark_have(&[1, 1, 1]);
+        assert_eq!(pm.upload_location("peer", 2, 1).unwrap(), (1 << 32) + 1);
+    }
+
+    #[test]
+    fn test_block_size() {
+        // pieces that the block size doesn't divide, and a last piece
+        // shorter than one block
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![[0; 20]; 3],
+            piece_length: 5000,
+            total_size: 5000 * 2 + 1000,
+            output_file: std::env::temp_dir().join("bt-c-client-block-size").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let pm = PieceManager::open(Arc::new(torrent), false, 2048).unwrap();
+        assert_eq!(pm.block_size(), 2048);
+        let lengths = |index: u32| -> Vec<u64> { pm.missing_pieces[&index].blocks.iter().map(|b| b.length()).collect() };
+        assert_eq!(lengths(0), [2048, 2048, 904]);
+        assert_eq!(lengths(1), [2048, 2048, 904]);
+        assert_eq!(lengths(2), [1000]);
+        assert_eq!(pm.missing_pieces[&1].blocks[2].offset(), 4096);
+
+        let defaults = RequestTuning::default();
+        assert!(defaults.validate().is_ok());
+        assert!(RequestTuning { block_size: 3000, ..defaults }.validate().is_err());
+        assert!(RequestTuning { block_size: 512, ..defaults }.validate().is_err());
+        assert!(RequestTuning { block_size: MAX_BLOCK_SIZE * 2, ..defaults }.validate().is_err());
+        assert!(RequestTuning { block_size: MAX_BLOCK_SIZE, ..defaults }.validate().is_ok());
+        assert!(RequestTuning { max_requests_per_peer: 0, ..defaults }.validate().is_err());
+        assert!(RequestTuning { max_requests_per_peer: MAX_REQUESTS_PER_PEER + 1, ..defaults }.validate().is_err());
+        assert!(RequestTuning { timeout: Duration::from_millis(10), ..defaults }.validate().is_err());
+        assert!(RequestTuning { timeout: Duration::from_secs(u64::MAX), ..defaults }.validate().is_err());
+    }
+
+    #[test]
+    fn test_pause() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![[0; 20]; 2],
+            piece_length: 16384,
+            total_size: 16384 * 2,
+            output_file: std::env::temp_dir().join("bt-c-client-pause").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        let peer = "peer".to_string();
+        pm.add_peer(peer.clone(), vec![1, 1]);
+        pm.mark_have(&[1, 0]);
+        pm.storage.write_at(0, &[7; 16]).unwrap();
+        assert!(pm.read_block(&peer, 0, 0, 16).is_ok());
+
+        // nothing is asked for or handed out, but nothing is lost either
+        pm.set_paused(true);
+        assert!(!pm.is_interesting(&peer));
+        assert!(pm.next_request(&peer).is_none());
+        assert!(pm.read_block(&peer, 0, 0, 16).is_err());
+        assert_eq!(pm.have_count(), 1);
+
+        pm.set_paused(false);
+        assert!(pm.is_interesting(&peer));
+        assert_eq!(pm.next_request(&peer).map(|b| b.piece()), Some(1));
+
+        // running out of space stops it the same way, until resumed
+        pm.write_failed(&io::Error::other("some other failure"));
+        assert!(!pm.is_paused());
+        pm.write_failed(&io::Error::from(io::ErrorKind::StorageFull));
+        assert!(pm.is_paused());
+        assert!(pm.disk_error().unwrap().starts_with("out of disk space"));
+        pm.set_paused(false);
+        assert_eq!(pm.disk_error(), None);
+    }
+
+    #[test]
+    fn test_picker_strategy() {
+        let torrent = Torrent {
+            info_hash: InfoHash::default(),
+            pieces: vec![[0; 20]],
+            piece_length: 16384,
+            total_size: 16384 * 20,
+            output_file: std::env::temp_dir().join("bt-c-client-picker").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut pm = PieceManager::new(Arc::new(torrent)).unwrap();
+        let piece = |i: u32| Piece::new(i, vec![Block::new(i as u64, 0, 16384)], [0; 20]);
+        let peer = "peer".to_string();
+        pm.add_peer(peer.clone(), vec![1; 4]);
+        pm.add_peer("other".to_string(), vec![1, 1, 0, 0]);
+        pm.missing_pieces = by_index(vec![piece(0), piece(1), piece(2), piece(3)]);
+
+        // rarest first by default
+        assert_eq!(pm.start_piece(&peer).map(|p| p.index), Some(2));
+        pm.set_picker(PickerKind::Sequential.build());
+        assert_eq!(pm.start_piece(&peer).map(|p| p.index), Some(0));
+
+        // time critical pieces still come first for a fast peer
+        pm.set_time_critical(3, true);
+        assert_eq!(pm.start_piece(&peer).map(|p| p.index), Some(3));
+        assert_eq!(pm.start_piece(&peer).map(|p| p.index), Some(1));
+    }
+}
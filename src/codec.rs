@@ -0,0 +1,143 @@
+use std::io::{self, ErrorKind};
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{client::MAX_BLOCK_SIZE, protocol::Message};
+
+// framing for the peer wire protocol: every message is a 4 byte big
+// endian length and then that many bytes, a length of 0 being a
+// keep-alive. the length is checked before anything is buffered, so a
+// peer announcing a multi-gigabyte message gets disconnected instead of
+// us trying to make room for it.
+
+// room for a block (we never ask for more than MAX_BLOCK_SIZE) and
+// anything else a peer sends, apart from the bitfield of a huge torrent
+const BASE_MAX_LENGTH: usize = 2 * MAX_BLOCK_SIZE as usize;
+
+#[derive(Debug, Clone)]
+pub struct MessageCodec {
+    max_length: usize,
+}
+
+impl MessageCodec {
+    pub fn new(max_length: usize) -> MessageCodec {
+        MessageCodec { max_length }
+    }
+
+    // big enough for everything a peer of this torrent has reason to
+    // send, including a bitfield for all of its pieces
+    pub fn for_torrent(num_pieces: usize) -> MessageCodec {
+        MessageCodec::new(BASE_MAX_LENGTH.max(1 + num_pieces.div_ceil(8)))
+    }
+
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Message>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if length > self.max_length {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("message of {} bytes is over the limit of {}", length, self.max_length),
+            ));
+        }
+
+        if src.len() < 4 + length {
+            src.reserve(4 + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(4);
+        if length == 0 {
+            return Ok(Some(Message::KeepAlive));
+        }
+        // the payload keeps pointing into the read buffer rather than
+        // being copied out of it
+        let mut body = src.split_to(length).freeze();
+        let id = body.get_u8();
+        Message::from_payload(id, body)
+            .map(Some)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> io::Result<()> {
+        message.encode_into(dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_partial_frames() {
+        let mut codec = MessageCodec::new(1024);
+        let mut buf = BytesMut::new();
+        codec.encode(Message::Have(7), &mut buf).unwrap();
+        codec.encode(Message::KeepAlive, &mut buf).unwrap();
+        let encoded = buf.split();
+
+        // nothing comes out until a whole message is there
+        buf.extend_from_slice(&encoded[..6]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&encoded[6..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::Have(7)));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(Message::KeepAlive));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_block_shares_the_read_buffer() {
+        let mut codec = MessageCodec::new(1024);
+        let mut buf = BytesMut::new();
+        codec.encode(Message::Piece { index: 1, begin: 0, block: vec![9; 64].into() }, &mut buf).unwrap();
+        let start = buf.as_ptr() as usize;
+        let end = start + buf.len();
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(Message::Piece { block, .. }) => {
+                assert_eq!(block, vec![9; 64]);
+                let at = block.as_ptr() as usize;
+                assert!(at >= start && at + block.len() <= end);
+            }
+            other => panic!("expected a piece, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_oversized_length_is_refused() {
+        let mut codec = MessageCodec::new(1024);
+        // a 4 GiB piece message, with nothing following the length yet
+        let mut buf = BytesMut::from(&[0xFF, 0xFF, 0xFF, 0xFF, 7][..]);
+        let err = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(buf.capacity() < 1024);
+
+        let mut buf = BytesMut::from(&[0, 0, 0, 1, 99][..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_limit_fits_the_bitfield() {
+        assert_eq!(MessageCodec::for_torrent(10).max_length(), BASE_MAX_LENGTH);
+        let codec = MessageCodec::for_torrent(4_000_000);
+        assert_eq!(codec.max_length(), 500_001);
+    }
+}
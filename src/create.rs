@@ -0,0 +1,324 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    os::unix::fs::FileExt as _,
+    path::{Path, PathBuf},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bencoding::{encoder, Bencode},
+    piece_map::PieceMap,
+};
+
+// makes a .torrent out of a file or a directory of files. the files are
+// laid end to end in path order and hashed a piece at a time, spread
+// over every core since hashing is what takes the time.
+
+const MIN_PIECE_LENGTH: u64 = 16 * 1024;
+const MAX_PIECE_LENGTH: u64 = 16 * 1024 * 1024;
+
+// piece counts around this keep the metadata small without making
+// pieces so big that a bad one costs a lot to download again
+const TARGET_PIECES: u64 = 1500;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CreateOptions {
+    // the first is the main tracker, any others go in the announce-list
+    pub announce: Vec<String>,
+    pub private: bool,
+    pub comment: Option<String>,
+    // picked from the total size when not given
+    pub piece_length: Option<u64>,
+}
+
+struct SourceFile {
+    path: PathBuf,
+    // path inside the torrent, one entry per directory
+    components: Vec<String>,
+    length: u64,
+}
+
+// the smallest power of two piece length that keeps the number of
+// pieces near TARGET_PIECES
+pub fn choose_piece_length(total_size: u64) -> u64 {
+    let mut length = MIN_PIECE_LENGTH;
+    while length < MAX_PIECE_LENGTH && total_size.div_ceil(length) > TARGET_PIECES {
+        length *= 2;
+    }
+    length
+}
+
+// every regular file under root, sorted so the same tree always gives
+// the same torrent
+fn walk(root: &Path, dir: &Path, files: &mut Vec<SourceFile>) -> io::Result<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<io::Result<_>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(root, &path, files)?;
+        } else if file_type.is_file() {
+            let components = path
+                .strip_prefix(root)
+                .map_err(io::Error::other)?
+                .iter()
+                .map(|c| c.to_string_lossy().to_string())
+                .collect();
+            files.push(SourceFile { length: entry.metadata()?.len(), path, components });
+        }
+    }
+
+    Ok(())
+}
+
+// reads length bytes starting at offset of the files laid end to end
+fn read_range(files: &[SourceFile], offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let end = offset + buf.len() as u64;
+    let mut start = 0;
+
+    for file in files {
+        let file_end = start + file.length;
+        if file_end > offset && start < end {
+            let from = offset.max(start);
+            let to = end.min(file_end);
+            let range = (from - offset) as usize..(to - offset) as usize;
+            File::open(&file.path)?.read_exact_at(&mut buf[range], from - start)?;
+        }
+        start = file_end;
+    }
+
+    Ok(())
+}
+
+fn hash_pieces(files: &[SourceFile], total_size: u64, piece_length: u64) -> io::Result<Vec<u8>> {
+    let num_pieces = total_size.div_ceil(piece_length) as usize;
+    let map = PieceMap::new(piece_length, total_size, num_pieces as u32);
+    let workers = thread::available_parallelism().map_or(1, |n| n.get()).min(num_pieces.max(1));
+
+    // each worker takes a run of consecutive pieces
+    let per_worker = num_pieces.div_ceil(workers);
+    let runs: Vec<io::Result<Vec<u8>>> = thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|w| {
+                scope.spawn(move || {
+                    let mut hashes = Vec::new();
+                    let mut buf = Vec::new();
+                    for index in (w * per_worker..(w + 1) * per_worker).take_while(|&i| i < num_pieces) {
+                        let piece = map.piece(index as u32).expect("every piece up to num_pieces exists");
+                        buf.resize((piece.end - piece.start) as usize, 0);
+                        read_range(files, piece.start, &mut buf)?;
+                        hashes.extend_from_slice(&Sha1::digest(&buf));
+                    }
+                    Ok(hashes)
+                })
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().expect("hashing thread panicked")).collect()
+    });
+
+    let mut pieces = Vec::with_capacity(num_pieces * 20);
+    for run in runs {
+        pieces.extend(run?);
+    }
+    Ok(pieces)
+}
+
+fn bytes(s: &str) -> Bencode {
+    Bencode::Bytes(s.as_bytes().to_vec())
+}
+
+// hashes the file or directory at path and returns the encoded .torrent
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Vec<u8>, String> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("{} has no name", path.display()))?
+        .to_string_lossy()
+        .to_string();
+
+    let metadata = fs::metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let single = metadata.is_file();
+    let files = if single {
+        vec![SourceFile { path: path.to_path_buf(), components: vec![name.clone()], length: metadata.len() }]
+    } else {
+        let mut files = Vec::new();
+        walk(path, path, &mut files).map_err(|e| e.to_string())?;
+        files
+    };
+
+    let total_size: u64 = files.iter().map(|f| f.length).sum();
+    if total_size == 0 {
+        return Err(format!("there is no data in {}", path.display()));
+    }
+
+    let piece_length = options.piece_length.unwrap_or_else(|| choose_piece_length(total_size));
+    if !piece_length.is_power_of_two() || piece_length < MIN_PIECE_LENGTH {
+        return Err(format!("piece length must be a power of two of at least {} bytes", MIN_PIECE_LENGTH));
+    }
+
+    let pieces = hash_pieces(&files, total_size, piece_length).map_err(|e| e.to_string())?;
+
+    let mut info = BTreeMap::new();
+    info.insert(b"name".to_vec(), bytes(&name));
+    info.insert(b"piece length".to_vec(), Bencode::Int(piece_length as i64));
+    info.insert(b"pieces".to_vec(), Bencode::Bytes(pieces));
+    if options.private {
+        info.insert(b"private".to_vec(), Bencode::Int(1));
+    }
+
+    if single {
+        info.insert(b"length".to_vec(), Bencode::Int(total_size as i64));
+    } else {
+        let list = files.iter().map(|file| {
+            let mut entry = BTreeMap::new();
+            entry.insert(b"length".to_vec(), Bencode::Int(file.length as i64));
+            entry.insert(b"path".to_vec(), Bencode::List(file.components.iter().map(|c| bytes(c)).collect()));
+            Bencode::Dict(entry)
+        });
+        info.insert(b"files".to_vec(), Bencode::List(list.collect()));
+    }
+
+    let mut dict = BTreeMap::new();
+    dict.insert(b"info".to_vec(), Bencode::Dict(info));
+    dict.insert(b"created by".to_vec(), bytes(concat!("bt-c ", env!("CARGO_PKG_VERSION"))));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    dict.insert(b"creation date".to_vec(), Bencode::Int(now as i64));
+
+    if let Some(first) = options.announce.first() {
+        dict.insert(b"announce".to_vec(), bytes(first));
+    }
+    if options.announce.len() > 1 {
+        // one tier per tracker, tried in the order given
+        let tiers = options.announce.iter().map(|url| Bencode::List(vec![bytes(url)]));
+        dict.insert(b"announce-list".to_vec(), Bencode::List(tiers.collect()));
+    }
+    if let Some(comment) = &options.comment {
+        dict.insert(b"comment".to_vec(), bytes(comment));
+    }
+
+    Ok(encoder::encode(&Bencode::Dict(dict)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bencoding::decoder, torrent::{build_torrent, parse_torrent}};
+
+    fn info(encoded: &[u8]) -> BTreeMap<Vec<u8>, Bencode> {
+        let Ok((Bencode::Dict(mut dict), _)) = decoder::decode(encoded) else { panic!("not a dict") };
+        let Some(Bencode::Dict(info)) = dict.remove(&b"info"[..]) else { panic!("no info dict") };
+        info
+    }
+
+    fn file_paths(info: &BTreeMap<Vec<u8>, Bencode>) -> Vec<String> {
+        let Some(Bencode::List(files)) = info.get(&b"files"[..]) else { panic!("no file list") };
+        files.iter().map(|file| {
+            let Bencode::Dict(file) = file else { panic!("file is not a dict") };
+            let Some(Bencode::List(path)) = file.get(&b"path"[..]) else { panic!("file has no path") };
+            let components: Vec<String> = path.iter().map(|c| match c {
+                Bencode::Bytes(b) => String::from_utf8_lossy(b).to_string(),
+                _ => panic!("path component is not a string"),
+            }).collect();
+            components.join("/")
+        }).collect()
+    }
+
+    #[test]
+    fn test_choose_piece_length() {
+        assert_eq!(choose_piece_length(1), MIN_PIECE_LENGTH);
+        // 1 GiB in 1500ish pieces
+        assert_eq!(choose_piece_length(1 << 30), 1 << 20);
+        assert_eq!(choose_piece_length(u64::MAX / 2), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn test_single_file() {
+        let dir = std::env::temp_dir().join("bt-c-create-single");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let data: Vec<u8> = (0..40000u32).map(|i| i as u8).collect();
+        fs::write(dir.join("data.bin"), &data).unwrap();
+
+        let options = CreateOptions {
+            announce: vec!["http://tracker.example/announce".to_string()],
+            comment: Some("hello".to_string()),
+            ..Default::default()
+        };
+        let encoded = create_torrent(&dir.join("data.bin"), &options).unwrap();
+        assert!(decoder::decode_strict(&encoded).is_ok());
+
+        let (bencode, _) = decoder::decode(&encoded).unwrap();
+        let torrent = build_torrent(&bencode).unwrap();
+        assert_eq!(torrent.announce, "http://tracker.example/announce");
+        assert_eq!(torrent.output_file, "data.bin");
+        assert_eq!(torrent.total_size, 40000);
+        assert_eq!(torrent.piece_length as u64, MIN_PIECE_LENGTH);
+        assert_eq!(torrent.comment.as_deref(), Some("hello"));
+        assert!(torrent.created_by.is_some_and(|c| c.starts_with("bt-c ")));
+        assert!(torrent.creation_date.is_some_and(|d| d > 0));
+        assert_eq!(torrent.encoding, None);
+
+        let expected: Vec<[u8; 20]> = data.chunks(MIN_PIECE_LENGTH as usize).map(|c| Sha1::digest(c).into()).collect();
+        assert_eq!(torrent.pieces, expected);
+    }
+
+    #[test]
+    fn test_directory() {
+        let dir = std::env::temp_dir().join("bt-c-create-dir");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+
+        // the pieces straddle both files
+        fs::write(dir.join("b.bin"), vec![2u8; 20000]).unwrap();
+        fs::write(dir.join("sub/a.bin"), vec![1u8; 30000]).unwrap();
+        fs::write(dir.join("empty"), b"").unwrap();
+
+        let options = CreateOptions {
+            announce: vec!["http://tracker.example/announce".to_string()],
+            private: true,
+            piece_length: Some(MIN_PIECE_LENGTH * 2),
+            ..Default::default()
+        };
+        let encoded = create_torrent(&dir, &options).unwrap();
+        let info = info(&encoded);
+
+        assert!(matches!(info.get(&b"private"[..]), Some(Bencode::Int(1))));
+        assert_eq!(file_paths(&info), vec!["b.bin", "empty", "sub/a.bin"]);
+
+        let mut data = vec![2u8; 20000];
+        data.extend(vec![1u8; 30000]);
+        let expected: Vec<u8> = data.chunks(MIN_PIECE_LENGTH as usize * 2).flat_map(|c| Sha1::digest(c).to_vec()).collect();
+        assert!(matches!(info.get(&b"pieces"[..]), Some(Bencode::Bytes(p)) if *p == expected));
+
+        // and it reads back in as the same directory of files
+        let torrent = parse_torrent(&encoded).unwrap();
+        assert!(torrent.multi_file && torrent.private);
+        assert_eq!(torrent.output_file, "bt-c-create-dir");
+        assert_eq!(torrent.total_size, 50000);
+        assert_eq!(torrent.pieces.concat(), expected);
+        let files: Vec<(&str, u64)> = torrent.files.iter().map(|f| (f.name.as_str(), f.length())).collect();
+        assert_eq!(files, vec![("b.bin", 20000), ("empty", 0), ("sub/a.bin", 30000)]);
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        let dir = std::env::temp_dir().join("bt-c-create-empty");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        assert!(create_torrent(&dir, &CreateOptions::default()).is_err());
+        assert!(create_torrent(&dir.join("missing"), &CreateOptions::default()).is_err());
+
+        fs::write(dir.join("file"), b"data").unwrap();
+        let options = CreateOptions { piece_length: Some(1000), ..Default::default() };
+        assert!(create_torrent(&dir, &options).is_err());
+    }
+}
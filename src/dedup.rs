@@ -0,0 +1,235 @@
+use std::{fs, ops::Range, path::{Path, PathBuf}};
+
+use log::warn;
+
+use crate::{filemap::{FileEntry, FileMap}, torrent::Torrent};
+
+// the same files often turn up in several torrents (cross seeding the
+// same release on different trackers, packs that include a single
+// release, ...). when a file in a new torrent is byte for byte the same
+// as one we already have it is hard linked rather than downloaded and
+// stored a second time.
+//
+// v1 torrents only hash pieces, not files, so two files are only known
+// to be identical when both start on a piece boundary, use the same
+// piece length and every piece covering them has the same hash. the
+// last piece can only be compared if it holds nothing but the file.
+
+// where a file of the torrent lives on disk
+pub fn file_path(torrent: &Torrent, index: usize) -> PathBuf {
+    FileMap::new(torrent).files()[index].path.clone()
+}
+
+// the files that can be linked at all: ones with data in them
+pub fn data_files(torrent: &Torrent) -> usize {
+    FileMap::new(torrent).files().iter().filter(|f| !f.padding && f.length > 0).count()
+}
+
+// the pieces the linked files fill in, one byte per piece like a peer
+// bitfield. a file's pieces hold nothing else, see identical_files.
+pub fn linked_pieces(torrent: &Torrent, linked: &[usize]) -> Vec<u8> {
+    let map = FileMap::new(torrent);
+    let mut have = vec![0u8; torrent.pieces.len()];
+    for &index in linked {
+        let Some(pieces) = file_pieces(torrent, &map.files()[index]) else { continue };
+        have[pieces.start as usize..pieces.end as usize].fill(1);
+    }
+    have
+}
+
+// the pieces that cover exactly this file, if they contain no data
+// from any other file
+fn file_pieces(torrent: &Torrent, file: &FileEntry) -> Option<Range<u32>> {
+    if file.length == 0 || file.padding {
+        return None;
+    }
+
+    torrent.piece_map().pieces_exactly(file.offset..file.offset + file.length)
+}
+
+// and their hashes
+fn file_hashes<'a>(torrent: &'a Torrent, file: &FileEntry) -> Option<&'a [[u8; 20]]> {
+    let pieces = file_pieces(torrent, file)?;
+    torrent.pieces.get(pieces.start as usize..pieces.end as usize)
+}
+
+// pairs of (file in a, file in b) that are known to hold the same bytes
+pub fn identical_files(a: &Torrent, b: &Torrent) -> Vec<(usize, usize)> {
+    if a.piece_length != b.piece_length {
+        return Vec::new();
+    }
+
+    // single file torrents have no files list, the map has them all
+    let (a_files, b_files) = (FileMap::new(a), FileMap::new(b));
+    let mut pairs = Vec::new();
+    for (i, file) in a_files.files().iter().enumerate() {
+        let Some(hashes) = file_hashes(a, file) else { continue };
+
+        let found = b_files.files().iter().position(|other| {
+            file.length == other.length && file_hashes(b, other) == Some(hashes)
+        });
+
+        if let Some(j) = found {
+            pairs.push((i, j));
+        }
+    }
+
+    pairs
+}
+
+// hard links every file of new that is identical to a file of existing,
+// whose data must already be complete on disk. source gives where each
+// of existing's files is now, which may not be under its output_file
+// any more. files that are already there are left alone. one that can't
+// be linked, say because it is on another filesystem, is downloaded
+// like any other. returns the indices of new's files that are in place.
+pub fn link_identical_files(existing: &Torrent, source: impl Fn(usize) -> PathBuf, new: &Torrent) -> Vec<usize> {
+    let mut linked = Vec::new();
+
+    for (from, to) in identical_files(existing, new) {
+        let target = file_path(new, to);
+        if !target.exists() {
+            let source = source(from);
+            if let Err(e) = link(&source, &target) {
+                warn!("couldn't link {} to {}, downloading it instead: {}", source.display(), target.display(), e);
+                continue;
+            }
+        }
+
+        linked.push(to);
+    }
+
+    linked
+}
+
+fn link(source: &Path, target: &Path) -> std::io::Result<()> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::hard_link(source, target)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::MetadataExt as _;
+
+    use sha1::{Digest, Sha1};
+
+    use crate::info_hash::InfoHash;
+
+    use super::*;
+    use crate::torrent::File;
+
+    fn test_torrent(output_file: PathBuf, files: &[(&str, &[u8])], piece_length: u32) -> Torrent {
+        let data: Vec<u8> = files.iter().flat_map(|(_, d)| d.to_vec()).collect();
+        let pieces = data
+            .chunks(piece_length as usize)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+
+        Torrent {
+            info_hash: InfoHash::new(Sha1::digest(&data).into()),
+            announce: String::new(),
+            multi_file: files.len() > 1,
+            piece_length,
+            total_size: data.len() as u64,
+            pieces,
+            output_file: output_file.to_string_lossy().to_string(),
+            files: files.iter().map(|(name, d)| File::new(name.to_string(), d.len() as u64)).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_single_file_match() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let a = test_torrent(PathBuf::from("a"), &[("a", &data)], 32);
+        let b = test_torrent(PathBuf::from("b"), &[("b", &data)], 32);
+
+        assert_eq!(identical_files(&a, &b), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn test_single_file_torrents_without_files_list() {
+        // parsed single file torrents leave files empty
+        let data: Vec<u8> = (0..100u8).collect();
+        let mut a = test_torrent(PathBuf::from("a"), &[("a", &data)], 32);
+        let mut b = test_torrent(PathBuf::from("b"), &[("b", &data)], 32);
+        a.files.clear();
+        b.files.clear();
+
+        assert_eq!(identical_files(&a, &b), vec![(0, 0)]);
+        assert_eq!(data_files(&b), 1);
+
+        // and an empty torrent has nothing that could be linked
+        assert_eq!(data_files(&test_torrent(PathBuf::from("c"), &[], 32)), 0);
+    }
+
+    #[test]
+    fn test_different_data_or_piece_length() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let mut other = data.clone();
+        other[50] ^= 0xFF;
+
+        let a = test_torrent(PathBuf::from("a"), &[("a", &data)], 32);
+        assert!(identical_files(&a, &test_torrent(PathBuf::from("b"), &[("b", &other)], 32)).is_empty());
+        assert!(identical_files(&a, &test_torrent(PathBuf::from("b"), &[("b", &data)], 16)).is_empty());
+    }
+
+    #[test]
+    fn test_multi_file_alignment() {
+        let first = [1u8; 64];
+        let second: Vec<u8> = (0..40u8).collect();
+        let pack = test_torrent(PathBuf::from("pack"), &[("first", &first), ("second", &second)], 32);
+
+        // both files start on a piece boundary and the second ends the torrent
+        let single = test_torrent(PathBuf::from("single"), &[("second", &second)], 32);
+        assert_eq!(identical_files(&pack, &single), vec![(1, 0)]);
+
+        // first ends on a boundary so it can be compared too
+        let single = test_torrent(PathBuf::from("single"), &[("first", &first)], 32);
+        assert_eq!(identical_files(&pack, &single), vec![(0, 0)]);
+
+        // a file that shares its last piece with the next one can't be
+        let unaligned = test_torrent(PathBuf::from("pack"), &[("short", &first[..50]), ("second", &second)], 32);
+        let single = test_torrent(PathBuf::from("single"), &[("short", &first[..50])], 32);
+        assert!(identical_files(&unaligned, &single).is_empty());
+    }
+
+    #[test]
+    fn test_link_identical_files() {
+        let dir = std::env::temp_dir().join("bt-c-dedup-link");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let data: Vec<u8> = (0..100u8).collect();
+        fs::write(dir.join("a"), &data).unwrap();
+
+        let a = test_torrent(dir.join("a"), &[("a", &data)], 32);
+        let b = test_torrent(dir.join("b"), &[("b", &data)], 32);
+        let source = |i| file_path(&a, i);
+        assert_eq!(link_identical_files(&a, source, &b), vec![0]);
+
+        let inode = |p: PathBuf| fs::metadata(p).unwrap().ino();
+        assert_eq!(inode(dir.join("a")), inode(dir.join("b")));
+
+        // linking again leaves the existing file alone
+        assert_eq!(link_identical_files(&a, source, &b), vec![0]);
+
+        // a source that can't be linked is left to be downloaded
+        let c = test_torrent(dir.join("c"), &[("c", &data)], 32);
+        assert!(link_identical_files(&a, |_| dir.join("gone"), &c).is_empty());
+        assert!(!dir.join("c").exists());
+    }
+
+    #[test]
+    fn test_linked_pieces() {
+        let first = [1u8; 64];
+        let second: Vec<u8> = (0..40u8).collect();
+        let pack = test_torrent(PathBuf::from("pack"), &[("first", &first), ("second", &second)], 32);
+
+        assert_eq!(linked_pieces(&pack, &[1]), vec![0, 0, 1, 1]);
+        assert_eq!(linked_pieces(&pack, &[0, 1]), vec![1, 1, 1, 1]);
+        assert_eq!(linked_pieces(&pack, &[]), vec![0, 0, 0, 0]);
+    }
+}
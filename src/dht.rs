@@ -0,0 +1,463 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs, io,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use rand::Rng;
+
+use crate::{
+    bencoding::{decoder, encoder, Bencode},
+    pex::Addr,
+};
+
+// the parts of a dht node (bep 5) that outlive a run. there is no krpc
+// over udp yet, so nothing fills the routing table on its own, but it
+// is saved on shutdown and loaded again at startup so that once there
+// is, a node only has to fall back on the bootstrap nodes when it has
+// nobody left to ask.
+//
+// node ids follow bep 42: the first 21 bits come from a hash of the
+// node's external ip, so nobody can pick ids next to a target to take
+// over its part of the keyspace without owning addresses to match. ours
+// is made again from our address once we know it, and nodes whose ids
+// don't fit their address are kept out of the table. nodes on local
+// networks are exempt, their addresses are nobody else's business.
+
+// nodes kept per bucket
+pub const K: usize = 8;
+
+// the well known routers most clients start from
+pub const DEFAULT_BOOTSTRAP_NODES: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+// what main keeps the table in, inside the download directory
+pub const STATE_FILE: &str = ".bt-c-dht";
+
+// nodes peers have told us about that are waiting to be pinged
+const MAX_CANDIDATES: usize = 256;
+
+// compact node info: a 20 byte id, then a 4 byte ip and 2 byte port
+const COMPACT_NODE_LENGTH: usize = 26;
+
+// the bits of an address that go into its node ids. the fewer the
+// bits kept, the more ids a single address can stand for.
+const IPV4_MASK: [u8; 4] = [0x03, 0x0f, 0x3f, 0xff];
+const IPV6_MASK: [u8; 8] = [0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NodeId([u8; 20]);
+
+impl NodeId {
+    pub fn new(bytes: [u8; 20]) -> NodeId {
+        NodeId(bytes)
+    }
+
+    pub fn random() -> NodeId {
+        NodeId(rand::rng().random())
+    }
+
+    // a random id that fits ip, see the comment at the top
+    pub fn secure(ip: IpAddr) -> NodeId {
+        let mut id: [u8; 20] = rand::rng().random();
+        let prefix = secure_prefix(ip, id[19]);
+        id[0] = (prefix >> 24) as u8;
+        id[1] = (prefix >> 16) as u8;
+        id[2] = (prefix >> 8) as u8 & 0xf8 | id[2] & 0x07;
+        NodeId(id)
+    }
+
+    // whether a node at ip may have this id
+    pub fn is_secure_for(&self, ip: IpAddr) -> bool {
+        if is_local(ip) {
+            return true;
+        }
+        let prefix = secure_prefix(ip, self.0[19]);
+        self.0[0] == (prefix >> 24) as u8 && self.0[1] == (prefix >> 16) as u8 && self.0[2] & 0xf8 == (prefix >> 8) as u8 & 0xf8
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        std::array::from_fn(|i| self.0[i] ^ other.0[i])
+    }
+
+    // nodes in bucket i share the first i bits with us and no more.
+    // None for our own id.
+    fn bucket(&self, other: &NodeId) -> Option<usize> {
+        let distance = self.distance(other);
+        let first = distance.iter().position(|&b| b != 0)?;
+        Some(first * 8 + distance[first].leading_zeros() as usize)
+    }
+}
+
+// the crc32c of the masked address, with three bits of the id's last
+// byte mixed in so one address can have eight different prefixes
+fn secure_prefix(ip: IpAddr, last: u8) -> u32 {
+    let r = last & 0x07;
+    let mut masked = match ip.to_canonical() {
+        IpAddr::V4(ip) => std::iter::zip(ip.octets(), IPV4_MASK).map(|(b, m)| b & m).collect::<Vec<u8>>(),
+        IpAddr::V6(ip) => std::iter::zip(ip.octets(), IPV6_MASK).map(|(b, m)| b & m).collect(),
+    };
+    masked[0] |= r << 5;
+    crc32c(&masked)
+}
+
+// crc-32 with the castagnoli polynomial, as bep 42 has it
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0x82F6_3B78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// addresses bep 42 leaves out, that don't mean anything past the lan
+fn is_local(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: Addr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoutingTable {
+    own_id: NodeId,
+    // oldest first in each bucket
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> RoutingTable {
+        RoutingTable { own_id, buckets: vec![Vec::new(); 160] }
+    }
+
+    pub fn own_id(&self) -> &NodeId {
+        &self.own_id
+    }
+
+    // adds the node, or moves it to the back of its bucket if we already
+    // know it. a full bucket keeps the nodes it has, long lived nodes
+    // being the ones most likely to stay. returns false if it wasn't added,
+    // which is also what happens to a node whose id doesn't fit its
+    // address.
+    pub fn insert(&mut self, node: Node) -> bool {
+        if node.addr.0.parse::<IpAddr>().is_ok_and(|ip| !node.id.is_secure_for(ip)) {
+            return false;
+        }
+        let Some(index) = self.own_id.bucket(&node.id) else { return false };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(pos) = bucket.iter().position(|n| n.id == node.id) {
+            bucket.remove(pos);
+        } else if bucket.len() >= K {
+            return false;
+        }
+        bucket.push(node);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flatten()
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<&Node> = self.nodes().collect();
+        nodes.sort_by_key(|n| n.id.distance(target));
+        nodes.into_iter().take(count).cloned().collect()
+    }
+
+    // only ipv4 nodes are written out, like in krpc's compact node info
+    pub fn encode(&self) -> Vec<u8> {
+        let mut nodes = Vec::with_capacity(self.len() * COMPACT_NODE_LENGTH);
+        for node in self.nodes() {
+            if let Ok(ip) = node.addr.0.parse::<Ipv4Addr>() {
+                nodes.extend_from_slice(node.id.as_bytes());
+                nodes.extend_from_slice(&ip.octets());
+                nodes.extend_from_slice(&node.addr.1.to_be_bytes());
+            }
+        }
+
+        let mut dict = BTreeMap::new();
+        dict.insert(b"id".to_vec(), Bencode::Bytes(self.own_id.as_bytes().to_vec()));
+        dict.insert(b"nodes".to_vec(), Bencode::Bytes(nodes));
+        encoder::encode(&Bencode::Dict(dict))
+    }
+
+    pub fn decode(data: &[u8]) -> Result<RoutingTable, String> {
+        let dict = match decoder::decode(data)? {
+            (Bencode::Dict(dict), _) => dict,
+            _ => return Err("dht state is not a dict".to_string()),
+        };
+
+        let own_id = match dict.get(&b"id"[..]) {
+            Some(Bencode::Bytes(id)) => NodeId::new(id.as_slice().try_into().map_err(|_| "dht node id must be 20 bytes")?),
+            _ => return Err("dht state has no node id".to_string()),
+        };
+        let nodes = match dict.get(&b"nodes"[..]) {
+            Some(Bencode::Bytes(nodes)) if nodes.len().is_multiple_of(COMPACT_NODE_LENGTH) => nodes,
+            _ => return Err("dht state nodes must be a multiple of 26 bytes".to_string()),
+        };
+
+        let mut table = RoutingTable::new(own_id);
+        for chunk in nodes.chunks(COMPACT_NODE_LENGTH) {
+            let id = NodeId::new(chunk[..20].try_into().unwrap());
+            let ip = Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]);
+            let port = u16::from_be_bytes([chunk[24], chunk[25]]);
+            table.insert(Node { id, addr: (ip.to_string(), port) });
+        }
+
+        Ok(table)
+    }
+}
+
+// a session's dht settings and routing table
+#[derive(Debug)]
+pub struct DhtState {
+    pub table: RoutingTable,
+    pub bootstrap_nodes: Vec<String>,
+    // where the table is kept between runs, if anywhere
+    pub state_file: Option<PathBuf>,
+    // dht nodes peers sent us in Port messages. we don't know their ids
+    // until they answer a ping, so they can't go in the table yet.
+    candidates: VecDeque<Addr>,
+}
+
+impl DhtState {
+    // picks up the table saved by the last run. one that can't be read
+    // is only worth a warning, the node just bootstraps from scratch.
+    pub fn load(state_file: Option<PathBuf>, bootstrap_nodes: Vec<String>) -> DhtState {
+        let saved = state_file.as_deref().and_then(|path| match fs::read(path) {
+            Ok(data) => RoutingTable::decode(&data)
+                .map_err(|e| warn!("ignoring dht state in {}: {}", path.display(), e))
+                .ok(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                warn!("couldn't read dht state from {}: {}", path.display(), e);
+                None
+            }
+        });
+
+        DhtState {
+            table: saved.unwrap_or_else(|| RoutingTable::new(NodeId::random())),
+            bootstrap_nodes,
+            state_file,
+            candidates: VecDeque::new(),
+        }
+    }
+
+    // returns false if we already had it. the oldest candidate makes
+    // room once there are too many.
+    pub fn add_candidate(&mut self, addr: Addr) -> bool {
+        if self.candidates.contains(&addr) || self.table.nodes().any(|n| n.addr == addr) {
+            return false;
+        }
+        if self.candidates.len() >= MAX_CANDIDATES {
+            self.candidates.pop_front();
+        }
+        self.candidates.push_back(addr);
+        true
+    }
+
+    pub fn candidates(&self) -> impl Iterator<Item = &Addr> {
+        self.candidates.iter()
+    }
+
+    // makes our id again when it doesn't fit our external address, the
+    // first time we learn it or after it changes. the nodes we know are
+    // kept, sorted into the buckets of the new id. returns true if the id
+    // changed.
+    pub fn set_external_ip(&mut self, ip: IpAddr) -> bool {
+        if self.table.own_id().is_secure_for(ip) {
+            return false;
+        }
+        let nodes: Vec<Node> = self.table.nodes().cloned().collect();
+        self.table = RoutingTable::new(NodeId::secure(ip));
+        for node in nodes {
+            self.table.insert(node);
+        }
+        true
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        match &self.state_file {
+            Some(path) => write_atomically(path, &self.table.encode()),
+            None => Ok(()),
+        }
+    }
+
+    // with too few nodes of our own to ask, start from the bootstrap nodes
+    pub fn needs_bootstrap(&self) -> bool {
+        self.table.len() < K
+    }
+}
+
+// a crash halfway through writing shouldn't lose the old table
+pub fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(first: u8) -> NodeId {
+        let mut bytes = [0; 20];
+        bytes[0] = first;
+        NodeId::new(bytes)
+    }
+
+    fn node(first: u8, ip: &str) -> Node {
+        Node { id: id(first), addr: (ip.to_string(), 6881) }
+    }
+
+    #[test]
+    fn test_buckets() {
+        let mut table = RoutingTable::new(id(0));
+        assert!(!table.insert(node(0, "10.0.0.1")));
+
+        // everything with the top bit set shares bucket 0
+        for i in 0..K as u8 {
+            assert!(table.insert(node(0x80 | i, "10.0.0.1")));
+        }
+        assert!(!table.insert(node(0xFF, "10.0.0.2")));
+        // a known node is refreshed rather than turned away
+        assert!(table.insert(node(0x80, "10.0.0.3")));
+        assert!(table.insert(node(0x01, "10.0.0.4")));
+        assert_eq!(table.len(), K + 1);
+
+        let closest = table.closest(&id(0), 2);
+        assert_eq!(closest[0].id, id(0x01));
+        assert_eq!(closest[1].id, id(0x80));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut table = RoutingTable::new(id(7));
+        table.insert(node(0x80, "10.0.0.1"));
+        table.insert(node(0x01, "192.168.1.2"));
+        assert_eq!(RoutingTable::decode(&table.encode()).unwrap(), table);
+
+        assert!(RoutingTable::decode(b"d2:id3:abc5:nodes0:e").is_err());
+        assert!(RoutingTable::decode(b"d2:id20:aaaaaaaaaaaaaaaaaaaa5:nodes3:abce").is_err());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let mut dht = DhtState::load(None, Vec::new());
+        let own = dht.table.own_id().as_bytes()[0];
+        dht.table.insert(node(own ^ 0x80, "10.0.0.1"));
+
+        assert!(dht.add_candidate(("10.0.0.2".to_string(), 7000)));
+        assert!(!dht.add_candidate(("10.0.0.2".to_string(), 7000)));
+        // already in the table
+        assert!(!dht.add_candidate(("10.0.0.1".to_string(), 6881)));
+
+        for port in 0..MAX_CANDIDATES as u16 {
+            dht.add_candidate(("10.0.0.3".to_string(), port));
+        }
+        assert_eq!(dht.candidates().count(), MAX_CANDIDATES);
+        assert!(!dht.candidates().any(|addr| addr.1 == 7000));
+    }
+
+    // the examples from bep 42
+    #[test]
+    fn test_secure_ids() {
+        let examples = [
+            ("124.31.75.21", [0x5f, 0xbf, 0xbf], 1),
+            ("21.75.31.124", [0x5a, 0x3c, 0xe9], 86),
+            ("65.23.51.170", [0xa5, 0xd4, 0x32], 22),
+            ("84.124.73.14", [0x1b, 0x03, 0x21], 65),
+            ("43.213.53.83", [0xe5, 0x6f, 0x6c], 90),
+        ];
+        for (ip, prefix, last) in examples {
+            let ip: IpAddr = ip.parse().unwrap();
+            let mut bytes = [0x55; 20];
+            bytes[..3].copy_from_slice(&prefix);
+            bytes[19] = last;
+            assert!(NodeId::new(bytes).is_secure_for(ip), "{}", ip);
+            bytes[1] ^= 0x01;
+            assert!(!NodeId::new(bytes).is_secure_for(ip), "{}", ip);
+            assert!(NodeId::secure(ip).is_secure_for(ip));
+        }
+
+        let ipv6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(NodeId::secure(ipv6).is_secure_for(ipv6));
+        // local addresses can have any id
+        for local in ["10.1.2.3", "192.168.0.1", "127.0.0.1", "fd00::1", "::ffff:172.16.0.1"] {
+            assert!(id(0x42).is_secure_for(local.parse().unwrap()), "{}", local);
+        }
+    }
+
+    #[test]
+    fn test_insecure_nodes() {
+        let mut table = RoutingTable::new(id(0));
+        let ip: IpAddr = "124.31.75.21".parse().unwrap();
+        assert!(!table.insert(Node { id: id(0x80), addr: (ip.to_string(), 6881) }));
+        assert!(table.insert(Node { id: NodeId::secure(ip), addr: (ip.to_string(), 6881) }));
+        // hostnames from the bootstrap list can't be checked
+        assert!(table.insert(Node { id: id(0x01), addr: ("router.example".to_string(), 6881) }));
+    }
+
+    #[test]
+    fn test_external_ip() {
+        let mut dht = DhtState::load(None, Vec::new());
+        dht.table = RoutingTable::new(id(0));
+        dht.table.insert(node(0x80, "10.0.0.1"));
+
+        let ip: IpAddr = "65.23.51.170".parse().unwrap();
+        assert!(dht.set_external_ip(ip));
+        assert!(dht.table.own_id().is_secure_for(ip));
+        assert_eq!(dht.table.nodes().map(|n| &n.addr.0).collect::<Vec<_>>(), ["10.0.0.1"]);
+        // an id that already fits is kept
+        let own = *dht.table.own_id();
+        assert!(!dht.set_external_ip(ip));
+        assert_eq!(dht.table.own_id(), &own);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join("bt-c-dht-state");
+        let _ = fs::remove_file(&path);
+        let bootstrap = vec!["router.example:6881".to_string()];
+
+        let mut dht = DhtState::load(Some(path.clone()), bootstrap.clone());
+        assert!(dht.needs_bootstrap());
+        dht.table.insert(node(0x80 ^ dht.table.own_id().as_bytes()[0], "10.0.0.1"));
+        dht.save().unwrap();
+
+        let reloaded = DhtState::load(Some(path.clone()), bootstrap.clone());
+        assert_eq!(reloaded.table, dht.table);
+
+        // junk on disk gets a fresh node instead of an error
+        fs::write(&path, b"junk").unwrap();
+        assert!(DhtState::load(Some(path.clone()), bootstrap).table.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use std::{io, sync::Arc, thread};
+
+use bytes::Bytes;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    client::read_block_at,
+    filemap::Storage,
+    hashing::{BlockWrite, BlockWritten},
+};
+
+// a torrent's disk, run as an actor like the announcer: one thread owns
+// the torrent's storage and does the block writes and upload reads its
+// connections send it, in the order they were sent. connections wait on
+// the reply, the rest of the runtime doesn't wait on the disk at all.
+//
+// closing is a message too, so every write sent before it is on disk by
+// the time it is flushed, and nothing sent after it gets written. hash
+// checks stay on the blocking pool (see hashing.rs), a 16 MiB piece being
+// read back would otherwise hold up every write behind it.
+
+pub enum DiskCommand {
+    Write { write: BlockWrite, reply: oneshot::Sender<BlockWritten> },
+    // a block of a piece we have, to send to a peer
+    Read { offset: u64, length: u32, reply: oneshot::Sender<io::Result<Bytes>> },
+    // flush what has been written and stop
+    Close { reply: oneshot::Sender<io::Result<()>> },
+}
+
+// how the torrent's connections reach its disk. the disk lives as long
+// as any of these do, or until it is closed.
+#[derive(Debug, Clone)]
+pub struct DiskHandle {
+    commands: mpsc::UnboundedSender<DiskCommand>,
+}
+
+impl DiskHandle {
+    pub async fn write(&self, write: BlockWrite) -> io::Result<BlockWritten> {
+        let (reply, written) = oneshot::channel();
+        self.commands.send(DiskCommand::Write { write, reply }).map_err(|_| closed())?;
+        written.await.map_err(|_| closed())
+    }
+
+    pub async fn read(&self, offset: u64, length: u32) -> io::Result<Bytes> {
+        let (reply, read) = oneshot::channel();
+        self.commands.send(DiskCommand::Read { offset, length, reply }).map_err(|_| closed())?;
+        read.await.map_err(|_| closed())?
+    }
+
+    // once every write sent before this is done. closing twice is fine,
+    // there is nothing left to flush the second time.
+    pub async fn close(&self) -> io::Result<()> {
+        let (reply, closed) = oneshot::channel();
+        if self.commands.send(DiskCommand::Close { reply }).is_err() {
+            return Ok(());
+        }
+        closed.await.unwrap_or(Ok(()))
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "the torrent's disk has been closed")
+}
+
+pub struct Disk {
+    pub storage: Arc<Storage>,
+}
+
+impl Disk {
+    // a thread of its own rather than a task, it spends its life blocked
+    // on the disk
+    pub fn spawn(self) -> io::Result<DiskHandle> {
+        let (commands, receiver) = mpsc::unbounded_channel();
+        thread::Builder::new().name("disk".to_string()).spawn(move || self.run(receiver))?;
+        Ok(DiskHandle { commands })
+    }
+
+    fn run(self, mut commands: mpsc::UnboundedReceiver<DiskCommand>) {
+        // None once the torrent is gone
+        while let Some(command) = commands.blocking_recv() {
+            match command {
+                // a connection that has gone away doesn't need telling
+                DiskCommand::Write { write, reply } => {
+                    let _ = reply.send(write.run(&self.storage));
+                }
+                DiskCommand::Read { offset, length, reply } => {
+                    let _ = reply.send(read_block_at(&self.storage, offset, length));
+                }
+                DiskCommand::Close { reply } => {
+                    let _ = reply.send(self.storage.flush());
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Torrent;
+
+    fn write(position: u64, data: &'static [u8]) -> BlockWrite {
+        BlockWrite { peer_id: "peer".to_string(), index: 0, offset: position, position, data: Bytes::from_static(data), hash: None }
+    }
+
+    #[tokio::test]
+    async fn test_close_comes_after_earlier_writes() {
+        let torrent = Torrent {
+            piece_length: 8,
+            total_size: 8,
+            output_file: std::env::temp_dir().join("bt-c-disk").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let _ = std::fs::remove_file(&torrent.output_file);
+        let disk = Disk { storage: Arc::new(Storage::open(&torrent).unwrap()) }.spawn().unwrap();
+
+        // sent without waiting, as a connection torn down mid-write would
+        let first = tokio::spawn({
+            let disk = disk.clone();
+            async move { disk.write(write(0, b"1234")).await }
+        });
+        tokio::task::yield_now().await;
+        disk.close().await.unwrap();
+        assert!(first.await.unwrap().unwrap().outcome.is_ok());
+
+        assert!(disk.write(write(4, b"5678")).await.is_err_and(|e| e.kind() == io::ErrorKind::NotConnected));
+        assert!(disk.read(0, 4).await.is_err());
+        disk.close().await.unwrap();
+
+        assert_eq!(std::fs::read(&torrent.output_file).unwrap(), b"1234");
+    }
+}
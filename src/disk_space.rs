@@ -0,0 +1,107 @@
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+};
+
+use crate::{
+    filemap::{self, FileMap},
+    torrent::Torrent,
+};
+
+// whether a torrent will fit where it is going, checked before its files
+// are opened. data already on disk, from an earlier run or another
+// client, doesn't need room again. files are written sparsely as pieces
+// come in, so what counts is the space they have taken up so far rather
+// than their length.
+//
+// this is only a guess made at the start, other downloads can still
+// fill the disk later on. PieceManager stops the torrent when that
+// happens.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DiskSpaceCheck {
+    // don't look
+    Off,
+    // add the torrent anyway, and say so
+    Warn,
+    // refuse to add a torrent that won't fit
+    #[default]
+    Fail,
+}
+
+// bytes free for us on the filesystem the path is on, or would be on
+// once it is created
+pub fn available(path: &Path) -> io::Result<u64> {
+    let existing = path.ancestors().find(|p| p.exists()).unwrap_or(Path::new("."));
+    let existing = if existing.as_os_str().is_empty() { Path::new(".") } else { existing };
+    let c_path = CString::new(existing.as_os_str().as_bytes()).map_err(io::Error::other)?;
+
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    // safe as c_path is a valid c string and stats is ours to fill in
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+// what the torrent's files still need on top of what they use already
+pub fn needed(torrent: &Torrent) -> u64 {
+    FileMap::new(torrent)
+        .files()
+        .iter()
+        .filter(|file| !file.padding)
+        .map(|file| {
+            // or its part file, while it is still downloading
+            let metadata = fs::metadata(&file.path).or_else(|_| fs::metadata(filemap::part_path(&file.path)));
+            let used = metadata.map_or(0, |m| m.blocks() * 512);
+            file.length.saturating_sub(used)
+        })
+        .sum()
+}
+
+pub fn check(torrent: &Torrent) -> Result<(), String> {
+    let needed = needed(torrent);
+    if needed == 0 {
+        return Ok(());
+    }
+    let available = available(Path::new(&torrent.output_file))
+        .map_err(|e| format!("couldn't find out how much space is free for {}: {}", torrent.output_file, e))?;
+    if needed > available {
+        return Err(format!(
+            "not enough disk space for {}: it needs {} more bytes and only {} are free",
+            torrent.output_file, needed, available
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_needed_and_check() {
+        let dir = std::env::temp_dir().join("bt-c-disk-space");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let torrent = |total_size| Torrent {
+            total_size,
+            output_file: dir.join("data").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(needed(&torrent(1 << 20)), 1 << 20);
+        // written data counts, the length of a sparse file doesn't
+        fs::write(dir.join("data"), vec![1u8; 1 << 16]).unwrap();
+        fs::OpenOptions::new().write(true).open(dir.join("data")).unwrap().set_len(1 << 20).unwrap();
+        let left = needed(&torrent(1 << 20));
+        assert!(((1 << 20) - (1 << 17)..1 << 20).contains(&left), "{}", left);
+
+        assert!(available(&dir.join("not").join("there")).unwrap() > 0);
+        assert!(check(&torrent(1 << 20)).is_ok());
+        assert!(check(&torrent(u64::MAX / 2)).unwrap_err().contains("not enough disk space"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use std::io;
+
+use crate::bencoding::decoder::BencodeError;
+
+// the ways the engine fails, for anything that wants to tell them apart
+// instead of just printing them. the messages stay the plain strings
+// they always were, the variant says which part of the engine gave up.
+#[derive(Debug, thiserror::Error)]
+pub enum BtError {
+    // data that isn't valid bencode
+    #[error("{0}")]
+    Bencode(#[from] BencodeError),
+    // valid bencode that isn't a valid torrent
+    #[error("{0}")]
+    Torrent(String),
+    // the tracker couldn't be reached or gave an answer we can't use
+    #[error("{0}")]
+    Tracker(String),
+    // a peer broke the wire protocol
+    #[error("{0}")]
+    Protocol(String),
+    // reading or writing the torrent's data on disk
+    #[error("{0}")]
+    Storage(#[from] io::Error),
+    // the session can't do what was asked of it, there is no torrent
+    // with that id, say, or one with the same info hash is already in it
+    #[error("{0}")]
+    Session(String),
+    // settings that can't work, for the session or one of its torrents
+    #[error("{0}")]
+    Config(String),
+}
+
+// only trackers are spoken to over http
+impl From<reqwest::Error> for BtError {
+    fn from(e: reqwest::Error) -> BtError {
+        BtError::Tracker(e.to_string())
+    }
+}
+
+// for the callers that still report errors as strings
+impl From<BtError> for String {
+    fn from(e: BtError) -> String {
+        e.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use super::*;
+    use crate::{bencoding::{decoder, Bencode}, torrent::build_torrent, tracker::TrackerResponse};
+
+    #[test]
+    fn test_matching_on_the_cause() {
+        let err: BtError = decoder::decode(b"i12").unwrap_err().into();
+        assert!(matches!(err, BtError::Bencode(BencodeError::UnexpectedEnd { .. })));
+        assert!(err.source().is_some());
+
+        let err: BtError = io::Error::new(io::ErrorKind::NotFound, "no such file").into();
+        assert!(matches!(&err, BtError::Storage(e) if e.kind() == io::ErrorKind::NotFound));
+        assert_eq!(String::from(err), "no such file");
+
+        assert!(matches!(build_torrent(&Bencode::Int(1)), Err(BtError::Torrent(_))));
+        assert!(matches!(TrackerResponse::parse(b"le"), Err(BtError::Tracker(_))));
+        assert!(matches!(TrackerResponse::parse(b"d8:interval"), Err(BtError::Bencode(_))));
+        assert_eq!(BtError::Tracker("tracker said no".to_string()).to_string(), "tracker said no");
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::sync::watch;
+
+// our address as the rest of the internet sees it, one for each address
+// family. trackers tell us in their answers (the "external ip" of bep
+// 24) and an optional check url can too. when it changes, e.g. an isp
+// handing out a new address to a long running seed, every torrent
+// announces again straight away instead of leaving peers to find us at
+// the old address until the next interval. the first address we learn
+// isn't a change, nobody had heard of us at any other.
+//
+// there is no dht node of our own yet. once there is, it should announce
+// again on a change as well.
+
+// how often the check url is asked, when there is one
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ExternalAddrs {
+    pub ipv4: Option<Ipv4Addr>,
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl ExternalAddrs {
+    pub fn iter(&self) -> impl Iterator<Item = IpAddr> {
+        self.ipv4.map(IpAddr::from).into_iter().chain(self.ipv6.map(IpAddr::from))
+    }
+}
+
+#[derive(Debug)]
+pub struct ExternalIp {
+    addrs: Mutex<ExternalAddrs>,
+    // bumped on every change, see the comment at the top
+    changes: watch::Sender<u64>,
+}
+
+impl Default for ExternalIp {
+    fn default() -> Self {
+        ExternalIp { addrs: Mutex::new(ExternalAddrs::default()), changes: watch::Sender::new(0) }
+    }
+}
+
+impl ExternalIp {
+    pub fn new() -> ExternalIp {
+        ExternalIp::default()
+    }
+
+    pub fn get(&self) -> ExternalAddrs {
+        *self.addrs.lock().unwrap()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    // returns true if the address changed. addresses that can't be
+    // anyone's external one (a tracker on the lan seeing our private
+    // address, say) are ignored.
+    pub fn report(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if !is_public(ip) {
+            return false;
+        }
+
+        let mut addrs = self.addrs.lock().unwrap();
+        let changed = match ip {
+            IpAddr::V4(v4) => addrs.ipv4.replace(v4).is_some_and(|old| old != v4),
+            IpAddr::V6(v6) => addrs.ipv6.replace(v6).is_some_and(|old| old != v6),
+        };
+        drop(addrs);
+        if changed {
+            self.changes.send_modify(|generation| *generation += 1);
+        }
+        changed
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => !(v4.is_private() || v4.is_loopback() || v4.is_link_local() || v4.is_unspecified() || v4.is_broadcast()),
+        // fc00::/7 being unique local and fe80::/10 link local
+        IpAddr::V6(v6) => !(v6.is_loopback() || v6.is_unspecified() || (v6.segments()[0] & 0xfe00) == 0xfc00 || (v6.segments()[0] & 0xffc0) == 0xfe80),
+    }
+}
+
+// asks a url that answers with our address and nothing else, the way
+// plenty of "what is my ip" services do
+pub async fn check(client: &reqwest::Client, url: &str) -> Result<IpAddr, String> {
+    let response = client.get(url).timeout(Duration::from_secs(30)).send().await.map_err(|e| e.to_string())?;
+    let body = response.error_for_status().map_err(|e| e.to_string())?.text().await.map_err(|e| e.to_string())?;
+    body.trim().parse().map_err(|_| format!("{} didn't answer with an ip address", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changes() {
+        let external = ExternalIp::new();
+        let changes = external.subscribe();
+
+        // learning it isn't a change, hearing it again neither
+        assert!(!external.report("203.0.113.7".parse().unwrap()));
+        assert!(!external.report("::ffff:203.0.113.7".parse().unwrap()));
+        assert!(!external.report("2001:db8::7".parse().unwrap()));
+        assert!(!changes.has_changed().unwrap());
+
+        assert!(external.report("198.51.100.1".parse().unwrap()));
+        assert!(changes.has_changed().unwrap());
+        assert_eq!(
+            external.get(),
+            ExternalAddrs { ipv4: Some("198.51.100.1".parse().unwrap()), ipv6: Some("2001:db8::7".parse().unwrap()) }
+        );
+
+        for private in ["192.168.1.2", "10.0.0.1", "127.0.0.1", "fd00::1", "fe80::1", "::1"] {
+            assert!(!external.report(private.parse().unwrap()));
+        }
+        assert_eq!(external.get().ipv4, Some("198.51.100.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+use crate::{
+    bencoding::{decoder, encoder, Bencode},
+    bundle::{Bundle, ResumeState},
+    info_hash::InfoHash,
+    torrent::{get_sha1_info_hash, Torrent},
+};
+
+// libtorrent's resume files, the .fastresume that qbittorrent and
+// deluge keep for each torrent, so torrents can move between them and us
+// without their data being checked again. it is a bencoded dict, of
+// which we read and write:
+//   file-format       "libtorrent resume file"
+//   file-version      1
+//   info-hash         the torrent's info hash
+//   pieces            one byte per piece, bit 0 set for the ones it has
+//   total_downloaded  bytes downloaded so far
+//   total_uploaded    bytes uploaded so far
+//   save_path         the directory the data is in
+//   name              the torrent's name
+//   paused            1 if it was paused
+//   info              the info dict, when the .torrent isn't kept
+//                     next to the resume file
+//   trackers          tiers of tracker urls
+//
+// qbittorrent keeps <info hash>.fastresume and <info hash>.torrent side
+// by side, which is what export writes too. deluge has one
+// torrents.fastresume for all of them, a dict from the hex info hash to
+// that torrent's resume file as a string.
+//
+// the rest of what libtorrent writes (peers, unfinished pieces, file
+// priorities, ...) is left out. pieces it had only half of are simply
+// downloaded again.
+
+pub const EXTENSION: &str = "fastresume";
+
+const FILE_FORMAT: &str = "libtorrent resume file";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FastResume {
+    pub info_hash: InfoHash,
+    pub resume: ResumeState,
+    pub save_path: Option<PathBuf>,
+    pub name: Option<String>,
+    pub paused: bool,
+    // the bencoded info dict
+    pub info: Option<Vec<u8>>,
+    pub trackers: Vec<Vec<String>>,
+}
+
+pub fn is_fastresume(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == EXTENSION)
+}
+
+// libtorrent only keeps tiers, a torrent with just announce is a tier
+// of its own
+fn tiers(torrent: &Torrent) -> Vec<Vec<String>> {
+    if !torrent.announce_list.is_empty() {
+        torrent.announce_list.clone()
+    } else if !torrent.announce.is_empty() {
+        vec![vec![torrent.announce.clone()]]
+    } else {
+        Vec::new()
+    }
+}
+
+impl FastResume {
+    // what libtorrent needs to pick the torrent up where we left it
+    pub fn from_torrent(torrent: &Torrent, resume: ResumeState, paused: bool) -> Result<FastResume, String> {
+        let info = match &torrent.info_bytes {
+            Some(info) => info.clone(),
+            None => {
+                let Bencode::Dict(metainfo) = torrent.to_bencode()? else { unreachable!("metainfo is always a dict") };
+                encoder::encode(&metainfo[&b"info"[..]])
+            }
+        };
+
+        let path = Path::new(&torrent.output_file);
+        Ok(FastResume {
+            info_hash: torrent.info_hash,
+            resume,
+            save_path: path.parent().map(Path::to_path_buf),
+            name: Some(torrent.name().to_string()),
+            paused,
+            info: Some(info),
+            trackers: tiers(torrent),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let bytes = |s: &str| Bencode::Bytes(s.as_bytes().to_vec());
+        let mut dict = BTreeMap::new();
+        dict.insert(b"file-format".to_vec(), bytes(FILE_FORMAT));
+        dict.insert(b"file-version".to_vec(), Bencode::Int(1));
+        dict.insert(b"info-hash".to_vec(), Bencode::Bytes(self.info_hash.as_bytes().to_vec()));
+        dict.insert(b"pieces".to_vec(), Bencode::Bytes(self.resume.have.iter().map(|&b| (b != 0) as u8).collect()));
+        dict.insert(b"total_downloaded".to_vec(), Bencode::Int(self.resume.downloaded as i64));
+        dict.insert(b"total_uploaded".to_vec(), Bencode::Int(self.resume.uploaded as i64));
+        dict.insert(b"paused".to_vec(), Bencode::Int(self.paused as i64));
+        if let Some(path) = &self.save_path {
+            dict.insert(b"save_path".to_vec(), bytes(&path.to_string_lossy()));
+        }
+        if let Some(name) = &self.name {
+            dict.insert(b"name".to_vec(), bytes(name));
+        }
+        if let Some(info) = self.info.as_ref().and_then(|info| decoder::decode(info).ok()) {
+            dict.insert(b"info".to_vec(), info.0);
+        }
+        let tiers = self.trackers.iter().map(|tier| Bencode::List(tier.iter().map(|url| bytes(url)).collect()));
+        dict.insert(b"trackers".to_vec(), Bencode::List(tiers.collect()));
+        encoder::encode(&Bencode::Dict(dict))
+    }
+
+    pub fn decode(data: &[u8]) -> Result<FastResume, String> {
+        match decoder::decode(data)? {
+            (Bencode::Dict(dict), _) => FastResume::from_dict(&dict),
+            _ => Err("resume file is not a dict".to_string()),
+        }
+    }
+
+    // every torrent in the file, whether it is libtorrent's own or
+    // deluge's collection of them
+    pub fn decode_all(data: &[u8]) -> Result<Vec<FastResume>, String> {
+        let dict = match decoder::decode(data)? {
+            (Bencode::Dict(dict), _) => dict,
+            _ => return Err("resume file is not a dict".to_string()),
+        };
+        if dict.contains_key(&b"file-format"[..]) {
+            return Ok(vec![FastResume::from_dict(&dict)?]);
+        }
+        dict.values()
+            .map(|value| match value {
+                Bencode::Bytes(resume) => FastResume::decode(resume),
+                _ => Err("resume file is neither libtorrent's nor deluge's".to_string()),
+            })
+            .collect()
+    }
+
+    fn from_dict(dict: &BTreeMap<Vec<u8>, Bencode>) -> Result<FastResume, String> {
+        let string = |key: &str| match dict.get(key.as_bytes()) {
+            Some(Bencode::Bytes(b)) => Some(String::from_utf8_lossy(b).to_string()),
+            _ => None,
+        };
+        let int = |key: &str| match dict.get(key.as_bytes()) {
+            Some(Bencode::Int(i)) if *i >= 0 => *i as u64,
+            _ => 0,
+        };
+
+        if string("file-format").as_deref() != Some(FILE_FORMAT) {
+            return Err("not a libtorrent resume file".to_string());
+        }
+        let info_hash = match dict.get(&b"info-hash"[..]) {
+            Some(Bencode::Bytes(hash)) => InfoHash::from_bytes(hash)?,
+            _ => return Err("resume file is missing info-hash".to_string()),
+        };
+        // a torrent libtorrent hadn't checked yet has no pieces, which
+        // is the same as having none of them
+        let have = match dict.get(&b"pieces"[..]) {
+            Some(Bencode::Bytes(pieces)) => pieces.iter().map(|b| b & 1).collect(),
+            _ => Vec::new(),
+        };
+        let trackers = match dict.get(&b"trackers"[..]) {
+            Some(Bencode::List(tiers)) => tiers
+                .iter()
+                .filter_map(|tier| match tier {
+                    Bencode::List(urls) => Some(urls.iter().filter_map(|url| match url {
+                        Bencode::Bytes(url) => Some(String::from_utf8_lossy(url).to_string()),
+                        _ => None,
+                    }).collect::<Vec<_>>()),
+                    _ => None,
+                })
+                .filter(|tier| !tier.is_empty())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        Ok(FastResume {
+            info_hash,
+            resume: ResumeState { have, downloaded: int("total_downloaded"), uploaded: int("total_uploaded") },
+            save_path: string("save_path").map(PathBuf::from),
+            name: string("name"),
+            paused: int("paused") != 0,
+            info: dict.get(&b"info"[..]).map(encoder::encode),
+            trackers,
+        })
+    }
+
+    // where the .torrent of a resume file in dir would be, if it isn't
+    // in the resume file itself
+    pub fn torrent_path(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("{}.torrent", self.info_hash))
+    }
+
+    // the resume file as a bundle, to be imported like one. metainfo is
+    // the torrent's .torrent, needed unless the info dict is in the
+    // resume file.
+    pub fn to_bundle(&self, metainfo: Option<&[u8]>) -> Result<Bundle, String> {
+        let metainfo = match (metainfo, &self.info) {
+            (Some(metainfo), _) => {
+                let (bencode, _) = decoder::decode(metainfo)?;
+                let info = match &bencode {
+                    Bencode::Dict(dict) => dict.get(&b"info"[..]).ok_or("the .torrent has no info dict")?,
+                    _ => return Err("the .torrent is not a dict".to_string()),
+                };
+                // one rebuilt by export from a torrent made up in code,
+                // see Torrent::to_bytes, hashes to something else. the info hash of the resume file is
+                // the one used, as with our own bundles.
+                if get_sha1_info_hash(info)? != self.info_hash {
+                    warn!("the .torrent for {} has another info hash, going by the resume file's", self.info_hash);
+                }
+                metainfo.to_vec()
+            }
+            (None, Some(info)) => {
+                let (info, _) = decoder::decode(info)?;
+                let tracker = self.trackers.first().and_then(|tier| tier.first()).cloned().unwrap_or_default();
+                let mut dict = BTreeMap::new();
+                dict.insert(b"announce".to_vec(), Bencode::Bytes(tracker.into_bytes()));
+                dict.insert(b"info".to_vec(), info);
+                if !self.trackers.is_empty() {
+                    let tiers = self.trackers.iter().map(|tier| {
+                        Bencode::List(tier.iter().map(|url| Bencode::Bytes(url.as_bytes().to_vec())).collect())
+                    });
+                    dict.insert(b"announce-list".to_vec(), Bencode::List(tiers.collect()));
+                }
+                encoder::encode(&Bencode::Dict(dict))
+            }
+            (None, None) => return Err(format!("resume file {} has no info dict and no .torrent was found for it", self.info_hash)),
+        };
+
+        let mut bundle = Bundle::new(self.info_hash, metainfo, self.resume.clone());
+        let num_pieces = bundle.torrent()?.pieces.len();
+        if bundle.resume.have.len() != num_pieces {
+            // libtorrent leaves the pieces out until it has checked them
+            if !bundle.resume.have.is_empty() {
+                return Err(format!("resume file has {} pieces, the torrent {}", bundle.resume.have.len(), num_pieces));
+            }
+            bundle.resume.have = vec![0; num_pieces];
+        }
+        Ok(bundle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{encode_metainfo, File};
+
+    fn test_torrent() -> Torrent {
+        Torrent {
+            info_hash: InfoHash::new([0xAB; 20]),
+            announce: "http://tracker.example/announce".to_string(),
+            announce_list: vec![vec!["http://tracker.example/announce".to_string()], vec!["udp://backup.example:80".to_string()]],
+            piece_length: 16,
+            total_size: 40,
+            pieces: vec![[1; 20], [2; 20], [3; 20]],
+            output_file: "/data/file.bin".to_string(),
+            files: vec![File::new("file.bin".to_string(), 40)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let torrent = test_torrent();
+        let resume = ResumeState { have: vec![1, 0, 1], downloaded: 32, uploaded: 1000 };
+        let mut fast = FastResume::from_torrent(&torrent, resume.clone(), true).unwrap();
+        assert_eq!(fast.save_path, Some(PathBuf::from("/data")));
+        assert_eq!(fast.name.as_deref(), Some("file.bin"));
+        assert_eq!(fast.trackers.len(), 2);
+
+        let decoded = FastResume::decode(&fast.encode()).unwrap();
+        assert_eq!(decoded, fast);
+
+        // the rebuilt info dict hashes to something else, so the bundle
+        // is checked against the info hash libtorrent knows it by
+        fast.info_hash = get_sha1_info_hash(&decoder::decode(fast.info.as_ref().unwrap()).unwrap().0).unwrap();
+        let bundle = fast.to_bundle(None).unwrap();
+        assert_eq!(bundle.resume, resume);
+        let imported = bundle.torrent().unwrap();
+        assert_eq!((imported.pieces, imported.announce_list), (torrent.pieces, torrent.announce_list));
+
+        // a .torrent for something else is refused
+        let other = encode_metainfo(&Torrent { pieces: vec![[9; 20]; 2], total_size: 32, ..test_torrent() }).unwrap();
+        assert!(fast.to_bundle(Some(&other)).is_err());
+        fast.info = None;
+        assert!(fast.to_bundle(None).is_err());
+    }
+
+    #[test]
+    fn test_decode_libtorrent_and_deluge() {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"file-format".to_vec(), Bencode::Bytes(FILE_FORMAT.as_bytes().to_vec()));
+        dict.insert(b"file-version".to_vec(), Bencode::Int(1));
+        dict.insert(b"info-hash".to_vec(), Bencode::Bytes(vec![0xCD; 20]));
+        // verified as well as had, in libtorrent's seed mode
+        dict.insert(b"pieces".to_vec(), Bencode::Bytes(vec![3, 0, 1]));
+        dict.insert(b"total_uploaded".to_vec(), Bencode::Int(500));
+        dict.insert(b"qBt-category".to_vec(), Bencode::Bytes(b"linux".to_vec()));
+        let single = encoder::encode(&Bencode::Dict(dict));
+
+        let fast = FastResume::decode(&single).unwrap();
+        assert_eq!(fast.resume, ResumeState { have: vec![1, 0, 1], downloaded: 0, uploaded: 500 });
+        assert_eq!(fast.torrent_path(Path::new("/state")), PathBuf::from(format!("/state/{}.torrent", "cd".repeat(20))));
+
+        let mut deluge = BTreeMap::new();
+        deluge.insert("cd".repeat(20).into_bytes(), Bencode::Bytes(single.clone()));
+        let all = FastResume::decode_all(&encoder::encode(&Bencode::Dict(deluge))).unwrap();
+        assert_eq!(all, vec![fast]);
+        assert_eq!(FastResume::decode_all(&single).unwrap().len(), 1);
+
+        assert!(FastResume::decode(b"d4:infoi1ee").is_err());
+        assert!(is_fastresume(Path::new("BT_backup/abc.fastresume")));
+        assert!(!is_fastresume(Path::new("out.bundle")));
+    }
+}
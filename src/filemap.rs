@@ -0,0 +1,546 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    ops::Range,
+    os::unix::fs::FileExt as _,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::warn;
+
+use crate::torrent::Torrent;
+
+// pieces are numbered across the torrent as if all of its files were
+// one long stream of bytes. the file map translates a range of that
+// stream into the parts of each file it covers, so a piece (or block)
+// that straddles several files gets split up without copying it.
+//
+// padding files (bep 47) take up space in the stream so that the next
+// file starts on a piece boundary, but are never written to disk. they
+// read back as zeros.
+//
+// files the torrent is still downloading can be written under a
+// temporary name, PART_SUFFIX added to the real one, and only renamed
+// to it once every piece checks out. anything looking at the download
+// directory never sees a half written file under its real name.
+
+pub const PART_SUFFIX: &str = ".part";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    // where the file starts in the torrent's byte stream
+    pub offset: u64,
+    pub length: u64,
+    pub padding: bool,
+}
+
+// the piece of a range that lands in one file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSpan {
+    pub file: usize,
+    pub file_offset: u64,
+    // which bytes of the range this covers
+    pub range: Range<usize>,
+}
+
+#[derive(Debug)]
+pub struct FileMap {
+    files: Vec<FileEntry>,
+    total_size: u64,
+}
+
+impl FileMap {
+    pub fn new(torrent: &Torrent) -> FileMap {
+        // single file torrents are written straight to the output file
+        if !torrent.multi_file {
+            let entry = FileEntry {
+                path: PathBuf::from(&torrent.output_file),
+                offset: 0,
+                length: torrent.total_size,
+                padding: false,
+            };
+            return FileMap { files: vec![entry], total_size: torrent.total_size };
+        }
+
+        let mut offset = 0;
+        let files = torrent.files.iter().map(|file| {
+            let entry = FileEntry {
+                path: Path::new(&torrent.output_file).join(&file.name),
+                offset,
+                length: file.length(),
+                padding: file.is_padding(),
+            };
+            offset += file.length();
+            entry
+        }).collect();
+
+        FileMap { files, total_size: offset }
+    }
+
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    // splits length bytes starting at offset into one span per file
+    // touched, in order. empty files never get a span.
+    pub fn spans(&self, offset: u64, length: u64) -> Result<Vec<FileSpan>, String> {
+        let end = offset.checked_add(length).ok_or("range overflows")?;
+        if end > self.total_size {
+            return Err(format!("range {}..{} is past the end of the torrent ({} bytes)", offset, end, self.total_size));
+        }
+
+        let mut spans = Vec::new();
+        if length == 0 {
+            return Ok(spans);
+        }
+
+        // first file that ends after offset
+        let first = self.files.partition_point(|f| f.offset + f.length <= offset);
+
+        for (index, file) in self.files.iter().enumerate().skip(first) {
+            if file.offset >= end {
+                break;
+            }
+            if file.length == 0 {
+                continue;
+            }
+
+            let start = offset.max(file.offset);
+            let stop = end.min(file.offset + file.length);
+            spans.push(FileSpan {
+                file: index,
+                file_offset: start - file.offset,
+                range: (start - offset) as usize..(stop - offset) as usize,
+            });
+        }
+
+        Ok(spans)
+    }
+}
+
+// the torrent's files on disk, accessed through the file map
+#[derive(Debug)]
+pub struct Storage {
+    map: FileMap,
+    // None for padding files, which are never opened
+    handles: Vec<Option<File>>,
+    // where each file is right now, see finalize
+    paths: Mutex<Vec<PathBuf>>,
+    // the torrent's output_file, and where it is since finalize moved it
+    location: Mutex<PathBuf>,
+}
+
+impl Storage {
+    // opens every file of the torrent, creating it (and any directories
+    // above it) if it isn't there yet. existing data is left alone.
+    pub fn open(torrent: &Torrent) -> io::Result<Storage> {
+        Storage::open_with(torrent, false)
+    }
+
+    // with part_files, a file that doesn't exist under its real name yet
+    // is written as a part file. one that does, data from an earlier run
+    // or already complete, is used where it is.
+    pub fn open_with(torrent: &Torrent, part_files: bool) -> io::Result<Storage> {
+        let map = FileMap::new(torrent);
+
+        let mut handles = Vec::with_capacity(map.files.len());
+        let mut paths = Vec::with_capacity(map.files.len());
+        for file in &map.files {
+            let path = if part_files && !file.padding && !file.path.exists() { part_path(&file.path) } else { file.path.clone() };
+            if file.padding {
+                handles.push(None);
+                paths.push(path);
+                continue;
+            }
+
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+
+            let fd = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            handles.push(Some(fd));
+            paths.push(path);
+        }
+
+        let location = PathBuf::from(&torrent.output_file);
+        Ok(Storage { map, handles, paths: Mutex::new(paths), location: Mutex::new(location) })
+    }
+
+    // gives part files their real names once the torrent is complete,
+    // moving it under another directory at the same time if there is
+    // one. a rename is atomic, so a file shows up complete or not at all.
+    // open handles don't care what the file is called, reads go on as
+    // before. another filesystem gets a copy of each file instead, and
+    // the handles read the originals until they are closed.
+    //
+    // if the move fails partway the files that made it are brought back
+    // and the torrent is finished where it is, so it never ends up split
+    // between the two. returns where the torrent's output_file is now.
+    pub fn finalize(&self, to: Option<&Path>) -> io::Result<PathBuf> {
+        let mut location = self.location.lock().unwrap();
+        let mut paths = self.paths.lock().unwrap();
+
+        if let Some(dir) = to {
+            let target_root = dir.join(location.file_name().unwrap_or_default());
+            if target_root != *location {
+                match self.move_files(&mut paths, &location, &target_root) {
+                    Ok(()) => {
+                        // a multi file torrent leaves its empty directories behind
+                        if location.is_dir() {
+                            let _ = remove_empty_dirs(&location);
+                        }
+                        *location = target_root;
+                        return Ok(location.clone());
+                    }
+                    Err(e) => warn!("couldn't move {} to {}, finishing it where it is: {}", location.display(), dir.display(), e),
+                }
+            }
+        }
+
+        let root = location.clone();
+        self.move_files(&mut paths, &root, &root)?;
+        Ok(root)
+    }
+
+    // gives every file its real name under to, for a torrent whose
+    // output_file is at from. paths follows each file that is moved.
+    fn move_files(&self, paths: &mut [PathBuf], from: &Path, to: &Path) -> io::Result<()> {
+        for (file, current) in self.map.files.iter().zip(paths.iter_mut()) {
+            if file.padding {
+                continue;
+            }
+            let relative = file.path.strip_prefix(from).unwrap_or(Path::new(""));
+            let target = if relative.as_os_str().is_empty() { to.to_path_buf() } else { to.join(relative) };
+            if *current == target {
+                continue;
+            }
+            move_file(current, &target)?;
+            *current = target;
+        }
+        Ok(())
+    }
+
+    // where the torrent's data is, normally its output_file
+    pub fn location(&self) -> PathBuf {
+        self.location.lock().unwrap().clone()
+    }
+
+    // where the file is right now, which finalize may have changed
+    pub fn file_path(&self, index: usize) -> PathBuf {
+        self.paths.lock().unwrap()[index].clone()
+    }
+
+    pub fn map(&self) -> &FileMap {
+        &self.map
+    }
+
+    pub fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        for span in self.map.spans(offset, data.len() as u64).map_err(io::Error::other)? {
+            if let Some(fd) = &self.handles[span.file] {
+                fd.write_all_at(&data[span.range], span.file_offset)?;
+            }
+        }
+        Ok(())
+    }
+
+    // writes go straight to the files, this only waits for the os to
+    // get them onto the disk
+    pub fn flush(&self) -> io::Result<()> {
+        for fd in self.handles.iter().flatten() {
+            fd.sync_data()?;
+        }
+        Ok(())
+    }
+
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        for span in self.map.spans(offset, buf.len() as u64).map_err(io::Error::other)? {
+            match &self.handles[span.file] {
+                Some(fd) => fd.read_exact_at(&mut buf[span.range], span.file_offset)?,
+                None => buf[span.range].fill(0),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(PART_SUFFIX);
+    PathBuf::from(name)
+}
+
+// a rename where there can be one, otherwise a copy that is on disk
+// before the original goes
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let copied = fs::copy(from, to).and_then(|_| File::open(to)?.sync_all());
+            if let Err(e) = copied {
+                let _ = fs::remove_file(to);
+                return Err(e);
+            }
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+fn remove_empty_dirs(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            remove_empty_dirs(&path)?;
+        }
+    }
+    fs::remove_dir(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{info_hash::InfoHash, torrent::File as TorrentFile};
+
+    fn test_torrent(output: &Path, files: Vec<TorrentFile>) -> Torrent {
+        let total_size = files.iter().map(|f| f.length()).sum();
+        Torrent {
+            info_hash: InfoHash::default(),
+            announce: String::new(),
+            multi_file: true,
+            piece_length: 16,
+            total_size,
+            pieces: vec![],
+            output_file: output.to_string_lossy().to_string(),
+            files,
+            ..Default::default()
+        }
+    }
+
+    // a, an empty file, b, 6 bytes of padding, c
+    fn test_map() -> FileMap {
+        FileMap::new(&test_torrent(Path::new("out"), vec![
+            TorrentFile::new("a".to_string(), 10),
+            TorrentFile::new("empty".to_string(), 0),
+            TorrentFile::new("b".to_string(), 16),
+            TorrentFile::padding(6),
+            TorrentFile::new("c".to_string(), 5),
+        ]))
+    }
+
+    fn span(file: usize, file_offset: u64, range: Range<usize>) -> FileSpan {
+        FileSpan { file, file_offset, range }
+    }
+
+    #[test]
+    fn test_offsets() {
+        let map = test_map();
+        let offsets: Vec<u64> = map.files().iter().map(|f| f.offset).collect();
+        assert_eq!(offsets, vec![0, 10, 10, 26, 32]);
+        assert_eq!(map.total_size(), 37);
+        assert_eq!(map.files()[2].path, Path::new("out").join("b"));
+        assert!(map.files()[3].padding);
+    }
+
+    #[test]
+    fn test_single_file() {
+        let mut torrent = test_torrent(Path::new("out.iso"), vec![]);
+        torrent.multi_file = false;
+        torrent.total_size = 100;
+
+        let map = FileMap::new(&torrent);
+        assert_eq!(map.files().len(), 1);
+        assert_eq!(map.files()[0].path, PathBuf::from("out.iso"));
+        assert_eq!(map.spans(90, 10).unwrap(), vec![span(0, 90, 0..10)]);
+    }
+
+    #[test]
+    fn test_spans_within_one_file() {
+        let map = test_map();
+        assert_eq!(map.spans(0, 10).unwrap(), vec![span(0, 0, 0..10)]);
+        assert_eq!(map.spans(3, 4).unwrap(), vec![span(0, 3, 0..4)]);
+        assert_eq!(map.spans(12, 14).unwrap(), vec![span(2, 2, 0..14)]);
+    }
+
+    #[test]
+    fn test_spans_on_boundaries() {
+        let map = test_map();
+
+        // starting exactly where a file starts skips the empty file before it
+        assert_eq!(map.spans(10, 16).unwrap(), vec![span(2, 0, 0..16)]);
+        // ending exactly where a file ends doesn't touch the next one
+        assert_eq!(map.spans(9, 1).unwrap(), vec![span(0, 9, 0..1)]);
+        assert_eq!(map.spans(9, 2).unwrap(), vec![span(0, 9, 0..1), span(2, 0, 1..2)]);
+        // the last byte of the torrent
+        assert_eq!(map.spans(36, 1).unwrap(), vec![span(4, 4, 0..1)]);
+    }
+
+    #[test]
+    fn test_spans_across_files_and_padding() {
+        let map = test_map();
+        assert_eq!(map.spans(0, 37).unwrap(), vec![
+            span(0, 0, 0..10),
+            span(2, 0, 10..26),
+            span(3, 0, 26..32),
+            span(4, 0, 32..37),
+        ]);
+        assert_eq!(map.spans(24, 10).unwrap(), vec![span(2, 14, 0..2), span(3, 0, 2..8), span(4, 0, 8..10)]);
+    }
+
+    #[test]
+    fn test_spans_out_of_range() {
+        let map = test_map();
+        assert!(map.spans(37, 0).unwrap().is_empty());
+        assert!(map.spans(36, 2).is_err());
+        assert!(map.spans(40, 1).is_err());
+        assert!(map.spans(u64::MAX, 2).is_err());
+    }
+
+    // every range of the map, checked against walking it a byte at a time
+    #[test]
+    fn test_spans_exhaustive() {
+        let map = test_map();
+        let owner = |pos: u64| map.files().iter().position(|f| pos >= f.offset && pos < f.offset + f.length).unwrap();
+
+        for offset in 0..=map.total_size() {
+            for length in 0..=map.total_size() - offset {
+                let spans = map.spans(offset, length).unwrap();
+
+                let mut expected: Vec<FileSpan> = Vec::new();
+                for i in 0..length {
+                    let file = owner(offset + i);
+                    match expected.last_mut() {
+                        Some(last) if last.file == file => last.range.end += 1,
+                        _ => expected.push(span(file, offset + i - map.files()[file].offset, i as usize..i as usize + 1)),
+                    }
+                }
+
+                assert_eq!(spans, expected, "offset {} length {}", offset, length);
+            }
+        }
+    }
+
+    #[test]
+    fn test_storage_round_trip() {
+        let dir = std::env::temp_dir().join("bt-c-filemap-storage");
+        let _ = fs::remove_dir_all(&dir);
+
+        let torrent = test_torrent(&dir, vec![
+            TorrentFile::new("a".to_string(), 10),
+            TorrentFile::new("sub/b".to_string(), 16),
+            TorrentFile::padding(6),
+            TorrentFile::new("c".to_string(), 5),
+        ]);
+        let storage = Storage::open(&torrent).unwrap();
+
+        let data: Vec<u8> = (1..=37).collect();
+        storage.write_at(0, &data).unwrap();
+
+        assert_eq!(fs::read(dir.join("a")).unwrap(), &data[..10]);
+        assert_eq!(fs::read(dir.join("sub/b")).unwrap(), &data[10..26]);
+        assert_eq!(fs::read(dir.join("c")).unwrap(), &data[32..]);
+
+        // padding is never written and reads back as zeros
+        let mut buf = vec![0xFF; 12];
+        storage.read_at(22, &mut buf).unwrap();
+        assert_eq!(buf, [&data[22..26], &[0; 6][..], &data[32..34]].concat());
+    }
+
+    #[test]
+    fn test_part_files() {
+        let root = std::env::temp_dir().join("bt-c-filemap-part");
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("incomplete").join("name");
+        let files = vec![TorrentFile::new("a".to_string(), 4), TorrentFile::new("sub/b".to_string(), 4)];
+
+        // only the part files are there while downloading
+        let storage = Storage::open_with(&test_torrent(&dir, files.clone()), true).unwrap();
+        storage.write_at(0, b"12345678").unwrap();
+        assert!(dir.join("a.part").exists() && dir.join("sub/b.part").exists());
+        assert!(!dir.join("a").exists());
+
+        // a second go carries on with them
+        drop(storage);
+        let storage = Storage::open_with(&test_torrent(&dir, files.clone()), true).unwrap();
+        assert_eq!(storage.finalize(None).unwrap(), dir);
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"1234");
+        assert!(!dir.join("a.part").exists());
+
+        // and moved, reads still work from the new place
+        let done = root.join("complete");
+        assert_eq!(storage.finalize(Some(&done)).unwrap(), done.join("name"));
+        assert_eq!(fs::read(done.join("name/sub/b")).unwrap(), b"5678");
+        assert!(!dir.exists());
+        let mut buf = [0; 8];
+        storage.read_at(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"12345678");
+
+        // files already under their real names are used as they are
+        let storage = Storage::open_with(&test_torrent(&done.join("name"), files), true).unwrap();
+        assert!(!done.join("name/a.part").exists());
+        storage.read_at(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"12345678");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_finalize_falls_back_to_in_place() {
+        let root = std::env::temp_dir().join("bt-c-filemap-fallback");
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("incomplete").join("name");
+        let files = vec![TorrentFile::new("a".to_string(), 4), TorrentFile::new("sub/b".to_string(), 4)];
+        let storage = Storage::open_with(&test_torrent(&dir, files), true).unwrap();
+        storage.write_at(0, b"12345678").unwrap();
+
+        // sub can't be made under the completed directory, a file is in
+        // the way, so a is moved and then has to come back
+        let done = root.join("complete");
+        fs::create_dir_all(done.join("name")).unwrap();
+        fs::write(done.join("name/sub"), b"").unwrap();
+        assert_eq!(storage.finalize(Some(&done)).unwrap(), dir);
+        assert_eq!(storage.location(), dir);
+        assert_eq!(fs::read(dir.join("a")).unwrap(), b"1234");
+        assert_eq!(fs::read(dir.join("sub/b")).unwrap(), b"5678");
+        assert!(!done.join("name/a").exists() && !dir.join("a.part").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_finalize_to_another_filesystem() {
+        use std::os::unix::fs::MetadataExt as _;
+
+        // /dev/shm is a tmpfs of its own where there is one
+        let other = Path::new("/dev/shm");
+        let root = std::env::temp_dir().join("bt-c-filemap-exdev");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let device = |path: &Path| fs::metadata(path).map(|m| m.dev()).ok();
+        if device(other).is_none() || device(other) == device(&root) {
+            return;
+        }
+
+        let dir = root.join("name");
+        let storage = Storage::open_with(&test_torrent(&dir, vec![TorrentFile::new("a".to_string(), 4)]), true).unwrap();
+        storage.write_at(0, b"1234").unwrap();
+
+        let done = other.join("bt-c-filemap-exdev");
+        let _ = fs::remove_dir_all(&done);
+        assert_eq!(storage.finalize(Some(&done)).unwrap(), done.join("name"));
+        assert_eq!(fs::read(done.join("name/a")).unwrap(), b"1234");
+        assert!(!dir.exists());
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_dir_all(&done).unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+use std::{fs, net::IpAddr, path::Path};
+
+// which country an address is in, from a maxmind database (the .mmdb
+// of geolite2 country or city, or anything else in that format like
+// db-ip's lite lists). read by hand, it is a simple format: a binary
+// tree walked one bit of the address at a time, whose leaves point into
+// a section of typed values.
+//
+// see: https://maxmind.github.io/MaxMind-DB/
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+// between the tree and the values
+const SEPARATOR: usize = 16;
+// values don't nest anywhere near this deep, pointers included
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Uint(u128),
+    Int(i32),
+    Double(f64),
+    Bytes(Vec<u8>),
+    Bool(bool),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u128> {
+        match self {
+            Value::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GeoIp {
+    data: Vec<u8>,
+    node_count: u32,
+    record_size: u16,
+    ip_version: u16,
+    database_type: String,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<GeoIp, String> {
+        let data = fs::read(path).map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+        GeoIp::from_bytes(data).map_err(|e| format!("{} isn't a maxmind database: {}", path.display(), e))
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<GeoIp, String> {
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or("it has no metadata")?;
+        let start = marker + METADATA_MARKER.len();
+        let (metadata, _) = decode(&data[start..], 0, 0)?;
+
+        let field = |key| metadata.get(key).and_then(Value::as_uint).ok_or_else(|| format!("its metadata has no {}", key));
+        let node_count = u32::try_from(field("node_count")?).map_err(|_| "it has too many nodes")?;
+        let record_size = field("record_size")? as u16;
+        let ip_version = field("ip_version")? as u16;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("records of {} bits aren't supported", record_size));
+        }
+        if ![4, 6].contains(&ip_version) {
+            return Err(format!("unknown ip version {}", ip_version));
+        }
+        let database_type = metadata.get("database_type").and_then(Value::as_str).unwrap_or_default().to_string();
+
+        let geoip = GeoIp { data, node_count, record_size, ip_version, database_type };
+        if geoip.tree_size() + SEPARATOR > marker {
+            return Err("its search tree is cut short".to_string());
+        }
+        Ok(geoip)
+    }
+
+    // e.g. "GeoLite2-Country"
+    pub fn database_type(&self) -> &str {
+        &self.database_type
+    }
+
+    // the two letter iso code of the country the address is in, or when
+    // the database doesn't know that, the one it is registered in
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record = self.lookup(ip)?;
+        ["country", "registered_country"]
+            .iter()
+            .find_map(|key| record.get(key)?.get("iso_code")?.as_str())
+            .map(str::to_string)
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<Value> {
+        // an ipv6 database has the ipv4 addresses at ::a.b.c.d
+        let (address, bits) = match (ip.to_canonical(), self.ip_version) {
+            (IpAddr::V4(ip), 4) => (u32::from(ip) as u128, 32),
+            (IpAddr::V4(ip), _) => (u32::from(ip) as u128, 128),
+            (IpAddr::V6(ip), 6) => (u128::from(ip), 128),
+            (IpAddr::V6(_), _) => return None,
+        };
+
+        let mut node = 0u32;
+        for i in (0..bits).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (address >> i) & 1 == 1)?;
+        }
+        // node_count itself means there is nothing for the address
+        if node <= self.node_count {
+            return None;
+        }
+        let offset = ((node - self.node_count) as usize).checked_sub(SEPARATOR)?;
+        let values = self.tree_size() + SEPARATOR;
+        decode(self.data.get(values..)?, offset, 0).ok().map(|(value, _)| value)
+    }
+
+    fn tree_size(&self) -> usize {
+        self.node_count as usize * self.record_size as usize / 4
+    }
+
+    fn record(&self, node: u32, right: bool) -> Option<u32> {
+        let size = self.record_size as usize / 4;
+        let bytes = self.data.get(node as usize * size..(node as usize + 1) * size)?;
+        let be = |b: &[u8]| b.iter().fold(0u32, |n, &b| n << 8 | b as u32);
+        Some(match (self.record_size, right) {
+            (24, false) => be(&bytes[..3]),
+            (24, true) => be(&bytes[3..]),
+            // the middle byte's high half goes with the left record
+            (28, false) => (bytes[3] as u32 & 0xf0) << 20 | be(&bytes[..3]),
+            (28, true) => (bytes[3] as u32 & 0x0f) << 24 | be(&bytes[4..]),
+            (_, false) => be(&bytes[..4]),
+            (_, true) => be(&bytes[4..]),
+        })
+    }
+}
+
+// the value at pos in section, and where the next one starts. pointers
+// are offsets into the same section.
+fn decode(section: &[u8], pos: usize, depth: usize) -> Result<(Value, usize), String> {
+    if depth > MAX_DEPTH {
+        return Err("its values nest too deep".to_string());
+    }
+    let short = || "a value is cut short".to_string();
+    let byte = |at: usize| section.get(at).copied().ok_or_else(short);
+    let be = |from: usize, n: usize| -> Result<u128, String> {
+        Ok(section.get(from..from + n).ok_or_else(short)?.iter().fold(0u128, |v, &b| v << 8 | b as u128))
+    };
+
+    let control = byte(pos)?;
+    let mut pos = pos + 1;
+    let mut kind = control >> 5;
+    if kind == 1 {
+        // the size bits say how long the pointer is
+        let (n, bias) = match (control >> 3) & 3 {
+            0 => (1, 0),
+            1 => (2, 2048),
+            2 => (3, 526336),
+            _ => (4, 0),
+        };
+        let high = if n == 4 { 0 } else { (control & 7) as u128 };
+        let target = (high << (8 * n) | be(pos, n)?) + bias;
+        let (value, _) = decode(section, target as usize, depth + 1)?;
+        return Ok((value, pos + n));
+    }
+    if kind == 0 {
+        kind = 7 + byte(pos)?;
+        pos += 1;
+    }
+    let size = match control & 0x1f {
+        29 => {
+            pos += 1;
+            29 + be(pos - 1, 1)? as usize
+        }
+        30 => {
+            pos += 2;
+            285 + be(pos - 2, 2)? as usize
+        }
+        31 => {
+            pos += 3;
+            65821 + be(pos - 3, 3)? as usize
+        }
+        n => n as usize,
+    };
+
+    let bytes = |pos: usize| section.get(pos..pos + size).ok_or_else(short);
+    let value = match kind {
+        2 => Value::String(String::from_utf8_lossy(bytes(pos)?).into_owned()),
+        3 => Value::Double(f64::from_be_bytes(bytes(pos)?.try_into().map_err(|_| "a double isn't 8 bytes")?)),
+        4 => Value::Bytes(bytes(pos)?.to_vec()),
+        5 | 6 | 9 | 10 => Value::Uint(be(pos, size)?),
+        8 => Value::Int(be(pos, size)? as u32 as i32),
+        15 => Value::Double(f32::from_be_bytes(bytes(pos)?.try_into().map_err(|_| "a float isn't 4 bytes")?) as f64),
+        14 => return Ok((Value::Bool(size != 0), pos)),
+        7 => {
+            let mut entries = Vec::new();
+            for _ in 0..size {
+                let (key, next) = decode(section, pos, depth + 1)?;
+                let (value, next) = decode(section, next, depth + 1)?;
+                let Value::String(key) = key else { return Err("a map key isn't a string".to_string()) };
+                entries.push((key, value));
+                pos = next;
+            }
+            return Ok((Value::Map(entries), pos));
+        }
+        11 => {
+            let mut items = Vec::new();
+            for _ in 0..size {
+                let (value, next) = decode(section, pos, depth + 1)?;
+                items.push(value);
+                pos = next;
+            }
+            return Ok((Value::Array(items), pos));
+        }
+        _ => return Err(format!("unknown value type {}", kind)),
+    };
+    Ok((value, pos + size))
+}
+
+// the country code of the address in "ip:port", for the peer list
+pub fn country_of(geoip: &GeoIp, address: &str) -> Option<String> {
+    let ip = match address.parse::<std::net::SocketAddr>() {
+        Ok(addr) => addr.ip(),
+        Err(_) => address.parse().ok()?,
+    };
+    geoip.country(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        [vec![2 << 5 | s.len() as u8], s.as_bytes().to_vec()].concat()
+    }
+
+    fn uint16(n: u16) -> Vec<u8> {
+        [vec![5 << 5 | 2], n.to_be_bytes().to_vec()].concat()
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut out = vec![7 << 5 | entries.len() as u8];
+        for (key, value) in entries {
+            out.extend(string(key));
+            out.extend(value);
+        }
+        out
+    }
+
+    fn country(code: &str) -> Vec<u8> {
+        map(&[("country", map(&[("iso_code", string(code))]))])
+    }
+
+    // an ipv4 database with 24 bit records: 0.0.0.0/2 is in nl,
+    // 64.0.0.0/2 in the us, and the rest isn't known
+    fn database() -> Vec<u8> {
+        let nl = country("NL");
+        let node_count = 2u32;
+        let record = |r: u32| r.to_be_bytes()[1..].to_vec();
+        let data = |offset: usize| node_count + SEPARATOR as u32 + offset as u32;
+        let mut db = Vec::new();
+        db.extend(record(1));
+        db.extend(record(node_count));
+        db.extend(record(data(0)));
+        db.extend(record(data(nl.len())));
+        db.extend([0; SEPARATOR]);
+        db.extend(&nl);
+        // a pointer to nl's country map, like the real databases share
+        // their values
+        db.extend(map(&[("registered_country", vec![1 << 5, 9]), ("country", map(&[("iso_code", string("US"))]))]));
+        db.extend(METADATA_MARKER);
+        db.extend(map(&[
+            ("node_count", uint16(2)),
+            ("record_size", uint16(24)),
+            ("ip_version", uint16(4)),
+            ("database_type", string("Test-Country")),
+        ]));
+        db
+    }
+
+    #[test]
+    fn test_country() {
+        let geoip = GeoIp::from_bytes(database()).unwrap();
+        assert_eq!(geoip.database_type(), "Test-Country");
+        assert_eq!(geoip.country("10.1.2.3".parse().unwrap()).as_deref(), Some("NL"));
+        assert_eq!(geoip.country("::ffff:10.1.2.3".parse().unwrap()).as_deref(), Some("NL"));
+        assert_eq!(geoip.country("100.1.2.3".parse().unwrap()).as_deref(), Some("US"));
+        let us = geoip.lookup("100.1.2.3".parse().unwrap()).unwrap();
+        assert_eq!(us.get("registered_country"), GeoIp::from_bytes(database()).unwrap().lookup("10.0.0.1".parse().unwrap()).unwrap().get("country"));
+        assert_eq!(geoip.country("200.1.2.3".parse().unwrap()), None);
+        assert_eq!(geoip.country("2001:db8::1".parse().unwrap()), None);
+
+        assert_eq!(country_of(&geoip, "100.1.2.3:6881").as_deref(), Some("US"));
+        assert_eq!(country_of(&geoip, "garbage"), None);
+    }
+
+    #[test]
+    fn test_not_a_database() {
+        assert_eq!(GeoIp::from_bytes(b"hello".to_vec()).unwrap_err(), "it has no metadata");
+        let db = [METADATA_MARKER, &map(&[("record_size", uint16(24))])].concat();
+        assert_eq!(GeoIp::from_bytes(db).unwrap_err(), "its metadata has no node_count");
+    }
+}
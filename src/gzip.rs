@@ -0,0 +1,335 @@
+use crate::bundle::crc32;
+
+// gunzip, for blocklists that are served as .gz. only the reading side,
+// the gzip wrapper (rfc 1952) around deflate (rfc 1951), done by hand
+// like the crc-32 rather than pulling in a compression crate for it.
+
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+const DEFLATE: u8 = 8;
+
+// header flags
+const FHCRC: u8 = 2;
+const FEXTRA: u8 = 4;
+const FNAME: u8 = 8;
+const FCOMMENT: u8 = 16;
+
+// the lengths and distances the length and distance codes stand for,
+// before their extra bits are added
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+
+// the order the code length code lengths of a dynamic block come in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+// the data of every member of the file, one after the other. more than
+// limit bytes of it is an error, so a small file can't inflate into
+// all of our memory.
+pub fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let start = out.len();
+        pos = skip_header(data, pos)?;
+
+        let mut bits = Bits { data, pos, buffer: 0, count: 0 };
+        inflate(&mut bits, &mut out, limit)?;
+        // the trailer starts on the next whole byte
+        pos = bits.pos - bits.count as usize / 8;
+
+        let trailer = data.get(pos..pos + 8).ok_or("gzip data ends before its trailer")?;
+        let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+        let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+        if crc != crc32(&out[start..]) {
+            return Err("gzip data is damaged, its crc doesn't match".to_string());
+        }
+        if size != (out.len() - start) as u32 {
+            return Err("gzip data is damaged, its length doesn't match".to_string());
+        }
+        pos += 8;
+    }
+    Ok(out)
+}
+
+// returns where the deflate data starts
+fn skip_header(data: &[u8], pos: usize) -> Result<usize, String> {
+    let header = data.get(pos..pos + 10).ok_or("gzip header is cut short")?;
+    if !is_gzip(header) {
+        return Err("not gzip data".to_string());
+    }
+    if header[2] != DEFLATE {
+        return Err(format!("unknown gzip compression method {}", header[2]));
+    }
+    let flags = header[3];
+    let mut pos = pos + 10;
+
+    let short = || "gzip header is cut short".to_string();
+    if flags & FEXTRA != 0 {
+        let length = data.get(pos..pos + 2).ok_or_else(short)?;
+        pos += 2 + u16::from_le_bytes([length[0], length[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data.get(pos..).and_then(|rest| rest.iter().position(|&b| b == 0)).ok_or_else(short)?;
+            pos += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    if pos > data.len() {
+        return Err(short());
+    }
+    Ok(pos)
+}
+
+// deflate reads its bits from the low end of each byte up
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buffer: u64,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn take(&mut self, n: u32) -> Result<u32, String> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or("deflate data is cut short")?;
+            self.buffer |= (byte as u64) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = (self.buffer & ((1 << n) - 1)) as u32;
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    // skips to the start of the next byte
+    fn align(&mut self) {
+        let partial = self.count % 8;
+        self.buffer >>= partial;
+        self.count -= partial;
+    }
+}
+
+// a canonical huffman code, as the number of codes of each length and
+// the symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Huffman, String> {
+        let mut counts = [0u16; 16];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // more codes of a length than there is room for can't be decoded.
+        // too few is allowed, a block with one distance code has that.
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = left * 2 - count as i32;
+            if left < 0 {
+                return Err("deflate data has an impossible huffman code".to_string());
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for length in 1..15 {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16, String> {
+        // first is the first code of each length, index where its
+        // symbols start
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.take(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("deflate data has a code that isn't in its huffman table".to_string())
+    }
+}
+
+fn inflate(bits: &mut Bits, out: &mut Vec<u8>, limit: usize) -> Result<(), String> {
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => stored(bits, out, limit)?,
+            1 => {
+                let (literals, distances) = fixed_codes()?;
+                codes(bits, out, limit, &literals, &distances)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(bits)?;
+                codes(bits, out, limit, &literals, &distances)?;
+            }
+            _ => return Err("deflate data has a block of an unknown type".to_string()),
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+fn stored(bits: &mut Bits, out: &mut Vec<u8>, limit: usize) -> Result<(), String> {
+    bits.align();
+    let length = bits.take(16)?;
+    if bits.take(16)? != !length & 0xffff {
+        return Err("deflate data has a stored block with a damaged length".to_string());
+    }
+    if out.len() + length as usize > limit {
+        return Err(format!("gzip data inflates to more than {} bytes", limit));
+    }
+    for _ in 0..length {
+        out.push(bits.take(8)? as u8);
+    }
+    Ok(())
+}
+
+fn fixed_codes() -> Result<(Huffman, Huffman), String> {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman), String> {
+    let literals = bits.take(5)? as usize + 257;
+    let distances = bits.take(5)? as usize + 1;
+    let code_lengths = bits.take(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err("deflate data has too many codes in a block".to_string());
+    }
+
+    let mut lengths = [0u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[i] = bits.take(3)? as u8;
+    }
+    let length_code = Huffman::new(&lengths)?;
+
+    let mut lengths = vec![0u8; literals + distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = length_code.decode(bits)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *i.checked_sub(1).and_then(|p| lengths.get(p)).ok_or("deflate data repeats a code length before the first")?;
+                (previous, 3 + bits.take(2)? as usize)
+            }
+            17 => (0, 3 + bits.take(3)? as usize),
+            _ => (0, 11 + bits.take(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err("deflate data has more code lengths than codes".to_string());
+        }
+        lengths[i..i + repeat].fill(length);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err("deflate data has no end of block code".to_string());
+    }
+    Ok((Huffman::new(&lengths[..literals])?, Huffman::new(&lengths[literals..])?))
+}
+
+fn codes(bits: &mut Bits, out: &mut Vec<u8>, limit: usize, literals: &Huffman, distances: &Huffman) -> Result<(), String> {
+    loop {
+        let symbol = literals.decode(bits)? as usize;
+        if symbol == 256 {
+            return Ok(());
+        }
+        if out.len() >= limit {
+            return Err(format!("gzip data inflates to more than {} bytes", limit));
+        }
+        if symbol < 256 {
+            out.push(symbol as u8);
+            continue;
+        }
+
+        let symbol = symbol - 257;
+        if symbol >= LENGTH_BASE.len() {
+            return Err("deflate data has a length code that doesn't exist".to_string());
+        }
+        let length = LENGTH_BASE[symbol] as usize + bits.take(LENGTH_EXTRA[symbol] as u32)? as usize;
+        let symbol = distances.decode(bits)? as usize;
+        if symbol >= DIST_BASE.len() {
+            return Err("deflate data has a distance code that doesn't exist".to_string());
+        }
+        let distance = DIST_BASE[symbol] as usize + bits.take(DIST_EXTRA[symbol] as u32)? as usize;
+        if distance > out.len() {
+            return Err("deflate data refers back past its start".to_string());
+        }
+        // the copy can overlap what it is writing, so a byte at a time
+        let start = out.len() - distance;
+        for i in 0..length {
+            out.push(out[start + i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // made with python's gzip module: stored, fixed and dynamic huffman
+    // blocks, and two members in one file
+    const STORED: &str = "1f8b08000000000000ff010c00f3ff68656c6c6f20776f726c640a2d3b08af0c000000";
+    // with the file name, a.txt, in the header
+    const FIXED: &str = "1f8b08080000000002ff612e74787400cbc85428c9482d4a0500ec76a3e308000000";
+    const DYNAMIC: &str = "1f8b08000000000002ff5dd04b0ac2401084e1bda7c8056c328f9e87b771115ca960ee0fa6aa2242339b7ff13503b5bf9fdbf2b9\
+                           bf1edbb2ded26a7cd733b2fb65ff830490ac0b2002c800d95295600552408ae524c20aa4821c874384158883b815176105d2409ad5\
+                           2cc20aa48374ab538415c80019e64d8415c80499d68a082b0ec769f1c539ae322aed7becfa5b9809f505d18de560a6010000";
+
+    #[test]
+    fn test_decompress() {
+        assert_eq!(decompress(&hex::decode(STORED).unwrap(), 1 << 20).unwrap(), b"hello world\n");
+        assert_eq!(decompress(&hex::decode(FIXED).unwrap(), 1 << 20).unwrap(), b"hi there");
+
+        let text: String = (0..12).map(|i| format!("some range {}:10.{}.{}.0-10.{}.{}.255\n", i, i, i * 7 % 256, i, i * 7 % 256)).collect();
+        assert_eq!(decompress(&hex::decode(DYNAMIC).unwrap(), 1 << 20).unwrap(), text.as_bytes());
+
+        let both = [hex::decode(STORED).unwrap(), hex::decode(FIXED).unwrap()].concat();
+        assert_eq!(decompress(&both, 1 << 20).unwrap(), b"hello world\nhi there");
+    }
+
+    #[test]
+    fn test_damaged() {
+        let mut data = hex::decode(FIXED).unwrap();
+        assert!(decompress(&data[..data.len() - 4], 1 << 20).unwrap_err().contains("trailer"));
+        assert!(decompress(&data, 4).unwrap_err().contains("more than 4 bytes"));
+        assert_eq!(decompress(b"hello", 1 << 20).unwrap_err(), "gzip header is cut short");
+
+        let crc = data.len() - 8;
+        data[crc] ^= 1;
+        assert!(decompress(&data, 1 << 20).unwrap_err().contains("crc"));
+    }
+}
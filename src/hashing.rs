@@ -0,0 +1,178 @@
+use std::{io, sync::Arc};
+
+use bytes::Bytes;
+use sha1::{Digest, Sha1};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::filemap::Storage;
+
+// checking a piece against its hash happens off the async runtime. a
+// connection only hashes the odd block inline, the one that is next in
+// line when it arrives. whatever is left when the last block lands
+// (blocks that came early, and the final comparison) is packed into a
+// job and run on tokio's blocking pool, so a 16 MiB piece that arrived
+// out of order doesn't hold up every other peer's messages while it
+// is read back and hashed.
+//
+// writing a block that has arrived happens off the runtime as well, and
+// off the torrent's lock: PieceManager::block_received hands back a
+// BlockWrite, which the torrent's disk runs (see disk.rs), and
+// block_written takes what came of it.
+
+// a block of a piece the hash hasn't covered yet, already on disk
+#[derive(Debug)]
+pub struct PendingBlock {
+    // offset into the torrent
+    pub position: u64,
+    pub length: u64,
+}
+
+pub struct HashJob {
+    pub index: u32,
+    // the piece's hash so far
+    pub hasher: Sha1,
+    // in order, starting where the hasher stopped
+    pub blocks: Vec<PendingBlock>,
+    pub expected: [u8; 20],
+    pub storage: Arc<Storage>,
+}
+
+// the piece's hash, taken along by a block write when there is a block
+// for it. that is the block being written, when it is the one the hash
+// is waiting on, or else one that came early and is read back.
+pub struct PieceHash {
+    pub hasher: Sha1,
+    pub early: Option<PendingBlock>,
+}
+
+pub struct BlockWrite {
+    pub peer_id: String,
+    pub index: u32,
+    // of the block in its piece, and in the torrent
+    pub offset: u64,
+    pub position: u64,
+    pub data: Bytes,
+    pub hash: Option<PieceHash>,
+}
+
+pub struct BlockWritten {
+    pub peer_id: String,
+    pub index: u32,
+    pub offset: u64,
+    pub outcome: io::Result<()>,
+    // the hasher back, and how many more bytes it covers. nothing is
+    // hashed when the write failed.
+    pub hash: Option<(Sha1, io::Result<u64>)>,
+}
+
+impl BlockWrite {
+    pub fn run(self, storage: &Storage) -> BlockWritten {
+        let outcome = storage.write_at(self.position, &self.data);
+        let hash = self.hash.map(|PieceHash { mut hasher, early }| {
+            let covered = match early {
+                _ if outcome.is_err() => Ok(0),
+                None => {
+                    hasher.update(&self.data);
+                    Ok(self.data.len() as u64)
+                }
+                Some(block) => {
+                    let mut data = vec![0u8; block.length as usize];
+                    storage.read_at(block.position, &mut data).map(|()| {
+                        hasher.update(&data);
+                        block.length
+                    })
+                }
+            };
+            (hasher, covered)
+        });
+        BlockWritten { peer_id: self.peer_id, index: self.index, offset: self.offset, outcome, hash }
+    }
+}
+
+#[derive(Debug)]
+pub struct HashResult {
+    pub index: u32,
+    // Ok(false) means the data was corrupt
+    pub outcome: io::Result<bool>,
+}
+
+impl HashJob {
+    pub fn run(self) -> HashResult {
+        let index = self.index;
+        HashResult { index, outcome: self.finish() }
+    }
+
+    fn finish(mut self) -> io::Result<bool> {
+        for block in &self.blocks {
+            let mut data = vec![0u8; block.length as usize];
+            self.storage.read_at(block.position, &mut data)?;
+            self.hasher.update(&data);
+        }
+
+        Ok(self.hasher.finalize()[..] == self.expected)
+    }
+}
+
+// hands jobs to the blocking pool. results come back in the order the
+// jobs finish, through the receiver made alongside it.
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    results: UnboundedSender<HashResult>,
+}
+
+impl Verifier {
+    pub fn new() -> (Verifier, UnboundedReceiver<HashResult>) {
+        let (results, receiver) = mpsc::unbounded_channel();
+        (Verifier { results }, receiver)
+    }
+
+    pub fn submit(&self, job: HashJob) {
+        let results = self.results.clone();
+        tokio::task::spawn_blocking(move || {
+            // nobody is left to care once the torrent has been stopped
+            let _ = results.send(job.run());
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Torrent;
+
+    #[tokio::test]
+    async fn test_jobs_run_off_the_runtime() {
+        let torrent = Torrent {
+            piece_length: 8,
+            total_size: 8,
+            output_file: std::env::temp_dir().join("bt-c-hashing").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let storage = Arc::new(Storage::open(&torrent).unwrap());
+        storage.write_at(0, b"12345678").unwrap();
+
+        let job = |expected: &[u8]| {
+            let mut hasher = Sha1::new();
+            hasher.update(b"12");
+            HashJob {
+                index: 0,
+                hasher,
+                blocks: vec![
+                    PendingBlock { position: 2, length: 2 },
+                    PendingBlock { position: 4, length: 4 },
+                ],
+                expected: Sha1::digest(expected).into(),
+                storage: storage.clone(),
+            }
+        };
+
+        let (verifier, mut results) = Verifier::new();
+        verifier.submit(job(b"12345678"));
+        let result = results.recv().await.unwrap();
+        assert_eq!(result.index, 0);
+        assert!(result.outcome.unwrap());
+
+        verifier.submit(job(b"something else"));
+        assert!(!results.recv().await.unwrap().outcome.unwrap());
+    }
+}
@@ -0,0 +1,116 @@
+use log::{info, warn};
+use tokio::process::Command;
+
+use crate::torrent::Torrent;
+
+// user commands run when something happens to a torrent, for unpacking
+// a finished download, telling a media library to rescan and the like.
+// each one goes to the shell with the torrent in its environment:
+//
+//   BT_EVENT         added, completed or error
+//   BT_TORRENT_NAME  the name the torrent gives its data
+//   BT_TORRENT_PATH  where its data is
+//   BT_INFO_HASH     in hex
+//   BT_ERROR         what went wrong, for error only
+//
+// they run in the background and nothing waits on them, a slow script
+// holds up nothing but itself.
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hooks {
+    pub on_add: Option<String>,
+    pub on_complete: Option<String>,
+    // the first time a piece can't be written to disk. a full disk fails
+    // every piece after it, once is enough to hear about it.
+    pub on_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookEvent {
+    Added,
+    Completed,
+    Error,
+}
+
+impl HookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookEvent::Added => "added",
+            HookEvent::Completed => "completed",
+            HookEvent::Error => "error",
+        }
+    }
+}
+
+impl Hooks {
+    pub fn command_for(&self, event: HookEvent) -> Option<&str> {
+        match event {
+            HookEvent::Added => self.on_add.as_deref(),
+            HookEvent::Completed => self.on_complete.as_deref(),
+            HookEvent::Error => self.on_error.as_deref(),
+        }
+    }
+
+    // does nothing if there is no command for the event
+    pub fn run(&self, event: HookEvent, torrent: &Torrent, error: Option<&str>) {
+        let Some(script) = self.command_for(event) else { return };
+        let mut command = command(script, event, torrent, error);
+        let script = script.to_string();
+        tokio::spawn(async move {
+            match command.status().await {
+                Ok(status) if status.success() => info!("ran the {} hook: {}", event.name(), script),
+                Ok(status) => warn!("the {} hook {} exited with {}", event.name(), script, status),
+                Err(e) => warn!("couldn't run the {} hook {}: {}", event.name(), script, e),
+            }
+        });
+    }
+}
+
+pub fn command(script: &str, event: HookEvent, torrent: &Torrent, error: Option<&str>) -> Command {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C");
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c");
+        command
+    };
+    command
+        .arg(script)
+        .env("BT_EVENT", event.name())
+        .env("BT_TORRENT_NAME", torrent.name())
+        .env("BT_TORRENT_PATH", &torrent.output_file)
+        .env("BT_INFO_HASH", torrent.info_hash.to_string());
+    if let Some(error) = error {
+        command.env("BT_ERROR", error);
+    }
+    command
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::info_hash::InfoHash;
+
+    #[tokio::test]
+    async fn test_environment() {
+        let torrent = Torrent {
+            info_hash: InfoHash::new([0xab; 20]),
+            output_file: "/data/ubuntu.iso".to_string(),
+            ..Default::default()
+        };
+        let script = r#"echo "$BT_EVENT|$BT_TORRENT_NAME|$BT_TORRENT_PATH|$BT_INFO_HASH|$BT_ERROR""#;
+
+        let output = command(script, HookEvent::Completed, &torrent, None).output().await.unwrap();
+        let hash = "ab".repeat(20);
+        assert_eq!(String::from_utf8_lossy(&output.stdout), format!("completed|ubuntu.iso|/data/ubuntu.iso|{}|\n", hash));
+
+        let output = command(script, HookEvent::Error, &torrent, Some("disk full")).output().await.unwrap();
+        assert!(String::from_utf8_lossy(&output.stdout).ends_with("|disk full\n"));
+
+        let hooks = Hooks { on_complete: Some("true".to_string()), ..Default::default() };
+        assert_eq!(hooks.command_for(HookEvent::Completed), Some("true"));
+        assert_eq!(hooks.command_for(HookEvent::Added), None);
+    }
+}
@@ -0,0 +1,93 @@
+use std::fmt;
+
+// the sha1 of a torrent's info dict, which is what identifies it to
+// trackers and peers. always 20 bytes, checked when one is made from
+// anything that isn't already an array.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct InfoHash([u8; 20]);
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+impl InfoHash {
+    pub fn new(bytes: [u8; 20]) -> InfoHash {
+        InfoHash(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<InfoHash, String> {
+        let bytes = bytes
+            .try_into()
+            .map_err(|_| format!("info hash must be 20 bytes, not {}", bytes.len()))?;
+        Ok(InfoHash(bytes))
+    }
+
+    pub fn from_hex(s: &str) -> Result<InfoHash, String> {
+        let bytes = hex::decode(s).map_err(|e| format!("invalid info hash {:?}: {}", s, e))?;
+        InfoHash::from_bytes(&bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    pub fn to_hex(self) -> String {
+        hex::encode(self.0)
+    }
+
+    // rfc 4648 base32 without padding, the older way of writing the hash
+    // in magnet links. 160 bits is exactly 32 characters.
+    pub fn to_base32(self) -> String {
+        let mut out = String::with_capacity(32);
+        for chunk in self.0.chunks(5) {
+            let bits = chunk.iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+            for i in (0..8).rev() {
+                out.push(BASE32_ALPHABET[(bits >> (i * 5) & 31) as usize] as char);
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl AsRef<[u8]> for InfoHash {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<[u8; 20]> for InfoHash {
+    fn from(bytes: [u8; 20]) -> InfoHash {
+        InfoHash(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_construction() {
+        assert!(InfoHash::from_bytes(&[0; 19]).is_err());
+        assert!(InfoHash::from_bytes(&[0; 21]).is_err());
+        assert_eq!(InfoHash::from_bytes(&[7; 20]).unwrap(), InfoHash::new([7; 20]));
+
+        let hash = InfoHash::from_hex("c12fe1c06bba254a9dc9f519b335aa7c1367a88a").unwrap();
+        assert_eq!(hash.as_bytes()[0], 0xc1);
+        assert!(InfoHash::from_hex("c12f").is_err());
+        assert!(InfoHash::from_hex("not hex").is_err());
+    }
+
+    #[test]
+    fn test_rendering() {
+        let hash = InfoHash::from_hex("c12fe1c06bba254a9dc9f519b335aa7c1367a88a").unwrap();
+        assert_eq!(hash.to_string(), "c12fe1c06bba254a9dc9f519b335aa7c1367a88a");
+        assert_eq!(hash.to_base32(), "YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK");
+
+        assert_eq!(InfoHash::new([0; 20]).to_base32(), "A".repeat(32));
+        assert_eq!(InfoHash::new([0xFF; 20]).to_base32(), "7".repeat(32));
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
+
+use tokio::sync::watch;
+
+// requesting from every peer in a big swarm spreads our requests over
+// too many pieces at once, so only a limited number of peers get our
+// interest at any time. the rest wait in line and the slowest interested
+// peer is periodically swapped out for the next one waiting.
+//
+// connections hold a receiver from subscribe() and wake up whenever
+// slots move around, rather than polling to see if they've been given one.
+
+struct Slot {
+    granted: Instant,
+}
+
+pub struct InterestManager {
+    max_interested: usize,
+    interested: HashMap<String, Slot>,
+    waiting: VecDeque<String>,
+    last_rotation: Instant,
+    changes: watch::Sender<u64>,
+}
+
+impl InterestManager {
+    pub fn new(max_interested: usize) -> InterestManager {
+        InterestManager {
+            max_interested,
+            interested: HashMap::new(),
+            waiting: VecDeque::new(),
+            last_rotation: Instant::now(),
+            changes: watch::Sender::new(0),
+        }
+    }
+
+    // fires whenever a slot changes hands or something else happens that
+    // could change which peers we want, e.g. a piece being completed
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    pub fn notify_changed(&self) {
+        self.changes.send_modify(|generation| *generation += 1);
+    }
+
+    // returns true if the peer holds (or was just given) a slot.
+    // otherwise the peer is queued and should ask again later.
+    pub fn request_slot(&mut self, peer_id: &str) -> bool {
+        if self.interested.contains_key(peer_id) {
+            return true;
+        }
+
+        if self.interested.len() < self.max_interested && self.waiting.front().is_none_or(|p| p == peer_id) {
+            self.waiting.retain(|p| p != peer_id);
+            self.grant(peer_id.to_string());
+            return true;
+        }
+
+        if !self.waiting.iter().any(|p| p == peer_id) {
+            self.waiting.push_back(peer_id.to_string());
+        }
+        false
+    }
+
+    // the peer disconnected or no longer has anything we want
+    pub fn release(&mut self, peer_id: &str) {
+        self.waiting.retain(|p| p != peer_id);
+
+        if self.interested.remove(peer_id).is_some() {
+            if let Some(next) = self.waiting.pop_front() {
+                self.grant(next);
+                self.notify_changed();
+            }
+        }
+    }
+
+    pub fn is_interested(&self, peer_id: &str) -> bool {
+        self.interested.contains_key(peer_id)
+    }
+
+    pub fn interested_count(&self) -> usize {
+        self.interested.len()
+    }
+
+    // swaps the slowest interested peer for the first waiting one,
+    // going by the smoothed download rates from the torrent's stats.
+    // peers granted since the last rotation haven't had a fair chance
+    // to prove themselves yet, so they are never picked.
+    // returns the peer that lost its slot, if any.
+    pub fn rotate(&mut self, now: Instant, rates: &HashMap<String, f64>) -> Option<String> {
+        let since = self.last_rotation;
+        self.last_rotation = now;
+
+        let evicted = if !self.waiting.is_empty() && self.interested.len() >= self.max_interested {
+            self.interested
+                .iter()
+                .filter(|(_, slot)| slot.granted < since)
+                .map(|(peer_id, _)| (peer_id, rates.get(peer_id).copied().unwrap_or(0.0)))
+                .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map(|(peer_id, _)| peer_id.clone())
+        } else {
+            None
+        };
+
+        let evicted = evicted?;
+        self.interested.remove(&evicted);
+        if let Some(next) = self.waiting.pop_front() {
+            self.grant(next);
+        }
+        self.waiting.push_back(evicted.clone());
+        self.notify_changed();
+
+        Some(evicted)
+    }
+
+    fn grant(&mut self, peer_id: String) {
+        self.interested.insert(peer_id, Slot { granted: Instant::now() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_cap_is_enforced() {
+        let mut im = InterestManager::new(2);
+        assert!(im.request_slot("a"));
+        assert!(im.request_slot("b"));
+        assert!(!im.request_slot("c"));
+        assert!(im.request_slot("a"));
+        assert_eq!(im.interested_count(), 2);
+    }
+
+    #[test]
+    fn test_release_grants_next_waiting() {
+        let mut im = InterestManager::new(1);
+        assert!(im.request_slot("a"));
+        assert!(!im.request_slot("b"));
+        assert!(!im.request_slot("c"));
+
+        im.release("a");
+        assert!(im.is_interested("b"));
+        assert!(!im.request_slot("c"));
+    }
+
+    #[test]
+    fn test_waiting_peer_cant_jump_the_queue() {
+        let mut im = InterestManager::new(1);
+        assert!(im.request_slot("a"));
+        assert!(!im.request_slot("b"));
+
+        im.release("b");
+        im.release("a");
+        assert!(im.request_slot("c"));
+    }
+
+    #[test]
+    fn test_rotate_evicts_slowest() {
+        let mut im = InterestManager::new(2);
+        im.request_slot("fast");
+        im.request_slot("slow");
+        im.request_slot("waiting");
+
+        let now = Instant::now() + Duration::from_secs(30);
+        im.rotate(now, &HashMap::new());
+
+        let rates = HashMap::from([("fast".to_string(), 1000.0), ("slow".to_string(), 10.0)]);
+        let evicted = im.rotate(now + Duration::from_secs(30), &rates);
+        assert_eq!(evicted.as_deref(), Some("slow"));
+        assert!(im.is_interested("fast"));
+        assert!(im.is_interested("waiting"));
+        assert!(!im.request_slot("slow"));
+    }
+
+    #[test]
+    fn test_rotate_spares_new_peers() {
+        let mut im = InterestManager::new(1);
+        im.request_slot("a");
+        im.request_slot("b");
+
+        // "a" got its slot after the last rotation point so it is exempt
+        assert_eq!(im.rotate(Instant::now(), &HashMap::new()), None);
+    }
+
+    #[test]
+    fn test_no_rotation_without_waiters() {
+        let mut im = InterestManager::new(2);
+        im.request_slot("a");
+        im.request_slot("b");
+        assert_eq!(im.rotate(Instant::now() + Duration::from_secs(30), &HashMap::new()), None);
+    }
+
+    #[test]
+    fn test_slot_changes_are_announced() {
+        let mut im = InterestManager::new(1);
+        let mut changes = im.subscribe();
+        im.request_slot("a");
+        im.request_slot("b");
+        assert!(!changes.has_changed().unwrap());
+
+        im.release("a");
+        assert!(changes.has_changed().unwrap());
+        changes.mark_unchanged();
+
+        // nobody was waiting, so nothing moved
+        im.release("b");
+        assert!(!changes.has_changed().unwrap());
+    }
+}
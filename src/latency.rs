@@ -0,0 +1,109 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+// peers fall into two tiers by how long their blocks take to arrive.
+// pieces something is waiting on (the last few pieces of the endgame,
+// pieces marked time critical) go to the quickest peers, which can turn
+// them around fastest. everything else goes to whoever has the
+// bandwidth, where a slow round trip doesn't matter as long as the
+// pipeline is deep enough.
+
+// weight given to each new sample, so a couple of slow blocks don't
+// knock a peer out of the fast tier
+const SMOOTHING: f64 = 0.25;
+
+// with only a few peers to go on there is no point in holding pieces
+// back for some of them, so everyone counts as fast
+const MIN_TIERED_PEERS: usize = 4;
+
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    // smoothed time between asking for a block and getting it
+    peers: HashMap<String, Duration>,
+}
+
+impl LatencyTracker {
+    pub fn new() -> LatencyTracker {
+        LatencyTracker::default()
+    }
+
+    pub fn record(&mut self, peer_id: &str, sample: Duration) {
+        let latency = match self.peers.get(peer_id) {
+            Some(&old) => old.mul_f64(1.0 - SMOOTHING) + sample.mul_f64(SMOOTHING),
+            None => sample,
+        };
+        self.peers.insert(peer_id.to_string(), latency);
+    }
+
+    pub fn latency(&self, peer_id: &str) -> Option<Duration> {
+        self.peers.get(peer_id).copied()
+    }
+
+    pub fn remove_peer(&mut self, peer_id: &str) {
+        self.peers.remove(peer_id);
+    }
+
+    // the quarter of measured peers with the lowest latency, or None
+    // when there are too few measured peers to bother telling apart
+    pub fn fast_peers(&self) -> Option<HashSet<String>> {
+        if self.peers.len() < MIN_TIERED_PEERS {
+            return None;
+        }
+
+        let mut peers: Vec<_> = self.peers.iter().collect();
+        peers.sort_by_key(|&(id, &latency)| (latency, id));
+        let count = peers.len().div_ceil(4);
+        Some(peers.into_iter().take(count).map(|(id, _)| id.clone()).collect())
+    }
+
+    pub fn is_fast(&self, peer_id: &str) -> bool {
+        self.fast_peers().is_none_or(|fast| fast.contains(peer_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let mut tracker = LatencyTracker::new();
+        tracker.record("a", ms(100));
+        assert_eq!(tracker.latency("a"), Some(ms(100)));
+
+        tracker.record("a", ms(500));
+        assert_eq!(tracker.latency("a"), Some(ms(200)));
+
+        tracker.remove_peer("a");
+        assert_eq!(tracker.latency("a"), None);
+    }
+
+    #[test]
+    fn test_tiers() {
+        let mut tracker = LatencyTracker::new();
+        for (peer, latency) in [("a", 400), ("b", 50), ("c", 900)] {
+            tracker.record(peer, ms(latency));
+        }
+
+        // too few peers, so nobody is held back
+        assert_eq!(tracker.fast_peers(), None);
+        assert!(tracker.is_fast("c"));
+
+        for (peer, latency) in [("d", 30), ("e", 600)] {
+            tracker.record(peer, ms(latency));
+        }
+
+        // five peers make a fast tier of two
+        assert_eq!(tracker.fast_peers(), Some(HashSet::from(["b".to_string(), "d".to_string()])));
+        assert!(tracker.is_fast("d"));
+        assert!(!tracker.is_fast("a"));
+        // peers we haven't measured yet start out in the bulk tier
+        assert!(!tracker.is_fast("new"));
+    }
+}
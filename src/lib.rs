@@ -0,0 +1,79 @@
+//! the torrent engine behind bt-c, for embedding in other programs. the
+//! bt-c binary is a thin command line over it and a good example of
+//! driving it.
+//!
+//! - [`Session`] is the way in: it owns every torrent, the settings that
+//!   apply across them and the listener peers dial. build one with
+//!   [`Session::builder`] and add torrents to it.
+//! - [`Torrent`] is parsed metainfo, see [`build_torrent`] and [`bencoding`].
+//! - [`TorrentClient`] runs one torrent: its peers, announces and seeding.
+//! - [`PieceManager`] is a torrent's view of its pieces, shared by all of
+//!   its peer connections.
+//! - [`Tracker`] announces to a torrent's http trackers, tier by tier.
+//! - [`Progress`] and [`ProgressReporter`] are how a torrent tells the
+//!   outside world how it is doing, without flooding it.
+//! - [`BtError`] is what the engine fails with.
+//!
+//! the modules not listed are public so the pieces can be used on their
+//! own, but they change more freely.
+
+pub mod bencoding;
+pub mod error;
+pub mod tracker;
+pub mod torrent;
+pub mod protocol;
+pub mod client;
+pub mod interest;
+pub mod session;
+pub mod rpc;
+pub mod transport;
+pub mod verify;
+pub mod peer_id;
+pub mod warnings;
+pub mod banlist;
+pub mod dedup;
+pub mod stats;
+pub mod filemap;
+pub mod piece_map;
+pub mod pex;
+pub mod pipeline;
+pub mod superseed;
+pub mod bundle;
+pub mod create;
+pub mod latency;
+pub mod info_hash;
+pub mod progress;
+pub mod choker;
+pub mod sources;
+pub mod dht;
+pub mod codec;
+pub mod peer_state;
+pub mod picker;
+pub mod hashing;
+pub mod disk;
+pub mod seeding;
+pub mod schedule;
+pub mod listener;
+pub mod announcer;
+pub mod peer_pool;
+pub mod churn;
+pub mod external_ip;
+pub mod hooks;
+pub mod notify;
+pub mod disk_space;
+pub mod fastresume;
+pub mod gzip;
+pub mod blocklist;
+pub mod geoip;
+pub mod shaper;
+pub mod test_vectors;
+
+pub use {
+    client::{ClientConfig, PieceManager, RequestTuning, TorrentClient},
+    error::BtError,
+    info_hash::InfoHash,
+    progress::{Progress, ProgressReporter},
+    session::{Session, SessionBuilder, TorrentId, TorrentState, TorrentStatus},
+    torrent::{build_torrent, parse_torrent, Torrent},
+    tracker::{AnnounceEvent, Tracker},
+};
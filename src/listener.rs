@@ -0,0 +1,234 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpListener,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    banlist::BanList,
+    info_hash::InfoHash,
+    pex::{display_addr, Addr},
+    protocol::{Handshake, HANDSHAKE_LENGTH},
+    transport::{BindTo, BoxedStream},
+};
+
+// peers dialing us. there is a listener for each address family on the
+// listen port, and the ipv6 one only takes ipv6 so the two can share the
+// port (on linux it would otherwise take ipv4 as well, mapped, and the
+// ipv4 bind would fail). the listener reads the peer's handshake to see
+// which torrent it is after, then leaves the connection for one of that
+// torrent's peer connections to answer.
+
+// connections left waiting for a torrent. past this the torrent is
+// turned away, it already has more peers than it can get to.
+const MAX_WAITING: usize = 16;
+
+// a backlog of connections the os holds for us between accepts
+const BACKLOG: i32 = 128;
+
+pub struct Incoming {
+    pub stream: BoxedStream,
+    pub addr: Addr,
+    // the peer's, already read off the stream
+    pub handshake: Handshake,
+    pub arrived: Instant,
+}
+
+pub type IncomingQueue = Arc<Mutex<VecDeque<Incoming>>>;
+
+// which torrent incoming connections go to, by the info hash they ask for
+#[derive(Clone, Default)]
+pub struct Routes {
+    torrents: Arc<Mutex<HashMap<InfoHash, IncomingQueue>>>,
+}
+
+impl Routes {
+    pub fn new() -> Routes {
+        Routes::default()
+    }
+
+    pub fn add(&self, info_hash: InfoHash, queue: IncomingQueue) {
+        self.torrents.lock().unwrap().insert(info_hash, queue);
+    }
+
+    pub fn remove(&self, info_hash: &InfoHash) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    // hands the connection back if nobody wants it
+    fn deliver(&self, incoming: Incoming) -> Result<(), Incoming> {
+        let torrents = self.torrents.lock().unwrap();
+        let Some(queue) = torrents.get(incoming.handshake.info_hash()) else { return Err(incoming) };
+        let mut queue = queue.lock().unwrap();
+        if queue.len() >= MAX_WAITING {
+            return Err(incoming);
+        }
+        queue.push_back(incoming);
+        Ok(())
+    }
+}
+
+// binds the port on every address family the machine has. a machine
+// without ipv6 makes do with ipv4, only failing both is an error. bound
+// to an address, there is just the one listener on it.
+pub fn bind(port: u16, to: Option<&BindTo>) -> io::Result<Vec<TcpListener>> {
+    if let Some(BindTo::Address(ip)) = to {
+        return Ok(vec![bind_addr(SocketAddr::new(*ip, port))?]);
+    }
+    let v4 = bind_to(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), to);
+    let v6 = bind_to(SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)), to);
+
+    match (v4, v6) {
+        (Ok(v4), Ok(v6)) => Ok(vec![v4, v6]),
+        (Ok(v4), Err(e)) => {
+            warn!("listening on ipv4 only, couldn't bind ipv6 port {}: {}", port, e);
+            Ok(vec![v4])
+        }
+        (Err(e), Ok(v6)) => {
+            warn!("listening on ipv6 only, couldn't bind ipv4 port {}: {}", port, e);
+            Ok(vec![v6])
+        }
+        (Err(e), Err(_)) => Err(e),
+    }
+}
+
+pub fn bind_addr(addr: SocketAddr) -> io::Result<TcpListener> {
+    bind_to(addr, None)
+}
+
+fn bind_to(addr: SocketAddr, to: Option<&BindTo>) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    match to {
+        Some(to) => to.apply(&socket, addr)?,
+        None => socket.bind(&addr.into())?,
+    }
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+// accepts connections until the task is dropped
+pub async fn serve(listener: TcpListener, routes: Routes, bans: Arc<Mutex<BanList>>, handshake_timeout: Duration) {
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // usually out of file descriptors, which takes a moment
+                // to get better
+                warn!("couldn't accept a peer: {}", e);
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+
+        let addr = (remote.ip().to_string(), remote.port());
+        if bans.lock().unwrap().is_banned(&addr.0) {
+            continue;
+        }
+        let _ = stream.set_nodelay(true);
+
+        let routes = routes.clone();
+        tokio::spawn(async move {
+            match timeout(handshake_timeout, read_handshake(Box::new(stream), addr.clone())).await {
+                Ok(Ok(incoming)) => {
+                    if let Err(incoming) = routes.deliver(incoming) {
+                        info!("turned away {}, not taking peers for {}", display_addr(&addr.0, addr.1), incoming.handshake.info_hash());
+                    }
+                }
+                Ok(Err(e)) => info!("bad handshake from {}: {}", display_addr(&addr.0, addr.1), e),
+                Err(_) => info!("{} connected but never sent a handshake", display_addr(&addr.0, addr.1)),
+            }
+        });
+    }
+}
+
+async fn read_handshake(mut stream: BoxedStream, addr: Addr) -> io::Result<Incoming> {
+    let mut data = [0u8; HANDSHAKE_LENGTH];
+    stream.read_exact(&mut data).await?;
+    let handshake = Handshake::decode(&data).map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    Ok(Incoming { stream, addr, handshake, arrived: Instant::now() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+    fn handshake(first: u8) -> Handshake {
+        Handshake::new(InfoHash::new([first; 20]), vec![b'-'; 20]).unwrap()
+    }
+
+    async fn wait_for(queue: &IncomingQueue) -> Incoming {
+        for _ in 0..100 {
+            if let Some(incoming) = queue.lock().unwrap().pop_front() {
+                return incoming;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        panic!("nothing arrived");
+    }
+
+    async fn listen(addr: SocketAddr, routes: &Routes) -> SocketAddr {
+        let listener = bind_addr(addr).unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, routes.clone(), Arc::new(Mutex::new(BanList::new())), Duration::from_secs(5)));
+        local
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_info_hash() {
+        let routes = Routes::new();
+        let queue = IncomingQueue::default();
+        routes.add(InfoHash::new([1; 20]), queue.clone());
+        let local = listen("127.0.0.1:0".parse().unwrap(), &routes).await;
+
+        let mut peer = TcpStream::connect(local).await.unwrap();
+        peer.write_all(&handshake(1).encode()).await.unwrap();
+        let incoming = wait_for(&queue).await;
+        assert_eq!(incoming.addr.0, "127.0.0.1");
+        assert_eq!(*incoming.handshake.info_hash(), InfoHash::new([1; 20]));
+
+        // a torrent we don't have gets hung up on
+        let mut other = TcpStream::connect(local).await.unwrap();
+        other.write_all(&handshake(2).encode()).await.unwrap();
+        let mut buf = [0u8; 1];
+        assert_eq!(other.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_bind_to_address() {
+        let to = BindTo::Address("127.0.0.1".parse().unwrap());
+        let listeners = bind(0, Some(&to)).unwrap();
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].local_addr().unwrap().ip().to_string(), "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn test_ipv6() {
+        let routes = Routes::new();
+        let queue = IncomingQueue::default();
+        routes.add(InfoHash::new([1; 20]), queue.clone());
+        // not every machine that runs the tests has ipv6
+        let Ok(listener) = bind_addr("[::1]:0".parse().unwrap()) else { return };
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, routes.clone(), Arc::new(Mutex::new(BanList::new())), Duration::from_secs(5)));
+
+        let mut peer = TcpStream::connect(local).await.unwrap();
+        peer.write_all(&handshake(1).encode()).await.unwrap();
+        assert_eq!(wait_for(&queue).await.addr.0, "::1");
+    }
+}
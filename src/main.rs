@@ -1,39 +1,547 @@
-mod bencoding;
-mod tracker;
-mod torrent;
-mod protocol;
-mod client;
+// the command line over the bt_c library, which does the actual work
+
+mod cli;
 
 use {
-    bencoding::decoder,
-    client::PieceManager,
-    std::{error, fs, sync::Arc},
-    torrent::{build_torrent, Torrent},
-    tracker::Tracker,
+    bt_c::{bundle, create, dht, parse_torrent, rpc, session, verify, ClientConfig, Session},
+    cli::{AddArgs, Command, CreateArgs, ExportArgs, ImportArgs, InfoArgs, LimitPeerArgs, PauseArgs, PeersArgs, ResumeArgs, SpeedArgs, TrackersArgs},
+    serde_json::{json, Value},
+    std::{env, error::Error, fs, process, sync::Arc, time::Duration},
+    tokio::sync::Mutex,
 };
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn error::Error>> {
-    let file_data_result = fs::read("debian-12.11.0-amd64-netinst.iso.torrent").expect("couldn't read data");
+async fn main() -> Result<(), Box<dyn Error>> {
+    let command = match cli::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
 
-    let file_data = match decoder::decode(&file_data_result) {
-        Ok((bencode, _)) => bencode,
-        Err(e) => panic!("error: {}", e),
+    // the session downloads in the background while the control api
+    // lets external tools drive it
+    let session = match command {
+        Command::Add(args) => {
+            let config = if args.low_memory { ClientConfig::low_memory() } else { ClientConfig::default() };
+            let config = ClientConfig {
+                super_seeding: args.super_seed,
+                seed_limits: args.seed_limits,
+                hooks: args.hooks.clone(),
+                webhook: args.webhook.clone(),
+                part_files: args.part_files,
+                completed_dir: args.completed_dir.clone(),
+                requests: args.requests,
+                ..config
+            };
+            let mut builder = Session::builder()
+                .download_dir(&args.dir)
+                .dht_state_file(args.dir.join(dht::STATE_FILE))
+                .resume_dir(args.dir.join(session::RESUME_DIR))
+                .client_config(config);
+            if let Some(url) = &args.blocklist {
+                builder = builder.blocklist(url);
+            }
+            if let Some(path) = &args.geoip {
+                builder = builder.geoip(path);
+            }
+            if let Some(url) = &args.proxy {
+                builder = builder.proxy(url).strict_proxy(args.strict_proxy);
+            }
+            if let Some(bind) = &args.bind {
+                builder = builder.bind(bind);
+            }
+            let session = builder.build()?;
+            let session = Mutex::new(session);
+            add(&session, *args).await?;
+            Arc::new(session)
+        }
+        Command::Export(args) => return export(args).await,
+        Command::Pause(args) => return pause(args).await,
+        Command::Resume(args) => return resume(args).await,
+        Command::Trackers(args) => return trackers(args).await,
+        Command::Peers(args) => return peers(args).await,
+        Command::Speed(args) => return speed(args).await,
+        Command::LimitPeer(args) => return limit_peer(args).await,
+        Command::Create(args) => return create(args),
+        Command::Info(args) => return info(args).await,
+        Command::Import(args) => {
+            let session = Session::builder()
+                .download_dir(&args.dir)
+                .dht_state_file(args.dir.join(dht::STATE_FILE))
+                .resume_dir(args.dir.join(session::RESUME_DIR))
+                .build()?;
+            let session = Mutex::new(session);
+            import(&session, args).await?;
+            Arc::new(session)
+        }
     };
 
-    let torrent_result = build_torrent(&file_data);
-    let torrent = match torrent_result {
-        Ok(t) => t,
-        Err(e) => panic!("couldn't create torrent from bencode: {}", e),
+    // without a listener we only ever get the peers we dial ourselves,
+    // which is worth a warning but no reason to stop
+    if let Err(e) = session.lock().await.listen() {
+        eprintln!("not accepting incoming peers: {}", e);
+    }
+
+    // downloads that finish hand their slot to the next torrent in
+    // line, and the scheduled rate limits come and go
+    let housekeeping = session.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(session::TICK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            housekeeping.lock().await.tick(std::time::SystemTime::now());
+        }
+    });
+
+    // runs until ctrl-c or a kill, then saves state for the next run
+    tokio::select! {
+        result = rpc::serve(session.clone(), rpc::DEFAULT_RPC_ADDR) => result?,
+        result = shutdown_signal() => {
+            result?;
+            session.lock().await.shutdown().await?;
+        }
+    }
+
+    Ok(())
+}
+
+// ctrl-c, or sigterm from a service manager
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+async fn add(session: &Mutex<Session>, args: AddArgs) -> Result<(), Box<dyn Error>> {
+    let torrent = parse_torrent(&fs::read(&args.torrent)?)?;
+
+    if args.adopt {
+        let pieces = torrent.pieces.len();
+        let (_, kept) = session.lock().await.adopt_torrent(torrent).await?;
+        println!("{} of {} pieces were already downloaded, getting the rest", kept, pieces);
+        return Ok(());
+    }
+
+    if !args.assume_complete {
+        session.lock().await.add_torrent(torrent).await?;
+        return Ok(());
+    }
+
+    // "i already have the data": make sure the files are where we expect
+    // and spot check some pieces instead of hashing everything
+    let files = verify::map_existing_files(&torrent, &args.dir)?;
+    let num_pieces = torrent.pieces.len();
+    let pieces = match args.sample {
+        Some(n) => verify::sample_pieces(num_pieces, n),
+        None => (0..num_pieces).collect(),
     };
 
-    let torrent = Arc::new(torrent);
+    let failed = verify::verify_pieces(&torrent, &files, &pieces)?;
+    if let Some(first) = failed.first() {
+        return Err(format!(
+            "{} of {} checked pieces don't match (first bad piece: {}), add without --assume-complete to download it",
+            failed.len(),
+            pieces.len(),
+            first
+        ).into());
+    }
+
+    println!("{} pieces checked, seeding {}", pieces.len(), args.dir.join(&torrent.output_file).display());
+    session.lock().await.add_complete_torrent(torrent).await?;
+
+    Ok(())
+}
+
+// asks the client that is already running to write out the bundle
+async fn export(args: ExportArgs) -> Result<(), Box<dyn Error>> {
+    // the server resolves relative paths against its own directory
+    let path = env::current_dir()?.join(&args.bundle);
+    rpc::call(rpc::DEFAULT_RPC_ADDR, "torrent-export", json!({ "id": args.id, "path": path })).await?;
+
+    println!("exported torrent {} to {}", args.id, path.display());
+    Ok(())
+}
+
+// pause and resume act on the client that is already running
+async fn pause(args: PauseArgs) -> Result<(), Box<dyn Error>> {
+    let params = json!({ "id": args.id, "announce_stopped": args.announce_stopped });
+    rpc::call(rpc::DEFAULT_RPC_ADDR, "torrent-pause", params).await?;
+
+    println!("paused torrent {}", args.id);
+    Ok(())
+}
+
+async fn resume(args: ResumeArgs) -> Result<(), Box<dyn Error>> {
+    rpc::call(rpc::DEFAULT_RPC_ADDR, "torrent-resume", json!({ "id": args.id })).await?;
+
+    println!("resumed torrent {}", args.id);
+    Ok(())
+}
+
+// what the running client has heard from each tracker, to tell which of
+// the announce-list actually works
+async fn trackers(args: TrackersArgs) -> Result<(), Box<dyn Error>> {
+    let trackers = rpc::call(rpc::DEFAULT_RPC_ADDR, "torrent-trackers", json!({ "id": args.id })).await?;
+    let trackers = trackers.as_array().cloned().unwrap_or_default();
+    if trackers.is_empty() {
+        println!("torrent {} has no trackers", args.id);
+    }
+    print_trackers(&trackers);
+    Ok(())
+}
+
+// one block per tracker, for trackers and info
+fn print_trackers(trackers: &[Value]) {
+    let number = |tracker: &Value, key: &str| tracker[key].as_u64().unwrap_or(0);
+    for tracker in trackers {
+        println!("{}", tracker["url"].as_str().unwrap_or_default());
+        let status = tracker["status"].as_str().unwrap_or_default();
+        if status == "unused" {
+            println!("    not announced to yet, a tracker before it has answered");
+            continue;
+        }
+        println!(
+            "    status:        {}, {} announces answered, {} failed",
+            status,
+            number(tracker, "successes"),
+            number(tracker, "total_failures")
+        );
+        if let Some(at) = tracker["last_response"].as_i64() {
+            println!(
+                "    last answer:   {} peers, {} seeders, {} leechers at {}",
+                number(tracker, "last_peers"),
+                number(tracker, "seeders"),
+                number(tracker, "leechers"),
+                format_date(at)
+            );
+        }
+        if let Some(at) = tracker["next_announce"].as_i64() {
+            println!("    next announce: {}", format_date(at));
+        }
+        if let Some(error) = tracker["error"].as_str() {
+            println!("    error:         {}", error);
+        }
+        if let Some(warning) = tracker["warning"].as_str() {
+            println!("    warning:       {}", warning);
+        }
+    }
+}
+
+async fn import(session: &Mutex<Session>, args: ImportArgs) -> Result<(), Box<dyn Error>> {
+    // one of ours, or a libtorrent .fastresume with any number of
+    // torrents in it
+    for bundle in bundle::read(&args.bundle)? {
+        let torrent = bundle.torrent()?;
+
+        // the bundle says which pieces are done, make sure the data that
+        // was copied over agrees before trusting it
+        let files = verify::map_existing_files(&torrent, &args.dir)?;
+        let had = bundle.resume.pieces();
+        let pieces: Vec<usize> = verify::sample_pieces(had.len(), cli::DEFAULT_SAMPLE)
+            .into_iter()
+            .map(|i| had[i])
+            .collect();
+
+        let failed = verify::verify_pieces(&torrent, &files, &pieces)?;
+        if let Some(first) = failed.first() {
+            return Err(format!(
+                "{} of {} checked pieces of {} don't match (first bad piece: {}), was all of the data copied?",
+                failed.len(),
+                pieces.len(),
+                torrent.output_file,
+                first
+            ).into());
+        }
+
+        let name = torrent.output_file.clone();
+        let id = session.lock().await.import_torrent(torrent, &bundle.resume).await?;
+        println!("imported {} as torrent {}", name, id);
+    }
+
+    Ok(())
+}
+
+fn create(args: CreateArgs) -> Result<(), Box<dyn Error>> {
+    let torrent = create::create_torrent(&args.path, &args.options)?;
+    fs::write(&args.output, torrent)?;
+
+    println!("wrote {}", args.output.display());
+    Ok(())
+}
+
+async fn info(args: InfoArgs) -> Result<(), Box<dyn Error>> {
+    let torrent = parse_torrent(&fs::read(&args.torrent)?)?;
 
-    let pm = PieceManager::new(torrent.clone())?;
-    pm.print();
+    if args.magnet {
+        println!("{}", torrent.to_magnet());
+        return Ok(());
+    }
 
-    let tracker = Tracker::new(torrent.clone());
-    tracker.connect(true, 0, 0).await?;
+    println!("name:          {}", torrent.output_file);
+    println!("info hash:     {}", torrent.info_hash);
+    println!("trackers:      {}", torrent.trackers().join(", "));
+    if !torrent.nodes.is_empty() {
+        let nodes: Vec<String> = torrent.nodes.iter().map(|(host, port)| format!("{}:{}", host, port)).collect();
+        println!("dht nodes:     {}", nodes.join(", "));
+    }
+    println!("size:          {} bytes", torrent.total_size);
+    println!("pieces:        {} of {} bytes", torrent.pieces.len(), torrent.piece_length);
+    println!("private:       {}", if torrent.private { "yes" } else { "no" });
+    if let Some(date) = torrent.creation_date {
+        println!("created:       {}", format_date(date));
+    }
+    if let Some(created_by) = &torrent.created_by {
+        println!("created by:    {}", created_by);
+    }
+    if let Some(comment) = &torrent.comment {
+        println!("comment:       {}", comment);
+    }
+    if let Some(encoding) = &torrent.encoding {
+        println!("encoding:      {}", encoding);
+    }
+
+    println!("files:");
+    for file in &torrent.files {
+        match file.md5sum() {
+            Some(md5sum) => println!("    {} ({} bytes, md5 {})", file.name, file.length(), md5sum),
+            None => println!("    {} ({} bytes)", file.name, file.length()),
+        }
+    }
+
+    // how announcing is going, if the running client has the torrent.
+    // without one there is nothing more to show.
+    if let Some((id, trackers)) = live_trackers(&torrent.info_hash.to_string()).await {
+        println!("trackers of running torrent {}:", id);
+        print_trackers(&trackers);
+    }
 
     Ok(())
 }
+
+async fn live_trackers(info_hash: &str) -> Option<(u64, Vec<Value>)> {
+    let torrents = rpc::call(rpc::DEFAULT_RPC_ADDR, "torrent-get", json!({})).await.ok()?;
+    let id = torrents.as_array()?.iter().find(|t| t["info_hash"].as_str() == Some(info_hash))?["id"].as_u64()?;
+    let trackers = rpc::call(rpc::DEFAULT_RPC_ADDR, "torrent-trackers", json!({ "id": id })).await.ok()?;
+    Some((id, trackers.as_array().cloned().unwrap_or_default()))
+}
+
+// how often peers --watch redraws
+const PEERS_REFRESH: Duration = Duration::from_secs(2);
+
+async fn peers(args: PeersArgs) -> Result<(), Box<dyn Error>> {
+    loop {
+        let status = rpc::call(rpc::DEFAULT_RPC_ADDR, "torrent-get", json!({ "id": args.id })).await?;
+        let peers = rpc::call(rpc::DEFAULT_RPC_ADDR, "torrent-peers", json!({ "id": args.id })).await?;
+        let table = peer_table(peers.as_array().map_or(&[], |p| p.as_slice()), status["pieces_total"].as_u64().unwrap_or(0));
+        if !args.watch {
+            print!("{}", table);
+            return Ok(());
+        }
+        // back to the top left of a cleared screen
+        print!("\x1b[2J\x1b[H{}", table);
+        tokio::time::sleep(PEERS_REFRESH).await;
+    }
+}
+
+// one line per peer, the ones sending us the most first
+fn peer_table(peers: &[Value], pieces_total: u64) -> String {
+    if peers.is_empty() {
+        return "no peers connected\n".to_string();
+    }
+    let rate = |peer: &Value, key: &str| peer["stats"][key].as_f64().unwrap_or(0.0);
+    let mut peers: Vec<&Value> = peers.iter().collect();
+    peers.sort_by(|a, b| rate(b, "download_rate").total_cmp(&rate(a, "download_rate")));
+
+    let mut table = format!(
+        "{:<22} {:<20} {:<5} {:>5} {:>11} {:>11} {:>4}  {}\n",
+        "address", "client", "flags", "done", "down", "up", "reqs", "country"
+    );
+    for peer in peers {
+        let pieces = peer["pieces"].as_u64().unwrap_or(0);
+        let done = (pieces * 100).checked_div(pieces_total).unwrap_or(0);
+        let client: String = peer["client"].as_str().unwrap_or("unknown").chars().take(20).collect();
+        table += &format!(
+            "{:<22} {:<20} {:<5} {:>4}% {:>11} {:>11} {:>4}  {}\n",
+            peer["address"].as_str().unwrap_or_default(),
+            client,
+            peer_flags(peer),
+            done,
+            format_rate(rate(peer, "download_rate")),
+            format_rate(rate(peer, "upload_rate")),
+            peer["requests"].as_u64().unwrap_or(0),
+            peer["country"].as_str().unwrap_or("-"),
+        );
+    }
+    table
+}
+
+// see the peers command in the usage for what they mean
+fn peer_flags(peer: &Value) -> String {
+    let flag = |key: &str| peer["state"][key].as_bool().unwrap_or(false);
+    let (am_choking, am_interested) = (flag("am_choking"), flag("am_interested"));
+    let (peer_choking, peer_interested) = (flag("peer_choking"), flag("peer_interested"));
+
+    let mut flags = String::new();
+    match (am_interested, peer_choking) {
+        (true, false) => flags.push('D'),
+        (true, true) => flags.push('d'),
+        (false, false) => flags.push('K'),
+        (false, true) => {}
+    }
+    match (peer_interested, am_choking) {
+        (true, false) => flags.push('U'),
+        (true, true) => flags.push('u'),
+        (false, false) => flags.push('?'),
+        (false, true) => {}
+    }
+    if peer["snubbed"].as_bool().unwrap_or(false) {
+        flags.push('S');
+    }
+    if ["download", "upload"].iter().any(|key| !peer["limits"][key].is_null()) {
+        flags.push('L');
+    }
+    flags
+}
+
+async fn limit_peer(args: LimitPeerArgs) -> Result<(), Box<dyn Error>> {
+    let params = json!({ "id": args.id, "address": args.address, "limits": args.limits });
+    rpc::call(rpc::DEFAULT_RPC_ADDR, "peer-limit-set", params).await?;
+
+    match args.limits {
+        Some(limits) => {
+            let rate = |limit: Option<u64>| limit.map_or("unlimited".to_string(), |bytes| format_rate(bytes as f64));
+            println!("{} is limited to {} down, {} up", args.address, rate(limits.download), rate(limits.upload));
+        }
+        None => println!("{} is back on the torrent's limits", args.address),
+    }
+    Ok(())
+}
+
+async fn speed(args: SpeedArgs) -> Result<(), Box<dyn Error>> {
+    let params = match args.id {
+        Some(id) => json!({ "id": id }),
+        None => json!({}),
+    };
+    loop {
+        let history = rpc::call(rpc::DEFAULT_RPC_ADDR, "rate-history", params.clone()).await?;
+        let series = |key: &str| -> Vec<u64> {
+            history[key].as_array().map_or(Vec::new(), |s| s.iter().map(|v| v.as_u64().unwrap_or(0)).collect())
+        };
+        let graph = format!("{}{}", rate_graph("down", &series("download")), rate_graph("up", &series("upload")));
+        if !args.watch {
+            print!("{}", graph);
+            return Ok(());
+        }
+        print!("\x1b[2J\x1b[H{}", graph);
+        tokio::time::sleep(PEERS_REFRESH).await;
+    }
+}
+
+// seconds averaged into each column of the graph
+const GRAPH_STEP: usize = 5;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// a line like "down  ▁▁▃▇█▆  now 1.2 MB/s, peak 2.0 MB/s", the oldest
+// seconds on the left
+fn rate_graph(label: &str, series: &[u64]) -> String {
+    let columns: Vec<f64> =
+        series.chunks(GRAPH_STEP).map(|c| c.iter().sum::<u64>() as f64 / c.len() as f64).collect();
+    let now = columns.last().copied().unwrap_or(0.0);
+    let peak = series.iter().copied().max().unwrap_or(0) as f64;
+    format!("{:<5} {}  now {}, peak {}\n", label, sparkline(&columns), format_rate(now), format_rate(peak))
+}
+
+// scaled to the largest value, all zeros are the lowest bar
+fn sparkline(values: &[f64]) -> String {
+    let max = values.iter().copied().fold(0.0, f64::max);
+    values
+        .iter()
+        .map(|&v| {
+            let level = if max > 0.0 { (v / max * (SPARKS.len() - 1) as f64).round() as usize } else { 0 };
+            SPARKS[level.min(SPARKS.len() - 1)]
+        })
+        .collect()
+}
+
+fn format_rate(bytes_per_sec: f64) -> String {
+    match bytes_per_sec {
+        r if r >= 1e6 => format!("{:.1} MB/s", r / 1e6),
+        r if r >= 1e3 => format!("{:.1} kB/s", r / 1e3),
+        r => format!("{:.0} B/s", r),
+    }
+}
+
+// unix time as a utc date. one too far out for chrono is shown as it is
+fn format_date(secs: i64) -> String {
+    match chrono::DateTime::from_timestamp(secs, 0) {
+        Some(date) => date.format("%Y-%m-%d %H:%M:%S utc").to_string(),
+        None => format!("{} (unix time)", secs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(0), "1970-01-01 00:00:00 utc");
+        assert_eq!(format_date(951782400), "2000-02-29 00:00:00 utc");
+        assert_eq!(format_date(1700000000), "2023-11-14 22:13:20 utc");
+        assert_eq!(format_date(-1), "1969-12-31 23:59:59 utc");
+    }
+
+    #[test]
+    fn test_peer_table() {
+        let peer = |address: &str, download_rate: f64, state: Value| {
+            json!({
+                "address": address,
+                "client": "Transmission 3.00",
+                "pieces": 25,
+                "stats": { "download_rate": download_rate, "upload_rate": 0.0 },
+                "snubbed": false,
+                "country": null,
+                "state": state,
+                "requests": 4,
+            })
+        };
+        let downloading = json!({ "am_choking": true, "am_interested": true, "peer_choking": false, "peer_interested": true });
+        let idle = json!({ "am_choking": false, "am_interested": false, "peer_choking": true, "peer_interested": false });
+        let peers = [peer("10.0.0.2:6881", 10.0, idle), peer("10.0.0.1:6881", 2_500_000.0, downloading)];
+
+        let table = peer_table(&peers, 100);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("10.0.0.1:6881          Transmission 3.00    Du      25%    2.5 MB/s       0 B/s    4  -"), "{}", lines[1]);
+        assert!(lines[2].contains(" ?  "), "{}", lines[2]);
+        assert_eq!(peer_table(&[], 100), "no peers connected\n");
+
+        let capped = json!({ "state": { "am_choking": true, "peer_choking": true }, "limits": { "download": null, "upload": 65536 } });
+        assert_eq!(peer_flags(&capped), "L");
+    }
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(sparkline(&[0.0, 1.0, 2.0, 7.0]), "▁▂▃█");
+        assert_eq!(sparkline(&[0.0, 0.0]), "▁▁");
+        assert_eq!(sparkline(&[]), "");
+
+        let mut series = vec![0; 300];
+        series[299] = 5000;
+        let graph = rate_graph("down", &series);
+        assert_eq!(graph.chars().filter(|c| SPARKS.contains(c)).count(), 60);
+        assert!(graph.ends_with("█  now 1.0 kB/s, peak 5.0 kB/s\n"), "{}", graph);
+    }
+}
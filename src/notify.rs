@@ -0,0 +1,146 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use reqwest::Client;
+use serde::Serialize;
+
+use crate::torrent::Torrent;
+
+// posts what happens to a torrent to a url as json, for chat bots, home
+// automation and anything else that would rather be told than poll the
+// rpc api. every event names the torrent:
+//
+//   {"event":"completed","name":"ubuntu.iso","info_hash":"ab..",
+//    "path":"/data/ubuntu.iso","time":1700000000}
+//
+// with the event's own fields next to those. nothing is retried, a
+// notification that can't be delivered is only warned about.
+
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Added,
+    Completed,
+    // the tracker stopped answering, sent when it goes from working to
+    // failing rather than for every retry
+    TrackerError { tracker: String, error: String },
+    // a piece that didn't match its hash and was thrown away
+    HashFailure { piece: u32 },
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    name: &'a str,
+    info_hash: String,
+    path: &'a str,
+    time: u64,
+}
+
+// one for each torrent, doing nothing when there is no url
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    url: Option<String>,
+    torrent: Arc<Torrent>,
+    client: Client,
+}
+
+impl Notifier {
+    pub fn new(url: Option<String>, torrent: Arc<Torrent>) -> Notifier {
+        Notifier { url, torrent, client: Client::new() }
+    }
+
+    // the torrent as it is now, after its data has been moved
+    pub fn set_torrent(&mut self, torrent: Arc<Torrent>) {
+        self.torrent = torrent;
+    }
+
+    pub fn body(&self, event: &Event, now: SystemTime) -> String {
+        let payload = Payload {
+            event,
+            name: self.torrent.name(),
+            info_hash: self.torrent.info_hash.to_string(),
+            path: &self.torrent.output_file,
+            time: now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        };
+        serde_json::to_string(&payload).expect("events always serialize")
+    }
+
+    // sent in the background
+    pub fn notify(&self, event: Event) {
+        let Some(url) = self.url.clone() else { return };
+        let body = self.body(&event, SystemTime::now());
+        let request = self.client.post(url.as_str()).header("content-type", "application/json").body(body).timeout(POST_TIMEOUT);
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {}
+                Ok(response) => warn!("webhook {} answered {}", url, response.status()),
+                Err(e) => warn!("couldn't post to webhook {}: {}", url, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::info_hash::InfoHash;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn torrent() -> Arc<Torrent> {
+        Arc::new(Torrent {
+            info_hash: InfoHash::new([0xab; 20]),
+            output_file: "/data/ubuntu.iso".to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_body() {
+        let notifier = Notifier::new(None, torrent());
+        let now = UNIX_EPOCH + Duration::from_secs(1700000000);
+        let body: serde_json::Value = serde_json::from_str(&notifier.body(&Event::HashFailure { piece: 7 }, now)).unwrap();
+        assert_eq!(body, serde_json::json!({
+            "event": "hash_failure",
+            "piece": 7,
+            "name": "ubuntu.iso",
+            "info_hash": "ab".repeat(20),
+            "path": "/data/ubuntu.iso",
+            "time": 1700000000,
+        }));
+
+        let body = notifier.body(&Event::Added, now);
+        assert!(body.starts_with(r#"{"event":"added","name":"ubuntu.iso""#));
+    }
+
+    #[tokio::test]
+    async fn test_posts_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let notifier = Notifier::new(Some(url), torrent());
+        notifier.notify(Event::TrackerError { tracker: "http://tracker/announce".to_string(), error: "timed out".to_string() });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut data = [0u8; 4096];
+        while !String::from_utf8_lossy(&request).contains("\"time\":") {
+            let n = stream.read(&mut data).await.unwrap();
+            assert!(n > 0);
+            request.extend_from_slice(&data[..n]);
+        }
+        stream.write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n").await.unwrap();
+
+        let request = String::from_utf8_lossy(&request);
+        assert!(request.starts_with("POST /hook "));
+        assert!(request.contains(r#""event":"tracker_error","tracker":"http://tracker/announce","error":"timed out""#));
+    }
+}
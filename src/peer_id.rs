@@ -0,0 +1,178 @@
+use std::fmt;
+
+// works out which client a peer is running from its peer id.
+// most clients follow one of two conventions:
+//   azureus style: '-' + two character client code + four version characters + '-'
+//       e.g. "-TR3000-" is transmission 3.00
+//   shadow style: one character client code + up to five version characters
+//       padded with '-', followed by "---"
+//       e.g. "S58B-----" is shadow 5.8.11
+// see: https://wiki.theory.org/BitTorrentSpecification#peer_id
+
+// what we put at the start of our own peer id
+pub const PEER_ID_PREFIX: &str = "-MY6969-";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    pub name: String,
+    pub version: String,
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.version.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} {}", self.name, self.version)
+        }
+    }
+}
+
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BB", "BitBuddy"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FW", "FrostWire"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("MY", "bt-c"),
+    ("PD", "Pando"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("TR", "Transmission"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow's client"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+pub fn identify_client(peer_id: &[u8]) -> Option<ClientInfo> {
+    if peer_id.len() != 20 {
+        return None;
+    }
+
+    azureus_style(peer_id).or_else(|| shadow_style(peer_id))
+}
+
+fn azureus_style(peer_id: &[u8]) -> Option<ClientInfo> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' || !peer_id[1..7].iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(c, _)| *c == code)
+        .map(|(_, name)| name.to_string())
+        .unwrap_or_else(|| format!("unknown ({})", code));
+
+    let digits = &peer_id[3..7];
+    let version = if code == "TR" {
+        // transmission writes major, then a two digit minor, then a
+        // release marker: "-TR2940-" is 2.94 and "-TR300Z-" a 3.00 beta
+        let minor = digits[1..3].iter().map(|&c| c as char).collect::<String>();
+        let beta = if digits[3] == b'Z' || digits[3] == b'X' { "+" } else { "" };
+        format!("{}.{}{}", digits[0] as char, minor, beta)
+    } else {
+        let mut parts: Vec<u32> = digits.iter().map(|&c| version_value(c)).collect::<Option<_>>()?;
+        while parts.len() > 2 && parts.last() == Some(&0) {
+            parts.pop();
+        }
+        parts.iter().map(u32::to_string).collect::<Vec<_>>().join(".")
+    };
+
+    Some(ClientInfo { name, version })
+}
+
+fn shadow_style(peer_id: &[u8]) -> Option<ClientInfo> {
+    let (_, name) = SHADOW_CLIENTS.iter().find(|(c, _)| *c == peer_id[0])?;
+
+    if &peer_id[6..9] != b"---" {
+        return None;
+    }
+
+    // version characters are padded with '-' once they run out
+    let version_chars = &peer_id[1..6];
+    let len = version_chars.iter().position(|&c| c == b'-').unwrap_or(version_chars.len());
+    if len == 0 || version_chars[len..].iter().any(|&c| c != b'-') {
+        return None;
+    }
+
+    let parts: Vec<String> = version_chars[..len]
+        .iter()
+        .map(|&c| shadow_value(c).map(|v| v.to_string()))
+        .collect::<Option<_>>()?;
+
+    Some(ClientInfo { name: name.to_string(), version: parts.join(".") })
+}
+
+// azureus style clients use digits and, for values over 9, capital letters
+fn version_value(c: u8) -> Option<u32> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 10),
+        _ => None,
+    }
+}
+
+fn shadow_value(c: u8) -> Option<u32> {
+    match c {
+        b'0'..=b'9' => Some((c - b'0') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identify(peer_id: &[u8]) -> Option<String> {
+        identify_client(peer_id).map(|c| c.to_string())
+    }
+
+    #[test]
+    fn test_azureus_style() {
+        assert_eq!(identify(b"-TR3000-abcdefghijkl").as_deref(), Some("Transmission 3.00"));
+        assert_eq!(identify(b"-TR294Z-abcdefghijkl").as_deref(), Some("Transmission 2.94+"));
+        assert_eq!(identify(b"-qB4250-abcdefghijkl").as_deref(), Some("qBittorrent 4.2.5"));
+        assert_eq!(identify(b"-DE13F0-abcdefghijkl").as_deref(), Some("Deluge 1.3.15"));
+        assert_eq!(identify(b"-AZ2060-abcdefghijkl").as_deref(), Some("Vuze 2.0.6"));
+        assert_eq!(identify(b"-MY6969-123456789012").as_deref(), Some("bt-c 6.9.6.9"));
+        assert_eq!(identify(b"-ZZ1000-abcdefghijkl").as_deref(), Some("unknown (ZZ) 1.0"));
+    }
+
+    #[test]
+    fn test_shadow_style() {
+        assert_eq!(identify(b"S58B-----abcdefghijk").as_deref(), Some("Shadow's client 5.8.11"));
+        assert_eq!(identify(b"T03I-----abcdefghijk").as_deref(), Some("BitTornado 0.3.18"));
+        assert_eq!(identify(b"A310-----abcdefghijk").as_deref(), Some("ABC 3.1.0"));
+    }
+
+    #[test]
+    fn test_unrecognised() {
+        assert_eq!(identify(&[0u8; 20]), None);
+        assert_eq!(identify(b"-TR3000-"), None);
+        assert_eq!(identify(b"Sabc--xx-abcdefghijk"), None);
+        assert_eq!(identify(b"X58B-----abcdefghijk"), None);
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{pex::Addr, sources::PeerSource};
+
+// every peer address a torrent has heard of, and how trying them has
+// gone. announces add to the pool instead of replacing what the last
+// one said, so a peer the tracker leaves out of its next answer is still
+// there to try. an address that won't connect is tried again later and
+// later, and dropped once it has failed too often in a row. one that
+// worked goes back in the pool when its connection closes, to be picked
+// up again after a short wait.
+//
+// peers that dial us aren't in the pool, the port they came from isn't
+// one they listen on.
+//
+// an address is in the pool once however many sources hand it out, and
+// remembers all of them. ipv4 addresses written as ipv6 (::ffff:1.2.3.4)
+// count as the ipv4 address they are. our own addresses are never added,
+// connecting to them would only get us talking to ourselves.
+
+// how many addresses a torrent keeps. past this the ones that have
+// failed most are dropped first.
+const DEFAULT_MAX_PEERS: usize = 1000;
+
+// failed connects in a row before an address is dropped
+const DEFAULT_MAX_FAILURES: u32 = 5;
+
+// the wait after the first failure, doubled for every one after it
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+// the wait before going back to a peer whose connection closed normally
+const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+// and to one we dropped for doing too little, see churn.rs
+const DROPPED_DELAY: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+struct KnownPeer {
+    // everyone who told us about it
+    sources: BTreeSet<PeerSource>,
+    attempts: u32,
+    failures: u32,
+    // None until it has been tried
+    retry_at: Option<Instant>,
+    // a connection is trying it, or talking to it
+    in_use: bool,
+}
+
+#[derive(Debug)]
+pub struct PeerPool {
+    peers: HashMap<Addr, KnownPeer>,
+    // addresses that are us
+    own: HashSet<Addr>,
+    max_peers: usize,
+    max_failures: u32,
+}
+
+impl Default for PeerPool {
+    fn default() -> Self {
+        PeerPool::with_limits(DEFAULT_MAX_PEERS, DEFAULT_MAX_FAILURES)
+    }
+}
+
+impl PeerPool {
+    pub fn new() -> PeerPool {
+        PeerPool::default()
+    }
+
+    pub fn with_limits(max_peers: usize, max_failures: u32) -> PeerPool {
+        PeerPool { peers: HashMap::new(), own: HashSet::new(), max_peers, max_failures }
+    }
+
+    // addresses already in the pool keep their history
+    pub fn add(&mut self, source: PeerSource, peers: &[Addr]) {
+        for addr in peers {
+            let addr = canonical(addr);
+            if self.own.contains(&addr) {
+                continue;
+            }
+            let peer = self.peers.entry(addr).or_insert(KnownPeer {
+                sources: BTreeSet::new(),
+                attempts: 0,
+                failures: 0,
+                retry_at: None,
+                in_use: false,
+            });
+            peer.sources.insert(source);
+        }
+        self.prune();
+    }
+
+    // one of our own addresses, whether we knew it beforehand or only
+    // found out by connecting to it
+    pub fn exclude(&mut self, addr: &Addr) {
+        let addr = canonical(addr);
+        self.peers.remove(&addr);
+        self.own.insert(addr);
+    }
+
+    // the address to try next, if any is due: the ones never tried come
+    // first, then the ones that have failed least. it is the caller's
+    // until it reports back with connected or failed.
+    pub fn next(&mut self, now: Instant) -> Option<Addr> {
+        let (addr, peer) = self
+            .peers
+            .iter_mut()
+            .filter(|(_, peer)| !peer.in_use && peer.retry_at.is_none_or(|at| at <= now))
+            .min_by_key(|(_, peer)| (peer.attempts > 0, peer.failures, peer.retry_at))?;
+        peer.in_use = true;
+        peer.attempts += 1;
+        Some(addr.clone())
+    }
+
+    // a connection to the peer closed after getting past the handshake
+    pub fn connected(&mut self, addr: &Addr, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.in_use = false;
+            peer.failures = 0;
+            peer.retry_at = Some(now + RECONNECT_DELAY);
+        }
+    }
+
+    // we hung up on the peer to make room for a better one
+    pub fn dropped(&mut self, addr: &Addr, now: Instant) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            peer.in_use = false;
+            peer.retry_at = Some(now + DROPPED_DELAY);
+        }
+    }
+
+    // the peer couldn't be reached, or hung up before the handshake was
+    // done. returns whether that was once too often and it was dropped.
+    pub fn failed(&mut self, addr: &Addr, now: Instant) -> bool {
+        let Some(peer) = self.peers.get_mut(addr) else { return false };
+        peer.in_use = false;
+        peer.failures += 1;
+        if peer.failures >= self.max_failures {
+            self.peers.remove(addr);
+            return true;
+        }
+        peer.retry_at = Some(now + backoff(peer.failures));
+        false
+    }
+
+    // addresses next would hand out right now
+    pub fn due(&self, now: Instant) -> usize {
+        self.peers.values().filter(|peer| !peer.in_use && peer.retry_at.is_none_or(|at| at <= now)).count()
+    }
+
+    // where we heard of the address, empty if it isn't in the pool
+    pub fn sources(&self, addr: &Addr) -> Vec<PeerSource> {
+        self.peers.get(&canonical(addr)).map_or_else(Vec::new, |peer| peer.sources.iter().copied().collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    fn prune(&mut self) {
+        while self.peers.len() > self.max_peers {
+            let worst = self
+                .peers
+                .iter()
+                .filter(|(_, peer)| !peer.in_use)
+                .max_by_key(|(_, peer)| (peer.failures, peer.attempts))
+                .map(|(addr, _)| addr.clone());
+            // everything left is in use, which the connection limit
+            // keeps well under max_peers
+            let Some(worst) = worst else { return };
+            self.peers.remove(&worst);
+        }
+    }
+}
+
+fn canonical((ip, port): &Addr) -> Addr {
+    match ip.parse::<IpAddr>() {
+        Ok(parsed) => (parsed.to_canonical().to_string(), *port),
+        Err(_) => (ip.clone(), *port),
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    RETRY_BACKOFF.saturating_mul(1 << (failures - 1).min(16)).min(MAX_RETRY_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(ip: &str) -> Addr {
+        (ip.to_string(), 6881)
+    }
+
+    #[test]
+    fn test_backoff_and_dropping() {
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+        let mut pool = PeerPool::with_limits(10, 3);
+        pool.add(PeerSource::Tracker, &[addr("10.0.0.1")]);
+
+        assert_eq!(pool.next(start), Some(addr("10.0.0.1")));
+        // already being tried
+        assert_eq!(pool.next(start), None);
+
+        assert!(!pool.failed(&addr("10.0.0.1"), start));
+        assert_eq!(pool.next(secs(29)), None);
+        assert_eq!(pool.next(secs(30)), Some(addr("10.0.0.1")));
+        assert!(!pool.failed(&addr("10.0.0.1"), secs(30)));
+        // twice as long the second time
+        assert_eq!(pool.next(secs(89)), None);
+        assert_eq!(pool.next(secs(90)), Some(addr("10.0.0.1")));
+
+        // the next announce mentioning it doesn't wipe its record
+        pool.add(PeerSource::Tracker, &[addr("10.0.0.1")]);
+        assert!(pool.failed(&addr("10.0.0.1"), secs(90)));
+        assert!(pool.is_empty());
+    }
+
+    #[test]
+    fn test_reconnect_after_close() {
+        let start = Instant::now();
+        let mut pool = PeerPool::new();
+        pool.add(PeerSource::Tracker, &[addr("10.0.0.1")]);
+        pool.next(start);
+        pool.failed(&addr("10.0.0.1"), start);
+        pool.next(start + Duration::from_secs(30));
+
+        // a connection that worked clears the failures
+        pool.connected(&addr("10.0.0.1"), start + Duration::from_secs(40));
+        assert_eq!(pool.next(start + Duration::from_secs(99)), None);
+        assert_eq!(pool.next(start + Duration::from_secs(100)), Some(addr("10.0.0.1")));
+        pool.connected(&addr("10.0.0.1"), start + Duration::from_secs(100));
+        assert!(!pool.failed(&addr("10.0.0.1"), start + Duration::from_secs(200)));
+
+        // one we dropped waits longer, with its record as it was
+        pool.next(start + Duration::from_secs(230));
+        pool.dropped(&addr("10.0.0.1"), start + Duration::from_secs(230));
+        assert_eq!(pool.due(start + Duration::from_secs(829)), 0);
+        assert_eq!(pool.due(start + Duration::from_secs(830)), 1);
+        assert_eq!(pool.sources(&addr("10.0.0.1")), vec![PeerSource::Tracker]);
+    }
+
+    #[test]
+    fn test_sources_and_own_addresses() {
+        let start = Instant::now();
+        let mut pool = PeerPool::new();
+        pool.add(PeerSource::Tracker, &[addr("10.0.0.1"), addr("10.0.0.2")]);
+        pool.add(PeerSource::Dht, &[addr("::ffff:10.0.0.1"), addr("2001:db8::1")]);
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.sources(&addr("10.0.0.1")), vec![PeerSource::Tracker, PeerSource::Dht]);
+        assert_eq!(pool.sources(&addr("2001:db8::1")), vec![PeerSource::Dht]);
+
+        // turned out to be us, and stays out
+        pool.exclude(&addr("10.0.0.2"));
+        pool.add(PeerSource::Pex, &[addr("10.0.0.2")]);
+        assert!(pool.sources(&addr("10.0.0.2")).is_empty());
+        assert_eq!(pool.due(start), 2);
+    }
+
+    #[test]
+    fn test_order_and_pruning() {
+        let start = Instant::now();
+        let mut pool = PeerPool::with_limits(3, 5);
+        pool.add(PeerSource::Tracker, &[addr("10.0.0.1"), addr("10.0.0.2")]);
+        let first = pool.next(start).unwrap();
+        pool.failed(&first, start);
+        let second = pool.next(start).unwrap();
+        pool.failed(&second, start);
+        pool.failed(&second, start);
+
+        // never tried beats everything that has been
+        pool.add(PeerSource::Dht, &[addr("10.0.0.3")]);
+        let later = start + Duration::from_secs(3600);
+        assert_eq!(pool.next(later), Some(addr("10.0.0.3")));
+        assert_eq!(pool.next(later), Some(first.clone()));
+
+        // full, so the one that failed most makes room. the two in use
+        // are kept whatever their record.
+        pool.add(PeerSource::Tracker, &[addr("10.0.0.4")]);
+        assert_eq!(pool.len(), 3);
+        assert!(pool.sources(&second).is_empty());
+        assert_eq!(pool.next(later), Some(addr("10.0.0.4")));
+    }
+}
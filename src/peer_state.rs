@@ -0,0 +1,91 @@
+use serde::Serialize;
+
+// the choke and interest flags of a connection, ours and the peer's.
+// a connection starts out choked and uninterested both ways.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct PeerState {
+    // we won't answer the peer's requests
+    pub am_choking: bool,
+    // we want something the peer has
+    pub am_interested: bool,
+    // the peer won't answer ours
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        PeerState {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+impl PeerState {
+    // whether requests we send would be answered
+    pub fn can_request(&self) -> bool {
+        self.am_interested && !self.peer_choking
+    }
+}
+
+// where a connection is in its life, one type per phase. each is only
+// made by using up the one before it, so phases only move forward and
+// none can be skipped: there is no Active without a handshake, and once
+// the peer's first message is in, the Bitfield that would have let it
+// send a bitfield is gone.
+//
+// closing isn't a phase of its own. the token goes when the connection
+// does, whatever phase it had reached, and the next peer on the same
+// PeerConnection starts over with a new Handshaking.
+
+// swapping handshakes
+#[derive(Debug)]
+pub struct Handshaking(());
+
+// handshakes are done, the peer may send its bitfield, which is only
+// allowed as its first message
+#[derive(Debug)]
+pub struct Bitfield(());
+
+// the peer has sent a message, so it really does speak bittorrent
+#[derive(Debug)]
+pub struct Active(());
+
+impl Handshaking {
+    pub fn start() -> Handshaking {
+        Handshaking(())
+    }
+
+    pub fn handshake_done(self) -> Bitfield {
+        Bitfield(())
+    }
+}
+
+impl Bitfield {
+    // the first message ends the phase, whether it was a bitfield or not
+    pub fn message_received(self) -> Active {
+        Active(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_initial_state() {
+        let state = PeerState::default();
+        assert!(state.am_choking && state.peer_choking);
+        assert!(!state.can_request());
+        assert!(PeerState { am_interested: true, peer_choking: false, ..state }.can_request());
+    }
+
+    // going back a phase, or skipping one, wouldn't compile
+    #[test]
+    fn test_phases_only_move_forward() {
+        let _: Active = Handshaking::start().handshake_done().message_received();
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bencoding::{decoder, encoder, Bencode},
+    tracker::TrackerResponse,
+};
+
+// peer exchange (bep 11) lets connected peers tell each other about the
+// rest of the swarm. it is sent over the extension protocol as ut_pex,
+// which we don't speak yet, so for now this is just the bookkeeping
+// both sides need once it lands, following what mainstream clients
+// enforce:
+//   - messages to the same peer are at least a minute apart
+//   - added and dropped hold at most 50 peers each
+//   - peers we already know about are ignored rather than queued twice
+
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+pub const MAX_ADDED: usize = 50;
+pub const MAX_DROPPED: usize = 50;
+
+pub type Addr = (String, u16);
+
+// ip:port, with the brackets an ipv6 address needs to tell its colons
+// from the port's
+pub fn display_addr(ip: &str, port: u16) -> String {
+    if ip.contains(':') {
+        format!("[{}]:{}", ip, port)
+    } else {
+        format!("{}:{}", ip, port)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<Addr>,
+    pub dropped: Vec<Addr>,
+}
+
+impl PexMessage {
+    // only ipv4 peers go in the compact lists, anything else is left out
+    pub fn encode(&self) -> Vec<u8> {
+        let added = compact(&self.added);
+        let flags = vec![0u8; added.len() / 6];
+
+        let mut dict = BTreeMap::new();
+        dict.insert(b"added".to_vec(), Bencode::Bytes(added));
+        dict.insert(b"added.f".to_vec(), Bencode::Bytes(flags));
+        dict.insert(b"dropped".to_vec(), Bencode::Bytes(compact(&self.dropped)));
+        encoder::encode(&Bencode::Dict(dict))
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<PexMessage, String> {
+        let dict = match decoder::decode(payload)? {
+            (Bencode::Dict(dict), _) => dict,
+            _ => return Err("pex message is not a dict".to_string()),
+        };
+
+        let peers = |key: &[u8]| match dict.get(key) {
+            Some(Bencode::Bytes(b)) => TrackerResponse::parse_peers(b).map_err(|e| e.to_string()),
+            Some(_) => Err(format!("pex {} is not a string", String::from_utf8_lossy(key))),
+            None => Ok(Vec::new()),
+        };
+
+        Ok(PexMessage { added: peers(b"added")?, dropped: peers(b"dropped")? })
+    }
+}
+
+fn compact(addrs: &[Addr]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(addrs.len() * 6);
+    for (ip, port) in addrs {
+        if let Ok(ip) = ip.parse::<Ipv4Addr>() {
+            data.extend_from_slice(&ip.octets());
+            data.extend_from_slice(&port.to_be_bytes());
+        }
+    }
+    data
+}
+
+// what we have told one peer so far
+#[derive(Debug, Default)]
+pub struct PexSender {
+    last_sent: Option<Instant>,
+    advertised: BTreeSet<Addr>,
+}
+
+impl PexSender {
+    pub fn new() -> PexSender {
+        PexSender::default()
+    }
+
+    // works out what has changed since the last message, given the peers
+    // we are connected to now. returns None if it is too soon to send
+    // another message or there is nothing new to say. anything over the
+    // caps is left for the next message.
+    pub fn next_message(&mut self, connected: &[Addr], now: Instant) -> Option<PexMessage> {
+        if self.last_sent.is_some_and(|last| now.duration_since(last) < PEX_INTERVAL) {
+            return None;
+        }
+
+        let connected: BTreeSet<&Addr> = connected.iter().collect();
+        let added: Vec<Addr> = connected
+            .iter()
+            .filter(|addr| !self.advertised.contains(**addr))
+            .take(MAX_ADDED)
+            .map(|addr| (*addr).clone())
+            .collect();
+        let dropped: Vec<Addr> = self.advertised
+            .iter()
+            .filter(|addr| !connected.contains(addr))
+            .take(MAX_DROPPED)
+            .cloned()
+            .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        for addr in &dropped {
+            self.advertised.remove(addr);
+        }
+        self.advertised.extend(added.iter().cloned());
+        self.last_sent = Some(now);
+
+        Some(PexMessage { added, dropped })
+    }
+}
+
+// what one peer has told us
+#[derive(Debug, Default)]
+pub struct PexReceiver {
+    last_received: Option<Instant>,
+}
+
+impl PexReceiver {
+    pub fn new() -> PexReceiver {
+        PexReceiver::default()
+    }
+
+    // returns the new peers from the message that are worth queueing.
+    // peers sending faster than the interval are ignored, as are
+    // oversized lists beyond the cap and anything known already.
+    pub fn accept<F: Fn(&Addr) -> bool>(&mut self, message: &PexMessage, now: Instant, known: F) -> Vec<Addr> {
+        if self.last_received.is_some_and(|last| now.duration_since(last) < PEX_INTERVAL) {
+            return Vec::new();
+        }
+        self.last_received = Some(now);
+
+        let mut seen = BTreeSet::new();
+        message.added
+            .iter()
+            .take(MAX_ADDED)
+            .filter(|addr| addr.1 != 0 && !known(addr) && seen.insert(*addr))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(i: u8) -> Addr {
+        (format!("10.0.0.{}", i), 6881)
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let message = PexMessage {
+            added: vec![addr(1), addr(2)],
+            dropped: vec![addr(3)],
+        };
+
+        let encoded = message.encode();
+        assert_eq!(PexMessage::decode(&encoded).unwrap(), message);
+        assert!(PexMessage::decode(b"le").is_err());
+        assert!(PexMessage::decode(b"d5:added3:abce").is_err());
+    }
+
+    #[test]
+    fn test_display_addr() {
+        assert_eq!(display_addr("10.0.0.1", 6881), "10.0.0.1:6881");
+        assert_eq!(display_addr("2001:db8::1", 6881), "[2001:db8::1]:6881");
+    }
+
+    #[test]
+    fn test_sender_throttles() {
+        let mut sender = PexSender::new();
+        let now = Instant::now();
+
+        let first = sender.next_message(&[addr(1), addr(2)], now).unwrap();
+        assert_eq!(first.added, vec![addr(1), addr(2)]);
+
+        // too soon, even though something changed
+        assert_eq!(sender.next_message(&[addr(2), addr(3)], now + Duration::from_secs(30)), None);
+
+        let second = sender.next_message(&[addr(2), addr(3)], now + PEX_INTERVAL).unwrap();
+        assert_eq!(second.added, vec![addr(3)]);
+        assert_eq!(second.dropped, vec![addr(1)]);
+
+        // nothing new means nothing is sent
+        assert_eq!(sender.next_message(&[addr(2), addr(3)], now + PEX_INTERVAL * 2), None);
+    }
+
+    #[test]
+    fn test_sender_caps_entries() {
+        let mut sender = PexSender::new();
+        let now = Instant::now();
+        let connected: Vec<Addr> = (0..80).map(addr).collect();
+
+        let first = sender.next_message(&connected, now).unwrap();
+        assert_eq!(first.added.len(), MAX_ADDED);
+
+        // the rest go out in the next message
+        let second = sender.next_message(&connected, now + PEX_INTERVAL).unwrap();
+        assert_eq!(second.added.len(), 30);
+    }
+
+    #[test]
+    fn test_receiver_dedupes_and_throttles() {
+        let mut receiver = PexReceiver::new();
+        let now = Instant::now();
+        let message = PexMessage {
+            added: vec![addr(1), addr(2), addr(1), ("10.0.0.9".to_string(), 0)],
+            dropped: vec![],
+        };
+
+        let new = receiver.accept(&message, now, |a| *a == addr(2));
+        assert_eq!(new, vec![addr(1)]);
+
+        // a second message inside the interval is ignored
+        assert!(receiver.accept(&message, now + Duration::from_secs(5), |_| false).is_empty());
+        assert_eq!(receiver.accept(&message, now + PEX_INTERVAL, |_| false).len(), 2);
+    }
+
+    #[test]
+    fn test_receiver_caps_entries() {
+        let mut receiver = PexReceiver::new();
+        let message = PexMessage { added: (0..100).map(addr).collect(), dropped: vec![] };
+        assert_eq!(receiver.accept(&message, Instant::now(), |_| false).len(), MAX_ADDED);
+    }
+}
@@ -0,0 +1,112 @@
+// which piece a peer starts on next, once the pieces already under way
+// have nothing left to ask it for. the piece manager deals with
+// everything that overrides the choice (time critical pieces, the
+// endgame, upload slots, memory limits), a strategy only ever sees the
+// pieces it is free to choose from.
+
+use rand::Rng;
+
+// pieces picked at random by RandomFirst before it goes rarest first
+pub const DEFAULT_RANDOM_PIECES: usize = 4;
+
+// a piece the peer has that nobody has started on yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub index: u32,
+    // how many connected peers have it, this one included
+    pub availability: u32,
+}
+
+pub trait PiecePicker: Send {
+    // have is how many pieces we have verified so far. None leaves the
+    // peer without a new piece for now.
+    fn pick(&mut self, candidates: &[Candidate], have: usize) -> Option<u32>;
+}
+
+// the strategies that come with the client, for picking one in a config
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PickerKind {
+    #[default]
+    RarestFirst,
+    Sequential,
+    // rarest first, after picking this many pieces at random
+    RandomFirst { pieces: usize },
+}
+
+impl PickerKind {
+    pub fn build(self) -> Box<dyn PiecePicker> {
+        match self {
+            PickerKind::RarestFirst => Box::new(RarestFirst),
+            PickerKind::Sequential => Box::new(Sequential),
+            PickerKind::RandomFirst { pieces } => Box::new(RandomFirst { pieces }),
+        }
+    }
+}
+
+// the pieces fewest peers have, so they are still around when those
+// peers leave. ties go to the lowest index.
+pub struct RarestFirst;
+
+impl PiecePicker for RarestFirst {
+    fn pick(&mut self, candidates: &[Candidate], _have: usize) -> Option<u32> {
+        candidates.iter().min_by_key(|c| (c.availability, c.index)).map(|c| c.index)
+    }
+}
+
+// in order from the start of the torrent, for playing media while it
+// downloads. bad for the swarm, as everyone ends up wanting the same
+// pieces.
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn pick(&mut self, candidates: &[Candidate], _have: usize) -> Option<u32> {
+        candidates.iter().map(|c| c.index).min()
+    }
+}
+
+// with nothing to offer, a new peer is better off finishing any piece
+// quickly than waiting on a rare one only a handful of slow peers have.
+// once it has a few pieces to trade it goes rarest first.
+pub struct RandomFirst {
+    pub pieces: usize,
+}
+
+impl PiecePicker for RandomFirst {
+    fn pick(&mut self, candidates: &[Candidate], have: usize) -> Option<u32> {
+        if have >= self.pieces || candidates.is_empty() {
+            return RarestFirst.pick(candidates, have);
+        }
+        Some(candidates[rand::rng().random_range(0..candidates.len())].index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(pieces: &[(u32, u32)]) -> Vec<Candidate> {
+        pieces.iter().map(|&(index, availability)| Candidate { index, availability }).collect()
+    }
+
+    #[test]
+    fn test_built_in_pickers() {
+        let pieces = candidates(&[(5, 2), (3, 4), (9, 1), (7, 1)]);
+        assert_eq!(PickerKind::RarestFirst.build().pick(&pieces, 0), Some(7));
+        assert_eq!(PickerKind::Sequential.build().pick(&pieces, 0), Some(3));
+        assert_eq!(PickerKind::default().build().pick(&[], 0), None);
+    }
+
+    #[test]
+    fn test_random_first() {
+        let pieces = candidates(&[(0, 3), (1, 3), (2, 3), (3, 1), (4, 3), (5, 3), (6, 3), (7, 3)]);
+        let mut picker = RandomFirst { pieces: DEFAULT_RANDOM_PIECES };
+
+        // any piece will do to begin with, not just the rarest
+        let picked: std::collections::HashSet<u32> = (0..200).filter_map(|_| picker.pick(&pieces, 0)).collect();
+        assert!(picked.len() > 1);
+        assert!(picked.iter().all(|&i| i < 8));
+
+        assert_eq!(picker.pick(&pieces, DEFAULT_RANDOM_PIECES), Some(3));
+        assert_eq!(picker.pick(&[], 0), None);
+    }
+}
@@ -0,0 +1,199 @@
+use std::ops::Range;
+
+use crate::filemap::{FileMap, FileSpan};
+
+// where each piece and block of a torrent sits in its stream of bytes,
+// so nothing works the lengths out by hand. every piece is piece_length
+// long apart from the last, which holds whatever is left over, and a
+// piece's last block is cut short the same way when the block size
+// doesn't divide the piece.
+//
+// a torrent whose hashes don't add up to its size (one made up in a
+// test, say) gets empty pieces past the end of the data rather than
+// lengths that underflow.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PieceMap {
+    piece_length: u64,
+    total_size: u64,
+    num_pieces: u32,
+}
+
+impl PieceMap {
+    pub fn new(piece_length: u64, total_size: u64, num_pieces: u32) -> PieceMap {
+        PieceMap { piece_length, total_size, num_pieces }
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.num_pieces
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.total_size
+    }
+
+    // the bytes of the stream a piece covers, None past the last piece
+    pub fn piece(&self, index: u32) -> Option<Range<u64>> {
+        if index >= self.num_pieces {
+            return None;
+        }
+        let start = (index as u64).saturating_mul(self.piece_length).min(self.total_size);
+        Some(start..start.saturating_add(self.piece_length).min(self.total_size))
+    }
+
+    pub fn piece_size(&self, index: u32) -> Option<u64> {
+        self.piece(index).map(|range| range.end - range.start)
+    }
+
+    // the blocks a piece is downloaded in, as (offset in the piece,
+    // length). nothing for a piece that doesn't exist.
+    pub fn blocks(&self, index: u32, block_size: u64) -> impl Iterator<Item = (u64, u64)> {
+        assert!(block_size > 0, "blocks can't be empty");
+        let size = self.piece_size(index).unwrap_or(0);
+        (0..size.div_ceil(block_size)).map(move |i| {
+            let begin = i * block_size;
+            (begin, block_size.min(size - begin))
+        })
+    }
+
+    // the bytes of the stream a block covers, if it is all within its
+    // piece. empty blocks don't count.
+    pub fn block(&self, index: u32, begin: u64, length: u64) -> Option<Range<u64>> {
+        let piece = self.piece(index)?;
+        let end = begin.checked_add(length)?;
+        if length == 0 || end > piece.end - piece.start {
+            return None;
+        }
+        Some(piece.start + begin..piece.start + end)
+    }
+
+    // the piece the byte at offset is in
+    pub fn piece_at(&self, offset: u64) -> Option<u32> {
+        if offset >= self.total_size || self.piece_length == 0 {
+            return None;
+        }
+        u32::try_from(offset / self.piece_length).ok().filter(|&index| index < self.num_pieces)
+    }
+
+    // the pieces that hold range and nothing else, when it starts on a
+    // piece boundary and ends on one or at the end of the torrent
+    pub fn pieces_exactly(&self, range: Range<u64>) -> Option<Range<u32>> {
+        let first = self.piece_at(range.start)?;
+        let last = if range.end == self.total_size { self.num_pieces - 1 } else { self.piece_at(range.end)?.checked_sub(1)? };
+        let starts_on_boundary = self.piece(first)?.start == range.start;
+        let ends_on_boundary = self.piece(last)?.end == range.end;
+        (starts_on_boundary && ends_on_boundary && first <= last).then_some(first..last + 1)
+    }
+
+    // the files a block lands in, see FileMap::spans
+    pub fn spans(&self, files: &FileMap, index: u32, begin: u64, length: u64) -> Result<Vec<FileSpan>, String> {
+        let range = self
+            .block(index, begin, length)
+            .ok_or_else(|| format!("{} bytes at {} aren't within piece {}", length, begin, index))?;
+        files.spans(range.start, range.end - range.start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{File, Torrent};
+
+    #[test]
+    fn test_last_piece() {
+        // piece_length dividing the size or not
+        let even = PieceMap::new(4, 12, 3);
+        assert_eq!(even.piece(2), Some(8..12));
+        assert_eq!(even.piece(3), None);
+        let short = PieceMap::new(4, 10, 3);
+        assert_eq!(short.piece(2), Some(8..10));
+        assert_eq!(short.blocks(2, 4).collect::<Vec<_>>(), [(0, 2)]);
+        assert_eq!(short.blocks(3, 4).count(), 0);
+
+        // more hashes than data
+        let over = PieceMap::new(4, 5, 3);
+        assert_eq!(over.piece_size(1), Some(1));
+        assert_eq!(over.piece_size(2), Some(0));
+        assert_eq!(over.block(2, 0, 1), None);
+    }
+
+    // every layout of a small torrent: the pieces tile the data, only
+    // the last one is short, and the blocks tile each piece
+    #[test]
+    fn test_exhaustive() {
+        for piece_length in 1..=9u64 {
+            for total_size in 0..=40u64 {
+                let num_pieces = total_size.div_ceil(piece_length) as u32;
+                let map = PieceMap::new(piece_length, total_size, num_pieces);
+
+                let mut next = 0;
+                for index in 0..num_pieces {
+                    let piece = map.piece(index).unwrap();
+                    assert_eq!(piece.start, next);
+                    let size = piece.end - piece.start;
+                    assert!(size > 0 && size <= piece_length);
+                    if index + 1 < num_pieces {
+                        assert_eq!(size, piece_length);
+                    }
+                    next = piece.end;
+
+                    for block_size in 1..=piece_length + 1 {
+                        let mut offset = 0;
+                        for (begin, length) in map.blocks(index, block_size) {
+                            assert_eq!(begin, offset);
+                            assert!(length > 0 && length <= block_size);
+                            assert_eq!(map.block(index, begin, length), Some(piece.start + begin..piece.start + begin + length));
+                            offset += length;
+                        }
+                        assert_eq!(offset, size);
+                    }
+                    assert_eq!(map.block(index, 0, size + 1), None);
+                    assert_eq!(map.block(index, size, 1), None);
+                }
+                assert_eq!(next, total_size);
+                assert_eq!(map.piece(num_pieces), None);
+
+                for offset in 0..total_size {
+                    let index = map.piece_at(offset).unwrap();
+                    assert!(map.piece(index).unwrap().contains(&offset));
+                }
+                assert_eq!(map.piece_at(total_size), None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pieces_exactly() {
+        let map = PieceMap::new(4, 14, 4);
+        assert_eq!(map.pieces_exactly(0..8), Some(0..2));
+        assert_eq!(map.pieces_exactly(4..14), Some(1..4));
+        assert_eq!(map.pieces_exactly(12..14), Some(3..4));
+        assert_eq!(map.pieces_exactly(2..8), None);
+        assert_eq!(map.pieces_exactly(4..10), None);
+        assert_eq!(map.pieces_exactly(4..4), None);
+        assert_eq!(map.pieces_exactly(16..20), None);
+    }
+
+    #[test]
+    fn test_spans() {
+        let torrent = Torrent {
+            multi_file: true,
+            piece_length: 4,
+            total_size: 10,
+            pieces: vec![[0; 20]; 3],
+            output_file: "out".to_string(),
+            files: vec![File::new("a".to_string(), 6), File::new("b".to_string(), 4)],
+            ..Default::default()
+        };
+        let map = torrent.piece_map();
+        let files = FileMap::new(&torrent);
+
+        let spans = map.spans(&files, 1, 0, 4).unwrap();
+        assert_eq!(spans, [
+            FileSpan { file: 0, file_offset: 4, range: 0..2 },
+            FileSpan { file: 1, file_offset: 0, range: 2..4 },
+        ]);
+        assert_eq!(map.spans(&files, 2, 1, 1).unwrap(), [FileSpan { file: 1, file_offset: 3, range: 0..1 }]);
+        assert!(map.spans(&files, 2, 0, 3).is_err());
+    }
+}
@@ -0,0 +1,274 @@
+use std::time::{Duration, Instant};
+
+// asking for one block at a time means waiting a full round trip
+// between blocks, which caps a peer at block size / latency no matter
+// how fast it is. instead each connection keeps several requests in
+// flight: enough to cover QUEUE_TIME worth of data at the peer's
+// measured speed, so slow peers don't hoard blocks and fast ones are
+// never left idle.
+//
+// a peer that leaves everything we asked for unanswered for a minute is
+// snubbing us, as mainline calls it. it only gets one request at a time
+// after that, and the piece manager leaves to other peers the pieces
+// they have too. there is no optimistic unchoke to give it another
+// chance, the one request is it, and the first block it does send makes
+// up for everything.
+
+// how much data, measured in time at the peer's current rate, to keep
+// requested ahead
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+
+const MIN_DEPTH: usize = 2;
+// unless set otherwise, see RequestTuning
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Request {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+// the requests we have sent one peer that haven't been answered yet
+#[derive(Debug)]
+pub struct Pipeline {
+    // each with when it was sent
+    outstanding: Vec<(Request, Instant)>,
+    depth: usize,
+    max_depth: usize,
+    last_block: Option<Instant>,
+    snubbed: bool,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::with_max_depth(DEFAULT_MAX_DEPTH)
+    }
+
+    // never more than max_depth requests in flight, however fast the
+    // peer is
+    pub fn with_max_depth(max_depth: usize) -> Pipeline {
+        Pipeline { outstanding: Vec::new(), depth: MIN_DEPTH.min(max_depth), max_depth, last_block: None, snubbed: false }
+    }
+
+    // number of requests to keep in flight for a peer sending us
+    // rate bytes per second
+    pub fn depth_for_rate(rate: f64, block_size: u32, max_depth: usize) -> usize {
+        let blocks = (rate * QUEUE_TIME.as_secs_f64() / block_size as f64).ceil();
+        (blocks as usize).clamp(MIN_DEPTH.min(max_depth), max_depth)
+    }
+
+    pub fn update_depth(&mut self, rate: f64, block_size: u32) {
+        self.depth = if self.snubbed { 1 } else { Pipeline::depth_for_rate(rate, block_size, self.max_depth) };
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.outstanding.len() < self.depth
+    }
+
+    pub fn add(&mut self, request: Request) {
+        self.add_at(request, Instant::now());
+    }
+
+    pub fn add_at(&mut self, request: Request, now: Instant) {
+        self.outstanding.push((request, now));
+    }
+
+    // the peer sent us a block. returns how long it took, or None if we
+    // never asked for it.
+    pub fn complete(&mut self, index: u32, begin: u32) -> Option<Duration> {
+        self.complete_at(index, begin, Instant::now())
+    }
+
+    // with several requests in flight a block also waits for the ones
+    // ahead of it, so the clock only starts once the previous block is in
+    pub fn complete_at(&mut self, index: u32, begin: u32, now: Instant) -> Option<Duration> {
+        let pos = self.outstanding.iter().position(|(r, _)| r.index == index && r.begin == begin)?;
+        let (_, sent) = self.outstanding.remove(pos);
+        self.snubbed = false;
+        let start = self.last_block.map_or(sent, |last| last.max(sent));
+        self.last_block = Some(now);
+        Some(now.saturating_duration_since(start))
+    }
+
+    // when the peer counts as snubbing us if nothing has arrived by
+    // then: a minute after the last block, or after the oldest request
+    // if that was sent since. None while nothing is outstanding, or
+    // once it is snubbing us.
+    pub fn snub_deadline(&self) -> Option<Instant> {
+        if self.snubbed {
+            return None;
+        }
+        let oldest = self.outstanding.iter().map(|(_, sent)| *sent).min()?;
+        Some(self.last_block.map_or(oldest, |last| last.max(oldest)) + SNUB_TIMEOUT)
+    }
+
+    // returns true if the peer has only now started snubbing us
+    pub fn check_snubbed(&mut self, now: Instant) -> bool {
+        if self.snub_deadline().is_some_and(|at| at <= now) {
+            self.snub();
+            return true;
+        }
+        false
+    }
+
+    // for a peer that left a request unanswered until it expired, see
+    // PieceManager::expire_requests
+    pub fn snub(&mut self) {
+        self.snubbed = true;
+        self.depth = 1;
+    }
+
+    // stops waiting on a request without it counting as answered.
+    // false if it wasn't outstanding.
+    pub fn cancel(&mut self, index: u32, begin: u32) -> bool {
+        let before = self.outstanding.len();
+        self.outstanding.retain(|(r, _)| r.index != index || r.begin != begin);
+        self.outstanding.len() < before
+    }
+
+    pub fn is_snubbed(&self) -> bool {
+        self.snubbed
+    }
+
+    // a choke means the peer has dropped everything we asked for. a peer
+    // that was snubbing us still is until it sends something.
+    pub fn clear(&mut self) {
+        self.outstanding.clear();
+        self.last_block = None;
+    }
+
+    pub fn len(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: u32 = 16384;
+
+    #[test]
+    fn test_depth_follows_rate() {
+        assert_eq!(Pipeline::depth_for_rate(0.0, BLOCK, DEFAULT_MAX_DEPTH), MIN_DEPTH);
+        // 64 KiB/s over three seconds is twelve blocks
+        assert_eq!(Pipeline::depth_for_rate(65536.0, BLOCK, DEFAULT_MAX_DEPTH), 12);
+        assert_eq!(Pipeline::depth_for_rate(100_000_000.0, BLOCK, DEFAULT_MAX_DEPTH), DEFAULT_MAX_DEPTH);
+        // or twenty four of half the size
+        assert_eq!(Pipeline::depth_for_rate(65536.0, BLOCK / 2, DEFAULT_MAX_DEPTH), 24);
+    }
+
+    #[test]
+    fn test_max_depth() {
+        assert_eq!(Pipeline::depth_for_rate(100_000_000.0, BLOCK, 200), 200);
+        assert_eq!(Pipeline::depth_for_rate(0.0, BLOCK, 1), 1);
+
+        let mut pipeline = Pipeline::with_max_depth(1);
+        pipeline.add(Request { index: 0, begin: 0, length: BLOCK });
+        assert!(!pipeline.has_room());
+        pipeline.update_depth(100_000_000.0, BLOCK);
+        assert_eq!(pipeline.depth(), 1);
+    }
+
+    #[test]
+    fn test_room_and_completion() {
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Request { index: 0, begin: 0, length: BLOCK });
+        assert!(pipeline.has_room());
+        pipeline.add(Request { index: 0, begin: BLOCK, length: BLOCK });
+        assert!(!pipeline.has_room());
+
+        assert!(pipeline.complete(0, 0).is_some());
+        assert!(pipeline.complete(0, 0).is_none());
+        assert!(pipeline.has_room());
+
+        pipeline.update_depth(65536.0, BLOCK);
+        assert_eq!(pipeline.depth(), 12);
+
+        pipeline.clear();
+        assert!(pipeline.is_empty());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Request { index: 0, begin: 0, length: BLOCK });
+        pipeline.add(Request { index: 0, begin: BLOCK, length: BLOCK });
+        assert!(pipeline.cancel(0, BLOCK));
+        assert!(!pipeline.cancel(0, BLOCK));
+        assert_eq!(pipeline.len(), 1);
+
+        pipeline.snub();
+        assert!(pipeline.is_snubbed() && !pipeline.has_room());
+        assert_eq!(pipeline.snub_deadline(), None);
+        // a block makes up for it as usual
+        assert!(pipeline.complete(0, 0).is_some());
+        assert!(!pipeline.is_snubbed());
+    }
+
+    #[test]
+    fn test_block_latency() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut pipeline = Pipeline::new();
+        pipeline.add_at(Request { index: 0, begin: 0, length: BLOCK }, at(0));
+        pipeline.add_at(Request { index: 0, begin: BLOCK, length: BLOCK }, at(0));
+
+        assert_eq!(pipeline.complete_at(0, 0, at(100)), Some(Duration::from_millis(100)));
+        // the second block was queued behind the first
+        assert_eq!(pipeline.complete_at(0, BLOCK, at(150)), Some(Duration::from_millis(50)));
+
+        // a request sent after the last block counts from when it was sent
+        pipeline.add_at(Request { index: 1, begin: 0, length: BLOCK }, at(400));
+        assert_eq!(pipeline.complete_at(1, 0, at(420)), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn test_snubbed() {
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+        let mut pipeline = Pipeline::new();
+        assert_eq!(pipeline.snub_deadline(), None);
+
+        pipeline.add_at(Request { index: 0, begin: 0, length: BLOCK }, secs(0));
+        pipeline.add_at(Request { index: 0, begin: BLOCK, length: BLOCK }, secs(10));
+        assert_eq!(pipeline.complete_at(0, 0, secs(30)), Some(Duration::from_secs(30)));
+        // a minute from the last block
+        assert_eq!(pipeline.snub_deadline(), Some(secs(90)));
+        assert!(!pipeline.check_snubbed(secs(89)));
+        assert!(pipeline.check_snubbed(secs(90)));
+        assert!(!pipeline.check_snubbed(secs(200)));
+        pipeline.update_depth(65536.0, BLOCK);
+        assert_eq!(pipeline.depth(), 1);
+
+        // a choke doesn't make up for it, a block does
+        pipeline.clear();
+        assert!(pipeline.is_snubbed());
+        pipeline.add_at(Request { index: 1, begin: 0, length: BLOCK }, secs(300));
+        pipeline.complete_at(1, 0, secs(301));
+        assert!(!pipeline.is_snubbed());
+        pipeline.update_depth(65536.0, BLOCK);
+        assert_eq!(pipeline.depth(), 12);
+    }
+}
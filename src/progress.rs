@@ -0,0 +1,94 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+// how far along a torrent is, counted in the pieces we want. for now
+// that is every piece, files can't be left out yet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub have: usize,
+    pub wanted: usize,
+}
+
+impl Progress {
+    pub fn fraction(&self) -> f64 {
+        if self.wanted == 0 {
+            0.0
+        } else {
+            self.have as f64 / self.wanted as f64
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.wanted > 0 && self.have >= self.wanted
+    }
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{} pieces downloaded ({:.2}%)", self.have, self.wanted, self.fraction() * 100.0)
+    }
+}
+
+// decides when progress is worth passing on, so whatever shows it (the
+// log, a terminal ui, event listeners) isn't told about every piece.
+// reports at most once per interval and never repeats itself, except
+// that finishing is always reported straight away.
+#[derive(Debug)]
+pub struct ProgressReporter {
+    interval: Duration,
+    last: Option<(Instant, Progress)>,
+}
+
+impl ProgressReporter {
+    pub fn new(interval: Duration) -> ProgressReporter {
+        ProgressReporter { interval, last: None }
+    }
+
+    // returns the progress if it should be reported now
+    pub fn update(&mut self, progress: Progress, now: Instant) -> Option<Progress> {
+        if let Some((at, last)) = self.last {
+            let due = now.duration_since(at) >= self.interval || (progress.is_done() && !last.is_done());
+            if last == progress || !due {
+                return None;
+            }
+        }
+
+        self.last = Some((now, progress));
+        Some(progress)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let progress = Progress { have: 1, wanted: 3 };
+        assert_eq!(progress.to_string(), "1/3 pieces downloaded (33.33%)");
+        assert_eq!(Progress { have: 0, wanted: 0 }.fraction(), 0.0);
+        assert!(!progress.is_done());
+        assert!(Progress { have: 3, wanted: 3 }.is_done());
+    }
+
+    #[test]
+    fn test_throttling() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut reporter = ProgressReporter::new(Duration::from_secs(5));
+        let progress = |have| Progress { have, wanted: 10 };
+
+        assert_eq!(reporter.update(progress(1), at(0)), Some(progress(1)));
+        assert_eq!(reporter.update(progress(2), at(1)), None);
+        assert_eq!(reporter.update(progress(3), at(5)), Some(progress(3)));
+
+        // nothing changed, so nothing to say
+        assert_eq!(reporter.update(progress(3), at(20)), None);
+
+        // finishing doesn't wait for the interval
+        assert_eq!(reporter.update(progress(10), at(21)), Some(progress(10)));
+        assert_eq!(reporter.update(progress(10), at(22)), None);
+    }
+}
@@ -1,10 +1,37 @@
-use std::collections::VecDeque;
-use std::error::Error;
-use std::thread::JoinHandle;
-use tokio::io::{BufReader, BufWriter};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use std::io::{self, ErrorKind};
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex};
+use std::time::{Duration, Instant};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::info;
+use tokio::io::{split, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, ReadHalf, WriteHalf};
+use tokio_util::codec::{Decoder, Encoder};
+use tokio::sync::{broadcast::{self, error::RecvError}, watch};
+use tokio::time::{sleep, sleep_until, timeout};
 
-use crate::client::PieceManager;
+use crate::{
+    banlist::BanList,
+    choker::UploadChoker,
+    churn::Evictions,
+    codec::MessageCodec,
+    disk::DiskHandle,
+    dht::DhtState,
+    error::BtError,
+    hashing::Verifier,
+    info_hash::InfoHash,
+    client::{ExpiredRequest, PeerContext, PeerRegistry, PieceManager},
+    interest::InterestManager,
+    listener::{Incoming, IncomingQueue},
+    peer_id::{identify_client, ClientInfo},
+    peer_pool::PeerPool,
+    peer_state::{Active, Bitfield, Handshaking, PeerState},
+    pex::display_addr,
+    pipeline::{Pipeline, Request},
+    session::PeerInfo,
+    shaper::{PeerLimits, SessionShaper, Shaper},
+    sources::{PeerSource, PeerSources},
+    transport::{BoxedStream, PeerTransport},
+    warnings,
+};
 
 // in version 1.0 of the bittorrent protocol the 
 // handshake message has a length of 68
@@ -13,22 +40,104 @@ use crate::client::PieceManager;
 // pstrlen = 19, pstr = "BitTorrent protocol"
 // thus the length is 49 + 19
 
-const HANDSHAKE_LENGTH: usize = 49 + 19; 
+pub const HANDSHAKE_LENGTH: usize = 49 + 19;
 
+// once connected peers send a keep-alive at least every two minutes,
+// so anything quiet for longer than that is gone
+const MESSAGE_TIMEOUT: Duration = Duration::from_secs(120);
 
+// we send ours a bit more often than that, so a slow write doesn't get
+// us dropped by a peer using the same two minute limit
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(100);
+
+// a peer connection takes addresses from the torrent's peer pool and downloads
+// from them one at a time until the torrent is stopped. peers that
+// dialed us come first, they are already waiting on an answer.
 pub struct PeerConnection {
-    state: Vec<u8>,
-    peer_state: Vec<u8>,
-    queue: VecDeque<u8>,
-    info_hash: Vec<u8>,
+    state: PeerState,
+    // set once the peer has sent a valid message, so a peer that never
+    // spoke bittorrent can be told from one that dropped later
+    established: bool,
+    peers: Arc<Mutex<PeerPool>>,
+    evictions: Evictions,
+    // set when the connection was hung up on from the evictions
+    evicted: bool,
+    // set when the peer at the other end turned out to be us
+    ourselves: bool,
+    incoming: IncomingQueue,
+    info_hash: InfoHash,
     peer_id: String,
+    // the peer's id in hex, which everything about the peer is kept
+    // under. ids are 20 arbitrary bytes and often not utf-8, a lossy
+    // string could make two peers one.
     remote_id: String,
-    reader: Option<BufReader<OwnedReadHalf>>,
-    writer: Option<BufWriter<OwnedWriteHalf>>,
-    piece_manager: PieceManager,
-    future: Option<JoinHandle<()>>
+    // and as text, only for showing
+    remote_name: String,
+    // the peer's ip, and ip:port for showing it
+    ip: String,
+    address: String,
+    client: Option<ClientInfo>,
+    pipeline: Pipeline,
+    // the flags and request count last put in connected, for the peer list
+    published: (PeerState, usize),
+    num_pieces: usize,
+    transport: Arc<dyn PeerTransport>,
+    reader: Option<BufReader<ReadHalf<BoxedStream>>>,
+    writer: Option<BufWriter<WriteHalf<BoxedStream>>>,
+    // bytes read but not yet made into messages
+    buffer: BytesMut,
+    codec: MessageCodec,
+    // when we last sent the peer anything, keep-alives included
+    last_write: tokio::time::Instant,
+    connect_timeout: Duration,
+    handshake_timeout: Duration,
+    piece_manager: Arc<Mutex<PieceManager>>,
+    interest: Arc<Mutex<InterestManager>>,
+    uploads: Arc<Mutex<UploadChoker>>,
+    connected: PeerRegistry,
+    bans: Arc<Mutex<BanList>>,
+    sources: Arc<Mutex<PeerSources>>,
+    haves: broadcast::Sender<u32>,
+    expired: broadcast::Sender<ExpiredRequest>,
+    verifier: Verifier,
+    disk: DiskHandle,
+    abort: Arc<AtomicBool>,
+    dht: Arc<AtomicBool>,
+    dht_state: Arc<Mutex<DhtState>>,
+    dht_port: u16,
+    // both ends set the dht bit in their handshake
+    peer_dht: bool,
+    // bytes read and written since they were last added to the stats
+    received: u64,
+    sent: u64,
+    // this peer's caps, and when it has used up enough of them that the
+    // next read or write has to wait
+    peer_limits: watch::Receiver<PeerLimits>,
+    shaper: Shaper,
+    // and the session's, shared with every other connection
+    session_limits: SessionShaper,
+    read_at: tokio::time::Instant,
+    write_at: tokio::time::Instant,
+}
+
+// what an established connection waits on besides the peer
+struct Events {
+    haves: broadcast::Receiver<u32>,
+    expired: broadcast::Receiver<ExpiredRequest>,
+    changes: watch::Receiver<u64>,
+    // when the peer last sent anything
+    last_message: tokio::time::Instant,
 }
 
+// what woke a connection up
+enum Turn {
+    Message(Message),
+    // anything else, and whether it could change our interest in the
+    // peer or what we can request from it
+    Woken(bool),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MessageType {
     Choke = 0,
     Unchoke = 1,
@@ -42,70 +151,1091 @@ pub enum MessageType {
     Port = 9,
 }
 
+impl TryFrom<u8> for MessageType {
+    type Error = String;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        Ok(match id {
+            0 => MessageType::Choke,
+            1 => MessageType::Unchoke,
+            2 => MessageType::Interested,
+            3 => MessageType::NotInterested,
+            4 => MessageType::Have,
+            5 => MessageType::Bitfield,
+            6 => MessageType::Request,
+            7 => MessageType::Piece,
+            8 => MessageType::Cancel,
+            9 => MessageType::Port,
+            _ => return Err(format!("unknown message id {}", id)),
+        })
+    }
+}
+
+// every message after the handshake has the format:
+// <length prefix><message id><payload>
+// the length prefix is a 4 byte big endian value and a
+// length of 0 (no id, no payload) is a keep-alive.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request { index: u32, begin: u32, length: u32 },
+    // a slice of the buffer the message was read into, so a block is
+    // never copied on its way from the socket to the file
+    Piece { index: u32, begin: u32, block: Bytes },
+    Cancel { index: u32, begin: u32, length: u32 },
+    Port(u16),
+}
+
+// bep 5: the last bit of the reserved bytes says the peer runs a dht
+// node and will send a Port message with its udp port
+const DHT_BIT: u8 = 0x01;
+
 pub struct Handshake {
-    info_hash: Vec<u8>,
+    reserved: [u8; 8],
+    info_hash: InfoHash,
     peer_id: Vec<u8>
 }
 
 impl Handshake {
     // create new handshake from peer id and info hash
-    pub fn new(info_hash: Vec<u8>, peer_id: Vec<u8>) -> Result<Handshake, Box<dyn Error>> {
-        if info_hash.len() != 20 {
-            return Err("info hash is not of the correct length!".into())
-        }
-
+    pub fn new(info_hash: InfoHash, peer_id: Vec<u8>) -> Result<Handshake, BtError> {
         if peer_id.len() != 20 {
-            return Err("peer id is not of the correct length!".into())
+            return Err(BtError::Protocol("peer id is not of the correct length!".to_string()))
         }
 
         Ok(Handshake {
+            reserved: [0; 8],
             info_hash,
             peer_id
         })
     }
 
+    pub fn with_dht(mut self, enabled: bool) -> Handshake {
+        if enabled {
+            self.reserved[7] |= DHT_BIT;
+        } else {
+            self.reserved[7] &= !DHT_BIT;
+        }
+        self
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & DHT_BIT != 0
+    }
+
+    pub fn info_hash(&self) -> &InfoHash {
+        &self.info_hash
+    }
+
+    pub fn peer_id(&self) -> &[u8] {
+        &self.peer_id
+    }
+
     pub fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(68);
         buf.push(19); // pstrlen
         buf.extend_from_slice(b"BitTorrent protocol"); // pstr
-        buf.extend_from_slice(&[0u8; 8]); // reserved bytes
-        buf.extend_from_slice(&self.info_hash); // info hash
+        buf.extend_from_slice(&self.reserved); // reserved bytes
+        buf.extend_from_slice(self.info_hash.as_bytes()); // info hash
         buf.extend_from_slice(&self.peer_id); // peer _id
         buf
     }
 
-    pub fn decode(data: &[u8]) -> Result<Handshake, Box<dyn Error>> {
+    pub fn decode(data: &[u8]) -> Result<Handshake, BtError> {
         if data.len() != HANDSHAKE_LENGTH {
-            return Err("invalid handshake length".into());
+            return Err(BtError::Protocol("invalid handshake length".to_string()));
         }
 
         let pstrlen = data[0];
         if pstrlen != 19 {
-            return Err("invalid pstrlen".into())
+            return Err(BtError::Protocol("invalid pstrlen".to_string()))
         }
 
         let pstr = &data[1..20];
         if pstr != b"BitTorrent protocol" {
-            return Err("invalid protocol string".into());
+            return Err(BtError::Protocol("invalid protocol string".to_string()));
         }
 
-        let info_hash = data[28..48].to_vec();
+        let info_hash = InfoHash::from_bytes(&data[28..48]).map_err(BtError::Protocol)?;
         let peer_id = data[48..68].to_vec();
 
-        Handshake::new(info_hash, peer_id)
+        let mut handshake = Handshake::new(info_hash, peer_id)?;
+        handshake.reserved.copy_from_slice(&data[20..28]);
+        Ok(handshake)
+    }
+}
+
+impl Message {
+    pub fn message_type(&self) -> Option<MessageType> {
+        match self {
+            Message::KeepAlive => None,
+            Message::Choke => Some(MessageType::Choke),
+            Message::Unchoke => Some(MessageType::Unchoke),
+            Message::Interested => Some(MessageType::Interested),
+            Message::NotInterested => Some(MessageType::NotInterested),
+            Message::Have(_) => Some(MessageType::Have),
+            Message::Bitfield(_) => Some(MessageType::Bitfield),
+            Message::Request { .. } => Some(MessageType::Request),
+            Message::Piece { .. } => Some(MessageType::Piece),
+            Message::Cancel { .. } => Some(MessageType::Cancel),
+            Message::Port(_) => Some(MessageType::Port),
+        }
+    }
+
+    // encodes the message including its length prefix
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        self.encode_into(&mut buf);
+        buf.to_vec()
+    }
+
+    // appends the encoded message to dst. the payload goes straight in,
+    // without being put together somewhere else first.
+    pub fn encode_into(&self, dst: &mut BytesMut) {
+        let payload_len = match self {
+            Message::KeepAlive => {
+                dst.put_u32(0);
+                return;
+            }
+            Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => 0,
+            Message::Have(_) => 4,
+            Message::Bitfield(bitfield) => bitfield.len(),
+            Message::Request { .. } | Message::Cancel { .. } => 12,
+            Message::Piece { block, .. } => 8 + block.len(),
+            Message::Port(_) => 2,
+        };
+
+        dst.reserve(5 + payload_len);
+        dst.put_u32(payload_len as u32 + 1);
+        if let Some(id) = self.message_type() {
+            dst.put_u8(id as u8);
+        }
+
+        match self {
+            Message::KeepAlive => {}
+            Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => {}
+            Message::Have(index) => dst.put_u32(*index),
+            Message::Bitfield(bitfield) => dst.extend_from_slice(bitfield),
+            Message::Request { index, begin, length } | Message::Cancel { index, begin, length } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.put_u32(*length);
+            }
+            Message::Piece { index, begin, block } => {
+                dst.put_u32(*index);
+                dst.put_u32(*begin);
+                dst.extend_from_slice(block);
+            }
+            Message::Port(port) => dst.put_u16(*port),
+        }
+    }
+
+    // decodes exactly one message (length prefix included).
+    // trailing or missing bytes are treated as an error.
+    pub fn decode(data: &[u8]) -> Result<Message, BtError> {
+        if data.len() < 4 {
+            return Err(BtError::Protocol("message is shorter than its length prefix".to_string()));
+        }
+
+        let length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if data.len() - 4 != length {
+            return Err(BtError::Protocol(format!("length prefix {} doesn't match message length {}", length, data.len() - 4)));
+        }
+
+        if length == 0 {
+            return Ok(Message::KeepAlive);
+        }
+
+        Message::from_payload(data[4], Bytes::copy_from_slice(&data[5..]))
+    }
+
+    // builds a message from its id and the payload following it. a
+    // piece's block ends up sharing the payload's memory.
+    pub fn from_payload(id: u8, payload: Bytes) -> Result<Message, BtError> {
+        let message_type = MessageType::try_from(id).map_err(BtError::Protocol)?;
+
+        let expect_len = |len: usize| -> Result<(), BtError> {
+            if payload.len() != len {
+                return Err(BtError::Protocol(format!("{:?} payload should be {} bytes, got {}", message_type, len, payload.len())));
+            }
+            Ok(())
+        };
+
+        let read_u32 = |at: usize| u32::from_be_bytes([payload[at], payload[at + 1], payload[at + 2], payload[at + 3]]);
+
+        let message = match message_type {
+            MessageType::Choke => { expect_len(0)?; Message::Choke }
+            MessageType::Unchoke => { expect_len(0)?; Message::Unchoke }
+            MessageType::Interested => { expect_len(0)?; Message::Interested }
+            MessageType::NotInterested => { expect_len(0)?; Message::NotInterested }
+            MessageType::Have => {
+                expect_len(4)?;
+                Message::Have(read_u32(0))
+            }
+            MessageType::Bitfield => Message::Bitfield(payload.to_vec()),
+            MessageType::Request => {
+                expect_len(12)?;
+                Message::Request { index: read_u32(0), begin: read_u32(4), length: read_u32(8) }
+            }
+            MessageType::Piece => {
+                if payload.len() < 8 {
+                    return Err(BtError::Protocol("piece payload is missing index or begin".to_string()));
+                }
+                Message::Piece { index: read_u32(0), begin: read_u32(4), block: payload.slice(8..) }
+            }
+            MessageType::Cancel => {
+                expect_len(12)?;
+                Message::Cancel { index: read_u32(0), begin: read_u32(4), length: read_u32(8) }
+            }
+            MessageType::Port => {
+                expect_len(2)?;
+                Message::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+        };
+
+        Ok(message)
+    }
+}
+
+impl PeerConnection {
+    pub fn new(peer_id: String, transport: Arc<dyn PeerTransport>, context: PeerContext) -> PeerConnection {
+        let PeerContext { peers, evictions, incoming, piece_manager, interest, uploads, connected, bans, sources, haves, abort, dht, dht_state, dht_port, connect_timeout, handshake_timeout, verifier, disk, max_requests_per_peer, expired, peer_limits, session_limits } = context;
+        let (info_hash, num_pieces) = {
+            let pm = piece_manager.lock().unwrap();
+            (pm.torrent().info_hash, pm.torrent().pieces.len())
+        };
+
+        PeerConnection {
+            state: PeerState::default(),
+            established: false,
+            peers,
+            evictions,
+            evicted: false,
+            ourselves: false,
+            incoming,
+            info_hash,
+            peer_id,
+            remote_id: String::new(),
+            remote_name: String::new(),
+            ip: String::new(),
+            address: String::new(),
+            client: None,
+            pipeline: Pipeline::with_max_depth(max_requests_per_peer),
+            published: (PeerState::default(), 0),
+            num_pieces,
+            transport,
+            reader: None,
+            writer: None,
+            buffer: BytesMut::new(),
+            codec: MessageCodec::for_torrent(num_pieces),
+            last_write: tokio::time::Instant::now(),
+            connect_timeout,
+            handshake_timeout,
+            piece_manager,
+            interest,
+            uploads,
+            connected,
+            bans,
+            sources,
+            haves,
+            expired,
+            verifier,
+            disk,
+            abort,
+            dht,
+            dht_state,
+            dht_port,
+            peer_dht: false,
+            received: 0,
+            sent: 0,
+            peer_limits,
+            shaper: Shaper::default(),
+            session_limits,
+            read_at: tokio::time::Instant::now(),
+            write_at: tokio::time::Instant::now(),
+        }
+    }
+
+    pub async fn start(&mut self) {
+        while !self.abort.load(Ordering::Relaxed) {
+            let incoming = self.incoming.lock().unwrap().pop_front();
+            let ((ip, port), result) = match incoming {
+                // the peer will have given up on one left too long
+                Some(peer) if peer.arrived.elapsed() > self.handshake_timeout => continue,
+                Some(peer) => (peer.addr.clone(), self.accept_from(peer).await),
+                None => {
+                    let next = self.peers.lock().unwrap().next(Instant::now());
+                    let Some((ip, port)) = next else {
+                        sleep(Duration::from_secs(1)).await;
+                        continue;
+                    };
+                    if self.bans.lock().unwrap().is_banned(&ip) {
+                        self.peers.lock().unwrap().failed(&(ip, port), Instant::now());
+                        continue;
+                    }
+                    let result = self.download_from(&ip, port).await;
+                    // only addresses we dialed came from the pool
+                    let mut peers = self.peers.lock().unwrap();
+                    if self.ourselves {
+                        info!("{} is us, not trying it again", display_addr(&ip, port));
+                        peers.exclude(&(ip.clone(), port));
+                    } else if self.evicted {
+                        peers.dropped(&(ip.clone(), port), Instant::now());
+                    } else if self.established {
+                        peers.connected(&(ip.clone(), port), Instant::now());
+                    } else if peers.failed(&(ip.clone(), port), Instant::now()) {
+                        info!("giving up on {}", display_addr(&ip, port));
+                    }
+                    drop(peers);
+                    ((ip, port), result)
+                }
+            };
+            if let Err(e) = &result {
+                info!("connection to {}:{} closed: {}", ip, port, e);
+            }
+
+            // peers that take the connection and never get as far as a
+            // valid message are counted against their address. one that
+            // can't be reached at all may just be offline, the pool backs
+            // off from it without banning it for every torrent. nor is a
+            // peer we already have a connection to at fault.
+            let reached = self.reader.is_some();
+            let duplicate = matches!(&result, Err(e) if e.kind() == ErrorKind::AlreadyExists);
+            let mut bans = self.bans.lock().unwrap();
+            match result {
+                Err(e) if reached && !duplicate && !self.established && !self.ourselves => {
+                    if bans.record_failure(&ip, "keeps failing the handshake", Instant::now()) {
+                        info!("banning {} for a while: {}", ip, e);
+                    }
+                }
+                _ => bans.record_success(&ip),
+            }
+            drop(bans);
+
+            self.cleanup();
+        }
+    }
+
+    async fn download_from(&mut self, ip: &str, port: u16) -> io::Result<()> {
+        self.ip = ip.to_string();
+        self.address = display_addr(ip, port);
+        timeout(self.connect_timeout, self.connect(ip, port))
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "connect timed out"))??;
+        let phase = timeout(self.handshake_timeout, self.handshake(Handshaking::start()))
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "handshake timed out"))??;
+        let sources = self.peers.lock().unwrap().sources(&(ip.to_string(), port));
+        self.established(phase, ip, port, sources).await
+    }
+
+    // the listener has already read the peer's handshake, only ours is
+    // left to send
+    async fn accept_from(&mut self, peer: Incoming) -> io::Result<()> {
+        let Incoming { stream, addr: (ip, port), handshake, .. } = peer;
+        self.ip = ip.clone();
+        self.address = display_addr(&ip, port);
+        let (reader, writer) = split(stream);
+        self.reader = Some(BufReader::new(reader));
+        self.writer = Some(BufWriter::new(writer));
+        self.received += HANDSHAKE_LENGTH as u64;
+
+        self.sources.lock().unwrap().record_discovered(PeerSource::Incoming, &[(ip.clone(), port)]);
+        timeout(self.handshake_timeout, self.send_handshake())
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "handshake timed out"))??;
+        let phase = self.handshake_received(Handshaking::start(), &handshake)?;
+        self.established(phase, &ip, port, vec![PeerSource::Incoming]).await
+    }
+
+    // everything after the handshakes, whoever dialed whom
+    async fn established(&mut self, phase: Bitfield, ip: &str, port: u16, sources: Vec<PeerSource>) -> io::Result<()> {
+        {
+            // the same peer at another address, or dialing us while we
+            // dial it. the connection that got there first keeps it, and
+            // this one mustn't tear down its state on the way out.
+            let mut connected = self.connected.lock().unwrap();
+            if connected.contains_key(&self.remote_id) {
+                self.remote_id.clear();
+                return Err(io::Error::new(ErrorKind::AlreadyExists, "already connected to this peer"));
+            }
+            connected.insert(self.remote_id.clone(), PeerInfo {
+                peer_id: self.remote_name.clone(),
+                address: self.address.clone(),
+                client: self.client.as_ref().map(ClientInfo::to_string),
+                sources,
+                pieces: 0,
+                stats: Default::default(),
+                snubbed: false,
+                country: None,
+                state: self.state,
+                requests: self.pipeline.len(),
+                limits: Default::default(),
+            });
+            self.published = (self.state, self.pipeline.len());
+        }
+        self.update_shaper();
+        self.sources.lock().unwrap().record_connected(&(ip.to_string(), port));
+
+        // a peer with nothing yet may skip the bitfield and only ever send
+        // haves, so it starts out with no pieces. a bitfield replaces this.
+        self.piece_manager.lock().unwrap().add_peer(self.remote_id.clone(), vec![0; self.num_pieces]);
+
+        // subscribed before the bitfield is built, so a piece verified in
+        // between is announced twice rather than not at all
+        let haves = self.haves.subscribe();
+        let expired = self.expired.subscribe();
+
+        // let the peer know what we can give them. super seeds hand out
+        // one piece at a time instead.
+        let (bitfield, super_seeding) = {
+            let pm = self.piece_manager.lock().unwrap();
+            (pm.bitfield(self.num_pieces), pm.is_super_seeding())
+        };
+        if super_seeding {
+            self.offer_super_seed_piece().await?;
+        } else if bitfield.contains(&1) {
+            self.send(Message::Bitfield(pack_bitfield(&bitfield))).await?;
+        }
+
+        // there is no dht node of our own yet, but peers that run one
+        // are told where it would be
+        if self.peer_dht {
+            self.send(Message::Port(self.dht_port)).await?;
+        }
+
+        // interest changes made elsewhere (the rotation task, another
+        // connection finishing a piece) wake us up through this
+        let changes = self.interest.lock().unwrap().subscribe();
+        let mut events = Events { haves, expired, changes, last_message: tokio::time::Instant::now() };
+
+        // a bitfield is only allowed as the peer's first message, so the
+        // turns until that arrives are taken with the Bitfield in hand
+        let active = loop {
+            if self.abort.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let (active, woken) = match self.next_turn(&mut events).await? {
+                Turn::Message(Message::Bitfield(bitfield)) => (self.bitfield_received(phase, &bitfield)?, true),
+                Turn::Message(message) => {
+                    let active = phase.message_received();
+                    let woken = self.handle_message(&active, message).await?;
+                    (active, woken)
+                }
+                Turn::Woken(woken) => {
+                    self.end_turn(ip, woken).await?;
+                    continue;
+                }
+            };
+            self.established = true;
+            self.end_turn(ip, woken).await?;
+            break active;
+        };
+
+        while !self.abort.load(Ordering::Relaxed) {
+            let woken = match self.next_turn(&mut events).await? {
+                Turn::Message(message) => self.handle_message(&active, message).await?,
+                Turn::Woken(woken) => woken,
+            };
+            self.end_turn(ip, woken).await?;
+        }
+
+        Ok(())
+    }
+
+    // waits for whatever the connection has to deal with next. messages
+    // go back to the caller to be handled in the phase it is in, the
+    // rest is dealt with here.
+    async fn next_turn(&mut self, events: &mut Events) -> io::Result<Turn> {
+        let deadline = events.last_message + MESSAGE_TIMEOUT;
+        let keep_alive = self.last_write + KEEP_ALIVE_INTERVAL;
+        let snub_at = self.pipeline.snub_deadline().map(tokio::time::Instant::from_std);
+
+        let woken = tokio::select! {
+            message = self.read_message() => {
+                events.last_message = tokio::time::Instant::now();
+                return Ok(Turn::Message(message?));
+            }
+            changed = events.changes.changed() => {
+                changed.map_err(|_| io::Error::new(ErrorKind::NotConnected, "torrent was stopped"))?;
+                true
+            }
+            have = events.haves.recv() => {
+                match have {
+                    Ok(index) => self.send(Message::Have(index)).await?,
+                    Err(RecvError::Lagged(missed)) => {
+                        warnings::warn("have backlog", || format!("{} fell behind and skipped {} have messages", self.address, missed));
+                    }
+                    Err(RecvError::Closed) => return Err(io::Error::new(ErrorKind::NotConnected, "torrent was stopped")),
+                }
+                false
+            }
+            request = events.expired.recv() => {
+                match request {
+                    Ok(request) if request.peer_id == self.remote_id => {
+                        self.request_expired(request).await?;
+                        true
+                    }
+                    Ok(_) => false,
+                    Err(RecvError::Lagged(missed)) => {
+                        warnings::warn("expiry backlog", || format!("{} fell behind and skipped {} expired requests", self.address, missed));
+                        false
+                    }
+                    Err(RecvError::Closed) => return Err(io::Error::new(ErrorKind::NotConnected, "torrent was stopped")),
+                }
+            }
+            _ = sleep_until(keep_alive) => {
+                self.send(Message::KeepAlive).await?;
+                false
+            }
+            _ = sleep_until(snub_at.unwrap_or(deadline)), if snub_at.is_some() => {
+                if self.pipeline.check_snubbed(Instant::now()) {
+                    info!("{} is snubbing us", self.address);
+                    self.piece_manager.lock().unwrap().set_snubbed(&self.remote_id, true);
+                }
+                true
+            }
+            _ = sleep_until(deadline) => {
+                return Err(io::Error::new(ErrorKind::TimedOut, "peer went quiet"));
+            }
+        };
+        Ok(Turn::Woken(woken))
+    }
+
+    async fn end_turn(&mut self, ip: &str, woken: bool) -> io::Result<()> {
+        // too many of this peer's pieces have failed their hash check.
+        // the wire counts go to the stats here, once per turn of the
+        // loop rather than taking the lock for every read and write.
+        let (received, sent) = self.take_wire();
+        let banned = {
+            let mut pm = self.piece_manager.lock().unwrap();
+            pm.stats().record_wire(received, sent);
+            pm.is_banned(&self.remote_id)
+        };
+        if banned {
+            self.bans.lock().unwrap().ban(ip, "sent too much corrupt data");
+            return Err(invalid_data("banned for sending corrupt data"));
+        }
+        if self.evictions.lock().unwrap().remove(&self.remote_id) {
+            self.evicted = true;
+            return Err(io::Error::new(ErrorKind::ConnectionAborted, "dropped to make room for a better peer"));
+        }
+
+        if woken {
+            self.update_choking().await?;
+            self.update_interest().await?;
+            self.request_if_ready().await?;
+            self.offer_super_seed_piece().await?;
+        }
+        if self.peer_limits.has_changed().unwrap_or(false) {
+            self.update_shaper();
+        }
+        self.publish();
+        Ok(())
+    }
+
+    fn update_shaper(&mut self) {
+        let limits = self.peer_limits.borrow_and_update().for_peer(&self.ip);
+        self.shaper.update(limits, Instant::now());
+    }
+
+    // only takes the lock when something has changed
+    fn publish(&mut self) {
+        let current = (self.state, self.pipeline.len());
+        if current == self.published {
+            return;
+        }
+        self.published = current;
+        if let Some(peer) = self.connected.lock().unwrap().get_mut(&self.remote_id) {
+            (peer.state, peer.requests) = current;
+        }
+    }
+
+    async fn connect(&mut self, ip: &str, port: u16) -> io::Result<()> {
+        let stream = self.transport.connect(ip, port).await?;
+        let (reader, writer) = split(stream);
+        self.reader = Some(BufReader::new(reader));
+        self.writer = Some(BufWriter::new(writer));
+        Ok(())
+    }
+
+    async fn handshake(&mut self, phase: Handshaking) -> io::Result<Bitfield> {
+        self.send_handshake().await?;
+
+        while self.buffer.len() < HANDSHAKE_LENGTH {
+            self.fill_buffer().await?;
+        }
+
+        let response = Handshake::decode(&self.buffer[..HANDSHAKE_LENGTH]).map_err(invalid_data)?;
+        self.buffer.advance(HANDSHAKE_LENGTH);
+        if *response.info_hash() != self.info_hash {
+            return Err(invalid_data(format!("peer answered for another torrent ({})", response.info_hash())));
+        }
+        self.handshake_received(phase, &response)
+    }
+
+    async fn send_handshake(&mut self) -> io::Result<()> {
+        let handshake = Handshake::new(self.info_hash, self.peer_id.as_bytes().to_vec())
+            .map_err(invalid_data)?
+            .with_dht(self.dht.load(Ordering::Relaxed));
+        self.write_bytes(&handshake.encode()).await
+    }
+
+    fn handshake_received(&mut self, phase: Handshaking, handshake: &Handshake) -> io::Result<Bitfield> {
+        if handshake.peer_id() == self.peer_id.as_bytes() {
+            self.ourselves = true;
+            return Err(io::Error::new(ErrorKind::AddrInUse, "connected to ourselves"));
+        }
+        self.remote_id = hex::encode(handshake.peer_id());
+        self.remote_name = String::from_utf8_lossy(handshake.peer_id()).to_string();
+        self.client = identify_client(handshake.peer_id());
+        self.peer_dht = self.dht.load(Ordering::Relaxed) && handshake.supports_dht();
+        Ok(phase.handshake_done())
+    }
+
+    fn bitfield_received(&mut self, phase: Bitfield, bitfield: &[u8]) -> io::Result<Active> {
+        let pieces = expand_bitfield(bitfield, self.num_pieces).map_err(invalid_data)?;
+        self.piece_manager.lock().unwrap().add_peer(self.remote_id.clone(), pieces);
+        Ok(phase.message_received())
+    }
+
+    // returns true if the message could change whether we are interested
+    // in the peer or are able to send it a request
+    async fn handle_message(&mut self, _: &Active, message: Message) -> io::Result<bool> {
+        match message {
+            Message::KeepAlive => {}
+            Message::Choke => {
+                // a choke throws away everything we had asked for. the
+                // blocks are picked up again once they expire.
+                self.state.peer_choking = true;
+                self.pipeline.clear();
+            }
+            Message::Unchoke => {
+                self.state.peer_choking = false;
+                return Ok(true);
+            }
+            Message::Interested => {
+                self.state.peer_interested = true;
+                self.update_choking().await?;
+            }
+            Message::NotInterested => {
+                self.state.peer_interested = false;
+                if self.uploads.lock().unwrap().release(&self.remote_id, Instant::now()) {
+                    self.interest.lock().unwrap().notify_changed();
+                }
+            }
+            Message::Have(index) => {
+                // the peer may have got the piece from someone we super
+                // seeded it to, who is then due another
+                if self.piece_manager.lock().unwrap().update_peer(self.remote_id.clone(), index) {
+                    self.interest.lock().unwrap().notify_changed();
+                }
+                return Ok(true);
+            }
+            // a bitfield in its place is taken before there is an Active
+            Message::Bitfield(_) => return Err(invalid_data("bitfield has to be the first message")),
+            Message::Piece { index, begin, block } => {
+                let was_snubbed = self.pipeline.is_snubbed();
+                let latency = self.pipeline.complete(index, begin);
+                let write = {
+                    let mut pm = self.piece_manager.lock().unwrap();
+                    if !pm.is_block(index, begin, block.len() as u32) {
+                        return Err(invalid_data(format!("sent {} bytes at {} of piece {}, which isn't a block", block.len(), begin, index)));
+                    }
+                    if was_snubbed && !self.pipeline.is_snubbed() {
+                        pm.set_snubbed(&self.remote_id, false);
+                    }
+                    if let Some(latency) = latency {
+                        pm.record_latency(&self.remote_id, latency);
+                    }
+                    pm.block_received(self.remote_id.clone(), index as u64, begin as u64, block)
+                };
+                let Some(write) = write else { return Ok(true) };
+
+                // the next message waits for the block to be on disk, but
+                // no other peer of the torrent does
+                let written = self.disk.write(write).await?;
+                let job = self.piece_manager.lock().unwrap().block_written(written);
+
+                // the torrent announces the piece once it checks out
+                if let Some(job) = job {
+                    self.verifier.submit(job);
+                }
+                return Ok(true);
+            }
+            Message::Request { index, begin, length } => {
+                self.piece_manager.lock().unwrap().check_request(index, begin, length).map_err(invalid_data)?;
+                // the choker may have given our slot away since we last
+                // woke up to tell the peer
+                if self.state.am_choking || !self.uploads.lock().unwrap().is_unchoked(&self.remote_id) {
+                    return Ok(false);
+                }
+
+                // read by the disk, as blocks we receive are written
+                let location = self.piece_manager.lock().unwrap().upload_location(&self.remote_id, index, begin);
+                let read = match location {
+                    Ok(offset) => self.disk.read(offset, length).await,
+                    Err(e) => Err(e),
+                };
+                match read {
+                    Ok(block) => {
+                        self.send(Message::Piece { index, begin, block }).await?;
+                        self.piece_manager.lock().unwrap().block_uploaded(&self.remote_id, length as u64);
+                    }
+                    Err(e) => info!("couldn't serve block {} of piece {}: {}", begin, index, e),
+                }
+            }
+            // requests are answered as soon as they arrive so there
+            // is never anything queued to cancel
+            Message::Cancel { .. } => {}
+            // the peer's dht node listens on the same ip. it is only a
+            // candidate until we can ping it and learn its id.
+            Message::Port(port) => {
+                if self.peer_dht && port != 0 {
+                    self.dht_state.lock().unwrap().add_candidate((self.ip.clone(), port));
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    // only stay interested while the peer has something we need
+    // and the interest manager has given us a slot
+    async fn update_interest(&mut self) -> io::Result<()> {
+        let wants = self.piece_manager.lock().unwrap().is_interesting(&self.remote_id);
+        let granted = {
+            let mut interest = self.interest.lock().unwrap();
+            if wants {
+                interest.request_slot(&self.remote_id)
+            } else {
+                interest.release(&self.remote_id);
+                false
+            }
+        };
+
+        let interested = self.state.am_interested;
+        if granted && !interested {
+            self.send(Message::Interested).await?;
+            self.state.am_interested = true;
+        } else if !granted && interested {
+            self.send(Message::NotInterested).await?;
+            self.state.am_interested = false;
+            self.pipeline.clear();
+        }
+
+        Ok(())
+    }
+
+    // anyone who wants something we have gets unchoked, once there is
+    // an upload slot for them. slots can also be taken away again by
+    // the choker, which wakes us up to pass it on.
+    async fn update_choking(&mut self) -> io::Result<()> {
+        // a paused torrent serves nobody, and gives its slot back
+        if self.piece_manager.lock().unwrap().is_paused() {
+            if !self.state.am_choking {
+                self.send(Message::Choke).await?;
+                self.state.am_choking = true;
+                self.uploads.lock().unwrap().release(&self.remote_id, Instant::now());
+            }
+            return Ok(());
+        }
+
+        if !self.state.peer_interested || self.piece_manager.lock().unwrap().have_count() == 0 {
+            return Ok(());
+        }
+
+        let unchoked = self.uploads.lock().unwrap().request_unchoke(&self.remote_id, Instant::now());
+        let choked = self.state.am_choking;
+        if unchoked && choked {
+            self.send(Message::Unchoke).await?;
+            self.state.am_choking = false;
+        } else if !unchoked && !choked {
+            self.send(Message::Choke).await?;
+            self.state.am_choking = true;
+        }
+
+        Ok(())
+    }
+
+    // while super seeding, tells the peer about the next piece it should
+    // have once it has passed the last one on
+    async fn offer_super_seed_piece(&mut self) -> io::Result<()> {
+        let offer = self.piece_manager.lock().unwrap().next_super_seed_offer(&self.remote_id);
+        if let Some(index) = offer {
+            self.send(Message::Have(index)).await?;
+        }
+        Ok(())
+    }
+
+    async fn request_if_ready(&mut self) -> io::Result<()> {
+        if self.state.can_request() && self.pipeline.has_room() {
+            self.request_blocks().await?;
+        }
+
+        Ok(())
+    }
+
+    // the piece manager has given up on a request to this peer and
+    // handed it to someone else
+    async fn request_expired(&mut self, request: ExpiredRequest) -> io::Result<()> {
+        if self.pipeline.cancel(request.index, request.begin) {
+            self.send(Message::Cancel { index: request.index, begin: request.begin, length: request.length }).await?;
+        }
+        if !self.pipeline.is_snubbed() {
+            info!("{} sat on block {} of piece {}, counting it as snubbing us", self.address, request.begin, request.index);
+            self.pipeline.snub();
+            self.piece_manager.lock().unwrap().set_snubbed(&self.remote_id, true);
+        }
+        Ok(())
+    }
+
+    // tops the pipeline back up, sending every new request in one write
+    async fn request_blocks(&mut self) -> io::Result<()> {
+        let mut requests = Vec::new();
+        {
+            let mut pm = self.piece_manager.lock().unwrap();
+            let rate = pm.stats().peer_snapshot(&self.remote_id, Instant::now()).map_or(0.0, |s| s.download_rate_avg);
+            self.pipeline.update_depth(rate, pm.block_size());
+
+            while self.pipeline.has_room() {
+                let Some(block) = pm.next_request(&self.remote_id) else { break };
+                let request = Request {
+                    index: block.piece() as u32,
+                    begin: block.offset() as u32,
+                    length: block.length() as u32,
+                };
+                self.pipeline.add(request);
+                requests.push(request);
+            }
+        }
+
+        if requests.is_empty() {
+            return Ok(());
+        }
+
+        let mut data = BytesMut::new();
+        for r in requests {
+            self.codec.encode(Message::Request { index: r.index, begin: r.begin, length: r.length }, &mut data)?;
+        }
+        self.write_bytes(&data).await
+    }
+
+    // reads the next full message off the wire. partial messages stay
+    // in the buffer, so dropping this future part way through is safe.
+    async fn read_message(&mut self) -> io::Result<Message> {
+        loop {
+            if let Some(message) = self.codec.decode(&mut self.buffer)? {
+                return Ok(message);
+            }
+
+            self.fill_buffer().await?;
+        }
+    }
+
+    async fn fill_buffer(&mut self) -> io::Result<()> {
+        // nothing is read until then, so this can be dropped part way
+        // through like read_message
+        sleep_until(self.read_at).await;
+        let reader = self.reader.as_mut().ok_or_else(not_connected)?;
+
+        if self.buffer.capacity() - self.buffer.len() < 4096 {
+            self.buffer.reserve(4096);
+        }
+        let n = match self.session_limits.read_limit(self.shaper.read_limit()) {
+            Some(limit) => reader.take(limit).read_buf(&mut self.buffer).await?,
+            None => reader.read_buf(&mut self.buffer).await?,
+        };
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "peer closed the connection"));
+        }
+        self.received += n as u64;
+        let now = Instant::now();
+        let wait = self.shaper.received(n, now).max(self.session_limits.received(n, now));
+        self.read_at = tokio::time::Instant::now() + wait;
+
+        Ok(())
+    }
+
+    async fn send(&mut self, message: Message) -> io::Result<()> {
+        let mut data = BytesMut::new();
+        self.codec.encode(message, &mut data)?;
+        self.write_bytes(&data).await
+    }
+
+    async fn write_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        sleep_until(self.write_at).await;
+        let writer = self.writer.as_mut().ok_or_else(not_connected)?;
+        writer.write_all(data).await?;
+        writer.flush().await?;
+        self.last_write = tokio::time::Instant::now();
+        self.sent += data.len() as u64;
+        let now = Instant::now();
+        let wait = self.shaper.sent(data.len(), now).max(self.session_limits.sent(data.len(), now));
+        self.write_at = self.last_write + wait;
+        Ok(())
+    }
+
+    // bytes read and written since the last call
+    fn take_wire(&mut self) -> (u64, u64) {
+        (std::mem::take(&mut self.received), std::mem::take(&mut self.sent))
+    }
+
+    // forget everything about the last peer before taking the next one
+    fn cleanup(&mut self) {
+        if !self.remote_id.is_empty() {
+            let (received, sent) = self.take_wire();
+            let mut pm = self.piece_manager.lock().unwrap();
+            pm.stats().record_wire(received, sent);
+            pm.delete_peer(self.remote_id.clone());
+            drop(pm);
+            self.interest.lock().unwrap().release(&self.remote_id);
+            if self.uploads.lock().unwrap().remove_peer(&self.remote_id, Instant::now()) {
+                self.interest.lock().unwrap().notify_changed();
+            }
+            self.connected.lock().unwrap().remove(&self.remote_id);
+            self.evictions.lock().unwrap().remove(&self.remote_id);
+        }
+        self.evicted = false;
+        self.ourselves = false;
+
+        self.state = PeerState::default();
+        self.established = false;
+        self.remote_id.clear();
+        self.remote_name.clear();
+        self.ip.clear();
+        self.address.clear();
+        self.client = None;
+        self.pipeline = Pipeline::with_max_depth(self.pipeline.max_depth());
+        self.published = (PeerState::default(), 0);
+        self.shaper = Shaper::default();
+        self.reader = None;
+        self.writer = None;
+        self.buffer.clear();
     }
 }
 
+fn invalid_data<E: ToString>(e: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(ErrorKind::NotConnected, "peer is not connected")
+}
+
+// the wire bitfield packs one bit per piece, high bit first.
+// PieceManager keeps one byte (0 or 1) per piece instead, so
+// these convert between the two.
+pub fn expand_bitfield(bitfield: &[u8], num_pieces: usize) -> Result<Vec<u8>, BtError> {
+    if bitfield.len() != num_pieces.div_ceil(8) {
+        return Err(BtError::Protocol(format!("bitfield is {} bytes, expected {} for {} pieces", bitfield.len(), num_pieces.div_ceil(8), num_pieces)));
+    }
+
+    let mut pieces = Vec::with_capacity(num_pieces);
+    for i in 0..bitfield.len() * 8 {
+        let bit = (bitfield[i / 8] >> (7 - i % 8)) & 1;
+        if i < num_pieces {
+            pieces.push(bit);
+        // spare bits at the end must be cleared
+        } else if bit != 0 {
+            return Err(BtError::Protocol("bitfield has spare bits set".to_string()));
+        }
+    }
+
+    Ok(pieces)
+}
+
+pub fn pack_bitfield(pieces: &[u8]) -> Vec<u8> {
+    let mut bitfield = vec![0u8; pieces.len().div_ceil(8)];
+    for (i, &have) in pieces.iter().enumerate() {
+        if have != 0 {
+            bitfield[i / 8] |= 1 << (7 - i % 8);
+        }
+    }
+    bitfield
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{disk::Disk, session::RateLimits, test_vectors, torrent::Torrent, transport::MemoryTransport};
+
+    const PEER_ID: &str = "-MY6969-123456789012";
+    const REMOTE_ID: &[u8] = b"-TR3000-abcdefghijkl";
+
+    fn test_piece_manager(name: &str) -> Arc<Mutex<PieceManager>> {
+        test_piece_manager_sized(name, 16384, 16384)
+    }
+
+    fn test_piece_manager_sized(name: &str, piece_length: u32, total_size: u64) -> Arc<Mutex<PieceManager>> {
+        let torrent = Torrent {
+            info_hash: InfoHash::new([0xAB; 20]),
+            announce: String::new(),
+            multi_file: false,
+            piece_length,
+            total_size,
+            pieces: vec![[0; 20]; total_size.div_ceil(piece_length as u64) as usize],
+            output_file: std::env::temp_dir().join(name).to_string_lossy().to_string(),
+            files: vec![],
+            ..Default::default()
+        };
+        Arc::new(Mutex::new(PieceManager::new(Arc::new(torrent)).unwrap()))
+    }
+
+    fn test_context(pm: Arc<Mutex<PieceManager>>, connected: PeerRegistry, abort: Arc<AtomicBool>) -> PeerContext {
+        let mut peers = PeerPool::new();
+        peers.add(PeerSource::Tracker, &[("10.0.0.1".to_string(), 6881)]);
+        let disk = Disk { storage: pm.lock().unwrap().storage() }.spawn().unwrap();
+        PeerContext {
+            peers: Arc::new(Mutex::new(peers)),
+            evictions: Evictions::default(),
+            incoming: IncomingQueue::default(),
+            piece_manager: pm,
+            interest: Arc::new(Mutex::new(InterestManager::new(4))),
+            uploads: Arc::new(Mutex::new(UploadChoker::new(None, None))),
+            connected,
+            bans: Arc::new(Mutex::new(BanList::new())),
+            sources: Arc::new(Mutex::new(PeerSources::new())),
+            haves: broadcast::Sender::new(16),
+            expired: broadcast::Sender::new(16),
+            abort,
+            dht: Arc::new(AtomicBool::new(false)),
+            dht_state: Arc::new(Mutex::new(DhtState::load(None, Vec::new()))),
+            dht_port: 6881,
+            connect_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(10),
+            verifier: Verifier::new().0,
+            disk,
+            max_requests_per_peer: 64,
+            peer_limits: watch::channel(PeerLimits::default()).1,
+            session_limits: SessionShaper::default(),
+        }
+    }
+
+    fn test_connection(transport: Arc<MemoryTransport>, pm: Arc<Mutex<PieceManager>>, connected: PeerRegistry, abort: Arc<AtomicBool>) -> PeerConnection {
+        PeerConnection::new(PEER_ID.to_string(), transport, test_context(pm, connected, abort))
+    }
+
+    async fn read_frame(stream: &mut BoxedStream) -> Message {
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length).await.unwrap();
+        let mut body = vec![0u8; u32::from_be_bytes(length) as usize];
+        stream.read_exact(&mut body).await.unwrap();
+        Message::decode(&[&length[..], &body].concat()).unwrap()
+    }
 
     #[test]
     fn test_handshake_encode_decode() {
-        let info_hash = vec![0xAB; 20];
+        let info_hash = InfoHash::new([0xAB; 20]);
         let peer_id = b"-MY6969-123456789012".to_vec();
 
-        let handshake = Handshake::new(info_hash.clone(), peer_id.clone()).unwrap();
+        let handshake = Handshake::new(info_hash, peer_id.clone()).unwrap();
         let encoded = handshake.encode();
         let decoded = Handshake::decode(&encoded).unwrap();
 
@@ -120,4 +1250,783 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_handshake_vectors() {
+        for v in test_vectors::handshakes() {
+            let handshake = Handshake::new(InfoHash::new(v.info_hash), v.peer_id.to_vec()).unwrap();
+            assert_eq!(handshake.encode(), v.encoded, "{}", v.name);
+
+            let decoded = Handshake::decode(&v.encoded).unwrap();
+            assert_eq!(decoded.info_hash().as_bytes(), &v.info_hash, "{}", v.name);
+            assert_eq!(decoded.peer_id(), v.peer_id, "{}", v.name);
+        }
+
+        for v in test_vectors::invalid_handshakes() {
+            assert!(Handshake::decode(&v.encoded).is_err(), "{}", v.name);
+        }
+    }
+
+    #[test]
+    fn test_message_vectors() {
+        for v in test_vectors::messages() {
+            assert_eq!(v.message.encode(), v.encoded, "{}", v.name);
+            assert_eq!(Message::decode(&v.encoded).unwrap(), v.message, "{}", v.name);
+        }
+
+        for v in test_vectors::invalid_messages() {
+            assert!(Message::decode(&v.encoded).is_err(), "{}", v.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_loopback_handshake_and_request() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-request");
+
+        let connected = PeerRegistry::default();
+        let mut conn = test_connection(transport, pm.clone(), connected.clone(), abort.clone());
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        let handshake = Handshake::decode(&data).unwrap();
+        assert_eq!(handshake.info_hash(), &InfoHash::new([0xAB; 20]));
+        assert_eq!(handshake.peer_id(), PEER_ID.as_bytes());
+
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+        remote.write_all(&Message::Bitfield(vec![0x80]).encode()).await.unwrap();
+
+        assert_eq!(read_frame(&mut remote).await, Message::Interested);
+
+        let peer = connected.lock().unwrap().get(&hex::encode(REMOTE_ID)).cloned().unwrap();
+        assert_eq!(peer.peer_id, "-TR3000-abcdefghijkl");
+        assert_eq!(peer.address, "10.0.0.1:6881");
+        assert_eq!(peer.client.as_deref(), Some("Transmission 3.00"));
+        assert_eq!(peer.sources, vec![PeerSource::Tracker]);
+
+        remote.write_all(&Message::Unchoke.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Request { index: 0, begin: 0, length: 16384 });
+
+        abort.store(true, Ordering::Relaxed);
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+
+        // the peer is forgotten once the connection is gone
+        assert!(!pm.lock().unwrap().is_interesting(&hex::encode(REMOTE_ID)));
+        assert!(connected.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_loopback_answers_incoming_peer() {
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-incoming");
+        let connected = PeerRegistry::default();
+        let context = PeerContext {
+            dht: Arc::new(AtomicBool::new(true)),
+            ..test_context(pm, connected.clone(), abort.clone())
+        };
+        let sources = context.sources.clone();
+        let dht_state = context.dht_state.clone();
+
+        // what the listener leaves behind: a stream with the peer's
+        // handshake already read off it
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        let mut remote: BoxedStream = Box::new(remote);
+        context.incoming.lock().unwrap().push_back(Incoming {
+            stream: Box::new(local),
+            addr: ("2001:db8::1".to_string(), 51413),
+            handshake: Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap().with_dht(true),
+            arrived: Instant::now(),
+        });
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), Arc::new(MemoryTransport::new()), context);
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        assert_eq!(Handshake::decode(&data).unwrap().peer_id(), PEER_ID.as_bytes());
+
+        assert_eq!(read_frame(&mut remote).await, Message::Port(6881));
+        remote.write_all(&Message::Bitfield(vec![0x80]).encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Interested);
+
+        let peer = connected.lock().unwrap().get(&hex::encode(REMOTE_ID)).cloned().unwrap();
+        assert_eq!(peer.address, "[2001:db8::1]:51413");
+        assert_eq!(peer.sources, vec![PeerSource::Incoming]);
+        let counts = sources.lock().unwrap().report();
+        assert_eq!(counts[&PeerSource::Incoming].connected, 1);
+
+        // the dht node is on the same ip, without the brackets
+        remote.write_all(&Message::Port(7000).encode()).await.unwrap();
+        remote.write_all(&Message::Unchoke.encode()).await.unwrap();
+        while !matches!(read_frame(&mut remote).await, Message::Request { .. }) {}
+        let candidates: Vec<(String, u16)> = dht_state.lock().unwrap().candidates().cloned().collect();
+        assert_eq!(candidates, vec![("2001:db8::1".to_string(), 7000)]);
+
+        abort.store(true, Ordering::Relaxed);
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_loopback_pipelines_requests() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager_sized("bt-c-loopback-pipeline", 65536, 65536);
+
+        let mut conn = test_connection(transport, pm, PeerRegistry::default(), abort.clone());
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+        remote.write_all(&Message::Bitfield(vec![0x80]).encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Interested);
+
+        // a new peer starts with two requests in flight, for different blocks
+        remote.write_all(&Message::Unchoke.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Request { index: 0, begin: 0, length: 16384 });
+        assert_eq!(read_frame(&mut remote).await, Message::Request { index: 0, begin: 16384, length: 16384 });
+
+        // answering one frees a slot for the next block
+        remote.write_all(&Message::Piece { index: 0, begin: 0, block: vec![0; 16384].into() }.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Request { index: 0, begin: 32768, length: 16384 });
+
+        abort.store(true, Ordering::Relaxed);
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_loopback_cancels_expired_requests() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager_sized("bt-c-loopback-expire", 65536, 65536);
+        let context = test_context(pm.clone(), PeerRegistry::default(), abort.clone());
+        let expired = context.expired.clone();
+
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+        remote.write_all(&Message::Bitfield(vec![0x80]).encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Interested);
+        remote.write_all(&Message::Unchoke.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Request { index: 0, begin: 0, length: 16384 });
+        assert_eq!(read_frame(&mut remote).await, Message::Request { index: 0, begin: 16384, length: 16384 });
+
+        // the peer sits on both until the timer gives up on them
+        let requests = pm.lock().unwrap().expire_requests_at(u128::MAX / 2);
+        assert_eq!(requests.len(), 2);
+        let peer_id = requests[0].peer_id.clone();
+        for request in requests {
+            expired.send(request).unwrap();
+        }
+        assert_eq!(read_frame(&mut remote).await, Message::Cancel { index: 0, begin: 0, length: 16384 });
+        assert_eq!(read_frame(&mut remote).await, Message::Cancel { index: 0, begin: 16384, length: 16384 });
+
+        // snubbed, so with nobody else around it gets one block at a time
+        assert_eq!(read_frame(&mut remote).await, Message::Request { index: 0, begin: 32768, length: 16384 });
+        assert!(pm.lock().unwrap().is_snubbed(&peer_id));
+
+        abort.store(true, Ordering::Relaxed);
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_loopback_announces_verified_pieces() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager_sized("bt-c-loopback-have", 16384, 32768);
+        let context = test_context(pm, PeerRegistry::default(), abort.clone());
+        let haves = context.haves.clone();
+
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+
+        // wait until the connection is in its loop and listening
+        while haves.receiver_count() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        // another connection just verified piece 1
+        haves.send(1).unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Have(1));
+
+        abort.store(true, Ordering::Relaxed);
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_loopback_caps_peer_upload() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-peer-cap");
+
+        let output = pm.lock().unwrap().torrent().output_file.clone();
+        std::fs::write(&output, vec![7u8; 16384]).unwrap();
+        pm.lock().unwrap().mark_complete();
+
+        let mut limits = PeerLimits::default();
+        limits.set("10.0.0.1", Some(RateLimits { download: None, upload: Some(2048) })).unwrap();
+        let (peer_limits, receiver) = watch::channel(limits);
+        let context = PeerContext { peer_limits: receiver, ..test_context(pm, PeerRegistry::default(), abort.clone()) };
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Bitfield(vec![0x80]));
+        remote.write_all(&Message::Interested.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Unchoke);
+
+        // a second's worth goes out at once. the rest waits, all but the
+        // last block, which is waited for after it has gone.
+        let start = tokio::time::Instant::now();
+        for begin in (0..5120).step_by(1024) {
+            remote.write_all(&Message::Request { index: 0, begin, length: 1024 }.encode()).await.unwrap();
+        }
+        for begin in (0..5120).step_by(1024) {
+            assert_eq!(read_frame(&mut remote).await, Message::Piece { index: 0, begin, block: vec![7u8; 1024].into() });
+        }
+        assert!(start.elapsed() >= Duration::from_millis(900), "{:?}", start.elapsed());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        abort.store(true, Ordering::Relaxed);
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        drop(peer_limits);
+    }
+
+    #[tokio::test]
+    async fn test_loopback_caps_session_upload() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-session-cap");
+
+        let output = pm.lock().unwrap().torrent().output_file.clone();
+        std::fs::write(&output, vec![7u8; 16384]).unwrap();
+        pm.lock().unwrap().mark_complete();
+
+        // the peer itself is unlimited, the session isn't
+        let session_limits = SessionShaper::default();
+        session_limits.update(RateLimits { download: None, upload: Some(2048) }, Instant::now());
+        let context = PeerContext { session_limits, ..test_context(pm, PeerRegistry::default(), abort.clone()) };
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Bitfield(vec![0x80]));
+        remote.write_all(&Message::Interested.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Unchoke);
+
+        let start = tokio::time::Instant::now();
+        for begin in (0..5120).step_by(1024) {
+            remote.write_all(&Message::Request { index: 0, begin, length: 1024 }.encode()).await.unwrap();
+        }
+        for begin in (0..5120).step_by(1024) {
+            assert_eq!(read_frame(&mut remote).await, Message::Piece { index: 0, begin, block: vec![7u8; 1024].into() });
+        }
+        assert!(start.elapsed() >= Duration::from_millis(900), "{:?}", start.elapsed());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        abort.store(true, Ordering::Relaxed);
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_loopback_seeds_complete_data() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-seed");
+
+        let output = pm.lock().unwrap().torrent().output_file.clone();
+        std::fs::write(&output, vec![7u8; 16384]).unwrap();
+        pm.lock().unwrap().mark_complete();
+
+        let mut conn = test_connection(transport, pm.clone(), PeerRegistry::default(), abort.clone());
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+
+        assert_eq!(read_frame(&mut remote).await, Message::Bitfield(vec![0x80]));
+
+        remote.write_all(&Message::Interested.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Unchoke);
+
+        remote.write_all(&Message::Request { index: 0, begin: 16, length: 8 }.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Piece { index: 0, begin: 16, block: vec![7u8; 8].into() });
+
+        // asking for a block past the end of the piece gets us hung up on
+        remote.write_all(&Message::Request { index: 0, begin: 16380, length: 8 }.encode()).await.unwrap();
+        let mut rest = Vec::new();
+        remote.read_to_end(&mut rest).await.unwrap();
+
+        abort.store(true, Ordering::Relaxed);
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        assert_eq!(pm.lock().unwrap().bytes_uploaded(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_loopback_super_seeds() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager_sized("bt-c-loopback-superseed", 16384, 32768);
+
+        let output = pm.lock().unwrap().torrent().output_file.clone();
+        std::fs::write(&output, vec![7u8; 32768]).unwrap();
+        pm.lock().unwrap().mark_complete();
+        pm.lock().unwrap().start_super_seeding();
+
+        let mut conn = test_connection(transport, pm.clone(), PeerRegistry::default(), abort.clone());
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+
+        // no bitfield, just the one piece
+        assert_eq!(read_frame(&mut remote).await, Message::Have(0));
+
+        remote.write_all(&Message::Interested.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Unchoke);
+
+        // the piece that wasn't offered is refused, the offered one is served
+        remote.write_all(&Message::Request { index: 1, begin: 0, length: 8 }.encode()).await.unwrap();
+        remote.write_all(&Message::Request { index: 0, begin: 0, length: 8 }.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Piece { index: 0, begin: 0, block: vec![7u8; 8].into() });
+
+        abort.store(true, Ordering::Relaxed);
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        assert_eq!(pm.lock().unwrap().bytes_uploaded(), 8);
+    }
+
+    #[test]
+    fn test_handshake_dht_bit() {
+        let handshake = Handshake::new(InfoHash::new([1; 20]), vec![2; 20]).unwrap().with_dht(true);
+        let encoded = handshake.encode();
+        assert_eq!(encoded[27], DHT_BIT);
+
+        let decoded = Handshake::decode(&encoded).unwrap();
+        assert!(decoded.supports_dht());
+        assert!(!decoded.with_dht(false).supports_dht());
+    }
+
+    #[tokio::test]
+    async fn test_loopback_sends_dht_port() {
+        for (ours, theirs) in [(true, true), (true, false), (false, true)] {
+            let transport = Arc::new(MemoryTransport::new());
+            let mut listener = transport.listen("10.0.0.1", 6881);
+            let abort = Arc::new(AtomicBool::new(false));
+            let pm = test_piece_manager("bt-c-loopback-dht");
+            let context = PeerContext {
+                dht: Arc::new(AtomicBool::new(ours)),
+                dht_port: 51413,
+                ..test_context(pm, PeerRegistry::default(), abort.clone())
+            };
+
+            let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+            let task = tokio::spawn(async move { conn.start().await });
+
+            let mut remote = listener.recv().await.unwrap();
+            let mut data = vec![0u8; HANDSHAKE_LENGTH];
+            remote.read_exact(&mut data).await.unwrap();
+            assert_eq!(Handshake::decode(&data).unwrap().supports_dht(), ours);
+
+            let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap().with_dht(theirs);
+            remote.write_all(&reply.encode()).await.unwrap();
+            remote.write_all(&Message::Bitfield(vec![0x80]).encode()).await.unwrap();
+
+            // the port only goes out when both ends want the dht
+            let expected = if ours && theirs { Message::Port(51413) } else { Message::Interested };
+            assert_eq!(read_frame(&mut remote).await, expected);
+
+            abort.store(true, Ordering::Relaxed);
+            drop(remote);
+            tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_loopback_records_dht_node() {
+        for theirs in [true, false] {
+            let transport = Arc::new(MemoryTransport::new());
+            let mut listener = transport.listen("10.0.0.1", 6881);
+            let abort = Arc::new(AtomicBool::new(false));
+            let pm = test_piece_manager("bt-c-loopback-dht-node");
+            let context = PeerContext {
+                dht: Arc::new(AtomicBool::new(true)),
+                ..test_context(pm, PeerRegistry::default(), abort.clone())
+            };
+            let dht_state = context.dht_state.clone();
+
+            let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+            let task = tokio::spawn(async move { conn.start().await });
+
+            let mut remote = listener.recv().await.unwrap();
+            let mut data = vec![0u8; HANDSHAKE_LENGTH];
+            remote.read_exact(&mut data).await.unwrap();
+
+            let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap().with_dht(theirs);
+            remote.write_all(&reply.encode()).await.unwrap();
+            remote.write_all(&Message::Bitfield(vec![0x80]).encode()).await.unwrap();
+            remote.write_all(&Message::Port(7000).encode()).await.unwrap();
+            remote.write_all(&Message::Unchoke.encode()).await.unwrap();
+
+            // the port is handled by the time the unchoke gets us requesting
+            while !matches!(read_frame(&mut remote).await, Message::Request { .. }) {}
+
+            // a peer that didn't set the dht bit has no business sending one
+            let candidates: Vec<(String, u16)> = dht_state.lock().unwrap().candidates().cloned().collect();
+            let expected = if theirs { vec![("10.0.0.1".to_string(), 7000)] } else { vec![] };
+            assert_eq!(candidates, expected);
+
+            abort.store(true, Ordering::Relaxed);
+            drop(remote);
+            tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_loopback_keep_alive_and_idle_timeout() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-keep-alive");
+        let mut conn = test_connection(transport, pm, PeerRegistry::default(), abort.clone());
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+
+        // with nothing else to say we still let the peer know we're here
+        let start = tokio::time::Instant::now();
+        assert_eq!(read_frame(&mut remote).await, Message::KeepAlive);
+        assert!(start.elapsed() >= KEEP_ALIVE_INTERVAL);
+
+        // but a peer that never says anything back is dropped
+        let mut rest = Vec::new();
+        remote.read_to_end(&mut rest).await.unwrap();
+        assert!(start.elapsed() >= MESSAGE_TIMEOUT);
+
+        abort.store(true, Ordering::Relaxed);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_loopback_rejects_late_bitfield() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-late-bitfield");
+        let mut conn = test_connection(transport, pm, PeerRegistry::default(), abort.clone());
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+        remote.write_all(&Message::Have(0).encode()).await.unwrap();
+        remote.write_all(&Message::Bitfield(vec![0x80]).encode()).await.unwrap();
+
+        // only the first message may be a bitfield
+        let mut rest = Vec::new();
+        remote.read_to_end(&mut rest).await.unwrap();
+
+        abort.store(true, Ordering::Relaxed);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_loopback_peer_without_bitfield() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-no-bitfield");
+        let mut conn = test_connection(transport, pm, PeerRegistry::default(), abort.clone());
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+
+        // a peer that started out empty skips the bitfield, and tells us
+        // about its pieces as it gets them
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+        remote.write_all(&Message::Have(0).encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Interested);
+
+        remote.write_all(&Message::Unchoke.encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Request { index: 0, begin: 0, length: 16384 });
+
+        abort.store(true, Ordering::Relaxed);
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_loopback_rejects_garbage_handshake() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-garbage");
+        let context = test_context(pm, PeerRegistry::default(), abort.clone());
+        let bans = context.bans.clone();
+
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+
+        abort.store(true, Ordering::Relaxed);
+        remote.write_all(&[0xFF; HANDSHAKE_LENGTH]).await.unwrap();
+
+        // the connection drops us rather than carrying on
+        let mut rest = Vec::new();
+        remote.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+
+        // one bad handshake counts against the address but isn't a ban yet
+        assert_eq!(bans.lock().unwrap().failures("10.0.0.1"), 1);
+        assert!(!bans.lock().unwrap().is_banned("10.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_loopback_rejects_wrong_info_hash() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-wrong-hash");
+        let connected = PeerRegistry::default();
+        let context = test_context(pm, connected.clone(), abort.clone());
+        let bans = context.bans.clone();
+
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+
+        abort.store(true, Ordering::Relaxed);
+        let reply = Handshake::new(InfoHash::new([0xCD; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+
+        // a well formed handshake for some other torrent is still refused
+        let mut rest = Vec::new();
+        remote.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+
+        assert!(connected.lock().unwrap().is_empty());
+        assert_eq!(bans.lock().unwrap().failures("10.0.0.1"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_loopback_drops_our_own_address() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-ourselves");
+        let connected = PeerRegistry::default();
+        let context = test_context(pm, connected.clone(), abort.clone());
+        let (bans, peers) = (context.bans.clone(), context.peers.clone());
+
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+        let task = tokio::spawn(async move { conn.start().await });
+
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+
+        abort.store(true, Ordering::Relaxed);
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), PEER_ID.as_bytes().to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+        let mut rest = Vec::new();
+        remote.read_to_end(&mut rest).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+
+        // it isn't anyone's fault, so nothing is held against the address,
+        // but it is never tried again
+        assert!(connected.lock().unwrap().is_empty());
+        assert_eq!(bans.lock().unwrap().failures("10.0.0.1"), 0);
+        let mut peers = peers.lock().unwrap();
+        peers.add(PeerSource::Tracker, &[("10.0.0.1".to_string(), 6881)]);
+        assert!(peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_connections_are_not_banned() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut first = transport.listen("10.0.0.1", 6881);
+        let mut second = transport.listen("10.0.0.2", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-duplicate");
+        let connected = PeerRegistry::default();
+        let context = test_context(pm.clone(), connected.clone(), abort.clone());
+        let bans = context.bans.clone();
+
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), transport.clone(), context);
+        let task = tokio::spawn(async move { conn.start().await });
+        let mut remote = first.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        let reply = Handshake::new(InfoHash::new([0xAB; 20]), REMOTE_ID.to_vec()).unwrap();
+        remote.write_all(&reply.encode()).await.unwrap();
+        remote.write_all(&Message::Bitfield(vec![0x80]).encode()).await.unwrap();
+        assert_eq!(read_frame(&mut remote).await, Message::Interested);
+
+        // the same peer at a second address, again and again
+        let mut peers = PeerPool::new();
+        peers.add(PeerSource::Tracker, &[("10.0.0.2".to_string(), 6881)]);
+        let context = PeerContext {
+            peers: Arc::new(Mutex::new(peers)),
+            bans: bans.clone(),
+            ..test_context(pm, connected.clone(), abort.clone())
+        };
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+        let duplicate = tokio::spawn(async move { conn.start().await });
+        let mut again = second.recv().await.unwrap();
+        again.read_exact(&mut data).await.unwrap();
+        abort.store(true, Ordering::Relaxed);
+        again.write_all(&reply.encode()).await.unwrap();
+        let mut rest = Vec::new();
+        again.read_to_end(&mut rest).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), duplicate).await.unwrap().unwrap();
+
+        // its handshake was fine, so nothing is held against the address,
+        // and the first connection still has the peer
+        assert_eq!(bans.lock().unwrap().failures("10.0.0.2"), 0);
+        assert_eq!(connected.lock().unwrap().len(), 1);
+
+        drop(remote);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+    }
+
+    // ids that aren't utf-8 would all come out the same as text
+    #[tokio::test]
+    async fn test_peers_are_told_apart_by_raw_id() {
+        let pm = test_piece_manager("bt-c-raw-peer-ids");
+        let mut conn = test_connection(Arc::new(MemoryTransport::new()), pm, PeerRegistry::default(), Arc::new(AtomicBool::new(false)));
+        let mut keys = Vec::new();
+        for id in [[0xFF; 20], [0xFE; 20]] {
+            let handshake = Handshake::new(InfoHash::new([0xAB; 20]), id.to_vec()).unwrap();
+            conn.handshake_received(Handshaking::start(), &handshake).unwrap();
+            keys.push(conn.remote_id.clone());
+        }
+        assert_ne!(keys[0], keys[1]);
+        assert_eq!(conn.remote_name, "\u{FFFD}".repeat(20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_loopback_handshake_timeout() {
+        let transport = Arc::new(MemoryTransport::new());
+        let mut listener = transport.listen("10.0.0.1", 6881);
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-handshake-timeout");
+        let context = PeerContext {
+            handshake_timeout: Duration::from_secs(3),
+            ..test_context(pm, PeerRegistry::default(), abort.clone())
+        };
+        let bans = context.bans.clone();
+
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+        let task = tokio::spawn(async move { conn.start().await });
+
+        // take their handshake and never answer it
+        let mut remote = listener.recv().await.unwrap();
+        let mut data = vec![0u8; HANDSHAKE_LENGTH];
+        remote.read_exact(&mut data).await.unwrap();
+        let start = tokio::time::Instant::now();
+
+        let mut rest = Vec::new();
+        remote.read_to_end(&mut rest).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(3));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        abort.store(true, Ordering::Relaxed);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        assert_eq!(bans.lock().unwrap().failures("10.0.0.1"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_peers_are_not_banned() {
+        // nothing listens on 10.0.0.1
+        let transport = Arc::new(MemoryTransport::new());
+        let abort = Arc::new(AtomicBool::new(false));
+        let pm = test_piece_manager("bt-c-loopback-unreachable");
+        let context = test_context(pm, PeerRegistry::default(), abort.clone());
+        let bans = context.bans.clone();
+
+        let mut conn = PeerConnection::new(PEER_ID.to_string(), transport, context);
+        let task = tokio::spawn(async move { conn.start().await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        abort.store(true, Ordering::Relaxed);
+        tokio::time::timeout(Duration::from_secs(5), task).await.unwrap().unwrap();
+        assert_eq!(bans.lock().unwrap().failures("10.0.0.1"), 0);
+    }
+
+    #[test]
+    fn test_bitfield_vectors() {
+        for v in test_vectors::bitfields() {
+            let expanded = expand_bitfield(&v.encoded, v.num_pieces).ok();
+            assert_eq!(expanded, v.pieces, "{}", v.name);
+
+            if let Some(pieces) = v.pieces {
+                assert_eq!(pack_bitfield(&pieces), v.encoded, "{}", v.name);
+            }
+        }
+    }
+
 }
\ No newline at end of file
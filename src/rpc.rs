@@ -0,0 +1,684 @@
+use std::{error::Error, fs, io, path::Path, sync::Arc, time::Duration};
+
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use rand::Rng;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+    sync::Mutex,
+    time::timeout,
+};
+
+use crate::{
+    bundle::{self, Bundle},
+    error::BtError,
+    fastresume,
+    schedule::SpeedSchedule,
+    session::{QueueLimits, RateLimits, Session, TorrentId},
+    torrent::{parse_torrent, Torrent},
+};
+
+// same port transmission uses so existing muscle memory carries over
+pub const DEFAULT_RPC_ADDR: &str = "127.0.0.1:9091";
+
+// requests are tiny, anything bigger than this is junk
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+// for the whole request to come in, so a connection that never
+// finishes its headers doesn't hang around forever
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+// every request has to carry the token the server made when it started,
+// as transmission does it. a web page can make the browser post to us,
+// but it can't read the 409 answer to learn the token, so it can't make
+// a request that is obeyed.
+pub const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+
+// json-rpc 2.0 error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+type RpcError = (i64, String);
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: Option<String>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+#[derive(Deserialize)]
+struct AddParams {
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct ExportParams {
+    id: TorrentId,
+    // where the bundle is written, on the machine running the session
+    path: String,
+}
+
+#[derive(Deserialize)]
+struct IdParams {
+    id: TorrentId,
+}
+
+#[derive(Deserialize)]
+struct PauseParams {
+    id: TorrentId,
+    // also tell the tracker we have stopped
+    #[serde(default)]
+    announce_stopped: bool,
+}
+
+#[derive(Deserialize)]
+struct QueueMoveParams {
+    id: TorrentId,
+    // 0 is the front of the queue
+    position: usize,
+}
+
+#[derive(Deserialize)]
+struct DhtParams {
+    enabled: bool,
+}
+
+#[derive(Deserialize)]
+struct TorrentDhtParams {
+    id: TorrentId,
+    // leaving this out (or null) clears the torrent's override
+    enabled: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct PeerLimitParams {
+    id: TorrentId,
+    // ip or ip:port, as the peer list shows it
+    address: String,
+    // null puts the peer back on the torrent's default
+    limits: Option<RateLimits>,
+}
+
+#[derive(Deserialize)]
+struct DeadlineParams {
+    id: TorrentId,
+    piece: u32,
+    // how long from now the piece is wanted by, null to drop the deadline
+    millis: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+struct GetParams {
+    id: Option<TorrentId>,
+}
+
+// listens for json-rpc requests sent as http POST bodies.
+// every connection is served on its own task.
+pub async fn serve(session: Arc<Mutex<Session>>, addr: &str) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("rpc server listening on {}", listener.local_addr()?);
+    let session_id: Arc<str> = hex::encode(rand::rng().random::<[u8; 24]>()).into();
+
+    loop {
+        let (stream, remote) = listener.accept().await?;
+        let session = session.clone();
+        let session_id = session_id.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &session, &session_id).await {
+                warn!("rpc connection from {} failed: {}", remote, e);
+            }
+        });
+    }
+}
+
+#[derive(Debug, Default)]
+struct HttpRequest {
+    method: String,
+    host: Option<String>,
+    origin: Option<String>,
+    session_id: Option<String>,
+    content_length: usize,
+}
+
+// minimal http/1.1 handling: read the headers we care about,
+// read the body, answer and close the connection.
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S, session: &Mutex<Session>, session_id: &str) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let request = timeout(READ_TIMEOUT, read_headers(&mut reader))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "took too long to send its headers"))??;
+
+    if request.method != "POST" {
+        return write_response(reader.get_mut(), "405 Method Not Allowed", "", "").await;
+    }
+
+    // a page on some other site, or one whose name was pointed at us
+    // to get around the browser's same origin rules
+    if !request.host.as_deref().is_none_or(is_local_host) || !request.origin.as_deref().is_none_or(is_local_origin) {
+        return write_response(reader.get_mut(), "403 Forbidden", "", "").await;
+    }
+
+    if request.session_id.as_deref() != Some(session_id) {
+        let header = format!("{}: {}\r\n", SESSION_ID_HEADER, session_id);
+        return write_response(reader.get_mut(), "409 Conflict", &header, "").await;
+    }
+
+    if request.content_length > MAX_BODY_SIZE {
+        return write_response(reader.get_mut(), "413 Payload Too Large", "", "").await;
+    }
+
+    let mut body = vec![0; request.content_length];
+    timeout(READ_TIMEOUT, reader.read_exact(&mut body))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "took too long to send its body"))??;
+
+    let response = handle_request(session, &body).await.to_string();
+    write_response(reader.get_mut(), "200 OK", "", &response).await
+}
+
+async fn read_headers<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<HttpRequest> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    let mut request = HttpRequest { method: request_line.split_whitespace().next().unwrap_or("").to_string(), ..Default::default() };
+
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            let (name, value) = (name.trim(), value.trim().to_string());
+            if name.eq_ignore_ascii_case("content-length") {
+                request.content_length = value.parse().unwrap_or(0);
+            } else if name.eq_ignore_ascii_case("host") {
+                request.host = Some(value);
+            } else if name.eq_ignore_ascii_case("origin") {
+                request.origin = Some(value);
+            } else if name.eq_ignore_ascii_case(SESSION_ID_HEADER) {
+                request.session_id = Some(value);
+            }
+        }
+    }
+    Ok(request)
+}
+
+// the name in a Host header, without its port or an ipv6 address's
+// brackets
+fn host_name(host: &str) -> &str {
+    match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or(""),
+        None => host.split(':').next().unwrap_or(""),
+    }
+}
+
+// localhost, or an address rather than a name. nobody can point an
+// address somewhere else the way they can a name of their own.
+fn is_local_host(host: &str) -> bool {
+    let name = host_name(host);
+    name.eq_ignore_ascii_case("localhost") || name.parse::<std::net::IpAddr>().is_ok()
+}
+
+// a page on this machine. anything else is another site having the
+// browser post to us.
+fn is_local_origin(origin: &str) -> bool {
+    let Some(rest) = origin.strip_prefix("http://").or_else(|| origin.strip_prefix("https://")) else { return false };
+    let name = host_name(rest.split('/').next().unwrap_or(""));
+    name.eq_ignore_ascii_case("localhost") || name.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+async fn write_response<S: AsyncWrite + Unpin>(stream: &mut S, status: &str, headers: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        headers,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+// decodes a single json-rpc request and returns the response object
+pub async fn handle_request(session: &Mutex<Session>, body: &[u8]) -> Value {
+    let request: Request = match serde_json::from_slice(body) {
+        Ok(r) => r,
+        Err(e) => return error_response(Value::Null, PARSE_ERROR, e.to_string()),
+    };
+
+    if request.jsonrpc.as_deref() != Some("2.0") {
+        return error_response(request.id, INVALID_REQUEST, "jsonrpc must be \"2.0\"".to_string());
+    }
+
+    match dispatch(session, &request.method, request.params).await {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": request.id }),
+        Err((code, message)) => error_response(request.id, code, message),
+    }
+}
+
+async fn dispatch(session: &Mutex<Session>, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "torrent-add" => {
+            let p: AddParams = parse_params(params)?;
+            // read and parsed before taking the session, which every
+            // other call and the tick are waiting on
+            let torrent = tokio::task::spawn_blocking(move || -> Result<Torrent, BtError> { parse_torrent(&fs::read(&p.path)?) })
+                .await
+                .map_err(server_error)?
+                .map_err(server_error)?;
+            let id = session.lock().await.add_torrent(torrent).await.map_err(server_error)?;
+            Ok(json!({ "id": id }))
+        }
+        "torrent-export" => {
+            let p: ExportParams = parse_params(params)?;
+            let path = Path::new(&p.path);
+            // libtorrent's format for a .fastresume, our own for anything else
+            if fastresume::is_fastresume(path) {
+                let (resume, metainfo) = session.lock().await.export_fastresume(p.id).map_err(server_error)?;
+                fs::write(path, resume.encode())
+                    .and_then(|_| fs::write(path.with_extension("torrent"), metainfo))
+                    .map_err(server_error)?;
+                return Ok(Value::Null);
+            }
+            let bundle = session.lock().await.export(p.id).map_err(server_error)?;
+            fs::write(path, bundle.encode()).map_err(server_error)?;
+            Ok(Value::Null)
+        }
+        "torrent-import" => {
+            let p: AddParams = parse_params(params)?;
+            // a deluge resume file can have any number of torrents in it,
+            // id is the first of them
+            let bundles = tokio::task::spawn_blocking(move || -> Result<Vec<(Torrent, Bundle)>, String> {
+                bundle::read(Path::new(&p.path))?
+                    .into_iter()
+                    .map(|bundle| Ok((bundle.torrent().map_err(|e| e.to_string())?, bundle)))
+                    .collect()
+            })
+            .await
+            .map_err(server_error)?
+            .map_err(server_error)?;
+            let mut ids = Vec::new();
+            for (torrent, bundle) in bundles {
+                ids.push(session.lock().await.import_torrent(torrent, &bundle.resume).await.map_err(server_error)?);
+            }
+            Ok(json!({ "id": ids.first(), "ids": ids }))
+        }
+        "torrent-remove" => {
+            let p: IdParams = parse_params(params)?;
+            session.lock().await.remove_torrent(p.id).map_err(server_error)?;
+            Ok(Value::Null)
+        }
+        "torrent-pause" => {
+            let p: PauseParams = parse_params(params)?;
+            session.lock().await.pause(p.id, p.announce_stopped).map_err(server_error)?;
+            Ok(Value::Null)
+        }
+        "torrent-resume" => {
+            let p: IdParams = parse_params(params)?;
+            session.lock().await.resume(p.id).map_err(server_error)?;
+            Ok(Value::Null)
+        }
+        // without an id this lists every torrent in the session
+        "torrent-get" => {
+            let p: GetParams = parse_optional_params(params)?;
+            let session = session.lock().await;
+            match p.id {
+                Some(id) => to_value(session.status(id).map_err(server_error)?),
+                None => to_value(session.list()),
+            }
+        }
+        "torrent-set-dht" => {
+            let p: TorrentDhtParams = parse_params(params)?;
+            session.lock().await.set_torrent_dht(p.id, p.enabled).map_err(server_error)?;
+            Ok(Value::Null)
+        }
+        "torrent-peers" => {
+            let p: IdParams = parse_params(params)?;
+            to_value(session.lock().await.peers(p.id).map_err(server_error)?)
+        }
+        "peer-limit-set" => {
+            let p: PeerLimitParams = parse_params(params)?;
+            session.lock().await.set_peer_rate_limits(p.id, &p.address, p.limits).map_err(server_error)?;
+            Ok(Value::Null)
+        }
+        // for players streaming a torrent, see TorrentClient::set_piece_deadline
+        "piece-deadline-set" => {
+            let p: DeadlineParams = parse_params(params)?;
+            let mut session = session.lock().await;
+            match p.millis {
+                Some(millis) => session.set_piece_deadline(p.id, p.piece, Duration::from_millis(millis)),
+                None => session.clear_piece_deadline(p.id, p.piece),
+            }
+            .map_err(server_error)?;
+            Ok(Value::Null)
+        }
+        "torrent-trackers" => {
+            let p: IdParams = parse_params(params)?;
+            to_value(session.lock().await.trackers(p.id).map_err(server_error)?)
+        }
+        "queue-move" => {
+            let p: QueueMoveParams = parse_params(params)?;
+            session.lock().await.set_queue_position(p.id, p.position).map_err(server_error)?;
+            Ok(Value::Null)
+        }
+        "queue-get" => to_value(session.lock().await.queue_limits()),
+        "queue-set" => {
+            let limits: QueueLimits = parse_params(params)?;
+            session.lock().await.set_queue_limits(limits).map_err(server_error)?;
+            Ok(Value::Null)
+        }
+        "session-get" => to_value(session.lock().await.rate_limits()),
+        "session-stats" => to_value(session.lock().await.stats()),
+        "blocklist-get" => to_value(session.lock().await.blocklist_status()),
+        // without an id the whole session's
+        "rate-history" => {
+            let p: GetParams = parse_optional_params(params)?;
+            to_value(session.lock().await.rate_history(p.id).map_err(server_error)?)
+        }
+        "session-pause" => {
+            session.lock().await.pause_all();
+            Ok(Value::Null)
+        }
+        "session-resume" => {
+            session.lock().await.resume_all();
+            Ok(Value::Null)
+        }
+        "session-set" => {
+            let limits: RateLimits = parse_params(params)?;
+            session.lock().await.set_rate_limits(limits);
+            Ok(Value::Null)
+        }
+        "alt-speed-get" => {
+            let session = session.lock().await;
+            Ok(json!({ "schedule": to_value(session.alt_speed_schedule())?, "active": session.is_alt_speed_active() }))
+        }
+        // null params clear the schedule
+        "alt-speed-set" => {
+            let schedule: Option<SpeedSchedule> = parse_params(params)?;
+            session.lock().await.set_alt_speed_schedule(schedule).map_err(server_error)?;
+            Ok(Value::Null)
+        }
+        "session-set-dht" => {
+            let p: DhtParams = parse_params(params)?;
+            session.lock().await.set_dht(p.enabled);
+            Ok(Value::Null)
+        }
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method: {}", method))),
+    }
+}
+
+// sends one request to a running client's rpc server and returns the
+// result, or the error it answered with
+pub async fn call(addr: &str, method: &str, params: Value) -> Result<Value, Box<dyn Error>> {
+    let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 }).to_string();
+    let client = reqwest::Client::new();
+    let url = format!("http://{}", addr);
+    let send = |session_id: Option<String>| {
+        let mut post = client.post(&url).body(request.clone());
+        if let Some(session_id) = session_id {
+            post = post.header(SESSION_ID_HEADER, session_id);
+        }
+        post.send()
+    };
+
+    // the first answer tells us the session id to send
+    let mut response = send(None).await?;
+    if response.status() == reqwest::StatusCode::CONFLICT {
+        let session_id = response.headers().get(SESSION_ID_HEADER).and_then(|v| v.to_str().ok()).map(str::to_string);
+        response = send(session_id).await?;
+    }
+    let body = response.error_for_status()?.bytes().await?;
+
+    let mut response: Value = serde_json::from_slice(&body)?;
+    match response["error"]["message"].as_str() {
+        Some(message) => Err(message.to_string().into()),
+        None => Ok(response["result"].take()),
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| (INVALID_PARAMS, e.to_string()))
+}
+
+fn parse_optional_params<T: DeserializeOwned + Default>(params: Value) -> Result<T, RpcError> {
+    if params.is_null() {
+        return Ok(T::default());
+    }
+    parse_params(params)
+}
+
+fn to_value<T: serde::Serialize>(value: T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(server_error)
+}
+
+fn server_error(e: impl ToString) -> RpcError {
+    (SERVER_ERROR, e.to_string())
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "error": { "code": code, "message": message },
+        "id": id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn call(session: &Mutex<Session>, body: &str) -> Value {
+        handle_request(session, body.as_bytes()).await
+    }
+
+    // a whole http exchange, headers and all
+    async fn exchange(session: &Mutex<Session>, request: String) -> String {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        client.write_all(request.as_bytes()).await.unwrap();
+        handle_connection(server, session, "secret").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    fn post(headers: &str) -> String {
+        let body = r#"{"jsonrpc":"2.0","method":"queue-get","id":1}"#;
+        format!("POST / HTTP/1.1\r\n{}Content-Length: {}\r\n\r\n{}", headers, body.len(), body)
+    }
+
+    #[tokio::test]
+    async fn test_session_id() {
+        let session = Mutex::new(Session::new());
+        let response = exchange(&session, post("Host: 127.0.0.1:9091\r\n")).await;
+        assert!(response.starts_with("HTTP/1.1 409"), "{}", response);
+        assert!(response.contains("X-Transmission-Session-Id: secret\r\n"));
+
+        let response = exchange(&session, post("Host: localhost:9091\r\nX-Transmission-Session-Id: wrong\r\n")).await;
+        assert!(response.starts_with("HTTP/1.1 409"));
+        let response = exchange(&session, post("Host: localhost:9091\r\nx-transmission-session-id: secret\r\n")).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.contains(r#""result""#));
+    }
+
+    #[tokio::test]
+    async fn test_foreign_pages() {
+        let session = Mutex::new(Session::new());
+        let token = "X-Transmission-Session-Id: secret\r\n";
+        for headers in ["Host: evil.example:9091\r\n", "Host: 127.0.0.1:9091\r\nOrigin: https://evil.example\r\n", "Host: [::1]:9091\r\nOrigin: http://10.0.0.1\r\n"] {
+            let response = exchange(&session, post(&format!("{}{}", headers, token))).await;
+            assert!(response.starts_with("HTTP/1.1 403"), "{}", headers);
+        }
+        let response = exchange(&session, post(&format!("Host: [::1]:9091\r\nOrigin: http://localhost:8080\r\n{}", token))).await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+    }
+
+    // the cli's side of it picks the session id up from the 409
+    #[tokio::test]
+    async fn test_call_learns_session_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let session = Mutex::new(Session::new());
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                handle_connection(stream, &session, "secret").await.unwrap();
+            }
+        });
+        let limits = super::call(&addr, "queue-get", Value::Null).await.unwrap();
+        assert!(limits.is_object());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_slow_headers() {
+        let session = Mutex::new(Session::new());
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"POST / HTTP/1.1\r\nHost: loc").await.unwrap();
+        let err = handle_connection(server, &session, "secret").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn test_parse_error() {
+        let session = Mutex::new(Session::new());
+        let res = call(&session, "{not json").await;
+        assert_eq!(res["error"]["code"], PARSE_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_method() {
+        let session = Mutex::new(Session::new());
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"nope","id":1}"#).await;
+        assert_eq!(res["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(res["id"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_empty_session() {
+        let session = Mutex::new(Session::new());
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"torrent-get","id":2}"#).await;
+        assert_eq!(res["result"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_torrent() {
+        let session = Mutex::new(Session::new());
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"torrent-pause","params":{"id":7},"id":3}"#).await;
+        assert_eq!(res["error"]["code"], SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_session_stats() {
+        let session = Mutex::new(Session::new());
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"session-stats","id":6}"#).await;
+        assert!(res["result"]["warnings"].is_object());
+
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"rate-history","id":7}"#).await;
+        assert_eq!(res["result"]["download"].as_array().unwrap().len(), crate::stats::HISTORY_SECONDS);
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"rate-history","params":{"id":9},"id":7}"#).await;
+        assert!(res["error"]["message"].as_str().unwrap().contains("no torrent"));
+
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"blocklist-get","id":7}"#).await;
+        assert_eq!(res["result"], json!({ "url": null, "updated": null, "entries": 0, "error": null }));
+    }
+
+    #[tokio::test]
+    async fn test_set_and_get_rate_limits() {
+        let session = Mutex::new(Session::new());
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"session-set","params":{"download":1024,"upload":null},"id":4}"#).await;
+        assert!(res.get("error").is_none());
+
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"session-get","id":5}"#).await;
+        assert_eq!(res["result"]["download"], 1024);
+        assert!(res["result"]["upload"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_queue_limits() {
+        let session = Mutex::new(Session::new());
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"queue-set","params":{"max_active_downloads":2,"max_active_seeds":null},"id":12}"#).await;
+        assert!(res.get("error").is_none());
+
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"queue-get","id":13}"#).await;
+        assert_eq!(res["result"]["max_active_downloads"], 2);
+        assert!(res["result"]["max_active_seeds"].is_null());
+
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"queue-set","params":{"max_active_seeds":0},"id":14}"#).await;
+        assert_eq!(res["error"]["code"], SERVER_ERROR);
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"queue-move","params":{"id":7,"position":0},"id":15}"#).await;
+        assert_eq!(res["error"]["code"], SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_alt_speed() {
+        let session = Mutex::new(Session::new());
+        let set = r#"{"jsonrpc":"2.0","method":"alt-speed-set","params":{"limits":{"download":1024,"upload":null},"days":["saturday","sunday"],"start":0,"end":1439},"id":16}"#;
+        let res = call(&session, set).await;
+        assert!(res.get("error").is_none());
+
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"alt-speed-get","id":17}"#).await;
+        assert_eq!(res["result"]["schedule"]["days"], json!(["saturday", "sunday"]));
+        assert_eq!(res["result"]["schedule"]["limits"]["download"], 1024);
+
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"alt-speed-set","params":{"limits":{},"days":["friday"],"start":60,"end":60},"id":18}"#).await;
+        assert_eq!(res["error"]["code"], SERVER_ERROR);
+
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"alt-speed-set","params":null,"id":19}"#).await;
+        assert!(res.get("error").is_none());
+        assert!(session.lock().await.alt_speed_schedule().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_pause_resume() {
+        let session = Mutex::new(Session::new());
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"session-pause","id":7}"#).await;
+        assert!(res.get("error").is_none());
+        assert!(session.lock().await.is_paused_all());
+
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"session-resume","id":8}"#).await;
+        assert!(res.get("error").is_none());
+        assert!(!session.lock().await.is_paused_all());
+    }
+
+    #[tokio::test]
+    async fn test_set_dht() {
+        let session = Mutex::new(Session::new());
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"session-set-dht","params":{"enabled":true},"id":9}"#).await;
+        assert!(res.get("error").is_none());
+        assert!(session.lock().await.dht_enabled());
+
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"session-set-dht","params":{},"id":10}"#).await;
+        assert_eq!(res["error"]["code"], INVALID_PARAMS);
+
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"torrent-set-dht","params":{"id":7,"enabled":null},"id":11}"#).await;
+        assert_eq!(res["error"]["code"], SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_peer_limit() {
+        let session = Mutex::new(Session::new());
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"peer-limit-set","params":{"id":7,"address":"10.0.0.1:6881","limits":{"upload":65536}},"id":19}"#).await;
+        assert_eq!(res["error"]["code"], SERVER_ERROR);
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"peer-limit-set","params":{"id":7},"id":20}"#).await;
+        assert_eq!(res["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn test_piece_deadline() {
+        let session = Mutex::new(Session::new());
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"piece-deadline-set","params":{"id":7,"piece":0,"millis":500},"id":21}"#).await;
+        assert_eq!(res["error"]["code"], SERVER_ERROR);
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"piece-deadline-set","params":{"id":7,"piece":0,"millis":null},"id":22}"#).await;
+        assert_eq!(res["error"]["code"], SERVER_ERROR);
+        let res = call(&session, r#"{"jsonrpc":"2.0","method":"piece-deadline-set","params":{"id":7},"id":23}"#).await;
+        assert_eq!(res["error"]["code"], INVALID_PARAMS);
+    }
+}
@@ -0,0 +1,150 @@
+use chrono::{DateTime, Datelike, TimeDelta, Timelike};
+use serde::{Deserialize, Serialize};
+
+use crate::session::RateLimits;
+
+// alternative rate limits for part of the week, e.g. throttling
+// during work hours. times are minutes after midnight in local time,
+// which is utc plus an offset given with the schedule, so it means the
+// same wherever the client runs. an end before the start runs past
+// midnight into the next day, so 22:00 to 06:00 on friday covers
+// friday night and saturday morning.
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Sunday,
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+}
+
+// the names the rpc api uses, for chrono's days
+impl From<chrono::Weekday> for Weekday {
+    fn from(day: chrono::Weekday) -> Weekday {
+        match day {
+            chrono::Weekday::Sun => Weekday::Sunday,
+            chrono::Weekday::Mon => Weekday::Monday,
+            chrono::Weekday::Tue => Weekday::Tuesday,
+            chrono::Weekday::Wed => Weekday::Wednesday,
+            chrono::Weekday::Thu => Weekday::Thursday,
+            chrono::Weekday::Fri => Weekday::Friday,
+            chrono::Weekday::Sat => Weekday::Saturday,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpeedSchedule {
+    // what applies while the schedule is on
+    pub limits: RateLimits,
+    // the days the schedule starts on
+    pub days: Vec<Weekday>,
+    pub start: u16,
+    pub end: u16,
+    // minutes east of utc
+    #[serde(default)]
+    pub utc_offset: i32,
+}
+
+impl SpeedSchedule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start >= MINUTES_PER_DAY || self.end >= MINUTES_PER_DAY {
+            return Err("schedule times are minutes after midnight, below 1440".to_string());
+        }
+        if self.start == self.end {
+            return Err("a schedule has to start and end at different times".to_string());
+        }
+        if self.days.is_empty() {
+            return Err("a schedule needs at least one day".to_string());
+        }
+        if self.utc_offset.abs() >= MINUTES_PER_DAY as i32 {
+            return Err(format!("utc offset of {} minutes is more than a day", self.utc_offset));
+        }
+        if self.limits.download == Some(0) || self.limits.upload == Some(0) {
+            return Err("a rate limit of 0 would stop all transfers, leave it unset for unlimited".to_string());
+        }
+        Ok(())
+    }
+
+    // whether the alternative limits apply at the given unix time
+    pub fn is_active(&self, unix_secs: i64) -> bool {
+        let Some(utc) = DateTime::from_timestamp(unix_secs, 0) else { return false };
+        let local = utc.naive_utc() + TimeDelta::minutes(self.utc_offset as i64);
+        let day = local.weekday();
+        let minute = (local.hour() * 60 + local.minute()) as u16;
+
+        let on = |day: chrono::Weekday| self.days.contains(&day.into());
+        if self.start < self.end {
+            on(day) && (self.start..self.end).contains(&minute)
+        } else {
+            (on(day) && minute >= self.start) || (on(day.pred()) && minute < self.end)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-01 was a monday
+    const MONDAY: i64 = 1704067200;
+
+    fn at(day: i64, hour: i64, minute: i64) -> i64 {
+        MONDAY + day * 86400 + hour * 3600 + minute * 60
+    }
+
+    fn schedule(days: &[Weekday], start: u16, end: u16) -> SpeedSchedule {
+        SpeedSchedule {
+            limits: RateLimits { download: Some(1024), upload: Some(512) },
+            days: days.to_vec(),
+            start,
+            end,
+            utc_offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_work_hours() {
+        let weekdays = [Weekday::Monday, Weekday::Tuesday, Weekday::Wednesday, Weekday::Thursday, Weekday::Friday];
+        let work = schedule(&weekdays, 9 * 60, 17 * 60);
+        assert!(work.validate().is_ok());
+
+        assert!(work.is_active(at(0, 9, 0)));
+        assert!(work.is_active(at(4, 16, 59)));
+        assert!(!work.is_active(at(0, 17, 0)));
+        assert!(!work.is_active(at(0, 8, 59)));
+        // saturday
+        assert!(!work.is_active(at(5, 12, 0)));
+
+        // 9:00 in utc+2 is 7:00 utc
+        let work = SpeedSchedule { utc_offset: 120, ..work };
+        assert!(work.is_active(at(0, 7, 0)));
+        assert!(!work.is_active(at(0, 15, 0)));
+    }
+
+    #[test]
+    fn test_overnight() {
+        let night = schedule(&[Weekday::Friday], 22 * 60, 6 * 60);
+        assert!(night.is_active(at(4, 23, 0)));
+        assert!(night.is_active(at(5, 5, 59)));
+        assert!(!night.is_active(at(5, 6, 0)));
+        // the early hours of friday belong to thursday night
+        assert!(!night.is_active(at(4, 1, 0)));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(schedule(&[Weekday::Monday], 60, 60).validate().is_err());
+        assert!(schedule(&[Weekday::Monday], 0, 1440).validate().is_err());
+        assert!(schedule(&[], 0, 60).validate().is_err());
+        let mut unlimited = schedule(&[Weekday::Monday], 0, 60);
+        unlimited.limits.upload = Some(0);
+        assert!(unlimited.validate().is_err());
+    }
+}
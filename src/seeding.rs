@@ -0,0 +1,144 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+// when a complete torrent has done its share. the first limit reached
+// stops it: the tracker is told we have gone and the torrent is left
+// finished, still in the session with its data on disk, but no longer
+// uploading. resuming it seeds on with no limits, the user having
+// asked for more.
+
+// how often a seeding torrent is checked against its limits
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SeedLimits {
+    // uploaded over downloaded, see share_ratio
+    pub ratio: Option<f64>,
+    // time spent seeding, not counting time paused
+    pub seed_time: Option<Duration>,
+    // time spent seeding without uploading anything to anyone
+    pub idle_timeout: Option<Duration>,
+}
+
+impl SeedLimits {
+    pub fn is_set(&self) -> bool {
+        self.ratio.is_some() || self.seed_time.is_some() || self.idle_timeout.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    Ratio,
+    SeedTime,
+    Idle,
+}
+
+// a torrent that was complete to begin with has downloaded nothing, so
+// it goes by its size instead. otherwise its ratio would be infinite
+// from the first byte it uploads.
+pub fn share_ratio(uploaded: u64, downloaded: u64, total_size: u64) -> f64 {
+    let base = if downloaded == 0 { total_size } else { downloaded };
+    if base == 0 {
+        return 0.0;
+    }
+    uploaded as f64 / base as f64
+}
+
+// keeps time while a torrent seeds
+#[derive(Debug, Clone)]
+pub struct SeedClock {
+    limits: SeedLimits,
+    seeded: Duration,
+    // None while the torrent isn't seeding
+    last_tick: Option<Instant>,
+    uploaded: u64,
+    last_upload: Instant,
+}
+
+impl SeedClock {
+    pub fn new(limits: SeedLimits, now: Instant) -> SeedClock {
+        SeedClock { limits, seeded: Duration::ZERO, last_tick: None, uploaded: 0, last_upload: now }
+    }
+
+    // seeding is whether the torrent is complete and not paused. returns
+    // the limit that was reached, if any.
+    pub fn tick(&mut self, now: Instant, seeding: bool, uploaded: u64, ratio: f64) -> Option<StopReason> {
+        if !seeding {
+            self.last_tick = None;
+            return None;
+        }
+
+        match self.last_tick {
+            Some(last) => self.seeded += now.saturating_duration_since(last),
+            // idle time starts over whenever seeding does, time spent
+            // downloading or paused doesn't count
+            None => self.last_upload = now,
+        }
+        self.last_tick = Some(now);
+        if uploaded > self.uploaded {
+            self.uploaded = uploaded;
+            self.last_upload = now;
+        }
+
+        if self.limits.ratio.is_some_and(|limit| ratio >= limit) {
+            Some(StopReason::Ratio)
+        } else if self.limits.seed_time.is_some_and(|limit| self.seeded >= limit) {
+            Some(StopReason::SeedTime)
+        } else if self.limits.idle_timeout.is_some_and(|limit| now.saturating_duration_since(self.last_upload) >= limit) {
+            Some(StopReason::Idle)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_share_ratio() {
+        assert_eq!(share_ratio(300, 100, 1000), 3.0);
+        // seeding data we never downloaded
+        assert_eq!(share_ratio(500, 0, 1000), 0.5);
+        assert_eq!(share_ratio(500, 0, 0), 0.0);
+    }
+
+    #[test]
+    fn test_limits() {
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+
+        let mut clock = SeedClock::new(SeedLimits { ratio: Some(2.0), ..Default::default() }, start);
+        assert_eq!(clock.tick(start, true, 100, 1.0), None);
+        assert_eq!(clock.tick(secs(5), true, 200, 2.0), Some(StopReason::Ratio));
+
+        // time paused or downloading isn't seed time
+        let limits = SeedLimits { seed_time: Some(Duration::from_secs(60)), ..Default::default() };
+        let mut clock = SeedClock::new(limits, start);
+        assert_eq!(clock.tick(start, true, 0, 0.0), None);
+        assert_eq!(clock.tick(secs(40), true, 0, 0.0), None);
+        assert_eq!(clock.tick(secs(100), false, 0, 0.0), None);
+        assert_eq!(clock.tick(secs(1000), true, 0, 0.0), None);
+        assert_eq!(clock.tick(secs(1010), true, 0, 0.0), None);
+        assert_eq!(clock.tick(secs(1020), true, 0, 0.0), Some(StopReason::SeedTime));
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let start = Instant::now();
+        let secs = |s| start + Duration::from_secs(s);
+        let limits = SeedLimits { idle_timeout: Some(Duration::from_secs(30)), ..Default::default() };
+        let mut clock = SeedClock::new(limits, start);
+
+        // still downloading, so the torrent can't be idle yet
+        assert_eq!(clock.tick(secs(100), false, 0, 0.0), None);
+        assert_eq!(clock.tick(secs(120), true, 0, 0.0), None);
+        // an upload starts the wait over
+        assert_eq!(clock.tick(secs(140), true, 16384, 0.0), None);
+        assert_eq!(clock.tick(secs(160), true, 16384, 0.0), None);
+        assert_eq!(clock.tick(secs(170), true, 16384, 0.0), Some(StopReason::Idle));
+    }
+}
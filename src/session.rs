@@ -0,0 +1,1785 @@
+use std::{collections::{BTreeMap, BTreeSet}, fs, io, net::SocketAddr, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use bytes::Bytes;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::{
+    banlist::BanList,
+    blocklist::{self, BlocklistStatus},
+    geoip::{self, GeoIp},
+    bundle::{Bundle, ResumeState},
+    dht::{self, DhtState, RoutingTable, DEFAULT_BOOTSTRAP_NODES},
+    external_ip::{self, ExternalAddrs, ExternalIp},
+    hooks::HookEvent,
+    notify::Event,
+    peer_state::PeerState,
+    disk_space::{self, DiskSpaceCheck},
+    fastresume::FastResume,
+    filemap::{self, FileMap},
+    client::{ClientConfig, TorrentClient, DEFAULT_LISTEN_PORT},
+    dedup,
+    error::BtError,
+    info_hash::InfoHash,
+    listener::{self, Routes},
+    peer_id::PEER_ID_PREFIX,
+    picker::PickerKind,
+    schedule::SpeedSchedule,
+    seeding::StopReason,
+    shaper::{self, SessionShaper},
+    sources::{PeerSource, PeerSources, SourceCounts},
+    stats::{Accounting, RateHistory, RateSeries, TransferSnapshot},
+    torrent::{encode_metainfo, parse_torrent, Torrent},
+    tracker::TrackerState,
+    transport::{BindTo, PeerTransport, Socks5Transport, TcpTransport},
+    verify, warnings,
+};
+
+pub type TorrentId = u64;
+
+// what main keeps resume data in, inside the download directory
+pub const RESUME_DIR: &str = ".bt-c-resume";
+
+// how often main calls tick, to let the queue catch up with torrents
+// that have finished and the rate limits with the schedule
+pub const TICK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TorrentState {
+    Downloading,
+    Seeding,
+    Paused,
+    // done seeding, see seeding::SeedLimits
+    Finished,
+    // waiting for one of the session's active slots
+    Queued,
+    // stopped by a problem that needs someone to fix it, see
+    // TorrentStatus::error. resuming tries again.
+    Error,
+}
+
+// global transfer limits in bytes per second. None means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct RateLimits {
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+// how many torrents run at once, the rest are queued. None means no
+// limit. paused and finished torrents don't take up a slot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueueLimits {
+    pub max_active_downloads: Option<usize>,
+    pub max_active_seeds: Option<usize>,
+}
+
+// point in time view of a torrent, handed out to the rpc server
+// so callers never hold on to the client itself
+#[derive(Debug, Clone, Serialize)]
+pub struct TorrentStatus {
+    pub id: TorrentId,
+    pub name: String,
+    pub info_hash: String,
+    pub state: TorrentState,
+    // what stopped the torrent, while it is in the error state
+    pub error: Option<String>,
+    // where its data is, which is name unless it has been moved to the
+    // completed directory
+    pub path: String,
+    pub pieces_have: usize,
+    pub pieces_total: usize,
+    pub progress: f64,
+    // 0 is the first torrent to get a slot
+    pub queue_position: usize,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub ratio: f64,
+    // the limit a finished torrent reached
+    pub stop_reason: Option<StopReason>,
+    pub total_size: u64,
+    // whether new connections advertise dht support
+    pub dht: bool,
+    pub tracker: TrackerState,
+    pub stats: TransferSnapshot,
+    pub accounting: Accounting,
+}
+
+// counters that apply to the whole session
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionStats {
+    // number of times each kind of warning has fired
+    pub warnings: BTreeMap<String, u64>,
+    // unique peers found and connected to through each discovery method
+    pub peer_sources: BTreeMap<PeerSource, SourceCounts>,
+    // our address as trackers, or the ip check, last saw it
+    pub external_ip: ExternalAddrs,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    pub address: String,
+    // e.g. "Transmission 3.00", when we recognise the peer id
+    pub client: Option<String>,
+    // how we found the peer
+    pub sources: Vec<PeerSource>,
+    pub pieces: usize,
+    pub stats: TransferSnapshot,
+    // the peer has left our requests unanswered for a minute
+    pub snubbed: bool,
+    // two letter iso code, when the session has a geoip database
+    pub country: Option<String>,
+    pub state: PeerState,
+    // blocks we have asked the peer for and not had yet
+    pub requests: usize,
+    // the caps it is held to, see shaper.rs
+    pub limits: RateLimits,
+}
+
+// a session owns every torrent the client is working on and the
+// settings that apply across all of them.
+pub struct Session {
+    torrents: BTreeMap<TorrentId, TorrentClient>,
+    next_id: TorrentId,
+    // every torrent, in the order they get active slots
+    queue: Vec<TorrentId>,
+    rate_limits: RateLimits,
+    // alternative limits that take over from rate_limits at set times
+    alt_schedule: Option<SpeedSchedule>,
+    alt_active: bool,
+    // whichever of those is in force, shared by every connection of
+    // every torrent, see shaper.rs
+    shaper: SessionShaper,
+    queue_limits: QueueLimits,
+    client_config: ClientConfig,
+    transport: Arc<dyn PeerTransport>,
+    bans: Arc<Mutex<BanList>>,
+    sources: Arc<Mutex<PeerSources>>,
+    // peers dialing the listen port are handed to the torrent they ask for
+    routes: Routes,
+    listeners: Vec<JoinHandle<()>>,
+    // torrents paused by pause_all, which resume_all will restart.
+    // None when the session isn't paused.
+    paused_all: Option<BTreeSet<TorrentId>>,
+    download_dir: PathBuf,
+    dht: bool,
+    // shared with every torrent so peers' dht nodes end up in one place
+    dht_state: Arc<Mutex<DhtState>>,
+    // shared with every torrent, see external_ip.rs
+    external_ip: Arc<ExternalIp>,
+    ip_check: Option<String>,
+    last_ip_check: Option<SystemTime>,
+    // the download of the list, when there is a url for one, updates
+    // this and swaps the list into bans
+    blocklist: Arc<Mutex<BlocklistStatus>>,
+    last_blocklist_update: Option<SystemTime>,
+    geoip: Option<GeoIp>,
+    proxy: Option<Proxy>,
+    bind: Option<BindTo>,
+    resume_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proxy {
+    pub kind: ProxyKind,
+    pub host: String,
+    pub port: u16,
+    // socks5h: the proxy looks up peers given by name, not us
+    pub remote_dns: bool,
+}
+
+impl Proxy {
+    // accepts socks5://host:port, socks5h://host:port and http://host:port
+    pub fn parse(url: &str) -> Result<Proxy, String> {
+        let (scheme, rest) = url.split_once("://").ok_or_else(|| format!("proxy {} has no scheme", url))?;
+        let kind = match scheme {
+            "socks5" | "socks5h" => ProxyKind::Socks5,
+            "http" => ProxyKind::Http,
+            _ => return Err(format!("unsupported proxy scheme: {}", scheme)),
+        };
+
+        let (host, port) = rest
+            .trim_end_matches('/')
+            .rsplit_once(':')
+            .ok_or_else(|| format!("proxy {} has no port", url))?;
+        let port = port.parse().map_err(|_| format!("invalid proxy port: {}", port))?;
+        if host.is_empty() || port == 0 {
+            return Err(format!("invalid proxy address: {}", url));
+        }
+
+        Ok(Proxy { kind, host: host.to_string(), port, remote_dns: scheme == "socks5h" })
+    }
+}
+
+// how a session gets made. the defaults give a session that downloads
+// into the current directory over plain tcp.
+pub struct SessionBuilder {
+    listen_port: u16,
+    udp_port: Option<u16>,
+    download_dir: PathBuf,
+    dht: bool,
+    dht_bootstrap_nodes: Vec<String>,
+    dht_state_file: Option<PathBuf>,
+    resume_dir: Option<PathBuf>,
+    rate_limits: RateLimits,
+    alt_schedule: Option<SpeedSchedule>,
+    queue_limits: QueueLimits,
+    peer_id_prefix: String,
+    proxy: Option<String>,
+    strict_proxy: bool,
+    bind: Option<String>,
+    ip_check: Option<String>,
+    blocklist: Option<String>,
+    geoip: Option<PathBuf>,
+    client_config: ClientConfig,
+    transport: Arc<dyn PeerTransport>,
+}
+
+impl Default for SessionBuilder {
+    fn default() -> Self {
+        SessionBuilder {
+            listen_port: DEFAULT_LISTEN_PORT,
+            udp_port: None,
+            download_dir: PathBuf::from("."),
+            dht: false,
+            dht_bootstrap_nodes: DEFAULT_BOOTSTRAP_NODES.iter().map(|s| s.to_string()).collect(),
+            dht_state_file: None,
+            resume_dir: None,
+            rate_limits: RateLimits::default(),
+            alt_schedule: None,
+            queue_limits: QueueLimits::default(),
+            peer_id_prefix: PEER_ID_PREFIX.to_string(),
+            proxy: None,
+            strict_proxy: false,
+            bind: None,
+            ip_check: None,
+            blocklist: None,
+            geoip: None,
+            client_config: ClientConfig::default(),
+            transport: Arc::new(TcpTransport::new()),
+        }
+    }
+}
+
+impl SessionBuilder {
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.listen_port = port;
+        self
+    }
+
+    // udp traffic goes through the listen port too unless this is set
+    pub fn udp_port(mut self, port: u16) -> Self {
+        self.udp_port = Some(port);
+        self
+    }
+
+    // where torrents are saved unless they are given an absolute path
+    pub fn download_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.download_dir = dir.into();
+        self
+    }
+
+    // torrents can override this, and private ones never use the dht
+    pub fn dht(mut self, enabled: bool) -> Self {
+        self.dht = enabled;
+        self
+    }
+
+    // "host:port" of the nodes a dht node with an empty routing table
+    // starts from
+    pub fn dht_bootstrap_nodes(mut self, nodes: Vec<String>) -> Self {
+        self.dht_bootstrap_nodes = nodes;
+        self
+    }
+
+    // the routing table is loaded from here when the session is built
+    // and written back by shutdown()
+    pub fn dht_state_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.dht_state_file = Some(path.into());
+        self
+    }
+
+    // shutdown() leaves each torrent's progress in here, one bundle per
+    // info hash, and adding the torrent again carries on from it
+    pub fn resume_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.resume_dir = Some(dir.into());
+        self
+    }
+
+    pub fn rate_limits(mut self, limits: RateLimits) -> Self {
+        self.rate_limits = limits;
+        self
+    }
+
+    pub fn alt_speed_schedule(mut self, schedule: SpeedSchedule) -> Self {
+        self.alt_schedule = Some(schedule);
+        self
+    }
+
+    pub fn queue_limits(mut self, limits: QueueLimits) -> Self {
+        self.queue_limits = limits;
+        self
+    }
+
+    pub fn peer_id_prefix(mut self, prefix: &str) -> Self {
+        self.peer_id_prefix = prefix.to_string();
+        self
+    }
+
+    // peers are dialed through a socks5 proxy. an http one is checked and
+    // kept but nothing goes through it yet, and neither do trackers,
+    // incoming peers or the dht.
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.proxy = Some(url.to_string());
+        self
+    }
+
+    // never dial a peer directly, not even while the proxy is down
+    pub fn strict_proxy(mut self, strict: bool) -> Self {
+        self.strict_proxy = strict;
+        self
+    }
+
+    // the interface (tun0, say) or source address peer connections and
+    // the listener are bound to, so they don't go out over the default
+    // route. peers are then dialed over tcp in place of any transport
+    // given. trackers aren't bound.
+    pub fn bind(mut self, interface_or_address: &str) -> Self {
+        self.bind = Some(interface_or_address.to_string());
+        self
+    }
+
+    // a url answering with our external address and nothing else, asked
+    // every so often on top of what trackers tell us
+    pub fn external_ip_check(mut self, url: &str) -> Self {
+        self.ip_check = Some(url.to_string());
+        self
+    }
+
+    // a list of addresses never to connect to or accept, see
+    // blocklist.rs. it is downloaded once the session is ticking and
+    // again every day.
+    pub fn blocklist(mut self, url: &str) -> Self {
+        self.blocklist = Some(url.to_string());
+        self
+    }
+
+    // a maxmind .mmdb to look up the country of each peer in
+    pub fn geoip<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.geoip = Some(path.into());
+        self
+    }
+
+    // every torrent added to the session is started with this config
+    pub fn client_config(mut self, config: ClientConfig) -> Self {
+        self.client_config = config;
+        self
+    }
+
+    // the transport peers are dialled over
+    pub fn transport(mut self, transport: Arc<dyn PeerTransport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn build(self) -> Result<Session, BtError> {
+        if self.listen_port == 0 {
+            return Err(BtError::Config("listen port can't be 0".to_string()));
+        }
+        if self.udp_port == Some(0) {
+            return Err(BtError::Config("udp port can't be 0".to_string()));
+        }
+
+        if !self.download_dir.is_dir() {
+            return Err(BtError::Config(format!("download directory {} doesn't exist", self.download_dir.display())));
+        }
+
+        if self.rate_limits.download == Some(0) || self.rate_limits.upload == Some(0) {
+            return Err(BtError::Config("a rate limit of 0 would stop all transfers, leave it unset for unlimited".to_string()));
+        }
+        validate_queue_limits(&self.queue_limits).map_err(BtError::Config)?;
+        if let Some(schedule) = &self.alt_schedule {
+            schedule.validate().map_err(BtError::Config)?;
+        }
+
+        // leave at least half of the peer id random
+        if self.peer_id_prefix.is_empty() || self.peer_id_prefix.len() > 10 || !self.peer_id_prefix.bytes().all(|b| b.is_ascii_graphic()) {
+            return Err(BtError::Config(format!("peer id prefix must be 1 to 10 printable ascii characters: {:?}", self.peer_id_prefix)));
+        }
+
+        let proxy = self.proxy.as_deref().map(Proxy::parse).transpose().map_err(BtError::Config)?;
+        let bind = self.bind.as_deref().map(BindTo::parse).transpose().map_err(BtError::Config)?;
+        let direct = match &bind {
+            Some(bind) => Arc::new(TcpTransport::bound(bind.clone())),
+            None => self.transport,
+        };
+        let transport = match &proxy {
+            Some(proxy) if proxy.kind == ProxyKind::Socks5 => {
+                let mut socks = Socks5Transport::new(&proxy.host, proxy.port, proxy.remote_dns);
+                if let Some(bind) = &bind {
+                    socks = socks.bind(bind.clone());
+                }
+                let socks = if self.strict_proxy { socks } else { socks.fallback(direct) };
+                Arc::new(socks) as Arc<dyn PeerTransport>
+            }
+            _ if self.strict_proxy => return Err(BtError::Config("a strict proxy needs a socks5 proxy to send peer connections through".to_string())),
+            _ => direct,
+        };
+        let geoip = self.geoip.as_deref().map(GeoIp::open).transpose().map_err(BtError::Config)?;
+
+        for node in &self.dht_bootstrap_nodes {
+            let valid = node.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok_and(|p| p != 0));
+            if !valid {
+                return Err(BtError::Config(format!("dht bootstrap node must be host:port: {:?}", node)));
+            }
+        }
+
+        let config = &self.client_config;
+        if config.max_peer_connections == 0 || config.max_interested_peers == 0 {
+            return Err(BtError::Config("a torrent needs at least one peer connection and one interested slot".to_string()));
+        }
+        if config.max_ongoing_pieces == Some(0) {
+            return Err(BtError::Config("a torrent needs to be able to download at least one piece at a time".to_string()));
+        }
+        if config.max_request_length == 0 {
+            return Err(BtError::Config("max request length must be at least 1".to_string()));
+        }
+        if config.max_in_flight_bytes.is_some_and(|max| max < config.requests.block_size as u64) {
+            return Err(BtError::Config(format!("the in-flight budget must hold at least one block of {} bytes", config.requests.block_size)));
+        }
+        config.requests.validate().map_err(BtError::Config)?;
+        shaper::validate(&config.peer_rate_limits).map_err(BtError::Config)?;
+        if config.max_upload_slots == Some(0) {
+            return Err(BtError::Config("a torrent needs at least one upload slot, leave it unset for unlimited".to_string()));
+        }
+        if config.max_hash_failures == 0 {
+            return Err(BtError::Config("max hash failures must be at least 1".to_string()));
+        }
+        if config.connect_timeout.is_zero() || config.handshake_timeout.is_zero() {
+            return Err(BtError::Config("connect and handshake timeouts must be more than 0".to_string()));
+        }
+        if config.churn_interval.is_some_and(|every| every.is_zero()) {
+            return Err(BtError::Config("a churn interval of 0 would drop peers nonstop, leave it unset to keep them".to_string()));
+        }
+        if let Some(url) = &self.ip_check {
+            if !is_http_url(url) {
+                return Err(BtError::Config(format!("external ip check must be an http(s) url: {}", url)));
+            }
+        }
+        if let Some(url) = &self.blocklist {
+            if !is_http_url(url) {
+                return Err(BtError::Config(format!("blocklist must be an http(s) url: {}", url)));
+            }
+        }
+        if let Some(url) = &config.webhook {
+            if !is_http_url(url) {
+                return Err(BtError::Config(format!("webhook must be an http(s) url: {}", url)));
+            }
+        }
+        if let Some(dir) = &config.completed_dir {
+            if !dir.is_dir() {
+                return Err(BtError::Config(format!("completed directory {} doesn't exist", dir.display())));
+            }
+        }
+
+        let session = Session {
+            torrents: BTreeMap::new(),
+            next_id: 1,
+            queue: Vec::new(),
+            rate_limits: self.rate_limits,
+            alt_active: self.alt_schedule.as_ref().is_some_and(|s| s.is_active(unix_time(SystemTime::now()))),
+            alt_schedule: self.alt_schedule,
+            shaper: SessionShaper::default(),
+            queue_limits: self.queue_limits,
+            client_config: ClientConfig {
+                peer_id_prefix: self.peer_id_prefix,
+                listen_port: self.listen_port,
+                udp_port: self.udp_port,
+                dht: self.dht,
+                ..self.client_config
+            },
+            transport,
+            bans: Arc::new(Mutex::new(BanList::new())),
+            sources: Arc::new(Mutex::new(PeerSources::new())),
+            routes: Routes::new(),
+            listeners: Vec::new(),
+            paused_all: None,
+            download_dir: self.download_dir,
+            dht: self.dht,
+            dht_state: Arc::new(Mutex::new(DhtState::load(self.dht_state_file, self.dht_bootstrap_nodes))),
+            external_ip: Arc::new(ExternalIp::new()),
+            ip_check: self.ip_check,
+            last_ip_check: None,
+            blocklist: Arc::new(Mutex::new(BlocklistStatus { url: self.blocklist, ..Default::default() })),
+            last_blocklist_update: None,
+            geoip,
+            proxy,
+            bind,
+            resume_dir: self.resume_dir,
+        };
+        session.apply_rate_limits();
+        Ok(session)
+    }
+}
+
+impl Session {
+    pub fn builder() -> SessionBuilder {
+        SessionBuilder::default()
+    }
+
+    // a session with every setting left at its default
+    pub fn new() -> Session {
+        Session::builder().build().expect("the default session config is valid")
+    }
+
+    pub fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.client_config.listen_port
+    }
+
+    // starts taking connections on the listen port, over ipv4 and ipv6
+    // where the machine has them. returns the addresses being listened on.
+    pub fn listen(&mut self) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs = Vec::new();
+        for socket in listener::bind(self.client_config.listen_port, self.bind.as_ref())? {
+            addrs.push(socket.local_addr()?);
+            self.listeners.push(tokio::spawn(listener::serve(
+                socket,
+                self.routes.clone(),
+                self.bans.clone(),
+                self.client_config.handshake_timeout,
+            )));
+        }
+        Ok(addrs)
+    }
+
+    pub fn udp_port(&self) -> u16 {
+        self.client_config.udp_port()
+    }
+
+    pub fn dht_enabled(&self) -> bool {
+        self.dht
+    }
+
+    // applies to every torrent that doesn't override it
+    pub fn set_dht(&mut self, enabled: bool) {
+        self.dht = enabled;
+        self.client_config.dht = enabled;
+        for client in self.torrents.values_mut() {
+            client.set_session_dht(enabled);
+        }
+    }
+
+    // None puts the torrent back on the session setting
+    pub fn set_torrent_dht(&mut self, id: TorrentId, enabled: Option<bool>) -> Result<(), BtError> {
+        let client = self.client_mut(id)?;
+        if enabled == Some(true) && client.torrent().private {
+            return Err(BtError::Config("dht can't be enabled for a private torrent".to_string()));
+        }
+        client.set_dht_override(enabled);
+        Ok(())
+    }
+
+    // see TorrentClient::read_piece. the future doesn't hold on to the
+    // session, so other calls can go on while it waits.
+    pub fn read_piece(&self, id: TorrentId, index: u32) -> impl std::future::Future<Output = Result<Bytes, BtError>> + Send + 'static {
+        let read = self.client(id).map(|client| client.read_piece(index));
+        async move { read?.await }
+    }
+
+    // see TorrentClient::set_piece_deadline
+    pub fn set_piece_deadline(&mut self, id: TorrentId, piece: u32, deadline: Duration) -> Result<(), BtError> {
+        self.client_mut(id)?.set_piece_deadline(piece, deadline)
+    }
+
+    pub fn set_piece_picker(&mut self, id: TorrentId, kind: PickerKind) -> Result<(), BtError> {
+        self.client_mut(id)?.set_piece_picker(kind);
+        Ok(())
+    }
+
+    pub fn clear_piece_deadline(&mut self, id: TorrentId, piece: u32) -> Result<(), BtError> {
+        self.client_mut(id)?.clear_deadline(piece);
+        Ok(())
+    }
+
+    pub fn dht_bootstrap_nodes(&self) -> Vec<String> {
+        self.dht_state.lock().unwrap().bootstrap_nodes.clone()
+    }
+
+    pub fn dht_table(&self) -> RoutingTable {
+        self.dht_state.lock().unwrap().table.clone()
+    }
+
+    // stops every torrent and saves what should survive until the next
+    // run: the downloaded data, each torrent's resume data and the dht
+    // routing table. trackers are told we are leaving on the way out.
+    // one thing failing doesn't stop the rest being saved.
+    pub async fn shutdown(&mut self) -> Result<(), BtError> {
+        for listener in self.listeners.drain(..) {
+            listener.abort();
+        }
+        let mut errors = Vec::new();
+        let mut goodbyes = JoinSet::new();
+        let mut flushed = BTreeSet::new();
+        for (&id, client) in self.torrents.iter_mut() {
+            goodbyes.spawn(client.announce_stopped());
+            match client.shutdown().await {
+                Ok(()) => {
+                    flushed.insert(id);
+                }
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+
+        // resume data for a torrent that couldn't be flushed might
+        // claim pieces that never made it to disk
+        for id in flushed {
+            if let Err(e) = self.save_resume(&self.torrents[&id]) {
+                errors.push(e);
+            }
+        }
+        if let Err(e) = self.dht_state.lock().unwrap().save() {
+            errors.push(format!("couldn't save dht state: {}", e));
+        }
+
+        while let Some(result) = goodbyes.join_next().await {
+            if let Ok(Err(e)) = result {
+                warn!("couldn't tell the tracker we stopped: {}", e);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(BtError::Storage(io::Error::other(errors.join(", "))))
+        }
+    }
+
+    fn save_resume(&self, client: &TorrentClient) -> Result<(), String> {
+        let Some(dir) = &self.resume_dir else { return Ok(()) };
+        let torrent = client.torrent();
+        let resume = client.resume_state().encode(&torrent.info_hash);
+
+        fs::create_dir_all(dir)
+            .and_then(|_| dht::write_atomically(&resume_path(dir, &torrent.info_hash), &resume))
+            .map_err(|e| format!("couldn't save resume data for {}: {}", torrent.output_file, e))
+    }
+
+    // what shutdown() saved for the torrent last time, if anything. the
+    // data is trusted to still be on disk, as with import().
+    fn saved_resume(&self, torrent: &Torrent) -> Option<ResumeState> {
+        let path = resume_path(self.resume_dir.as_ref()?, &torrent.info_hash);
+        let data = fs::read(&path).ok()?;
+        match ResumeState::decode(&data, &torrent.info_hash, torrent.pieces.len()) {
+            Ok(resume) => Some(resume),
+            Err(e) => {
+                warn!("ignoring resume data in {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+
+    pub fn bound_to(&self) -> Option<&BindTo> {
+        self.bind.as_ref()
+    }
+
+    // adds the torrent and starts downloading it straight away
+    pub async fn add_torrent(&mut self, torrent: Torrent) -> Result<TorrentId, BtError> {
+        self.insert(torrent, false, None).await
+    }
+
+    // hard links any files the new torrent shares with torrents we have
+    // already finished, and returns the pieces that fills in, if any. a
+    // torrent that only shares some of its files with others (a pack
+    // with a release we have, a re-release) downloads just the rest.
+    fn link_from_existing(&self, torrent: &Torrent) -> Option<ResumeState> {
+        if dedup::data_files(torrent) == 0 {
+            return None;
+        }
+
+        let mut linked = Vec::new();
+        for client in self.torrents.values().filter(|c| c.is_complete()) {
+            // where the files are now, which is the completed directory
+            // once they have been moved there
+            let storage = client.storage();
+            let found = dedup::link_identical_files(client.torrent(), |i| storage.file_path(i), torrent);
+            if !found.is_empty() {
+                info!("{} shares {} files with {}, linking them instead of downloading", torrent.output_file, found.len(), storage.location().display());
+            }
+            linked.extend(found);
+        }
+        if linked.is_empty() {
+            return None;
+        }
+
+        let have = dedup::linked_pieces(torrent, &linked);
+        Some(ResumeState { have, downloaded: 0, uploaded: 0 })
+    }
+
+    // adds a torrent whose data has already been checked on disk and seeds it
+    pub async fn add_complete_torrent(&mut self, torrent: Torrent) -> Result<TorrentId, BtError> {
+        self.insert(torrent, true, None).await
+    }
+
+    // packs up a torrent so it can be imported into another session
+    pub fn export(&self, id: TorrentId) -> Result<Bundle, BtError> {
+        let client = self.client(id)?;
+        let torrent = client.torrent();
+
+        Ok(Bundle::new(torrent.info_hash, encode_metainfo(torrent)?, client.resume_state()))
+    }
+
+    // the same for libtorrent based clients, see fastresume.rs. the
+    // .torrent goes next to it, for those that don't read the info dict
+    // out of the resume file.
+    pub fn export_fastresume(&self, id: TorrentId) -> Result<(FastResume, Vec<u8>), BtError> {
+        let client = self.client(id)?;
+        let torrent = client.torrent();
+        let paused = client.state() == TorrentState::Paused;
+
+        let resume = FastResume::from_torrent(torrent, client.resume_state(), paused).map_err(BtError::Torrent)?;
+        Ok((resume, encode_metainfo(torrent)?))
+    }
+
+    // adds an exported torrent, carrying on from where it was. the data
+    // should already be in the download directory and is trusted to
+    // match the bundle.
+    pub async fn import(&mut self, bundle: &Bundle) -> Result<TorrentId, BtError> {
+        self.import_torrent(bundle.torrent()?, &bundle.resume).await
+    }
+
+    // import for a torrent already parsed out of its bundle, so that can
+    // be done without holding up the session
+    pub async fn import_torrent(&mut self, torrent: Torrent, resume: &ResumeState) -> Result<TorrentId, BtError> {
+        self.insert(torrent, false, Some(resume)).await
+    }
+
+    // adds a torrent some other client was downloading into the download
+    // directory, to carry on with it here. every piece is hashed, the
+    // ones that match are kept and the rest are downloaded. files the
+    // other client hadn't finished are given our part file names.
+    // returns how many pieces were kept as well.
+    pub async fn adopt_torrent(&mut self, mut torrent: Torrent) -> Result<(TorrentId, usize), BtError> {
+        self.place(&mut torrent)?;
+
+        // hashing a big torrent takes a while
+        let checking = torrent.clone();
+        let (found, have) = tokio::task::spawn_blocking(move || {
+            let found = verify::find_partial_files(&checking);
+            let have = verify::check_partial(&checking, &found);
+            (found, have)
+        }).await.map_err(io::Error::other)?;
+
+        for (file, found) in FileMap::new(&torrent).files().iter().zip(found) {
+            let Some(found) = found.filter(|path| *path != file.path) else { continue };
+            let target = if self.client_config.part_files { filemap::part_path(&file.path) } else { file.path.clone() };
+            if found != target {
+                fs::rename(&found, &target).map_err(|e| io::Error::new(e.kind(), format!("couldn't rename {}: {}", found.display(), e)))?;
+            }
+        }
+
+        let kept = have.iter().filter(|&&b| b != 0).count();
+        info!("{} of {} pieces of {} were already downloaded", kept, have.len(), torrent.output_file);
+        let resume = ResumeState { have, downloaded: 0, uploaded: 0 };
+        let id = self.insert_placed(torrent, false, Some(&resume)).await?;
+        Ok((id, kept))
+    }
+
+    // relative output paths are kept under the download directory, or
+    // the completed one once they have been moved there
+    fn place(&self, torrent: &mut Torrent) -> Result<(), BtError> {
+        let completed = self.client_config.completed_dir.as_ref().map(|dir| dir.join(&torrent.output_file)).filter(|path| path.exists());
+        let output = completed.unwrap_or_else(|| self.download_dir.join(&torrent.output_file));
+        torrent.output_file = output.to_string_lossy().to_string();
+
+        if self.torrents.values().any(|c| c.torrent().info_hash == torrent.info_hash) {
+            return Err(BtError::Session("torrent has already been added".to_string()));
+        }
+        Ok(())
+    }
+
+    async fn insert(&mut self, mut torrent: Torrent, complete: bool, resume: Option<&ResumeState>) -> Result<TorrentId, BtError> {
+        self.place(&mut torrent)?;
+        self.insert_placed(torrent, complete, resume).await
+    }
+
+    async fn insert_placed(&mut self, torrent: Torrent, mut complete: bool, resume: Option<&ResumeState>) -> Result<TorrentId, BtError> {
+        let saved = if complete || resume.is_some() { None } else { self.saved_resume(&torrent) };
+        let mut resume = resume.or(saved.as_ref());
+
+        let linked = if !complete && resume.is_none() { self.link_from_existing(&torrent) } else { None };
+        match &linked {
+            Some(linked) if linked.is_complete() => complete = true,
+            Some(linked) => resume = Some(linked),
+            None => {}
+        }
+
+        let check = self.client_config.disk_space_check;
+        if !complete && check != DiskSpaceCheck::Off {
+            if let Err(e) = disk_space::check(&torrent) {
+                if check == DiskSpaceCheck::Fail {
+                    return Err(io::Error::new(io::ErrorKind::StorageFull, e).into());
+                }
+                warn!("{}", e);
+            }
+        }
+
+        let mut client = TorrentClient::new(
+            torrent,
+            self.client_config.clone(),
+            self.transport.clone(),
+            self.bans.clone(),
+            self.sources.clone(),
+            self.dht_state.clone(),
+            self.external_ip.clone(),
+        ).await?;
+        client.share_rate_limits(self.shaper.clone());
+        if complete {
+            client.assume_complete().await;
+        }
+        if let Some(resume) = resume {
+            client.restore(resume).await;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // anything added while everything is paused waits for resume_all
+        if let Some(paused) = &mut self.paused_all {
+            client.pause(false);
+            paused.insert(id);
+        }
+        self.routes.add(client.torrent().info_hash, client.incoming());
+        self.client_config.hooks.run(HookEvent::Added, client.torrent(), None);
+        client.notify(Event::Added);
+        self.torrents.insert(id, client);
+        self.queue.push(id);
+        self.update_queue();
+
+        Ok(id)
+    }
+
+    // reads, decodes and adds the .torrent file at the given path
+    pub async fn add_torrent_file(&mut self, path: &Path) -> Result<TorrentId, BtError> {
+        let torrent = parse_torrent(&fs::read(path)?)?;
+
+        self.add_torrent(torrent).await
+    }
+
+    pub fn remove_torrent(&mut self, id: TorrentId) -> Result<(), BtError> {
+        let client = self.torrents.remove(&id).ok_or_else(|| no_torrent(id))?;
+        self.routes.remove(&client.torrent().info_hash);
+        self.queue.retain(|&queued| queued != id);
+        self.update_queue();
+        Ok(())
+    }
+
+    // gives the torrents at the front of the queue the active slots and
+    // queues the rest, stopping running torrents if it has to. called
+    // whenever a torrent is added, removed, paused or moved, and
+    // regularly by main to catch downloads that have finished.
+    pub fn update_queue(&mut self) {
+        let mut downloads = 0;
+        let mut seeds = 0;
+        // slots pause_all frees up stay free until resume_all
+        let holding = self.paused_all.is_some();
+
+        for id in &self.queue {
+            let Some(client) = self.torrents.get_mut(id) else { continue };
+            let state = client.state();
+            if !matches!(state, TorrentState::Downloading | TorrentState::Seeding | TorrentState::Queued) {
+                continue;
+            }
+
+            let (active, limit) = if client.is_complete() {
+                (&mut seeds, self.queue_limits.max_active_seeds)
+            } else {
+                (&mut downloads, self.queue_limits.max_active_downloads)
+            };
+            if limit.is_some_and(|limit| *active >= limit) {
+                if state != TorrentState::Queued {
+                    info!("queueing {}", client.torrent().output_file);
+                    client.enqueue();
+                }
+                continue;
+            }
+
+            if state == TorrentState::Queued && holding {
+                continue;
+            }
+            *active += 1;
+            if state == TorrentState::Queued || !client.is_started() {
+                client.dequeue();
+            }
+        }
+    }
+
+    // moves the torrent to the given place in the queue, 0 being the
+    // front. anything past the end puts it last.
+    pub fn set_queue_position(&mut self, id: TorrentId, position: usize) -> Result<(), BtError> {
+        let from = self.queue.iter().position(|&queued| queued == id).ok_or_else(|| no_torrent(id))?;
+        self.queue.remove(from);
+        self.queue.insert(position.min(self.queue.len()), id);
+        self.update_queue();
+        Ok(())
+    }
+
+    pub fn queue_limits(&self) -> QueueLimits {
+        self.queue_limits
+    }
+
+    pub fn set_queue_limits(&mut self, limits: QueueLimits) -> Result<(), BtError> {
+        validate_queue_limits(&limits).map_err(BtError::Config)?;
+        self.queue_limits = limits;
+        self.update_queue();
+        Ok(())
+    }
+
+    // pausing or resuming a single torrent takes it out of the hands of
+    // pause_all/resume_all, so resume_all won't undo an explicit pause
+    pub fn pause(&mut self, id: TorrentId, announce_stopped: bool) -> Result<(), BtError> {
+        self.client_mut(id)?.pause(announce_stopped);
+        if let Some(paused) = &mut self.paused_all {
+            paused.remove(&id);
+        }
+        self.update_queue();
+        Ok(())
+    }
+
+    pub fn resume(&mut self, id: TorrentId) -> Result<(), BtError> {
+        self.client_mut(id)?.resume();
+        if let Some(paused) = &mut self.paused_all {
+            paused.remove(&id);
+        }
+        self.update_queue();
+        Ok(())
+    }
+
+    // pauses every torrent that is running, remembering which ones
+    // they were. torrents that were already paused or are finished are
+    // left out.
+    pub fn pause_all(&mut self) {
+        let paused = self.paused_all.get_or_insert_with(BTreeSet::new);
+        for (&id, client) in self.torrents.iter_mut() {
+            if matches!(client.state(), TorrentState::Downloading | TorrentState::Seeding) {
+                client.pause(false);
+                paused.insert(id);
+            }
+        }
+    }
+
+    // resumes only the torrents pause_all paused
+    pub fn resume_all(&mut self) {
+        for id in self.paused_all.take().unwrap_or_default() {
+            if let Some(client) = self.torrents.get_mut(&id) {
+                client.resume();
+            }
+        }
+        self.update_queue();
+    }
+
+    pub fn is_paused_all(&self) -> bool {
+        self.paused_all.is_some()
+    }
+
+    pub fn status(&self, id: TorrentId) -> Result<TorrentStatus, BtError> {
+        let mut status = self.client(id)?.status(id);
+        status.queue_position = self.queue_position(id);
+        Ok(status)
+    }
+
+    pub fn list(&self) -> Vec<TorrentStatus> {
+        self.torrents
+            .iter()
+            .map(|(&id, client)| TorrentStatus { queue_position: self.queue_position(id), ..client.status(id) })
+            .collect()
+    }
+
+    fn queue_position(&self, id: TorrentId) -> usize {
+        self.queue.iter().position(|&queued| queued == id).unwrap_or(self.queue.len())
+    }
+
+    // see TorrentClient::set_peer_rate_limits
+    pub fn set_peer_rate_limits(&self, id: TorrentId, address: &str, limits: Option<RateLimits>) -> Result<(), BtError> {
+        self.client(id)?.set_peer_rate_limits(address, limits)
+    }
+
+    pub fn peers(&self, id: TorrentId) -> Result<Vec<PeerInfo>, BtError> {
+        let mut peers = self.client(id)?.peers();
+        if let Some(geoip) = &self.geoip {
+            for peer in &mut peers {
+                peer.country = geoip::country_of(geoip, &peer.address);
+            }
+        }
+        Ok(peers)
+    }
+
+    // one torrent's, or without an id every torrent's added together
+    pub fn rate_history(&self, id: Option<TorrentId>) -> Result<RateSeries, BtError> {
+        if let Some(id) = id {
+            return Ok(self.client(id)?.rate_history());
+        }
+        let mut total = RateHistory::new().series(std::time::Instant::now());
+        for client in self.torrents.values() {
+            total.add(&client.rate_history());
+        }
+        Ok(total)
+    }
+
+    pub fn trackers(&self, id: TorrentId) -> Result<Vec<TrackerState>, BtError> {
+        Ok(self.client(id)?.trackers())
+    }
+
+    pub fn stats(&self) -> SessionStats {
+        SessionStats {
+            warnings: warnings::counts(),
+            peer_sources: self.sources.lock().unwrap().report(),
+            external_ip: self.external_ip.get(),
+        }
+    }
+
+    pub fn blocklist_status(&self) -> BlocklistStatus {
+        self.blocklist.lock().unwrap().clone()
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        self.rate_limits
+    }
+
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.rate_limits = limits;
+        self.apply_rate_limits();
+    }
+
+    // the limits in force right now, the scheduled ones or the usual
+    pub fn active_rate_limits(&self) -> RateLimits {
+        match &self.alt_schedule {
+            Some(schedule) if self.alt_active => schedule.limits,
+            _ => self.rate_limits,
+        }
+    }
+
+    // a bucket whose rate is the same keeps what is in it, so this can
+    // run on every tick
+    fn apply_rate_limits(&self) {
+        self.shaper.update(self.active_rate_limits(), Instant::now());
+    }
+
+    pub fn alt_speed_schedule(&self) -> Option<&SpeedSchedule> {
+        self.alt_schedule.as_ref()
+    }
+
+    pub fn is_alt_speed_active(&self) -> bool {
+        self.alt_active
+    }
+
+    // None goes back to the usual limits all of the time
+    pub fn set_alt_speed_schedule(&mut self, schedule: Option<SpeedSchedule>) -> Result<(), BtError> {
+        if let Some(schedule) = &schedule {
+            schedule.validate().map_err(BtError::Config)?;
+        }
+        self.alt_schedule = schedule;
+        self.update_alt_speed(SystemTime::now());
+        self.apply_rate_limits();
+        Ok(())
+    }
+
+    // the session's regular housekeeping, see TICK_INTERVAL
+    pub fn tick(&mut self, now: SystemTime) {
+        self.update_queue();
+        self.update_alt_speed(now);
+        self.apply_rate_limits();
+        self.check_external_ip(now);
+        self.update_dht_id();
+        self.update_blocklist(now);
+    }
+
+    // our dht id has to match our external address (bep 42), ipv4 if we
+    // have both since that is what most of the dht is on
+    fn update_dht_id(&mut self) {
+        let Some(ip) = self.external_ip.get().iter().next() else { return };
+        if self.dht_state.lock().unwrap().set_external_ip(ip) {
+            info!("made a new dht node id to go with our external address {}", ip);
+        }
+    }
+
+    // in the background like the ip check. the old list stays in force
+    // until the new one is in, and for good if the download fails.
+    fn update_blocklist(&mut self, now: SystemTime) {
+        let (url, failed) = {
+            let status = self.blocklist.lock().unwrap();
+            let Some(url) = status.url.clone() else { return };
+            (url, status.error.is_some())
+        };
+        let every = if failed { blocklist::RETRY_INTERVAL } else { blocklist::UPDATE_INTERVAL };
+        let due = self.last_blocklist_update.is_none_or(|last| now.duration_since(last).unwrap_or_default() >= every);
+        if !due {
+            return;
+        }
+        self.last_blocklist_update = Some(now);
+
+        let status = self.blocklist.clone();
+        let bans = self.bans.clone();
+        tokio::spawn(async move {
+            match blocklist::download(&reqwest::Client::new(), &url).await {
+                Ok(list) => {
+                    info!("blocklist updated from {}, {} entries", url, list.len());
+                    status.lock().unwrap().record_update(&list, SystemTime::now());
+                    bans.lock().unwrap().set_blocklist(list);
+                }
+                Err(e) => {
+                    warn!("couldn't update the blocklist from {}: {}", url, e);
+                    status.lock().unwrap().error = Some(e);
+                }
+            }
+        });
+    }
+
+    // the answer turns up in the background, a change reaches the
+    // torrents' announcers from there
+    fn check_external_ip(&mut self, now: SystemTime) {
+        let Some(url) = &self.ip_check else { return };
+        let due = self.last_ip_check.is_none_or(|last| now.duration_since(last).unwrap_or_default() >= external_ip::CHECK_INTERVAL);
+        if !due {
+            return;
+        }
+        self.last_ip_check = Some(now);
+
+        let url = url.clone();
+        let external = self.external_ip.clone();
+        tokio::spawn(async move {
+            match external_ip::check(&reqwest::Client::new(), &url).await {
+                Ok(ip) => {
+                    if external.report(ip) {
+                        info!("our external address is now {}, announcing again", ip);
+                    }
+                }
+                Err(e) => warn!("couldn't check our external address: {}", e),
+            }
+        });
+    }
+
+    fn update_alt_speed(&mut self, now: SystemTime) {
+        let active = self.alt_schedule.as_ref().is_some_and(|s| s.is_active(unix_time(now)));
+        if active != self.alt_active {
+            self.alt_active = active;
+            info!("switching to the {} rate limits: {:?}", if active { "scheduled" } else { "usual" }, self.active_rate_limits());
+        }
+    }
+
+    fn client(&self, id: TorrentId) -> Result<&TorrentClient, BtError> {
+        self.torrents.get(&id).ok_or_else(|| no_torrent(id))
+    }
+
+    fn client_mut(&mut self, id: TorrentId) -> Result<&mut TorrentClient, BtError> {
+        self.torrents.get_mut(&id).ok_or_else(|| no_torrent(id))
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session::new()
+    }
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64)
+}
+
+fn no_torrent(id: TorrentId) -> BtError {
+    BtError::Session(format!("no torrent with id {}", id))
+}
+
+// a limit of 0 would leave every torrent queued for good
+fn validate_queue_limits(limits: &QueueLimits) -> Result<(), String> {
+    if limits.max_active_downloads == Some(0) || limits.max_active_seeds == Some(0) {
+        return Err("a queue limit of 0 would never let a torrent run, leave it unset for unlimited".to_string());
+    }
+    Ok(())
+}
+
+fn is_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+fn resume_path(dir: &Path, info_hash: &InfoHash) -> PathBuf {
+    dir.join(format!("{}.resume", info_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::RequestTuning, schedule::Weekday, torrent::File, tracker::TrackerStatus, transport::MemoryTransport};
+    use sha1::{Digest, Sha1};
+
+    // the gzipped list below, a comment and "bad peers:10.9.0.0-10.9.255.255"
+    const GZIPPED_BLOCKLIST: &str =
+        "1f8b08000000000002ff535628492d2e51c8c92c2ee14a4a4c5128484d2d2ab63234d0b3d433d033d005338c4c4d41980b00fbcdb29d2c000000";
+
+    #[tokio::test]
+    async fn test_blocklist_update() {
+        use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/list.p2p.gz", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 4096];
+            let _ = stream.read(&mut request).await.unwrap();
+            let body = hex::decode(GZIPPED_BLOCKLIST).unwrap();
+            let head = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+        });
+
+        let mut session = Session::builder().blocklist(&url).build().unwrap();
+        assert_eq!(session.blocklist_status().updated, None);
+        let now = SystemTime::now();
+        session.tick(now);
+        while session.blocklist_status().updated.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let status = session.blocklist_status();
+        assert_eq!((status.url, status.entries, status.error), (Some(url), 1, None));
+        assert!(session.bans.lock().unwrap().is_banned("10.9.1.2"));
+        // not due again for a day
+        session.tick(now + Duration::from_secs(60 * 60));
+        assert_eq!(session.last_blocklist_update, Some(now));
+    }
+
+    #[test]
+    fn test_dht_id_follows_external_ip() {
+        let mut session = Session::builder().build().unwrap();
+        let ip: std::net::IpAddr = "84.124.73.14".parse().unwrap();
+        session.external_ip.report(ip);
+        session.tick(SystemTime::now());
+        assert!(session.dht_table().own_id().is_secure_for(ip));
+    }
+
+    fn test_torrent(name: &str, hash: u8) -> Torrent {
+        Torrent {
+            info_hash: InfoHash::new([hash; 20]),
+            announce: String::new(),
+            multi_file: false,
+            piece_length: 16384,
+            total_size: 16384,
+            pieces: vec![[0; 20]],
+            output_file: std::env::temp_dir().join(name).to_string_lossy().to_string(),
+            files: vec![],
+            ..Default::default()
+        }
+    }
+
+    async fn test_session() -> (Session, TorrentId, TorrentId) {
+        let mut session = Session::builder()
+            .transport(Arc::new(MemoryTransport::new()))
+            .build()
+            .unwrap();
+        let a = session.add_torrent(test_torrent("bt-c-session-a", 1)).await.unwrap();
+        let b = session.add_torrent(test_torrent("bt-c-session-b", 2)).await.unwrap();
+        (session, a, b)
+    }
+
+    fn state(session: &Session, id: TorrentId) -> TorrentState {
+        session.status(id).unwrap().state
+    }
+
+    #[test]
+    fn test_alt_speed_schedule() {
+        let usual = RateLimits { download: Some(100_000), upload: None };
+        let mut every_day = SpeedSchedule {
+            limits: RateLimits { download: Some(10_000), upload: Some(5_000) },
+            days: Vec::new(),
+            start: 9 * 60,
+            end: 17 * 60,
+            utc_offset: 0,
+        };
+        assert!(Session::builder().alt_speed_schedule(every_day.clone()).build().is_err());
+        every_day.days = vec![
+            Weekday::Sunday, Weekday::Monday, Weekday::Tuesday, Weekday::Wednesday,
+            Weekday::Thursday, Weekday::Friday, Weekday::Saturday,
+        ];
+
+        let mut session = Session::builder().rate_limits(usual).build().unwrap();
+        session.set_alt_speed_schedule(Some(every_day.clone())).unwrap();
+        let noon = UNIX_EPOCH + Duration::from_secs(12 * 3600);
+        let evening = UNIX_EPOCH + Duration::from_secs(20 * 3600);
+
+        session.tick(noon);
+        assert!(session.is_alt_speed_active());
+        assert_eq!(session.active_rate_limits(), every_day.limits);
+        // changing the usual limits doesn't end the scheduled ones early
+        session.set_rate_limits(RateLimits::default());
+        assert_eq!(session.active_rate_limits(), every_day.limits);
+
+        session.tick(evening);
+        assert_eq!(session.active_rate_limits(), RateLimits::default());
+
+        session.tick(noon);
+        session.set_alt_speed_schedule(None).unwrap();
+        assert!(!session.is_alt_speed_active());
+        assert_eq!(session.active_rate_limits(), RateLimits::default());
+    }
+
+    #[test]
+    fn test_rate_limits_reach_the_shaper() {
+        let capped = RateLimits { download: None, upload: Some(5_000) };
+        let mut session = Session::builder().rate_limits(capped).build().unwrap();
+        let now = Instant::now();
+        // a second's worth, then a second's wait for the next
+        assert_eq!(session.shaper.sent(5_000, now), Duration::ZERO);
+        assert!(session.shaper.sent(5_000, now) > Duration::from_millis(900));
+
+        session.set_rate_limits(RateLimits::default());
+        assert_eq!(session.shaper.sent(1 << 30, now), Duration::ZERO);
+
+        // and the scheduled limits once the tick sees they are due
+        let every_day = SpeedSchedule {
+            limits: capped,
+            days: vec![
+                Weekday::Sunday, Weekday::Monday, Weekday::Tuesday, Weekday::Wednesday,
+                Weekday::Thursday, Weekday::Friday, Weekday::Saturday,
+            ],
+            start: 9 * 60,
+            end: 17 * 60,
+            utc_offset: 0,
+        };
+        session.set_alt_speed_schedule(Some(every_day)).unwrap();
+        session.tick(UNIX_EPOCH + Duration::from_secs(12 * 3600));
+        assert_eq!(session.shaper.sent(5_000, now), Duration::ZERO);
+        assert!(session.shaper.sent(5_000, now) > Duration::from_millis(900));
+    }
+
+    #[tokio::test]
+    async fn test_add_errors() {
+        let (mut session, _, _) = test_session().await;
+        let again = session.add_torrent(test_torrent("bt-c-session-a", 1)).await;
+        assert!(matches!(again, Err(BtError::Session(_))));
+
+        let missing = session.add_torrent_file(Path::new("/does/not/exist.torrent")).await;
+        assert!(matches!(missing, Err(BtError::Storage(e)) if e.kind() == io::ErrorKind::NotFound));
+
+        let path = std::env::temp_dir().join("bt-c-session-not-a-torrent");
+        fs::write(&path, b"d4:infoi1ee").unwrap();
+        assert!(matches!(session.add_torrent_file(&path).await, Err(BtError::Torrent(_))));
+    }
+
+    #[tokio::test]
+    async fn test_trackerless_torrents_never_announce() {
+        let (session, a, _) = test_session().await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let tracker = session.status(a).unwrap().tracker;
+        assert_eq!((tracker.total_failures, tracker.successes), (0, 0));
+        assert!(session.trackers(a).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queue() {
+        let mut session = Session::builder()
+            .transport(Arc::new(MemoryTransport::new()))
+            .queue_limits(QueueLimits { max_active_downloads: Some(1), max_active_seeds: None })
+            .build()
+            .unwrap();
+        let a = session.add_torrent(test_torrent("bt-c-session-queue-a", 11)).await.unwrap();
+        let b = session.add_torrent(test_torrent("bt-c-session-queue-b", 12)).await.unwrap();
+        let c = session.add_complete_torrent(test_torrent("bt-c-session-queue-c", 13)).await.unwrap();
+        assert_eq!(state(&session, a), TorrentState::Downloading);
+        assert_eq!(state(&session, b), TorrentState::Queued);
+        // seeds don't count against the download limit
+        assert_eq!(state(&session, c), TorrentState::Seeding);
+        assert_eq!(session.status(b).unwrap().queue_position, 1);
+
+        // moving b to the front takes a's slot
+        session.set_queue_position(b, 0).unwrap();
+        assert_eq!(state(&session, b), TorrentState::Downloading);
+        assert_eq!(state(&session, a), TorrentState::Queued);
+
+        // a paused torrent gives up its slot
+        session.pause(b, false).unwrap();
+        assert_eq!(state(&session, a), TorrentState::Downloading);
+        session.resume(b).unwrap();
+        assert_eq!(state(&session, b), TorrentState::Downloading);
+        assert_eq!(state(&session, a), TorrentState::Queued);
+
+        session.remove_torrent(b).unwrap();
+        assert!(matches!(session.status(b), Err(BtError::Session(_))));
+        assert_eq!(state(&session, a), TorrentState::Downloading);
+        assert_eq!(session.status(c).unwrap().queue_position, 1);
+
+        session.set_queue_limits(QueueLimits { max_active_downloads: None, max_active_seeds: Some(1) }).unwrap();
+        let d = session.add_complete_torrent(test_torrent("bt-c-session-queue-d", 14)).await.unwrap();
+        assert_eq!(state(&session, d), TorrentState::Queued);
+    }
+
+    #[tokio::test]
+    async fn test_links_shared_files() {
+        let dir = std::env::temp_dir().join("bt-c-session-link");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let first = vec![1u8; 32768];
+        let second = vec![2u8; 32768];
+        let hashes = |data: &[u8]| data.chunks(16384).map(|c| Sha1::digest(c).into()).collect::<Vec<[u8; 20]>>();
+        fs::write(dir.join("first"), &first).unwrap();
+        let single = Torrent {
+            info_hash: InfoHash::new([31; 20]),
+            piece_length: 16384,
+            total_size: 32768,
+            pieces: hashes(&first),
+            output_file: dir.join("first").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let pack = Torrent {
+            info_hash: InfoHash::new([32; 20]),
+            multi_file: true,
+            piece_length: 16384,
+            total_size: 65536,
+            pieces: hashes(&[first.clone(), second].concat()),
+            output_file: dir.join("pack").to_string_lossy().to_string(),
+            files: vec![File::new("first".to_string(), 32768), File::new("second".to_string(), 32768)],
+            ..Default::default()
+        };
+
+        let mut session = Session::builder()
+            .transport(Arc::new(MemoryTransport::new()))
+            .build()
+            .unwrap();
+        session.add_complete_torrent(single).await.unwrap();
+
+        // the file the pack shares is linked, the other one downloaded
+        let id = session.add_torrent(pack).await.unwrap();
+        assert_eq!(fs::read(dir.join("pack").join("first")).unwrap(), first);
+        let status = session.status(id).unwrap();
+        assert_eq!((status.pieces_have, status.state), (2, TorrentState::Downloading));
+    }
+
+    #[tokio::test]
+    async fn test_pause_all_holds_the_queue() {
+        let mut session = Session::builder()
+            .transport(Arc::new(MemoryTransport::new()))
+            .queue_limits(QueueLimits { max_active_downloads: Some(1), max_active_seeds: None })
+            .build()
+            .unwrap();
+        let a = session.add_torrent(test_torrent("bt-c-session-hold-a", 21)).await.unwrap();
+        let b = session.add_torrent(test_torrent("bt-c-session-hold-b", 22)).await.unwrap();
+
+        // a's slot is free while everything is paused, but nothing takes it
+        session.pause_all();
+        session.tick(SystemTime::now());
+        assert_eq!(state(&session, a), TorrentState::Paused);
+        assert_eq!(state(&session, b), TorrentState::Queued);
+
+        session.resume_all();
+        assert_eq!(state(&session, a), TorrentState::Downloading);
+        assert_eq!(state(&session, b), TorrentState::Queued);
+    }
+
+    #[tokio::test]
+    async fn test_resume_all_keeps_individual_pauses() {
+        let (mut session, a, b) = test_session().await;
+        session.pause(a, false).unwrap();
+
+        session.pause_all();
+        assert!(session.is_paused_all());
+        assert_eq!(state(&session, b), TorrentState::Paused);
+
+        session.resume_all();
+        assert!(!session.is_paused_all());
+        assert_eq!(state(&session, a), TorrentState::Paused);
+        assert_eq!(state(&session, b), TorrentState::Downloading);
+    }
+
+    #[tokio::test]
+    async fn test_explicit_changes_during_pause_all() {
+        let (mut session, a, b) = test_session().await;
+        session.pause_all();
+
+        // paused again by hand, so it stays paused
+        session.pause(a, false).unwrap();
+        // resumed by hand, then added while paused
+        session.resume(b).unwrap();
+        let c = session.add_torrent(test_torrent("bt-c-session-c", 3)).await.unwrap();
+        assert_eq!(state(&session, c), TorrentState::Paused);
+
+        session.resume_all();
+        assert_eq!(state(&session, a), TorrentState::Paused);
+        assert_eq!(state(&session, b), TorrentState::Downloading);
+        assert_eq!(state(&session, c), TorrentState::Downloading);
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let dir = std::env::temp_dir().join("bt-c-session-import");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut torrent = test_torrent("bt-c-session-export", 4);
+        torrent.files = vec![crate::torrent::File::new("export.bin".to_string(), 16384)];
+
+        let (mut session, _, _) = test_session().await;
+        let id = session.add_complete_torrent(torrent).await.unwrap();
+        let bundle = session.export(id).unwrap();
+        assert!(session.export(99).is_err());
+
+        // picked up in a session somewhere else as the same torrent, complete
+        let mut other = Session::builder()
+            .download_dir(&dir)
+            .transport(Arc::new(MemoryTransport::new()))
+            .build()
+            .unwrap();
+        let imported = other.import(&Bundle::decode(&bundle.encode()).unwrap()).await.unwrap();
+
+        let status = other.status(imported).unwrap();
+        assert_eq!(status.info_hash, hex::encode([4; 20]));
+        assert_eq!(status.state, TorrentState::Seeding);
+        assert_eq!(status.name, dir.join("export.bin").to_string_lossy());
+
+        // and through qbittorrent's files, the .torrent kept next to them
+        let (resume, metainfo) = session.export_fastresume(id).unwrap();
+        assert_eq!(resume.name.as_deref(), Some("bt-c-session-export"));
+        let path = dir.join(format!("{}.fastresume", resume.info_hash));
+        fs::write(&path, resume.encode()).unwrap();
+        fs::write(resume.torrent_path(&dir), metainfo).unwrap();
+        let [bundle] = &crate::bundle::read(&path).unwrap()[..] else { panic!() };
+        let mut other = Session::builder().download_dir(&dir).transport(Arc::new(MemoryTransport::new())).build().unwrap();
+        let imported = other.import(bundle).await.unwrap();
+        assert_eq!(state(&other, imported), TorrentState::Seeding);
+    }
+
+    #[tokio::test]
+    async fn test_disk_space_check() {
+        let (mut session, _, _) = test_session().await;
+        let huge = Torrent { total_size: u64::MAX / 2, ..test_torrent("bt-c-session-huge", 6) };
+        let error = session.add_torrent(huge).await.unwrap_err();
+        assert!(error.to_string().contains("not enough disk space"), "{}", error);
+        assert_eq!(session.list().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_adopt_torrent() {
+        use sha1::{Digest, Sha1};
+
+        let dir = std::env::temp_dir().join("bt-c-session-adopt");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..32768u32).map(|i| i as u8).collect();
+        let torrent = || Torrent {
+            total_size: 32768,
+            pieces: data.chunks(16384).map(|chunk| Sha1::digest(chunk).into()).collect(),
+            output_file: "adopted.iso".to_string(),
+            ..test_torrent("", 20)
+        };
+        // another client got the first piece and half of the second
+        fs::write(dir.join("adopted.iso.!qB"), &data[..24576]).unwrap();
+
+        let mut session = Session::builder().transport(Arc::new(MemoryTransport::new())).download_dir(&dir).build().unwrap();
+        let (id, kept) = session.adopt_torrent(torrent()).await.unwrap();
+        assert_eq!(kept, 1);
+        let status = session.status(id).unwrap();
+        assert_eq!((status.pieces_have, status.state), (1, TorrentState::Downloading));
+        assert!(dir.join("adopted.iso.part").exists() && !dir.join("adopted.iso.!qB").exists());
+
+        // with all of it there, it is seeded under the real name
+        session.remove_torrent(id).unwrap();
+        fs::write(dir.join("adopted.iso.part"), &data).unwrap();
+        let (id, kept) = session.adopt_torrent(torrent()).await.unwrap();
+        assert_eq!((kept, state(&session, id)), (2, TorrentState::Seeding));
+        assert_eq!(fs::read(dir.join("adopted.iso")).unwrap(), data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_builder_validates() {
+        assert!(matches!(Session::builder().listen_port(0).build(), Err(BtError::Config(_))));
+        assert!(Session::builder().udp_port(0).build().is_err());
+        assert!(Session::builder().dht_bootstrap_nodes(vec!["router.example".to_string()]).build().is_err());
+        assert!(Session::builder().dht_bootstrap_nodes(vec![":6881".to_string()]).build().is_err());
+        assert!(Session::builder().download_dir("/does/not/exist").build().is_err());
+        assert!(Session::builder().rate_limits(RateLimits { download: Some(0), upload: None }).build().is_err());
+        assert!(Session::builder().queue_limits(QueueLimits { max_active_downloads: Some(0), max_active_seeds: None }).build().is_err());
+        assert!(Session::builder().peer_id_prefix("").build().is_err());
+        assert!(Session::builder().peer_id_prefix("-way-too-long-prefix-").build().is_err());
+        assert!(Session::builder().peer_id_prefix("-a b-").build().is_err());
+        assert!(Session::builder().proxy("ftp://proxy:21").build().is_err());
+        assert!(Session::builder().proxy("socks5://proxy").build().is_err());
+        assert!(Session::builder().strict_proxy(true).build().is_err());
+        assert!(Session::builder().bind("not an interface").build().is_err());
+        assert!(Session::builder().proxy("http://proxy:3128").strict_proxy(true).build().is_err());
+        assert!(Session::builder().external_ip_check("ifconfig.me").build().is_err());
+        assert!(Session::builder().blocklist("/etc/blocklist.p2p").build().is_err());
+        assert!(Session::builder().geoip("/does/not/exist.mmdb").build().is_err());
+        let config = ClientConfig { webhook: Some("hooks.example".to_string()), ..ClientConfig::default() };
+        assert!(Session::builder().client_config(config).build().is_err());
+        let config = ClientConfig { completed_dir: Some(PathBuf::from("/does/not/exist")), ..ClientConfig::default() };
+        assert!(Session::builder().client_config(config).build().is_err());
+
+        let config = ClientConfig { max_peer_connections: 0, ..ClientConfig::default() };
+        assert!(Session::builder().client_config(config).build().is_err());
+        let config = ClientConfig { max_in_flight_bytes: Some(1024), ..ClientConfig::low_memory() };
+        assert!(Session::builder().client_config(config).build().is_err());
+        let config = ClientConfig { max_ongoing_pieces: Some(0), ..ClientConfig::low_memory() };
+        assert!(Session::builder().client_config(config).build().is_err());
+        let config = ClientConfig { handshake_timeout: Duration::ZERO, ..ClientConfig::default() };
+        assert!(Session::builder().client_config(config).build().is_err());
+        let config = ClientConfig { churn_interval: Some(Duration::ZERO), ..ClientConfig::default() };
+        assert!(Session::builder().client_config(config).build().is_err());
+        let config = ClientConfig { max_upload_slots: Some(0), ..ClientConfig::default() };
+        assert!(Session::builder().client_config(config).build().is_err());
+        let config = ClientConfig { requests: RequestTuning { block_size: 1000, ..RequestTuning::default() }, ..ClientConfig::default() };
+        assert!(Session::builder().client_config(config).build().is_err());
+        assert!(Session::builder().client_config(ClientConfig::low_memory()).build().is_ok());
+    }
+
+    #[test]
+    fn test_builder_settings() {
+        let session = Session::builder()
+            .listen_port(51413)
+            .download_dir(std::env::temp_dir())
+            .dht(true)
+            .peer_id_prefix("-XX0100-")
+            .proxy("socks5h://127.0.0.1:9050")
+            .bind("tun0")
+            .build()
+            .unwrap();
+
+        assert_eq!(session.listen_port(), 51413);
+        // udp shares the listen port unless told otherwise
+        assert_eq!(session.udp_port(), 51413);
+        assert_eq!(Session::builder().udp_port(6882).build().unwrap().udp_port(), 6882);
+        assert_eq!(session.download_dir(), std::env::temp_dir());
+        assert!(session.dht_enabled());
+        assert_eq!(session.client_config.peer_id_prefix, "-XX0100-");
+        assert_eq!(session.proxy(), Some(&Proxy { kind: ProxyKind::Socks5, host: "127.0.0.1".to_string(), port: 9050, remote_dns: true }));
+        assert!(!Proxy::parse("socks5://127.0.0.1:1080").unwrap().remote_dns);
+        assert_eq!(session.bound_to(), Some(&BindTo::Interface("tun0".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_saves_resume_data() {
+        let dir = std::env::temp_dir().join("bt-c-session-resume");
+        let _ = fs::remove_dir_all(&dir);
+        let session = || Session::builder().transport(Arc::new(MemoryTransport::new())).resume_dir(&dir).build().unwrap();
+
+        let mut first = session();
+        first.add_complete_torrent(test_torrent("bt-c-session-resume-data", 4)).await.unwrap();
+        first.shutdown().await.unwrap();
+        assert!(resume_path(&dir, &InfoHash::new([4; 20])).is_file());
+
+        // the next run picks up where this one left off
+        let mut second = session();
+        let id = second.add_torrent(test_torrent("bt-c-session-resume-data", 4)).await.unwrap();
+        assert_eq!(second.status(id).unwrap().pieces_have, 1);
+        assert_eq!(state(&second, id), TorrentState::Seeding);
+
+        // a session that doesn't keep resume data starts from scratch
+        let (mut third, _, _) = test_session().await;
+        let id = third.add_torrent(test_torrent("bt-c-session-resume-data", 4)).await.unwrap();
+        assert_eq!(third.status(id).unwrap().pieces_have, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_trackers() {
+        let (mut session, a, _) = test_session().await;
+        let first = "http://127.0.0.1:1/announce".to_string();
+        let second = "http://127.0.0.1:2/announce".to_string();
+        let torrent = Torrent {
+            announce: first.clone(),
+            announce_list: vec![vec![first.clone()], vec![second.clone()]],
+            ..test_torrent("bt-c-session-trackers", 5)
+        };
+        let id = session.add_torrent(torrent).await.unwrap();
+
+        let trackers = session.trackers(id).unwrap();
+        assert_eq!(trackers.iter().map(|t| t.url.as_str()).collect::<Vec<_>>(), vec![first.as_str(), second.as_str()]);
+        assert_ne!(trackers[0].status, TrackerStatus::Unused);
+        assert_eq!(trackers[1], TrackerState::unused(&second));
+
+        assert!(session.trackers(a).unwrap().is_empty());
+        assert!(session.trackers(99).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dht_overrides() {
+        let (mut session, a, b) = test_session().await;
+        let private = Torrent { private: true, ..test_torrent("bt-c-session-private", 3) };
+        let c = session.add_torrent(private).await.unwrap();
+        let dht = |session: &Session, id| session.status(id).unwrap().dht;
+
+        assert!(!dht(&session, a));
+        session.set_dht(true);
+        assert!(dht(&session, a) && dht(&session, b));
+        assert!(!dht(&session, c));
+
+        // an override sticks when the session setting changes
+        session.set_torrent_dht(a, Some(false)).unwrap();
+        assert!(!dht(&session, a));
+        session.set_dht(false);
+        session.set_torrent_dht(b, Some(true)).unwrap();
+        session.set_dht(true);
+        session.set_dht(false);
+        assert!(dht(&session, b));
+
+        session.set_torrent_dht(a, None).unwrap();
+        assert!(!dht(&session, a));
+
+        assert!(session.set_torrent_dht(c, Some(true)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_peer_rate_limits() {
+        let (session, a, _) = test_session().await;
+        let capped = RateLimits { download: None, upload: Some(65536) };
+        session.set_peer_rate_limits(a, "10.0.0.1:6881", Some(capped)).unwrap();
+        session.set_peer_rate_limits(a, "10.0.0.1", None).unwrap();
+        assert!(session.set_peer_rate_limits(a, "10.0.0.1", Some(RateLimits { download: Some(10), upload: None })).is_err());
+        assert!(session.set_peer_rate_limits(a, "somewhere", Some(capped)).is_err());
+        assert!(session.set_peer_rate_limits(99, "10.0.0.1", Some(capped)).is_err());
+
+        let config = ClientConfig { peer_rate_limits: RateLimits { download: Some(0), upload: None }, ..ClientConfig::default() };
+        assert!(Session::builder().client_config(config).build().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_trackerless_nodes() {
+        let (mut session, _, _) = test_session().await;
+        let nodes = vec![("dht.example".to_string(), 6881)];
+        let trackerless = Torrent { nodes: nodes.clone(), ..test_torrent("bt-c-session-nodes", 3) };
+        session.add_torrent(trackerless).await.unwrap();
+        let private = Torrent { nodes: vec![("10.0.0.9".to_string(), 6881)], private: true, ..test_torrent("bt-c-session-private-nodes", 4) };
+        session.add_torrent(private).await.unwrap();
+
+        let dht = session.dht_state.lock().unwrap();
+        assert_eq!(dht.candidates().cloned().collect::<Vec<_>>(), nodes);
+    }
+
+    #[tokio::test]
+    async fn test_read_piece() {
+        let (mut session, _, _) = test_session().await;
+        let data: Vec<u8> = (0..16384u32).map(|i| (i * 3) as u8).collect();
+        let torrent = Torrent { pieces: vec![Sha1::digest(&data).into()], ..test_torrent("bt-c-session-read-piece", 5) };
+        fs::write(&torrent.output_file, &data).unwrap();
+        let id = session.add_complete_torrent(torrent).await.unwrap();
+
+        assert_eq!(session.read_piece(id, 0).await.unwrap(), data);
+        assert!(session.read_piece(id, 1).await.is_err());
+        assert!(session.read_piece(99, 0).await.is_err());
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::session::RateLimits;
+
+// caps on what single peers get, on top of the torrent's own limits, so
+// one fast peer can be held back to leave room for the rest. every peer
+// gets the torrent's default and any of them can be given caps of its
+// own, by ip so they hold when it reconnects.
+//
+// each direction of a connection has a token bucket. the connection
+// reads or writes whatever it has and runs up a debt when that is more
+// than the bucket holds, then waits the debt off before its next read or
+// write. tcp's own flow control does the rest.
+
+// a bucket holds this much of its rate, so a peer that has been quiet
+// can send a burst
+const BURST: Duration = Duration::from_secs(1);
+
+// capped reads are never smaller than this
+const MIN_READ: u64 = 1024;
+// slower than this and each read waits so long the peer seems gone
+const MIN_RATE: u64 = 1024;
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    // starts full
+    pub fn new(rate: u64, now: Instant) -> TokenBucket {
+        TokenBucket { rate, tokens: capacity(rate), updated: now }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    // takes bytes out of the bucket and says how long to wait before
+    // using them, nothing when there were enough in it
+    pub fn take(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.updated = self.updated.max(now);
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(capacity(self.rate));
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.rate as f64)
+    }
+}
+
+fn capacity(rate: u64) -> f64 {
+    rate as f64 * BURST.as_secs_f64()
+}
+
+// the caps for every peer of a torrent
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PeerLimits {
+    default: RateLimits,
+    by_ip: HashMap<IpAddr, RateLimits>,
+}
+
+impl PeerLimits {
+    pub fn new(default: RateLimits) -> PeerLimits {
+        PeerLimits { default, by_ip: HashMap::new() }
+    }
+
+    // what the peer at ip (or ip:port) gets
+    pub fn for_peer(&self, address: &str) -> RateLimits {
+        parse_ip(address).and_then(|ip| self.by_ip.get(&ip)).copied().unwrap_or(self.default)
+    }
+
+    // caps of the peer's own in place of the default, or with None back
+    // to the default. they replace the default as a whole, a direction
+    // left unset is unlimited.
+    pub fn set(&mut self, address: &str, limits: Option<RateLimits>) -> Result<(), String> {
+        let ip = parse_ip(address).ok_or_else(|| format!("not a peer address: {}", address))?;
+        match limits {
+            Some(limits) => {
+                validate(&limits)?;
+                self.by_ip.insert(ip, limits);
+            }
+            None => {
+                self.by_ip.remove(&ip);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn validate(limits: &RateLimits) -> Result<(), String> {
+    if limits.download == Some(0) || limits.upload == Some(0) {
+        return Err("a peer limit of 0 would stop its transfers, choke or ban it instead".to_string());
+    }
+    if [limits.download, limits.upload].iter().flatten().any(|&rate| rate < MIN_RATE) {
+        return Err(format!("peer limits must be at least {} bytes a second", MIN_RATE));
+    }
+    Ok(())
+}
+
+// peers are shown as ip:port, and ipv6 ones in brackets
+fn parse_ip(address: &str) -> Option<IpAddr> {
+    let ip = address.parse::<SocketAddr>().map(|addr| addr.ip()).or_else(|_| address.parse::<IpAddr>()).ok()?;
+    Some(ip.to_canonical())
+}
+
+// both directions of one connection
+#[derive(Debug, Clone, Default)]
+pub struct Shaper {
+    download: Option<TokenBucket>,
+    upload: Option<TokenBucket>,
+}
+
+impl Shaper {
+    // a bucket whose rate hasn't changed keeps what is in it
+    pub fn update(&mut self, limits: RateLimits, now: Instant) {
+        let bucket = |bucket: &mut Option<TokenBucket>, rate: Option<u64>| match rate {
+            Some(rate) if bucket.as_ref().is_some_and(|b| b.rate() == rate) => {}
+            Some(rate) => *bucket = Some(TokenBucket::new(rate, now)),
+            None => *bucket = None,
+        };
+        bucket(&mut self.download, limits.download);
+        bucket(&mut self.upload, limits.upload);
+    }
+
+    // how much to read at once while downloading is capped, a quarter
+    // of a second's worth. one big read would leave a long wait before
+    // the next, long enough for the peer to seem to have gone quiet.
+    pub fn read_limit(&self) -> Option<u64> {
+        self.download.as_ref().map(|bucket| (bucket.rate() / 4).max(MIN_READ))
+    }
+
+    pub fn received(&mut self, bytes: usize, now: Instant) -> Duration {
+        self.download.as_mut().map_or(Duration::ZERO, |bucket| bucket.take(bytes, now))
+    }
+
+    pub fn sent(&mut self, bytes: usize, now: Instant) -> Duration {
+        self.upload.as_mut().map_or(Duration::ZERO, |bucket| bucket.take(bytes, now))
+    }
+}
+
+// the session's caps, one pair of buckets that every connection of
+// every torrent takes from. a connection waits for whichever of its own
+// and the session's buckets is further in debt, so many peers together
+// get no more than the session allows.
+#[derive(Debug, Clone, Default)]
+pub struct SessionShaper(Arc<Mutex<Shaper>>);
+
+impl SessionShaper {
+    // called on every session tick, see Shaper::update
+    pub fn update(&self, limits: RateLimits, now: Instant) {
+        self.0.lock().unwrap().update(limits, now);
+    }
+
+    // the smaller of the connection's own read size and the session's
+    pub fn read_limit(&self, own: Option<u64>) -> Option<u64> {
+        match (own, self.0.lock().unwrap().read_limit()) {
+            (Some(own), Some(session)) => Some(own.min(session)),
+            (own, session) => own.or(session),
+        }
+    }
+
+    pub fn received(&self, bytes: usize, now: Instant) -> Duration {
+        self.0.lock().unwrap().received(bytes, now)
+    }
+
+    pub fn sent(&self, bytes: usize, now: Instant) -> Duration {
+        self.0.lock().unwrap().sent(bytes, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        // a second's worth goes straight out
+        assert_eq!(bucket.take(1000, start), Duration::ZERO);
+        assert_eq!(bucket.take(500, start), Duration::from_millis(500));
+        // the debt is paid off after half a second, then it fills again
+        assert_eq!(bucket.take(0, start + Duration::from_millis(500)), Duration::ZERO);
+        assert_eq!(bucket.take(250, start + Duration::from_millis(750)), Duration::ZERO);
+        // but never past a second's worth
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.take(1500, later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_peer_limits() {
+        let capped = RateLimits { download: Some(1 << 20), upload: None };
+        let mut limits = PeerLimits::new(RateLimits { download: None, upload: Some(1 << 16) });
+        limits.set("10.0.0.1:6881", Some(capped)).unwrap();
+        assert_eq!(limits.for_peer("10.0.0.1"), capped);
+        assert_eq!(limits.for_peer("10.0.0.1:51413"), capped);
+        assert_eq!(limits.for_peer("::ffff:10.0.0.1"), capped);
+        assert_eq!(limits.for_peer("10.0.0.2:6881").upload, Some(1 << 16));
+
+        limits.set("[2001:db8::1]:6881", Some(capped)).unwrap();
+        assert_eq!(limits.for_peer("2001:db8::1"), capped);
+
+        limits.set("10.0.0.1", None).unwrap();
+        assert_eq!(limits.for_peer("10.0.0.1").download, None);
+        assert!(limits.set("peer.example:6881", Some(capped)).is_err());
+        assert!(limits.set("10.0.0.1", Some(RateLimits { download: Some(0), upload: None })).is_err());
+    }
+
+    #[test]
+    fn test_shaper() {
+        let now = Instant::now();
+        let mut shaper = Shaper::default();
+        assert_eq!(shaper.sent(1 << 30, now), Duration::ZERO);
+
+        shaper.update(RateLimits { download: Some(100), upload: Some(100) }, now);
+        assert_eq!(shaper.received(200, now), Duration::from_secs(1));
+        // the same limit again leaves the debt alone, a new one starts over
+        shaper.update(RateLimits { download: Some(100), upload: Some(200) }, now);
+        assert_eq!(shaper.received(0, now), Duration::from_secs(1));
+        assert_eq!(shaper.sent(200, now), Duration::ZERO);
+        shaper.update(RateLimits::default(), now);
+        assert_eq!(shaper.received(1 << 30, now), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_session_shaper() {
+        let now = Instant::now();
+        let session = SessionShaper::default();
+        assert_eq!(session.read_limit(Some(4096)), Some(4096));
+
+        // every clone takes from the same bucket
+        session.update(RateLimits { download: Some(8192), upload: Some(100) }, now);
+        let other = session.clone();
+        assert_eq!(session.sent(100, now), Duration::ZERO);
+        assert_eq!(other.sent(100, now), Duration::from_secs(1));
+        assert_eq!(other.read_limit(None), Some(2048));
+        assert_eq!(other.read_limit(Some(1024)), Some(1024));
+    }
+}
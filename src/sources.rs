@@ -0,0 +1,116 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::pex::Addr;
+
+// where the peers a session hears about come from, so it is possible to
+// tell whether the dht or pex is pulling its weight. peers that dial us
+// count as their own source. only trackers hand out peers so far, the
+// others (local service discovery among them) are counted once they
+// exist.
+//
+// every count is of unique addresses over the whole session: a peer
+// announced again, or for a second torrent, counts once. a peer that
+// more than one source told us about counts for each of them.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+    Incoming,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct SourceCounts {
+    // peers this source told us about
+    pub discovered: u64,
+    // how many of them we managed to connect to
+    pub connected: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct PeerSources {
+    sources: HashMap<Addr, BTreeSet<PeerSource>>,
+    connected: HashSet<Addr>,
+    counts: BTreeMap<PeerSource, SourceCounts>,
+}
+
+impl PeerSources {
+    pub fn new() -> PeerSources {
+        PeerSources::default()
+    }
+
+    pub fn record_discovered(&mut self, source: PeerSource, peers: &[Addr]) {
+        for addr in peers {
+            if !self.sources.entry(addr.clone()).or_default().insert(source) {
+                continue;
+            }
+
+            let counts = self.counts.entry(source).or_default();
+            counts.discovered += 1;
+            // found again by another source after we had already got to it
+            if self.connected.contains(addr) {
+                counts.connected += 1;
+            }
+        }
+    }
+
+    // a handshake with the peer went through
+    pub fn record_connected(&mut self, addr: &Addr) {
+        if !self.connected.insert(addr.clone()) {
+            return;
+        }
+
+        for source in self.sources.get(addr).into_iter().flatten() {
+            self.counts.entry(*source).or_default().connected += 1;
+        }
+    }
+
+    pub fn report(&self) -> BTreeMap<PeerSource, SourceCounts> {
+        self.counts.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(ip: &str) -> Addr {
+        (ip.to_string(), 6881)
+    }
+
+    #[test]
+    fn test_unique_counts() {
+        let mut sources = PeerSources::new();
+        sources.record_discovered(PeerSource::Tracker, &[addr("10.0.0.1"), addr("10.0.0.2")]);
+        // the next announce hands out the same peers again
+        sources.record_discovered(PeerSource::Tracker, &[addr("10.0.0.1"), addr("10.0.0.2")]);
+        sources.record_connected(&addr("10.0.0.1"));
+        sources.record_connected(&addr("10.0.0.1"));
+
+        let report = sources.report();
+        assert_eq!(report[&PeerSource::Tracker], SourceCounts { discovered: 2, connected: 1 });
+        assert!(!report.contains_key(&PeerSource::Dht));
+    }
+
+    #[test]
+    fn test_shared_peers_count_for_each_source() {
+        let mut sources = PeerSources::new();
+        sources.record_discovered(PeerSource::Tracker, &[addr("10.0.0.1")]);
+        sources.record_connected(&addr("10.0.0.1"));
+        sources.record_discovered(PeerSource::Pex, &[addr("10.0.0.1"), addr("10.0.0.3")]);
+
+        let report = sources.report();
+        assert_eq!(report[&PeerSource::Tracker], SourceCounts { discovered: 1, connected: 1 });
+        assert_eq!(report[&PeerSource::Pex], SourceCounts { discovered: 2, connected: 1 });
+
+        // peers we connect to without anyone telling us about them
+        // (e.g. incoming ones) aren't credited to a source
+        sources.record_connected(&addr("10.0.0.9"));
+        assert_eq!(sources.report(), report);
+    }
+}
@@ -0,0 +1,499 @@
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::choker::GiveTake;
+
+// transfer statistics for a torrent and each of its peers. rates are
+// measured over one second samples: the current rate is the last full
+// sample and the average is an exponentially weighted moving average
+// of the samples, which is what the choker goes by since it doesn't
+// jump around when a peer has one slow second.
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+// weight of the newest sample in the average. 0.2 makes it cover
+// roughly the last five seconds.
+const SMOOTHING: f64 = 0.2;
+
+// measures one direction of traffic
+#[derive(Debug, Clone)]
+pub struct RateMeter {
+    total: u64,
+    sample_bytes: u64,
+    sample_start: Instant,
+    current: f64,
+    average: f64,
+}
+
+impl RateMeter {
+    pub fn new(now: Instant) -> RateMeter {
+        RateMeter {
+            total: 0,
+            sample_bytes: 0,
+            sample_start: now,
+            current: 0.0,
+            average: 0.0,
+        }
+    }
+
+    pub fn record(&mut self, bytes: u64, now: Instant) {
+        self.advance(now);
+        self.total += bytes;
+        self.sample_bytes += bytes;
+    }
+
+    // closes the current sample once it is at least a second old.
+    // a long gap counts as several samples at the same rate, so the
+    // average decays properly while a peer is idle.
+    fn advance(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.sample_start);
+        if elapsed < SAMPLE_INTERVAL {
+            return;
+        }
+
+        let rate = self.sample_bytes as f64 / elapsed.as_secs_f64();
+        let samples = (elapsed.as_secs_f64() / SAMPLE_INTERVAL.as_secs_f64()).floor() as i32;
+        self.average = rate + (self.average - rate) * (1.0 - SMOOTHING).powi(samples);
+        self.current = rate;
+        self.sample_bytes = 0;
+        self.sample_start = now;
+    }
+
+    // bytes per second over the last full sample
+    pub fn rate(&mut self, now: Instant) -> f64 {
+        self.advance(now);
+        self.current
+    }
+
+    // smoothed bytes per second
+    pub fn average(&mut self, now: Instant) -> f64 {
+        self.advance(now);
+        self.average
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    // counts bytes moved before we started measuring, e.g. by another
+    // session, without them showing up in the rate
+    pub fn carry_over(&mut self, bytes: u64) {
+        self.total += bytes;
+    }
+}
+
+// point in time view of the counters, handed out over the rpc api
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct TransferSnapshot {
+    // raw bytes off the wire, including anything that was later thrown away
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub download_rate: f64,
+    pub download_rate_avg: f64,
+    pub upload_rate: f64,
+    pub upload_rate_avg: f64,
+    // bytes we received but couldn't use: duplicates and corrupt pieces
+    pub wasted: u64,
+    pub hash_failures: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct TransferStats {
+    download: RateMeter,
+    upload: RateMeter,
+    wasted: u64,
+    hash_failures: u64,
+}
+
+impl TransferStats {
+    pub fn new(now: Instant) -> TransferStats {
+        TransferStats {
+            download: RateMeter::new(now),
+            upload: RateMeter::new(now),
+            wasted: 0,
+            hash_failures: 0,
+        }
+    }
+
+    pub fn snapshot(&mut self, now: Instant) -> TransferSnapshot {
+        TransferSnapshot {
+            downloaded: self.download.total(),
+            uploaded: self.upload.total(),
+            download_rate: self.download.rate(now),
+            download_rate_avg: self.download.average(now),
+            upload_rate: self.upload.rate(now),
+            upload_rate_avg: self.upload.average(now),
+            wasted: self.wasted,
+            hash_failures: self.hash_failures,
+        }
+    }
+}
+
+// where the torrent's bytes went, for the rpc api. the meters above
+// count every block that arrives, these keep apart what the tracker
+// should hear about from what it shouldn't.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Accounting {
+    // block data that ended up in a piece that passed its hash check
+    pub payload_downloaded: u64,
+    pub payload_uploaded: u64,
+    // block data we threw away
+    pub wasted: u64,
+    // everything else on the wire: the handshake, message headers,
+    // requests, haves and so on
+    pub overhead_downloaded: u64,
+    pub overhead_uploaded: u64,
+}
+
+// how many whole seconds of history each torrent keeps for graphs
+pub const HISTORY_SECONDS: usize = 300;
+
+// the history keeps the second under way too
+const HISTORY_BUCKETS: usize = HISTORY_SECONDS + 1;
+
+// what the history's seconds count from. it is the same for every
+// torrent, so their histories line up and can be added together.
+pub fn history_epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+fn history_second(now: Instant) -> u64 {
+    now.saturating_duration_since(history_epoch()).as_secs()
+}
+
+// block data moved in each second, oldest first and up to the last
+// whole second, so each entry is a rate in bytes per second
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct RateSeries {
+    pub download: Vec<u64>,
+    pub upload: Vec<u64>,
+}
+
+impl RateSeries {
+    // adds another torrent's series taken at the same time
+    pub fn add(&mut self, other: &RateSeries) {
+        if self.download.is_empty() {
+            *self = other.clone();
+            return;
+        }
+        self.download.iter_mut().zip(&other.download).for_each(|(a, b)| *a += b);
+        self.upload.iter_mut().zip(&other.upload).for_each(|(a, b)| *a += b);
+    }
+}
+
+// a ring of per second buckets. nothing samples it, bytes go into the
+// bucket for the second they arrive in and buckets are cleared as the
+// seconds they were for fall out of the window.
+#[derive(Debug, Clone)]
+pub struct RateHistory {
+    download: [u64; HISTORY_BUCKETS],
+    upload: [u64; HISTORY_BUCKETS],
+    // the second the newest bucket is for
+    latest: u64,
+}
+
+impl RateHistory {
+    pub fn new() -> RateHistory {
+        RateHistory { download: [0; HISTORY_BUCKETS], upload: [0; HISTORY_BUCKETS], latest: 0 }
+    }
+
+    fn advance(&mut self, second: u64) {
+        if second <= self.latest {
+            return;
+        }
+        let cleared = (second - self.latest).min(HISTORY_BUCKETS as u64);
+        for s in second + 1 - cleared..=second {
+            let i = (s % HISTORY_BUCKETS as u64) as usize;
+            self.download[i] = 0;
+            self.upload[i] = 0;
+        }
+        self.latest = second;
+    }
+
+    pub fn record(&mut self, downloaded: u64, uploaded: u64, now: Instant) {
+        let second = history_second(now);
+        self.advance(second);
+        if second + (HISTORY_BUCKETS as u64) <= self.latest {
+            return;
+        }
+        let i = (second % HISTORY_BUCKETS as u64) as usize;
+        self.download[i] += downloaded;
+        self.upload[i] += uploaded;
+    }
+
+    // the HISTORY_SECONDS whole seconds before now
+    pub fn series(&mut self, now: Instant) -> RateSeries {
+        let current = history_second(now);
+        self.advance(current);
+        let mut series = RateSeries::default();
+        for s in current as i64 - HISTORY_SECONDS as i64..current as i64 {
+            // from before the epoch
+            let i = (s >= 0).then(|| (s as u64 % HISTORY_BUCKETS as u64) as usize);
+            series.download.push(i.map_or(0, |i| self.download[i]));
+            series.upload.push(i.map_or(0, |i| self.upload[i]));
+        }
+        series
+    }
+}
+
+impl Default for RateHistory {
+    fn default() -> Self {
+        RateHistory::new()
+    }
+}
+
+// the stats for a whole torrent along with each connected peer's share
+#[derive(Debug)]
+pub struct StatsTracker {
+    torrent: TransferStats,
+    peers: HashMap<String, TransferStats>,
+    verified: u64,
+    // this session's block data and the bytes the connections moved in
+    // all, the difference being protocol overhead
+    payload_received: u64,
+    payload_sent: u64,
+    wire_received: u64,
+    wire_sent: u64,
+    history: RateHistory,
+}
+
+impl StatsTracker {
+    pub fn new() -> StatsTracker {
+        StatsTracker {
+            torrent: TransferStats::new(Instant::now()),
+            peers: HashMap::new(),
+            verified: 0,
+            payload_received: 0,
+            payload_sent: 0,
+            wire_received: 0,
+            wire_sent: 0,
+            history: RateHistory::new(),
+        }
+    }
+
+    fn peer(&mut self, peer_id: &str, now: Instant) -> &mut TransferStats {
+        self.peers.entry(peer_id.to_string()).or_insert_with(|| TransferStats::new(now))
+    }
+
+    pub fn record_download(&mut self, peer_id: &str, bytes: u64, now: Instant) {
+        self.torrent.download.record(bytes, now);
+        self.peer(peer_id, now).download.record(bytes, now);
+        self.history.record(bytes, 0, now);
+        self.payload_received += bytes;
+    }
+
+    pub fn record_upload(&mut self, peer_id: &str, bytes: u64, now: Instant) {
+        self.torrent.upload.record(bytes, now);
+        self.peer(peer_id, now).upload.record(bytes, now);
+        self.history.record(0, bytes, now);
+        self.payload_sent += bytes;
+    }
+
+    // a piece of this many bytes passed its hash check
+    pub fn record_verified(&mut self, bytes: u64) {
+        self.verified += bytes;
+    }
+
+    // everything a connection read and wrote, payload included
+    pub fn record_wire(&mut self, received: u64, sent: u64) {
+        self.wire_received += received;
+        self.wire_sent += sent;
+    }
+
+    pub fn record_wasted(&mut self, peer_id: &str, bytes: u64, now: Instant) {
+        self.torrent.wasted += bytes;
+        self.peer(peer_id, now).wasted += bytes;
+    }
+
+    // a piece failed its hash check. every peer that sent part of it
+    // gets the failure counted against them.
+    pub fn record_hash_failure(&mut self, peer_ids: &[String], bytes: u64, now: Instant) {
+        self.torrent.hash_failures += 1;
+        self.torrent.wasted += bytes;
+        for peer_id in peer_ids {
+            self.peer(peer_id, now).hash_failures += 1;
+        }
+    }
+
+    // totals from an earlier session of the same torrent. downloaded
+    // is what that session had verified.
+    pub fn carry_over(&mut self, downloaded: u64, uploaded: u64) {
+        self.torrent.download.carry_over(downloaded);
+        self.torrent.upload.carry_over(uploaded);
+        self.verified += downloaded;
+    }
+
+    pub fn remove_peer(&mut self, peer_id: &str) {
+        self.peers.remove(peer_id);
+    }
+
+    pub fn torrent(&mut self, now: Instant) -> TransferSnapshot {
+        self.torrent.snapshot(now)
+    }
+
+    pub fn history(&mut self, now: Instant) -> RateSeries {
+        self.history.series(now)
+    }
+
+    pub fn peer_snapshot(&mut self, peer_id: &str, now: Instant) -> Option<TransferSnapshot> {
+        self.peers.get_mut(peer_id).map(|stats| stats.snapshot(now))
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.torrent.upload.total()
+    }
+
+    // verified payload, this session's and any carried over
+    pub fn verified(&self) -> u64 {
+        self.verified
+    }
+
+    pub fn accounting(&self) -> Accounting {
+        Accounting {
+            payload_downloaded: self.verified,
+            payload_uploaded: self.torrent.upload.total(),
+            wasted: self.torrent.wasted,
+            // connections hand their wire counts over a little after
+            // the payload is recorded
+            overhead_downloaded: self.wire_received.saturating_sub(self.payload_received),
+            overhead_uploaded: self.wire_sent.saturating_sub(self.payload_sent),
+        }
+    }
+
+    // what each connected peer has taken from us and given back
+    pub fn give_take(&self) -> HashMap<String, GiveTake> {
+        self.peers
+            .iter()
+            .map(|(peer_id, stats)| {
+                (peer_id.clone(), GiveTake { uploaded: stats.upload.total(), downloaded: stats.download.total() })
+            })
+            .collect()
+    }
+
+    // smoothed download rate of every peer, for the choker
+    pub fn download_rates(&mut self, now: Instant) -> HashMap<String, f64> {
+        self.peers
+            .iter_mut()
+            .map(|(peer_id, stats)| (peer_id.clone(), stats.download.average(now)))
+            .collect()
+    }
+}
+
+impl Default for StatsTracker {
+    fn default() -> Self {
+        StatsTracker::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_over_sample() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start);
+
+        meter.record(1000, start);
+        meter.record(1000, start + Duration::from_millis(500));
+        // the sample isn't closed until a full second has passed
+        assert_eq!(meter.rate(start + Duration::from_millis(900)), 0.0);
+
+        assert_eq!(meter.rate(start + Duration::from_secs(2)), 1000.0);
+        assert_eq!(meter.total(), 2000);
+
+        meter.carry_over(5000);
+        assert_eq!(meter.total(), 7000);
+        assert_eq!(meter.rate(start + Duration::from_secs(3)), 0.0);
+    }
+
+    #[test]
+    fn test_average_smooths_and_decays() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start);
+
+        meter.record(1000, start);
+        let average = meter.average(start + Duration::from_secs(1));
+        assert!((average - 200.0).abs() < 1e-9);
+
+        // ten idle seconds pull the average most of the way back down
+        let later = meter.average(start + Duration::from_secs(11));
+        assert!(later < average * 0.2);
+        assert_eq!(meter.rate(start + Duration::from_secs(11)), 0.0);
+    }
+
+    #[test]
+    fn test_history() {
+        let start = history_epoch() + Duration::from_secs(1000);
+        let mut history = RateHistory::new();
+        history.record(100, 0, start);
+        history.record(50, 10, start + Duration::from_millis(999));
+        history.record(7, 0, start + Duration::from_secs(2));
+        // the second under way isn't in the series yet
+        history.record(1, 1, start + Duration::from_secs(3));
+
+        let series = history.series(start + Duration::from_secs(3));
+        assert_eq!(series.download.len(), HISTORY_SECONDS);
+        assert_eq!(series.download[HISTORY_SECONDS - 3..], [150, 0, 7]);
+        assert_eq!(series.upload[HISTORY_SECONDS - 3..], [10, 0, 0]);
+
+        // a second that has fallen out of the window is gone for good
+        let later = start + Duration::from_secs(HISTORY_SECONDS as u64 + 1);
+        assert_eq!(history.series(later).download[0], 0);
+        assert_eq!(history.series(later).download[1..3], [7, 1]);
+        let series = history.series(later + Duration::from_secs(HISTORY_SECONDS as u64));
+        assert!(series.download.iter().all(|&b| b == 0));
+
+        let mut total = RateSeries::default();
+        total.add(&RateSeries { download: vec![1, 2], upload: vec![0, 0] });
+        total.add(&RateSeries { download: vec![10, 20], upload: vec![5, 5] });
+        assert_eq!(total, RateSeries { download: vec![11, 22], upload: vec![5, 5] });
+    }
+
+    #[test]
+    fn test_tracker_counts_per_peer() {
+        let now = Instant::now();
+        let mut stats = StatsTracker::new();
+
+        stats.record_download("a", 100, now);
+        stats.record_download("b", 50, now);
+        stats.record_upload("a", 10, now);
+        stats.record_wasted("b", 16, now);
+        stats.record_hash_failure(&["a".to_string(), "b".to_string()], 32, now);
+
+        let torrent = stats.torrent(now);
+        assert_eq!(torrent.downloaded, 150);
+        assert_eq!(torrent.uploaded, 10);
+        assert_eq!(torrent.wasted, 48);
+        assert_eq!(torrent.hash_failures, 1);
+
+        let b = stats.peer_snapshot("b", now).unwrap();
+        assert_eq!(b.downloaded, 50);
+        assert_eq!(b.wasted, 16);
+        assert_eq!(b.hash_failures, 1);
+
+        let accounting = stats.accounting();
+        assert_eq!(accounting.payload_downloaded, 0);
+        assert_eq!(accounting.wasted, 48);
+        assert_eq!(accounting.overhead_downloaded, 0);
+
+        stats.record_verified(100);
+        stats.record_wire(150 + 68 + 26, 10 + 68 + 13);
+        stats.carry_over(1000, 0);
+        let accounting = stats.accounting();
+        assert_eq!(accounting.payload_downloaded, 1100);
+        assert_eq!(accounting.payload_uploaded, 10);
+        assert_eq!(accounting.overhead_downloaded, 94);
+        assert_eq!(accounting.overhead_uploaded, 81);
+
+        stats.remove_peer("b");
+        assert_eq!(stats.peer_snapshot("b", now), None);
+        assert_eq!(stats.download_rates(now).len(), 1);
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::{BTreeSet, HashMap};
+
+// super seeding (bep 16) is for the first seed of a new torrent. rather
+// than advertising every piece, each peer is told about a single piece
+// it doesn't have, and only gets another once that piece has turned up
+// at some other peer, meaning the first peer passed it on. the swarm
+// ends up doing most of the uploading and the seed sends each piece
+// close to once.
+//
+// peers connected to a super seed get no bitfield, just a Have for the
+// piece they have been offered.
+
+#[derive(Debug)]
+pub struct SuperSeeder {
+    // times each piece has been offered to a peer
+    offered: Vec<u32>,
+    // the piece each peer is waiting to pass on
+    current: HashMap<String, u32>,
+    // every piece each peer has been offered, which is all it is served
+    history: HashMap<String, BTreeSet<u32>>,
+}
+
+impl SuperSeeder {
+    pub fn new(num_pieces: usize) -> SuperSeeder {
+        SuperSeeder {
+            offered: vec![0; num_pieces],
+            current: HashMap::new(),
+            history: HashMap::new(),
+        }
+    }
+
+    // picks a new piece to offer the peer if it isn't still waiting on
+    // the last one. goes for the piece that has been offered and seen
+    // the least, given how many peers have each piece (availability)
+    // and what the peer has itself.
+    pub fn next_offer(&mut self, peer_id: &str, peer_pieces: &[u8], availability: &[u32]) -> Option<u32> {
+        if self.current.contains_key(peer_id) {
+            return None;
+        }
+
+        let index = (0..self.offered.len())
+            .filter(|&i| peer_pieces.get(i).is_none_or(|&b| b == 0))
+            .min_by_key(|&i| (self.offered[i] + availability.get(i).copied().unwrap_or(0), i))? as u32;
+
+        self.offered[index as usize] += 1;
+        self.current.insert(peer_id.to_string(), index);
+        self.history.entry(peer_id.to_string()).or_default().insert(index);
+        Some(index)
+    }
+
+    // a peer announced a piece. every other peer that was offered it has
+    // done its job and can be offered another. returns whether anyone
+    // was freed up.
+    pub fn peer_has(&mut self, peer_id: &str, index: u32) -> bool {
+        let before = self.current.len();
+        self.current.retain(|other, offer| other == peer_id || *offer != index);
+        self.current.len() != before
+    }
+
+    // the peer's bitfield turned up after we had already offered it
+    // something. if it has that piece already the offer is wasted, so
+    // let it have another.
+    pub fn peer_bitfield(&mut self, peer_id: &str, pieces: &[u8]) {
+        if let Some(&index) = self.current.get(peer_id) {
+            if pieces.get(index as usize).is_some_and(|&b| b != 0) {
+                self.current.remove(peer_id);
+            }
+        }
+    }
+
+    pub fn is_waiting(&self, peer_id: &str) -> bool {
+        self.current.contains_key(peer_id)
+    }
+
+    // pieces we didn't offer the peer aren't served to it
+    pub fn may_serve(&self, peer_id: &str, index: u32) -> bool {
+        self.history.get(peer_id).is_some_and(|offers| offers.contains(&index))
+    }
+
+    pub fn remove_peer(&mut self, peer_id: &str) {
+        self.current.remove(peer_id);
+        self.history.remove(peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_one_offer_until_propagated() {
+        let mut seeder = SuperSeeder::new(4);
+        let none = [0u8; 4];
+
+        assert_eq!(seeder.next_offer("a", &none, &[0; 4]), Some(0));
+        assert_eq!(seeder.next_offer("a", &none, &[0; 4]), None);
+        // b is offered something else
+        assert_eq!(seeder.next_offer("b", &none, &[0; 4]), Some(1));
+
+        // a saying it has its own piece doesn't count
+        assert!(!seeder.peer_has("a", 0));
+        assert_eq!(seeder.next_offer("a", &[1, 0, 0, 0], &[1, 0, 0, 0]), None);
+
+        // b getting it does
+        assert!(seeder.peer_has("b", 0));
+        assert_eq!(seeder.next_offer("a", &[1, 0, 0, 0], &[2, 0, 0, 0]), Some(2));
+    }
+
+    #[test]
+    fn test_skips_pieces_the_peer_has() {
+        let mut seeder = SuperSeeder::new(3);
+        assert_eq!(seeder.next_offer("a", &[1, 1, 0], &[1, 1, 0]), Some(2));
+        assert_eq!(SuperSeeder::new(3).next_offer("a", &[1, 1, 1], &[1, 1, 1]), None);
+    }
+
+    #[test]
+    fn test_late_bitfield_replaces_offer() {
+        let mut seeder = SuperSeeder::new(2);
+        assert_eq!(seeder.next_offer("a", &[], &[0, 0]), Some(0));
+
+        seeder.peer_bitfield("a", &[0, 1]);
+        assert!(seeder.is_waiting("a"));
+
+        seeder.peer_bitfield("a", &[1, 0]);
+        assert!(!seeder.is_waiting("a"));
+        assert_eq!(seeder.next_offer("a", &[1, 0], &[1, 0]), Some(1));
+    }
+
+    #[test]
+    fn test_serves_only_offered_pieces() {
+        let mut seeder = SuperSeeder::new(2);
+        seeder.next_offer("a", &[0, 0], &[0, 0]);
+        assert!(seeder.may_serve("a", 0));
+        assert!(!seeder.may_serve("a", 1));
+        assert!(!seeder.may_serve("b", 0));
+
+        seeder.remove_peer("a");
+        assert!(!seeder.may_serve("a", 0));
+    }
+}
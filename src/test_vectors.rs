@@ -0,0 +1,353 @@
+// known-good (and known-bad) wire data for the peer protocol and the
+// tracker announce. every vector is written out byte by byte from the
+// spec rather than produced by our own encoders, so they pin down the
+// exact wire format that protocol.rs and tracker.rs guarantee.
+//
+// see: https://wiki.theory.org/BitTorrentSpecification
+
+use bytes::Bytes;
+
+use crate::{protocol::Message, tracker::AnnounceEvent};
+
+pub struct HandshakeVector {
+    pub name: &'static str,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub encoded: Vec<u8>,
+}
+
+pub struct InvalidVector {
+    pub name: &'static str,
+    pub encoded: Vec<u8>,
+}
+
+pub struct MessageVector {
+    pub name: &'static str,
+    pub message: Message,
+    pub encoded: Vec<u8>,
+}
+
+// pieces is None when the bitfield must be rejected
+pub struct BitfieldVector {
+    pub name: &'static str,
+    pub num_pieces: usize,
+    pub encoded: Vec<u8>,
+    pub pieces: Option<Vec<u8>>,
+}
+
+pub struct AnnounceUrlVector {
+    pub name: &'static str,
+    pub announce: &'static str,
+    pub info_hash: [u8; 20],
+    pub peer_id: &'static str,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+    pub numwant: Option<u32>,
+    pub key: Option<&'static str>,
+    pub tracker_id: Option<&'static str>,
+    pub ip: Option<&'static str>,
+    pub ipv6: Option<&'static str>,
+    pub expected: &'static str,
+}
+
+// peers is None when the blob must be rejected
+pub struct CompactPeersVector {
+    pub name: &'static str,
+    pub encoded: Vec<u8>,
+    pub peers: Option<Vec<(&'static str, u16)>>,
+}
+
+const PSTR: &[u8] = b"BitTorrent protocol";
+const PEER_ID: [u8; 20] = *b"-MY6969-123456789012";
+
+fn handshake_bytes(pstrlen: u8, pstr: &[u8], info_hash: &[u8], peer_id: &[u8]) -> Vec<u8> {
+    [&[pstrlen][..], pstr, &[0u8; 8], info_hash, peer_id].concat()
+}
+
+pub fn handshakes() -> Vec<HandshakeVector> {
+    let counting: [u8; 20] = core::array::from_fn(|i| i as u8);
+
+    vec![
+        HandshakeVector {
+            name: "repeated info hash byte",
+            info_hash: [0xAB; 20],
+            peer_id: PEER_ID,
+            encoded: handshake_bytes(19, PSTR, &[0xAB; 20], &PEER_ID),
+        },
+        HandshakeVector {
+            name: "counting info hash",
+            info_hash: counting,
+            peer_id: *b"-TR3000-abcdefghijkl",
+            encoded: handshake_bytes(19, PSTR, &counting, b"-TR3000-abcdefghijkl"),
+        },
+    ]
+}
+
+pub fn invalid_handshakes() -> Vec<InvalidVector> {
+    vec![
+        InvalidVector {
+            name: "truncated",
+            encoded: handshake_bytes(19, PSTR, &[0xAB; 20], &PEER_ID)[..67].to_vec(),
+        },
+        InvalidVector {
+            name: "wrong pstrlen",
+            encoded: handshake_bytes(18, PSTR, &[0xAB; 20], &PEER_ID),
+        },
+        InvalidVector {
+            name: "wrong protocol string",
+            encoded: handshake_bytes(19, b"BitTorrent protocoL", &[0xAB; 20], &PEER_ID),
+        },
+    ]
+}
+
+pub fn messages() -> Vec<MessageVector> {
+    vec![
+        MessageVector { name: "keep-alive", message: Message::KeepAlive, encoded: vec![0, 0, 0, 0] },
+        MessageVector { name: "choke", message: Message::Choke, encoded: vec![0, 0, 0, 1, 0] },
+        MessageVector { name: "unchoke", message: Message::Unchoke, encoded: vec![0, 0, 0, 1, 1] },
+        MessageVector { name: "interested", message: Message::Interested, encoded: vec![0, 0, 0, 1, 2] },
+        MessageVector { name: "not interested", message: Message::NotInterested, encoded: vec![0, 0, 0, 1, 3] },
+        MessageVector {
+            name: "have",
+            message: Message::Have(0x0102_0304),
+            encoded: vec![0, 0, 0, 5, 4, 1, 2, 3, 4],
+        },
+        MessageVector {
+            name: "bitfield",
+            message: Message::Bitfield(vec![0b1010_0000, 0xFF]),
+            encoded: vec![0, 0, 0, 3, 5, 0b1010_0000, 0xFF],
+        },
+        MessageVector {
+            name: "request",
+            message: Message::Request { index: 1, begin: 0x4000, length: 0x4000 },
+            encoded: vec![0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+        },
+        MessageVector {
+            name: "piece",
+            message: Message::Piece { index: 2, begin: 16, block: Bytes::from_static(b"data") },
+            encoded: vec![0, 0, 0, 13, 7, 0, 0, 0, 2, 0, 0, 0, 16, b'd', b'a', b't', b'a'],
+        },
+        MessageVector {
+            name: "empty piece",
+            message: Message::Piece { index: 0, begin: 0, block: Bytes::new() },
+            encoded: vec![0, 0, 0, 9, 7, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+        MessageVector {
+            name: "cancel",
+            message: Message::Cancel { index: 1, begin: 0x4000, length: 0x4000 },
+            encoded: vec![0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+        },
+        MessageVector {
+            name: "port",
+            message: Message::Port(6881),
+            encoded: vec![0, 0, 0, 3, 9, 0x1A, 0xE1],
+        },
+    ]
+}
+
+pub fn invalid_messages() -> Vec<InvalidVector> {
+    vec![
+        InvalidVector { name: "no length prefix", encoded: vec![0, 0, 1] },
+        InvalidVector { name: "prefix longer than data", encoded: vec![0, 0, 0, 5, 4, 0, 0] },
+        InvalidVector { name: "prefix shorter than data", encoded: vec![0, 0, 0, 1, 0, 0] },
+        InvalidVector { name: "unknown id", encoded: vec![0, 0, 0, 1, 20] },
+        InvalidVector { name: "choke with payload", encoded: vec![0, 0, 0, 2, 0, 0] },
+        InvalidVector { name: "short have", encoded: vec![0, 0, 0, 3, 4, 0, 1] },
+        InvalidVector { name: "short request", encoded: vec![0, 0, 0, 9, 6, 0, 0, 0, 1, 0, 0, 0, 0] },
+        InvalidVector { name: "piece without begin", encoded: vec![0, 0, 0, 5, 7, 0, 0, 0, 1] },
+        InvalidVector { name: "long port", encoded: vec![0, 0, 0, 4, 9, 0, 0x1A, 0xE1] },
+    ]
+}
+
+pub fn bitfields() -> Vec<BitfieldVector> {
+    vec![
+        BitfieldVector {
+            name: "exact byte",
+            num_pieces: 8,
+            encoded: vec![0b1000_0001],
+            pieces: Some(vec![1, 0, 0, 0, 0, 0, 0, 1]),
+        },
+        BitfieldVector {
+            name: "spare bits clear",
+            num_pieces: 10,
+            encoded: vec![0xFF, 0b1100_0000],
+            pieces: Some(vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1]),
+        },
+        BitfieldVector {
+            name: "empty torrent",
+            num_pieces: 0,
+            encoded: vec![],
+            pieces: Some(vec![]),
+        },
+        BitfieldVector {
+            name: "spare bits set",
+            num_pieces: 10,
+            encoded: vec![0xFF, 0b1110_0000],
+            pieces: None,
+        },
+        BitfieldVector {
+            name: "too short",
+            num_pieces: 9,
+            encoded: vec![0xFF],
+            pieces: None,
+        },
+        BitfieldVector {
+            name: "too long",
+            num_pieces: 8,
+            encoded: vec![0xFF, 0x00],
+            pieces: None,
+        },
+    ]
+}
+
+pub fn announce_urls() -> Vec<AnnounceUrlVector> {
+    vec![
+        AnnounceUrlVector {
+            name: "started",
+            announce: "http://tracker.example/announce",
+            info_hash: [0xAB; 20],
+            peer_id: "-MY6969-123456789012",
+            port: 6889,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1000,
+            event: Some(AnnounceEvent::Started),
+            numwant: None,
+            key: None,
+            tracker_id: None,
+            ip: None,
+            ipv6: None,
+            expected: "http://tracker.example/announce\
+                ?info_hash=%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB\
+                &peer_id=-MY6969-123456789012&port=6889&uploaded=0&downloaded=0&left=1000&compact=1\
+                &event=started",
+        },
+        AnnounceUrlVector {
+            name: "regular",
+            announce: "http://tracker.example:6969/announce",
+            info_hash: core::array::from_fn(|i| i as u8),
+            peer_id: "-MY6969-123456789012",
+            port: 6889,
+            uploaded: 512,
+            downloaded: 256,
+            left: 744,
+            event: None,
+            numwant: None,
+            key: None,
+            tracker_id: None,
+            ip: None,
+            ipv6: None,
+            expected: "http://tracker.example:6969/announce\
+                ?info_hash=%00%01%02%03%04%05%06%07%08%09%0A%0B%0C%0D%0E%0F%10%11%12%13\
+                &peer_id=-MY6969-123456789012&port=6889&uploaded=512&downloaded=256&left=744&compact=1",
+        },
+        AnnounceUrlVector {
+            name: "stopped",
+            announce: "http://tracker.example/announce",
+            info_hash: [0xAB; 20],
+            peer_id: "-MY6969-123456789012",
+            port: 6889,
+            uploaded: 2048,
+            downloaded: 1000,
+            left: 0,
+            event: Some(AnnounceEvent::Stopped),
+            numwant: Some(0),
+            key: None,
+            tracker_id: None,
+            ip: None,
+            ipv6: None,
+            expected: "http://tracker.example/announce\
+                ?info_hash=%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB\
+                &peer_id=-MY6969-123456789012&port=6889&uploaded=2048&downloaded=1000&left=0&compact=1\
+                &numwant=0&event=stopped",
+        },
+        AnnounceUrlVector {
+            name: "escaping and an existing query",
+            announce: "http://tracker.example/announce.php?passkey=abc123",
+            info_hash: *b"ab-._~AZ09 /?&=%\xff\x00\x7fz",
+            peer_id: "-X%&69-1234567890123",
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            event: None,
+            numwant: None,
+            key: None,
+            tracker_id: None,
+            ip: None,
+            ipv6: None,
+            expected: "http://tracker.example/announce.php?passkey=abc123\
+                &info_hash=ab-._~AZ09%20%2F%3F%26%3D%25%FF%00%7Fz\
+                &peer_id=-X%25%2669-1234567890123&port=6881&uploaded=0&downloaded=0&left=0&compact=1",
+        },
+        AnnounceUrlVector {
+            name: "numwant, key and tracker id",
+            announce: "http://tracker.example/announce",
+            info_hash: [0xAB; 20],
+            peer_id: "-MY6969-123456789012",
+            port: 6889,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1000,
+            event: Some(AnnounceEvent::Started),
+            numwant: Some(200),
+            key: Some("1A2B3C4D"),
+            tracker_id: Some("id 7"),
+            ip: None,
+            ipv6: None,
+            expected: "http://tracker.example/announce\
+                ?info_hash=%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB\
+                &peer_id=-MY6969-123456789012&port=6889&uploaded=0&downloaded=0&left=1000&compact=1\
+                &numwant=200&key=1A2B3C4D&trackerid=id%207&event=started",
+        },
+        // bep 7
+        AnnounceUrlVector {
+            name: "ip and ipv6",
+            announce: "http://tracker.example/announce",
+            info_hash: [0xAB; 20],
+            peer_id: "-MY6969-123456789012",
+            port: 6889,
+            uploaded: 0,
+            downloaded: 0,
+            left: 1000,
+            event: None,
+            numwant: None,
+            key: None,
+            tracker_id: None,
+            ip: Some("203.0.113.7"),
+            ipv6: Some("2001:db8::1"),
+            expected: "http://tracker.example/announce\
+                ?info_hash=%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB%AB\
+                &peer_id=-MY6969-123456789012&port=6889&uploaded=0&downloaded=0&left=1000&compact=1\
+                &ip=203.0.113.7&ipv6=2001%3Adb8%3A%3A1",
+        },
+    ]
+}
+
+pub fn compact_peers() -> Vec<CompactPeersVector> {
+    vec![
+        CompactPeersVector {
+            name: "single peer",
+            encoded: vec![127, 0, 0, 1, 0x1A, 0xE1],
+            peers: Some(vec![("127.0.0.1", 6881)]),
+        },
+        CompactPeersVector {
+            name: "two peers",
+            encoded: vec![10, 0, 0, 2, 0x00, 0x50, 192, 168, 1, 255, 0xFF, 0xFF],
+            peers: Some(vec![("10.0.0.2", 80), ("192.168.1.255", 65535)]),
+        },
+        CompactPeersVector {
+            name: "empty",
+            encoded: vec![],
+            peers: Some(vec![]),
+        },
+        CompactPeersVector {
+            name: "truncated",
+            encoded: vec![127, 0, 0, 1, 0x1A],
+            peers: None,
+        },
+    ]
+}
@@ -1,15 +1,51 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, path::Path};
+
 use sha1::{Digest, Sha1};
 
-use crate::bencoding::{encoder, Bencode};
+use crate::{
+    bencoding::{decoder, encoder, Bencode, FieldError},
+    error::BtError,
+    info_hash::InfoHash,
+    piece_map::PieceMap,
+};
 
 // file struct for single file torrents. 
 // TODO: implement multi-file struct for multi file torrents
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct File {
     pub name: String,
     length: u64,
+    // bep 47 padding file, only there to line the next file up with a piece
+    padding: bool,
+    // hex md5 of the file, hardly any torrent has one
+    md5sum: Option<String>,
+}
+
+impl File {
+    pub fn new(name: String, length: u64) -> File {
+        File { name, length, padding: false, md5sum: None }
+    }
+
+    pub fn padding(length: u64) -> File {
+        File { name: format!(".pad/{}", length), length, padding: true, md5sum: None }
+    }
+
+    pub fn with_md5sum(self, md5sum: Option<String>) -> File {
+        File { md5sum, ..self }
+    }
+
+    pub fn md5sum(&self) -> Option<&str> {
+        self.md5sum.as_deref()
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_padding(&self) -> bool {
+        self.padding
+    }
 }
 
 // this is bad gems but i cba rewriting this 
@@ -17,83 +53,503 @@ pub struct File {
 // return the values and get rid of the torrent 
 // struct entirely. but this will do.
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct Torrent {
-    pub info_hash: Vec<u8>,
+    pub info_hash: InfoHash,
     pub announce: String,
+    // bep 12 tiers of trackers, empty when the torrent only has announce
+    pub announce_list: Vec<Vec<String>>,
     pub multi_file: bool,
     pub piece_length: u32,
     pub total_size: u64,
-    pub pieces: Vec<u8>,
+    // the sha1 of every piece, in order
+    pub pieces: Vec<[u8; 20]>,
     pub output_file: String,
     pub files: Vec<File>,
+    // bep 27: peers only come from the tracker, so no dht or pex
+    pub private: bool,
+    // bep 5 dht nodes to start from as (host, port), which is all a
+    // trackerless torrent has to go on
+    pub nodes: Vec<(String, u16)>,
+    // informational fields outside the info dict. none of them are
+    // needed to download the torrent.
+    // seconds since the unix epoch
+    pub creation_date: Option<i64>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // the character set the strings were written in, usually utf-8
+    pub encoding: Option<String>,
+    // the info dict as it was encoded, with the keys we don't read. the
+    // info hash is the hash of these bytes, so writing the torrent out
+    // again keeps them as they are. None for a torrent made up in code,
+    // whose info dict is rebuilt from the fields above.
+    pub info_bytes: Option<Vec<u8>>,
 }
 
 // get the sha1 hash of the bencode of the info dict
 // for sending to the tracker as a param
-pub fn get_sha1_info_hash(bencode: &Bencode) -> Result<Vec<u8>, String> {
+pub fn get_sha1_info_hash(bencode: &Bencode) -> Result<InfoHash, BtError> {
     let encoded = encoder::encode(bencode);
-    
+    Ok(raw_info_hash(&encoded))
+}
+
+fn raw_info_hash(info: &[u8]) -> InfoHash {
     let mut hasher = Sha1::new();
-    hasher.update(&encoded);
-    Ok(hasher.finalize().to_vec())
+    hasher.update(info);
+    InfoHash::new(hasher.finalize().into())
 }
 
-// takes bencoded torrent data and returns a torrent object
-pub fn build_torrent(bencode: &Bencode) -> Result<Torrent, String> {
-    let dict = match bencode {
-        Bencode::Dict(d) => d,
-        _ => return Err("top level bencode is not a dict".to_string()),
-    };
+// a .torrent file as it is on disk. unlike build_torrent on the decoded
+// dict, the info hash is of the info dict's own bytes, so a torrent that
+// wasn't encoded quite to the letter (keys out of order, say) still has
+// the info hash everyone else knows it by. the bytes are kept in
+// info_bytes for writing it out again.
+pub fn parse_torrent(data: &[u8]) -> Result<Torrent, BtError> {
+    let (bencode, _) = decoder::decode(data)?;
+    let mut torrent = build_torrent(&bencode)?;
+    if let Some(info) = decoder::raw_value(data, b"info")? {
+        torrent.info_hash = raw_info_hash(info);
+        torrent.info_bytes = Some(info.to_vec());
+    }
+    Ok(torrent)
+}
 
-    let announce = match dict.get(&b"announce"[..]) {
-        Some(Bencode::Bytes(b)) => String::from_utf8(b.clone()).map_err(|e| e.to_string())?,
-        _ => return Err("couldn't find announce url".to_string()),
-    };
+// optional strings are kept even if they aren't valid utf-8, a bad
+// comment isn't worth refusing the torrent over
+fn optional_string(dict: &Bencode, key: &str) -> Option<String> {
+    dict.get(key)?.as_bytes().map(|b| String::from_utf8_lossy(b).to_string())
+}
 
-    let info = match dict.get(&b"info"[..]) {
-        Some(Bencode::Dict(d)) => d,
-        _ => return Err("couldn't find info dict".to_string()),
+// tiers that aren't lists of strings are skipped rather than failing
+// the whole torrent, announce is always there to fall back on
+fn announce_list(dict: &Bencode) -> Vec<Vec<String>> {
+    let Ok(tiers) = dict.get_list("announce-list") else {
+        return Vec::new();
     };
 
-    let info_bencode = match dict.get(&b"info"[..]) {
-        Some(info @ Bencode::Dict(_)) => info,
-        _ => return Err("cant find info bencode".to_string()),
-    };
+    tiers
+        .iter()
+        .filter_map(|tier| tier.as_list())
+        .map(|urls| urls.iter().filter_map(|url| url.as_str().map(str::to_string)).collect::<Vec<_>>())
+        .filter(|tier| !tier.is_empty())
+        .collect()
+}
 
-    let name = match info.get(&b"name"[..]) {
-        Some(Bencode::Bytes(b)) => String::from_utf8(b.clone()).map_err(|e| e.to_string())?,
-        _ => return Err("couldn't get name field from info dict".to_string())
+// nodes is a list of [host, port] pairs. like announce-list, the ones
+// that aren't are skipped.
+fn dht_nodes(dict: &Bencode) -> Vec<(String, u16)> {
+    let Ok(nodes) = dict.get_list("nodes") else {
+        return Vec::new();
     };
 
-    let length = match info.get(&b"length"[..]) {
-        Some(Bencode::Int(i)) => *i as u64,
-        _ => return Err("couldn't get length field from info dict".to_string())
-    };
+    nodes
+        .iter()
+        .filter_map(|node| match node.as_list()? {
+            [host, port] => {
+                let port = u16::try_from(port.as_int()?).ok().filter(|&p| p != 0)?;
+                Some((host.as_str().filter(|h| !h.is_empty())?.to_string(), port))
+            }
+            _ => None,
+        })
+        .collect()
+}
 
-    let piece_length = match info.get(&b"piece length"[..])  {
-        Some(Bencode::Int(i)) => *i as u32,
-        _ => return Err("couldn't find pieces length".to_string())
-    };
+// characters outside rfc 3986's unreserved set as %XX
+pub fn percent_encode(data: &[u8]) -> String {
+    data.iter()
+        .map(|&b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl Torrent {
+    // the name the torrent gives its data. output_file ends in it, once
+    // a session has put it under the download directory.
+    pub fn name(&self) -> &str {
+        Path::new(&self.output_file)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(&self.output_file)
+    }
+
+    // the length and place of every piece and block
+    pub fn piece_map(&self) -> PieceMap {
+        let num_pieces = u32::try_from(self.pieces.len()).unwrap_or(u32::MAX);
+        PieceMap::new(self.piece_length as u64, self.total_size, num_pieces)
+    }
+
+    // every tracker, in tier order, without repeats
+    pub fn trackers(&self) -> Vec<&str> {
+        let mut trackers: Vec<&str> = Vec::new();
+        let all = std::iter::once(self.announce.as_str()).chain(self.announce_list.iter().flatten().map(String::as_str));
+        for url in all {
+            if !url.is_empty() && !trackers.contains(&url) {
+                trackers.push(url);
+            }
+        }
+        trackers
+    }
+
+    // a magnet link for sharing the torrent without the .torrent file.
+    // there is only a btih since we don't read v2 torrents, which would
+    // also get a btmh.
+    pub fn to_magnet(&self) -> String {
+        let mut magnet = format!("magnet:?xt=urn:btih:{}&dn={}", self.info_hash, percent_encode(self.name().as_bytes()));
+        for tracker in self.trackers() {
+            magnet.push_str("&tr=");
+            magnet.push_str(&percent_encode(tracker.as_bytes()));
+        }
+        magnet
+    }
+}
+
+// takes bencoded torrent data and returns a torrent object
+pub fn build_torrent(bencode: &Bencode) -> Result<Torrent, BtError> {
+    let invalid = |e: FieldError| BtError::Torrent(format!("not a valid torrent: {}", e));
+    let invalid_info = |e: FieldError| BtError::Torrent(format!("not a valid torrent, in its info dict: {}", e));
 
-    let pieces = match info.get(&b"pieces"[..]) {
-        Some(Bencode::Bytes(b)) => b.to_vec(),
-        _ => return Err("couldn't get pieces from info dict".to_string()),
+    let announce_list = announce_list(bencode);
+    let nodes = dht_nodes(bencode);
+    // a trackerless torrent has dht nodes instead. one with only an
+    // announce-list starts from its first tracker.
+    let first_listed = announce_list.iter().flatten().next().cloned();
+    let announce = match (bencode.get_str("announce"), first_listed) {
+        (Ok(url), _) => url.to_string(),
+        (Err(_), Some(url)) => url,
+        (Err(_), None) if !nodes.is_empty() => String::new(),
+        (Err(e), None) => return Err(invalid(e)),
     };
+    let info = bencode.get_dict("info").map_err(invalid)?;
+
+    let name = info.get_str("name").map_err(invalid_info)?.to_string();
+    if !is_plain_name(&name) {
+        return Err(BtError::Torrent(format!("not a valid torrent, {:?} can't be used as its name", name)));
+    }
+    let piece_length = info.get_int("piece length").map_err(invalid_info)?;
+    let piece_length = u32::try_from(piece_length)
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| BtError::Torrent(format!("not a valid torrent, its piece length is {}", piece_length)))?;
+    let pieces = split_piece_hashes(info.get_bytes("pieces").map_err(invalid_info)?)?;
+    let private = info.get("private").and_then(Bencode::as_int) == Some(1);
 
-    let file = File {
-        name: name.clone(),
-        length,
+    // a directory of files has a list of them, a single file a length
+    let (files, multi_file) = match info.get("files") {
+        Some(files) => (parse_files(files)?, true),
+        None => {
+            // checked rather than cast, a negative length or a piece
+            // length past 4 GiB would otherwise wrap around into a
+            // number that looks fine
+            let length = info.get_int("length").map_err(invalid_info)?;
+            let length = u64::try_from(length).map_err(|_| BtError::Torrent(format!("not a valid torrent, its length is {}", length)))?;
+            (vec![File::new(name.clone(), length).with_md5sum(optional_string(info, "md5sum"))], false)
+        }
     };
+    let total_size = files
+        .iter()
+        .try_fold(0u64, |total, file| total.checked_add(file.length))
+        .ok_or_else(|| BtError::Torrent("not a valid torrent, its files add up to more than 2^64 bytes".to_string()))?;
 
     Ok(Torrent {
-        info_hash: get_sha1_info_hash(info_bencode)?,
-        announce, 
-        multi_file: false,
+        info_hash: get_sha1_info_hash(info)?,
+        announce,
+        announce_list,
+        multi_file,
         piece_length,
-        total_size: length,
+        total_size,
         pieces,
         output_file: name,
-        files: vec![file]
+        files,
+        private,
+        nodes,
+        creation_date: bencode.get("creation date").and_then(Bencode::as_int),
+        comment: optional_string(bencode, "comment"),
+        created_by: optional_string(bencode, "created by"),
+        encoding: optional_string(bencode, "encoding"),
+        info_bytes: Some(encoder::encode(info)),
     })
 }
+
+// the files of a directory torrent, in the order their data comes. each
+// has a path of one or more names under the torrent's directory, none
+// of which may lead out of it.
+fn parse_files(files: &Bencode) -> Result<Vec<File>, BtError> {
+    let invalid = |message: String| BtError::Torrent(format!("not a valid torrent, in its file list: {}", message));
+    let files = files.as_list().ok_or_else(|| invalid("files isn't a list".to_string()))?;
+    if files.is_empty() {
+        return Err(invalid("there are no files".to_string()));
+    }
+
+    files.iter().enumerate().map(|(i, file)| {
+        let length = file.get_int("length").map_err(|e| invalid(format!("file {}: {}", i, e)))?;
+        let length = u64::try_from(length).map_err(|_| invalid(format!("file {} has a length of {}", i, length)))?;
+
+        let path = file.get_list("path").map_err(|e| invalid(format!("file {}: {}", i, e)))?;
+        let components: Option<Vec<&str>> = path.iter().map(|c| c.as_str().filter(|c| is_plain_name(c))).collect();
+        let components = components.filter(|c| !c.is_empty()).ok_or_else(|| invalid(format!("file {} has a path that can't be used", i)))?;
+
+        // bep 47 padding files are marked with a p
+        let padding = file.get("attr").and_then(Bencode::as_str).is_some_and(|attr| attr.contains('p'));
+        Ok(File { name: components.join("/"), length, padding, md5sum: optional_string(file, "md5sum") })
+    }).collect()
+}
+
+// one name in a path, that can be written under the download directory
+// and stays there
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0'])
+}
+
+// the info dict has every piece's hash one after the other
+fn split_piece_hashes(pieces: &[u8]) -> Result<Vec<[u8; 20]>, BtError> {
+    if !pieces.len().is_multiple_of(20) {
+        return Err(BtError::Torrent(format!("pieces is {} bytes, which isn't a whole number of hashes", pieces.len())));
+    }
+    // pieces are numbered with a u32 on the wire
+    if pieces.len() / 20 > u32::MAX as usize {
+        return Err(BtError::Torrent(format!("{} pieces is more than the protocol can number", pieces.len() / 20)));
+    }
+    Ok(pieces.chunks_exact(20).map(|hash| hash.try_into().unwrap()).collect())
+}
+
+// see Torrent::to_bytes
+pub fn encode_metainfo(torrent: &Torrent) -> Result<Vec<u8>, BtError> {
+    torrent.to_bytes()
+}
+
+// the info dict from the fields build_torrent fills in, for a torrent
+// without info_bytes
+fn rebuild_info(torrent: &Torrent) -> Result<Bencode, BtError> {
+    let [file] = &torrent.files[..] else {
+        return Err(BtError::Torrent("only single file torrents can be rebuilt".to_string()));
+    };
+
+    let mut info = BTreeMap::new();
+    info.insert(b"name".to_vec(), Bencode::Bytes(file.name.as_bytes().to_vec()));
+    info.insert(b"length".to_vec(), Bencode::Int(file.length() as i64));
+    info.insert(b"piece length".to_vec(), Bencode::Int(torrent.piece_length as i64));
+    info.insert(b"pieces".to_vec(), Bencode::Bytes(torrent.pieces.as_flattened().to_vec()));
+    if torrent.private {
+        info.insert(b"private".to_vec(), Bencode::Int(1));
+    }
+    if let Some(md5sum) = file.md5sum() {
+        info.insert(b"md5sum".to_vec(), Bencode::Bytes(md5sum.as_bytes().to_vec()));
+    }
+    Ok(Bencode::Dict(info))
+}
+
+impl Torrent {
+    // the torrent as a metainfo dict, to be saved as a .torrent. keys
+    // outside the info dict that build_torrent doesn't read (url-list
+    // and the like) aren't in it. neither are the info dict's, if there
+    // are no info_bytes to take it from.
+    pub fn to_bencode(&self) -> Result<Bencode, BtError> {
+        let info = match &self.info_bytes {
+            Some(bytes) => decoder::decode(bytes)?.0,
+            None => rebuild_info(self)?,
+        };
+
+        let mut dict = BTreeMap::new();
+        if !self.announce.is_empty() || self.nodes.is_empty() {
+            dict.insert(b"announce".to_vec(), Bencode::Bytes(self.announce.as_bytes().to_vec()));
+        }
+        dict.insert(b"info".to_vec(), info);
+        if !self.nodes.is_empty() {
+            let nodes = self.nodes.iter().map(|(host, port)| {
+                Bencode::List(vec![Bencode::Bytes(host.as_bytes().to_vec()), Bencode::Int(*port as i64)])
+            });
+            dict.insert(b"nodes".to_vec(), Bencode::List(nodes.collect()));
+        }
+        if !self.announce_list.is_empty() {
+            let tiers = self.announce_list.iter().map(|tier| {
+                Bencode::List(tier.iter().map(|url| Bencode::Bytes(url.as_bytes().to_vec())).collect())
+            });
+            dict.insert(b"announce-list".to_vec(), Bencode::List(tiers.collect()));
+        }
+        if let Some(date) = self.creation_date {
+            dict.insert(b"creation date".to_vec(), Bencode::Int(date));
+        }
+        let strings = [("comment", &self.comment), ("created by", &self.created_by), ("encoding", &self.encoding)];
+        for (key, value) in strings {
+            if let Some(value) = value {
+                dict.insert(key.as_bytes().to_vec(), Bencode::Bytes(value.as_bytes().to_vec()));
+            }
+        }
+
+        Ok(Bencode::Dict(dict))
+    }
+
+    // to_bencode encoded, with info_bytes put in as they are rather than
+    // encoded again, so the info hash can't change on the way
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BtError> {
+        let Bencode::Dict(dict) = self.to_bencode()? else { unreachable!("metainfo is always a dict") };
+        let Some(info) = &self.info_bytes else { return Ok(encoder::encode(&Bencode::Dict(dict))) };
+
+        let mut data = vec![b'd'];
+        for (key, value) in &dict {
+            data.extend(encoder::encode(&Bencode::Bytes(key.clone())));
+            if key == b"info" {
+                data.extend_from_slice(info);
+            } else {
+                data.extend(encoder::encode(value));
+            }
+        }
+        data.push(b'e');
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode(b"Some-File_1.0~.iso"), "Some-File_1.0~.iso");
+        assert_eq!(percent_encode(b"a b&c"), "a%20b%26c");
+        assert_eq!(percent_encode(&[0x00, 0xFF]), "%00%FF");
+    }
+
+    #[test]
+    fn test_magnet() {
+        let torrent = Torrent {
+            info_hash: InfoHash::from_hex("c12fe1c06bba254a9dc9f519b335aa7c1367a88a").unwrap(),
+            announce: "http://a.example/announce".to_string(),
+            announce_list: vec![
+                vec!["http://a.example/announce".to_string(), "udp://b.example:80".to_string()],
+                vec!["http://c.example/announce?key=1".to_string()],
+            ],
+            output_file: "/downloads/My File.iso".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(torrent.name(), "My File.iso");
+        assert_eq!(
+            torrent.to_magnet(),
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=My%20File.iso\
+                &tr=http%3A%2F%2Fa.example%2Fannounce\
+                &tr=udp%3A%2F%2Fb.example%3A80\
+                &tr=http%3A%2F%2Fc.example%2Fannounce%3Fkey%3D1"
+        );
+    }
+
+    #[test]
+    fn test_announce_list() {
+        let (bencode, _) = crate::bencoding::decoder::decode(
+            b"d8:announce1:a13:announce-listll1:a1:belei5el1:cee4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces0:ee",
+        ).unwrap();
+        let torrent = build_torrent(&bencode).unwrap();
+        assert_eq!(torrent.announce_list, vec![vec!["a".to_string(), "b".to_string()], vec!["c".to_string()]]);
+        assert_eq!(torrent.trackers(), vec!["a", "b", "c"]);
+
+        // without an announce the first listed tracker is announced to
+        let (bencode, _) = crate::bencoding::decoder::decode(
+            b"d13:announce-listll1:a1:belei5el1:cee4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces0:ee",
+        ).unwrap();
+        let torrent = build_torrent(&bencode).unwrap();
+        assert_eq!(torrent.announce, "a");
+        assert_eq!(torrent.trackers(), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_file_list() {
+        let torrent = |files: &str| {
+            let data = format!("d8:announce1:a4:infod5:files{}4:name3:dir12:piece lengthi16e6:pieces0:ee", files);
+            parse_torrent(data.as_bytes())
+        };
+
+        let parsed = torrent("ld6:lengthi3e4:pathl1:a1:bee\
+            d4:attr1:p6:lengthi13e4:pathl4:.pad2:13eed6:lengthi5e4:pathl1:ceee").unwrap();
+        assert!(parsed.multi_file);
+        assert_eq!(parsed.total_size, 21);
+        let names: Vec<(&str, u64, bool)> = parsed.files.iter().map(|f| (f.name.as_str(), f.length(), f.is_padding())).collect();
+        assert_eq!(names, vec![("a/b", 3, false), (".pad/13", 13, true), ("c", 5, false)]);
+
+        // nothing that could end up outside the torrent's directory
+        for bad in ["l2:..e", "l1:a2:..e", "l3:a/be", "le", "l0:e"] {
+            assert!(torrent(&format!("ld6:lengthi1e4:path{}ee", bad)).is_err(), "{}", bad);
+        }
+        assert!(torrent("le").is_err());
+        assert!(torrent("ld6:lengthi-1e4:pathl1:aeee").is_err());
+        assert!(parse_torrent(b"d8:announce1:a4:infod6:lengthi1e4:name2:..12:piece lengthi16e6:pieces0:ee").is_err());
+    }
+
+    #[test]
+    fn test_trackerless() {
+        let data = b"d4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae\
+            5:nodesll11:dht.examplei6881eel8:10.0.0.1i0eel1:xel8:10.0.0.2i6882eeee";
+        let torrent = parse_torrent(data).unwrap();
+        assert_eq!(torrent.announce, "");
+        assert!(torrent.trackers().is_empty());
+        assert_eq!(torrent.nodes, [("dht.example".to_string(), 6881), ("10.0.0.2".to_string(), 6882)]);
+
+        let bytes = torrent.to_bytes().unwrap();
+        assert!(!bytes.windows(8).any(|w| w == b"announce"));
+        let again = parse_torrent(&bytes).unwrap();
+        assert_eq!((again.announce, again.nodes), (torrent.announce, torrent.nodes));
+
+        // with neither trackers nor nodes there is nowhere to find peers
+        assert!(parse_torrent(b"d4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaaee").is_err());
+    }
+
+    #[test]
+    fn test_piece_hashes() {
+        let metainfo = |pieces: &[u8]| {
+            let mut data = b"d8:announce1:a4:infod6:lengthi2e4:name1:x12:piece lengthi1e6:pieces".to_vec();
+            data.extend(format!("{}:", pieces.len()).as_bytes());
+            data.extend(pieces);
+            data.extend(b"ee");
+            build_torrent(&crate::bencoding::decoder::decode(&data).unwrap().0)
+        };
+
+        let mut pieces = vec![1u8; 20];
+        pieces.extend([2u8; 20]);
+        let torrent = metainfo(&pieces).unwrap();
+        assert_eq!(torrent.pieces, vec![[1; 20], [2; 20]]);
+
+        assert!(metainfo(&pieces[..30]).is_err());
+
+        let with_lengths = |length: &str, piece_length: &str| {
+            let data = format!("d8:announce1:a4:infod6:lengthi{}e4:name1:x12:piece lengthi{}e6:pieces0:ee", length, piece_length);
+            build_torrent(&crate::bencoding::decoder::decode(data.as_bytes()).unwrap().0)
+        };
+        assert!(with_lengths("1", "16384").is_ok());
+        assert!(with_lengths("-1", "16384").is_err());
+        assert!(with_lengths("1", "0").is_err());
+        // 4 GiB + 16 KiB, which used to come out as 16 KiB
+        assert!(with_lengths("1", "4294983680").is_err());
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        // source is a key we don't read, it stays in the info dict all
+        // the same
+        let data = b"d8:announce1:a7:comment2:hi4:infod6:lengthi1e4:name1:x12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:abcee";
+        let torrent = build_torrent(&decoder::decode(data).unwrap().0).unwrap();
+        assert_eq!(torrent.to_bytes().unwrap(), data);
+
+        let again = build_torrent(&torrent.to_bencode().unwrap()).unwrap();
+        assert_eq!(again.info_hash, torrent.info_hash);
+
+        // without them the info dict is made from what we kept
+        let rebuilt = Torrent { info_bytes: None, ..torrent.clone() }.to_bytes().unwrap();
+        let rebuilt = build_torrent(&decoder::decode(&rebuilt).unwrap().0).unwrap();
+        assert_ne!(rebuilt.info_hash, torrent.info_hash);
+        assert_eq!((rebuilt.pieces, rebuilt.comment), (torrent.pieces, torrent.comment));
+    }
+
+    #[test]
+    fn test_parse_keeps_the_raw_info_dict() {
+        // name comes before length, which isn't how bencode sorts them
+        let info = b"d4:name1:x6:lengthi1e12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let data = [&b"d8:announce1:a4:info"[..], info, b"e"].concat();
+
+        let torrent = parse_torrent(&data).unwrap();
+        assert_eq!(torrent.info_hash, InfoHash::new(Sha1::digest(info).into()));
+        assert_ne!(torrent.info_hash, build_torrent(&decoder::decode(&data).unwrap().0).unwrap().info_hash);
+        assert_eq!(torrent.to_bytes().unwrap(), data);
+        assert_eq!(parse_torrent(&torrent.to_bytes().unwrap()).unwrap().info_hash, torrent.info_hash);
+    }
+}
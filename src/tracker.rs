@@ -1,27 +1,331 @@
-use std::{collections::BTreeMap, error, sync::Arc, time};
-use crate::{bencoding::{self, Bencode}, torrent::Torrent};
+use std::{net::{IpAddr, Ipv6Addr}, sync::{Arc, Mutex}, time::{self, Duration, SystemTime, UNIX_EPOCH}};
+use crate::{bencoding::{self, Bencode}, error::BtError, info_hash::InfoHash, torrent::{percent_encode, Torrent}};
+use log::warn;
 use reqwest::{Client, Response};
-use rand::{self, Rng};
-use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use rand::{self, seq::SliceRandom, Rng};
+use serde::Serialize;
+
+// no sane announce response comes anywhere near this. anything bigger
+// is cut off before it gets decoded.
+const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+// a failed announce is retried after RETRY_BASE, doubling with every
+// failure in a row up to MAX_RETRY
+const RETRY_BASE: Duration = Duration::from_secs(15);
+const MAX_RETRY: Duration = Duration::from_secs(30 * 60);
+
+// a tracker asking for announces more often than this (or sending 0, or
+// a negative interval) gets them this often anyway
+const MIN_INTERVAL: u32 = 60;
+
+// peers asked for while we still have connections to fill, and
+// otherwise. most trackers cap what they hand out well below the first.
+pub const NUMWANT_NEEDED: u32 = 200;
+pub const DEFAULT_NUMWANT: u32 = 50;
+
+// the goodbye announce on shutdown gets less time than a regular one,
+// nobody wants to wait on a dead tracker to quit
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct Tracker {
     torrent: Arc<Torrent>,
     peer_id: String,
+    port: u16,
+    // random and fixed for as long as we run, so the tracker can tell it
+    // is still us if our ip changes
+    key: String,
     http_client: Client,
+    trackers: Mutex<TrackerList>,
+    // sent as ip= and ipv6=, see AnnounceParams
+    ip: Option<IpAddr>,
+    ipv6: Option<Ipv6Addr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrackerStatus {
+    // nothing has been sent yet
+    Waiting,
+    Working,
+    Error,
+    // further down the torrent's announce-list and not tried yet, one
+    // before it has always answered
+    Unused,
+}
+
+// every tracker of the torrent in tiers (bep 12). each announce goes
+// down the tiers from the top until a tracker answers, and the one that
+// does moves to the front of its tier so it is asked first next time.
+#[derive(Debug)]
+struct TrackerList {
+    tiers: Vec<Vec<TrackerState>>,
+    // the tracker announced to last, whose state is the torrent's
+    current: usize,
+    // the tracker that answered last, which is the one that gets told
+    // when we stop
+    answered: Option<String>,
+}
+
+impl TrackerList {
+    // an announce-list replaces announce, and each tier is shuffled once
+    fn new(torrent: &Torrent) -> TrackerList {
+        let listed = match torrent.announce_list.is_empty() {
+            true => vec![vec![torrent.announce.clone()]],
+            false => torrent.announce_list.clone(),
+        };
+
+        let mut seen: Vec<String> = Vec::new();
+        let mut tiers = Vec::new();
+        for tier in listed {
+            let mut tier: Vec<String> = tier.into_iter().filter(|url| !url.is_empty() && !seen.contains(url)).collect();
+            if tier.is_empty() {
+                continue;
+            }
+            seen.extend(tier.iter().cloned());
+            tier.shuffle(&mut rand::rng());
+            tiers.push(tier);
+        }
+
+        let tiers = tiers.into_iter().enumerate().map(|(i, tier)| {
+            tier.iter().enumerate().map(|(j, url)| match (i, j) {
+                (0, 0) => TrackerState::new(url),
+                _ => TrackerState::unused(url),
+            }).collect()
+        }).collect();
+        TrackerList { tiers, current: 0, answered: None }
+    }
+
+    // (tier, url) of every tracker in the order they are tried
+    fn order(&self) -> Vec<(usize, String)> {
+        self.tiers.iter().enumerate().flat_map(|(i, tier)| tier.iter().map(move |t| (i, t.url.clone()))).collect()
+    }
+
+    fn all(&self) -> impl Iterator<Item = &TrackerState> {
+        self.tiers.iter().flatten()
+    }
+
+    fn get_mut(&mut self, url: &str) -> Option<&mut TrackerState> {
+        self.tiers.iter_mut().flatten().find(|t| t.url == url)
+    }
+
+    fn set_current(&mut self, url: &str) {
+        let current = self.all().position(|t| t.url == url);
+        self.current = current.unwrap_or(0);
+    }
+
+    fn move_to_front(&mut self, tier: usize, url: &str) {
+        let tier = &mut self.tiers[tier];
+        if let Some(i) = tier.iter().position(|t| t.url == url) {
+            let tracker = tier.remove(i);
+            tier.insert(0, tracker);
+        }
+        self.set_current(url);
+    }
+}
+
+// how announcing is going, handed out over the rpc api
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TrackerState {
+    pub url: String,
+    pub status: TrackerStatus,
+    // what went wrong last time, while the tracker is in error
+    pub error: Option<String>,
+    // the last warning message the tracker sent, if the announce after
+    // it didn't clear it
+    pub warning: Option<String>,
+    // the shortest time the tracker lets us wait between announces
+    pub min_interval: Option<u64>,
+    pub tracker_id: Option<String>,
+    // failures since the last announce that worked
+    pub consecutive_failures: u32,
+    pub total_failures: u64,
+    // unix time of the next announce, once one is scheduled
+    pub next_announce: Option<u64>,
+    // announces the tracker answered
+    pub successes: u64,
+    // unix time of the last answer, and what was in it
+    pub last_response: Option<u64>,
+    pub last_peers: usize,
+    pub seeders: u64,
+    pub leechers: u64,
+}
+
+impl TrackerState {
+    pub fn new(url: &str) -> TrackerState {
+        TrackerState {
+            url: url.to_string(),
+            status: TrackerStatus::Waiting,
+            error: None,
+            warning: None,
+            min_interval: None,
+            tracker_id: None,
+            consecutive_failures: 0,
+            total_failures: 0,
+            next_announce: None,
+            successes: 0,
+            last_response: None,
+            last_peers: 0,
+            seeders: 0,
+            leechers: 0,
+        }
+    }
+
+    pub fn unused(url: &str) -> TrackerState {
+        TrackerState { status: TrackerStatus::Unused, ..TrackerState::new(url) }
+    }
+
+    // returns how long to wait before the next announce
+    pub fn record_success(&mut self, response: &TrackerResponse, now: SystemTime) -> Duration {
+        self.status = TrackerStatus::Working;
+        self.error = None;
+        self.warning = response.warning.clone();
+        self.min_interval = response.min_interval.map(u64::from);
+        // trackers only send it when it changes
+        if response.tracker_id.is_some() {
+            self.tracker_id = response.tracker_id.clone();
+        }
+        self.consecutive_failures = 0;
+        self.successes += 1;
+        self.last_response = unix_secs(now);
+        self.last_peers = response.peers.len();
+        self.seeders = response.complete;
+        self.leechers = response.incomplete;
+
+        let wait = self.at_least_min_interval(Duration::from_secs(response.interval as u64));
+        self.schedule(wait, now);
+        wait
+    }
+
+    // returns how long to wait before trying again
+    pub fn record_failure(&mut self, error: String, now: SystemTime) -> Duration {
+        self.status = TrackerStatus::Error;
+        self.error = Some(error);
+        self.consecutive_failures += 1;
+        self.total_failures += 1;
+
+        let delay = self.at_least_min_interval(retry_delay(self.consecutive_failures));
+        self.schedule(delay, now);
+        delay
+    }
+
+    // even retries wait out the min interval, trackers that set one
+    // are likely to refuse anything sooner
+    fn at_least_min_interval(&self, wait: Duration) -> Duration {
+        wait.max(Duration::from_secs(self.min_interval.unwrap_or(0)))
+    }
+
+    fn schedule(&mut self, wait: Duration, now: SystemTime) {
+        self.next_announce = unix_secs(now + wait);
+    }
+}
+
+fn unix_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs())
+}
+
+// how many peers to ask for: plenty while there are connections to fill,
+// none once we are seeding with every connection in use
+pub fn numwant(complete: bool, connected: usize, max_connections: usize) -> u32 {
+    match (complete, connected < max_connections) {
+        (true, false) => 0,
+        (false, true) => NUMWANT_NEEDED,
+        _ => DEFAULT_NUMWANT,
+    }
+}
+
+// capped exponential backoff after the given number of failures in a row
+pub fn retry_delay(failures: u32) -> Duration {
+    let doublings = failures.saturating_sub(1).min(16);
+    (RETRY_BASE * 2u32.pow(doublings)).min(MAX_RETRY)
+}
+
+// what an announce tells the tracker about, besides our totals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+// the byte counts an announce reports. downloaded is only payload that
+// passed its hash check and left goes by the pieces we have, so neither
+// is thrown off by wasted data or a short last piece.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Totals {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+// everything that goes in the query string of an announce
+pub struct AnnounceParams<'a> {
+    pub info_hash: &'a InfoHash,
+    pub peer_id: &'a str,
+    // the port we tell the tracker peers can reach us on
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    // None for the regular announces in between
+    pub event: Option<AnnounceEvent>,
+    // None leaves it to the tracker, usually 50
+    pub numwant: Option<u32>,
+    pub key: Option<&'a str>,
+    // whatever the tracker last gave us as its "tracker id"
+    pub tracker_id: Option<&'a str>,
+    // the address to hand out instead of the one the announce came
+    // from, and (bep 7) an ipv6 address to hand out as well, so peers
+    // reach us over ipv6 while we announce over ipv4
+    pub ip: Option<IpAddr>,
+    pub ipv6: Option<Ipv6Addr>,
 }
 
 pub struct TrackerResponse {
-    pub failure: String,
+    // something the tracker wants a human to see, the announce still worked
+    pub warning: Option<String>,
     pub interval: u32,
+    // we must not announce more often than this, whatever interval says
+    pub min_interval: Option<u32>,
+    // to be sent back on the announces that follow
+    pub tracker_id: Option<String>,
     pub complete: u64,
     pub incomplete: u64,
     pub peers: Vec<(String, u16)>,
+    // our address as the tracker saw it (bep 24)
+    pub external_ip: Option<IpAddr>,
 }
 
 impl TrackerResponse {
-    fn parse_peers(data: &[u8]) -> Result<Vec<(String, u16)>, Box<dyn error::Error>> {
-        if data.len() % 6 != 0 {
-            return Err("peers field length is not a multiple of 6".into());
+    // parses the compact ipv6 peer list of bep 7 (each peer is 18 bytes:
+    // 16 IP + 2 port)
+    pub fn parse_peers6(data: &[u8]) -> Result<Vec<(String, u16)>, BtError> {
+        if !data.len().is_multiple_of(18) {
+            return Err(BtError::Tracker("peers6 field length is not a multiple of 18".to_string()));
+        }
+
+        Ok(data
+            .chunks(18)
+            .map(|chunk| {
+                let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&chunk[..16]).unwrap());
+                (ip.to_string(), u16::from_be_bytes([chunk[16], chunk[17]]))
+            })
+            .collect())
+    }
+
+    // parses the compact peer list (each peer is 6 bytes: 4 IP + 2 port)
+    pub fn parse_peers(data: &[u8]) -> Result<Vec<(String, u16)>, BtError> {
+        if !data.len().is_multiple_of(6) {
+            return Err(BtError::Tracker("peers field length is not a multiple of 6".to_string()));
         }
 
         let mut result = Vec::new();
@@ -38,65 +342,77 @@ impl TrackerResponse {
     }
     
 
-    // parses the response from tracker and returns a TrackerResponse
-    pub async fn new(response: Response) -> Result<TrackerResponse, Box<dyn error::Error>> {
-        // converts the response to bytes to be decoded
-        let bytes = response.bytes().await?;
-        println!("{:#?}", bytes);
-        
+    // parses the response from tracker and returns a TrackerResponse.
+    // the content type isn't looked at, plenty of trackers send bencode
+    // as text/plain or text/html.
+    pub async fn new(response: Response) -> Result<TrackerResponse, BtError> {
+        let bytes = read_body(response).await?;
+        Self::parse(&bytes)
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<TrackerResponse, BtError> {
+        // some trackers put a newline or two in front of the dictionary
+        let start = bytes.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(bytes.len());
+
         // decodes the bytes into bencode format
-        let bencode = bencoding::decoder::decode(&bytes)?;
+        let (dict, _) = bencoding::decoder::decode(&bytes[start..])?;
 
-        // gets the top level dictionary from the bencoded response
-        let dict = match bencode {
-            (Bencode::Dict(d), _) => d,
-            _ => return Err("tracker response does not contain a top-level dictionary".into()),
-        };
+        // the top level has to be a dictionary
+        if dict.as_dict().is_none() {
+            return Err(BtError::Tracker("tracker response does not contain a top-level dictionary".to_string()));
+        }
+        let text = |key: &str| dict.get(key).and_then(Bencode::as_bytes).map(|b| String::from_utf8_lossy(b).to_string());
+
+        // a tracker that turns us down sends only the reason, the
+        // announce failed whatever else is in there
+        let failure = dict.get_str("failure reason").unwrap_or_default();
+        if !failure.is_empty() {
+            return Err(BtError::Tracker(format!("tracker said: {}", failure)));
+        }
 
-        // gets the response failure reason (if applicable, defaults to an empty string if not).
-        let failure = dict
-        .get(&b"failure reason"[..])
-        .and_then(|b| match b {
-            Bencode::Bytes(bytes) => Some(String::from_utf8(bytes.clone()).ok()?),
-            _ => None,
-        })
-        .unwrap_or_default();
-        
         // gets the tracker request interval in seconds
-        let interval = match dict.get(&b"interval"[..]) {
-            Some(Bencode::Int(i)) => i.clone() as u32,
-            _ => return Err("couldn't get interval:(".into()),
-        };
+        let interval = dict.get_int("interval").map_err(|e| BtError::Tracker(format!("couldn't get interval: {}", e)))?;
+        let interval = interval.clamp(MIN_INTERVAL as i64, u32::MAX as i64) as u32;
 
+        let min_interval = dict.get("min interval").and_then(Bencode::as_int).filter(|&i| i >= 0).map(|i| i as u32);
+        let warning = text("warning message");
+        let tracker_id = text("tracker id");
 
         // gets the number of peers within the entire file (i.e. seeders)
-        let complete = match dict.get(&b"complete"[..]) {
-            Some(Bencode::Int(i)) => i.clone() as u64,
-            _ => 0,
-        };
+        let complete = dict.get_int("complete").unwrap_or(0) as u64;
 
         // gets the number of non-seeding peers within the entire file (i.e. leechers)
-        let incomplete = match dict.get(&b"incomplete"[..]) {
-            Some(Bencode::Int(i)) => i.clone() as u64,
-            _ => 0,
-        };
+        let incomplete = dict.get_int("incomplete").unwrap_or(0) as u64;
 
-        // gets the compact peer list as a byte string (each peer is 6 bytes: 4 IP + 2 port)
-        let raw_peers = match dict.get(&b"peers"[..]) {
-            Some(Bencode::Bytes(b)) => b.clone(),
-            _ => return Err("couldn't get peers dict from tracker response".into()),
+        // the compact peer lists, ipv4 in peers and ipv6 in peers6. a
+        // tracker that only has ipv6 peers may leave out peers entirely.
+        let peers4 = dict.get("peers");
+        let peers6 = dict.get("peers6");
+        let mut peers = match peers4 {
+            Some(Bencode::Bytes(b)) => Self::parse_peers(b)?,
+            None if peers6.is_some() => Vec::new(),
+            _ => return Err(BtError::Tracker("couldn't get peers dict from tracker response".to_string())),
         };
+        match peers6 {
+            Some(Bencode::Bytes(b)) => peers.extend(Self::parse_peers6(b)?),
+            Some(_) => return Err(BtError::Tracker("peers6 in tracker response is not a string".to_string())),
+            None => {}
+        }
 
-        let peers = Self::parse_peers(&raw_peers)?;
-
+        // 4 or 16 bytes, anything else isn't an address
+        let external_ip = match dict.get("external ip").and_then(Bencode::as_bytes) {
+            Some(b) if b.len() == 4 => Some(IpAddr::from(<[u8; 4]>::try_from(b).unwrap())),
+            Some(b) if b.len() == 16 => Some(IpAddr::from(<[u8; 16]>::try_from(b).unwrap())),
+            _ => None,
+        };
 
-        Ok(TrackerResponse { failure, interval,complete, incomplete, peers: peers })
+        Ok(TrackerResponse { warning, interval, min_interval, tracker_id, complete, incomplete, peers, external_ip })
     }
 
     // print formatted tracker response data
     pub fn print(self) {
         println!(
-            "Failure reason (if applicable): {}. \n Interval: {}. Complete: {}. Incomplete: {}.", self.failure, self.interval, self.complete, self.incomplete
+            "Interval: {}. Complete: {}. Incomplete: {}.", self.interval, self.complete, self.incomplete
         );
 
         println!("peer list:");
@@ -106,52 +422,222 @@ impl TrackerResponse {
     }
 }
 
+// reads the body of a tracker response, giving up once it goes past
+// MAX_RESPONSE_SIZE rather than buffering whatever the tracker sends
+async fn read_body(mut response: Response) -> Result<Vec<u8>, BtError> {
+    if response.content_length().is_some_and(|len| len > MAX_RESPONSE_SIZE as u64) {
+        return Err(BtError::Tracker(format!("tracker response is larger than {} bytes", MAX_RESPONSE_SIZE)));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > MAX_RESPONSE_SIZE {
+            return Err(BtError::Tracker(format!("tracker response is larger than {} bytes", MAX_RESPONSE_SIZE)));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+// builds the announce url in bittorrent specific format.
+// see here for formatting details: https://wiki.theory.org/BitTorrentSpecification#Tracker_HTTP/HTTPS_Protocol
+// the info hash and peer id are raw bytes, escaped like any other
+// binary query value. private trackers often put a passkey in the
+// announce url's own query, which ours is added on to.
+pub fn announce_url(announce: &str, params: &AnnounceParams) -> String {
+    let separator = if announce.contains('?') { '&' } else { '?' };
+
+    let mut query = format!(
+        "{}info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+        separator,
+        percent_encode(params.info_hash.as_bytes()),
+        percent_encode(params.peer_id.as_bytes()),
+        params.port,
+        params.uploaded,
+        params.downloaded,
+        params.left
+    );
+
+    if let Some(numwant) = params.numwant {
+        query.push_str(&format!("&numwant={}", numwant));
+    }
+    if let Some(key) = params.key {
+        query.push_str(&format!("&key={}", percent_encode(key.as_bytes())));
+    }
+    if let Some(tracker_id) = params.tracker_id {
+        query.push_str(&format!("&trackerid={}", percent_encode(tracker_id.as_bytes())));
+    }
+    if let Some(ip) = params.ip {
+        query.push_str(&format!("&ip={}", percent_encode(ip.to_string().as_bytes())));
+    }
+    if let Some(ipv6) = params.ipv6 {
+        query.push_str(&format!("&ipv6={}", percent_encode(ipv6.to_string().as_bytes())));
+    }
+
+    if let Some(event) = params.event {
+        query.push_str(&format!("&event={}", event.as_str()));
+    }
+
+    format!("{}{}", announce, query)
+}
+
+// 8 random hex digits, like most clients send
+fn random_key() -> String {
+    format!("{:08X}", rand::rng().random::<u32>())
+}
+
 // helper function to generate random digits to create peer id
-pub fn calculate_peer_id() -> String {
-    let client_code = "-MY6969-";
+// the prefix identifies the client (see peer_id.rs) and the rest of
+// the 20 bytes are filled with random digits
+pub fn calculate_peer_id(prefix: &str) -> String {
     let mut rng = rand::rng();
-    let random_digits: u64 = rng.random_range(100_000_000_000..=999_999_999_999);
-    format!("{}{}", client_code, random_digits)
+    let random_digits: String = (prefix.len()..20)
+        .map(|_| char::from(b'0' + rng.random_range(0..10)))
+        .collect();
+    format!("{}{}", prefix, random_digits)
 }
 
 
 
 impl Tracker {
-    pub fn new(torrent: Arc<Torrent>) -> Tracker {
+    pub fn new(torrent: Arc<Torrent>, peer_id_prefix: &str, port: u16) -> Tracker {
         Tracker {
-            torrent,
-            peer_id: calculate_peer_id(),
+            peer_id: calculate_peer_id(peer_id_prefix),
+            port,
+            key: random_key(),
             http_client: Client::new(),
+            trackers: Mutex::new(TrackerList::new(&torrent)),
+            ip: None,
+            ipv6: None,
+            torrent,
         }
     }
 
-    // connects to the tracker for the given torrent
-    pub async fn connect(&self, first: bool, uploaded: u64, downloaded: u64) -> Result<(), Box<dyn error::Error>> {
-        let info_hash_param = self.torrent.info_hash.iter()
-            .map(|&byte| format!("%{:02X}", byte))
-            .collect::<String>();
-        
-        let (uploaded_str, downloaded_str) = (uploaded.to_string(), downloaded.to_string());
-        let left_str = (self.torrent.total_size - downloaded).to_string();
-        
-        // builds query in bittorrent specific format. 
-        // see here for formatting details: https://wiki.theory.org/BitTorrentSpecification#Tracker_HTTP/HTTPS_Protocol
-        let mut query = format!(
-            "?info_hash={}&peer_id={}&port=6889&uploaded={}&downloaded={}&left={}&compact=1",
-            info_hash_param,
-            self.peer_id,
-            uploaded_str,
-            downloaded_str,
-            left_str
-        );
-        
-        // if this is our first request add that to the query
-        if first {
-            query.push_str("&event=started");
+    // the ip and ipv6 parameters of every announce
+    pub fn with_addresses(mut self, ip: Option<IpAddr>, ipv6: Option<Ipv6Addr>) -> Tracker {
+        self.ip = ip;
+        self.ipv6 = ipv6;
+        self
+    }
+
+    pub fn peer_id(&self) -> &str {
+        &self.peer_id
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // the tracker announced to last, or the first one to try
+    pub fn state(&self) -> TrackerState {
+        let trackers = self.trackers.lock().unwrap();
+        let current = trackers.all().nth(trackers.current).cloned();
+        current.unwrap_or_else(|| TrackerState::new(""))
+    }
+
+    // every tracker, in the order they are tried
+    pub fn states(&self) -> Vec<TrackerState> {
+        self.trackers.lock().unwrap().all().cloned().collect()
+    }
+
+    // announces to each tracker in turn until one answers, keeping track
+    // of how each went. returns the response, if there was one, along
+    // with how long to wait before the next announce: the tracker's
+    // interval, or a growing delay while they are all failing.
+    pub async fn announce(&self, event: Option<AnnounceEvent>, totals: Totals, numwant: u32) -> (Result<TrackerResponse, BtError>, Duration) {
+        let order = self.trackers.lock().unwrap().order();
+        let mut failed: Option<(BtError, Duration)> = None;
+
+        for (tier, url) in &order {
+            // a tracker we fail over to hasn't heard started from us yet
+            let (event, tracker_id) = {
+                let mut trackers = self.trackers.lock().unwrap();
+                let state = trackers.get_mut(url).unwrap();
+                let event = match event {
+                    None if state.successes == 0 => Some(AnnounceEvent::Started),
+                    event => event,
+                };
+                (event, state.tracker_id.clone())
+            };
+            let result = self.connect(url, event, tracker_id.as_deref(), totals, numwant).await;
+
+            let mut trackers = self.trackers.lock().unwrap();
+            let now = SystemTime::now();
+            let state = trackers.get_mut(url).unwrap();
+            match result {
+                Ok(response) => {
+                    if let Some(warning) = &response.warning {
+                        warn!("tracker {} says: {}", url, warning);
+                    }
+                    let wait = state.record_success(&response, now);
+                    trackers.move_to_front(*tier, url);
+                    trackers.answered = Some(url.clone());
+                    return (Ok(response), wait);
+                }
+                Err(e) => {
+                    let delay = state.record_failure(e.to_string(), now);
+                    if order.len() > 1 {
+                        warn!("tracker {} failed, trying the next one: {}", url, e);
+                    }
+                    let delay = failed.as_ref().map_or(delay, |(_, wait)| delay.min(*wait));
+                    failed = Some((e, delay));
+                }
+            }
         }
-        
-        // build formatted query string
-        let url = format!("{}{}", self.torrent.announce, query);
+
+        // the next round starts from the top again
+        let mut trackers = self.trackers.lock().unwrap();
+        trackers.current = 0;
+        match failed {
+            Some((e, wait)) => (Err(e), wait),
+            None => (Err(BtError::Tracker("the torrent has no trackers".to_string())), MAX_RETRY),
+        }
+    }
+
+    // tells the tracker we are leaving so it stops handing out our
+    // address. a tracker we never got through to doesn't know about us,
+    // so there is nothing to tell it. whatever it answers is ignored.
+    pub async fn stop(&self, totals: Totals) -> Result<(), BtError> {
+        let (announce, tracker_id) = {
+            let mut trackers = self.trackers.lock().unwrap();
+            let Some(announce) = trackers.answered.clone() else { return Ok(()) };
+            let tracker_id = trackers.get_mut(&announce).and_then(|t| t.tracker_id.clone());
+            (announce, tracker_id)
+        };
+
+        let url = self.url(&announce, Some(AnnounceEvent::Stopped), tracker_id.as_deref(), totals, 0);
+        let res = self.http_client.get(&url).timeout(STOP_TIMEOUT).send().await?;
+        if !res.status().is_success() {
+            return Err(BtError::Tracker(format!("error response from tracker: {}", res.status())));
+        }
+        Ok(())
+    }
+
+    fn url(&self, announce: &str, event: Option<AnnounceEvent>, tracker_id: Option<&str>, totals: Totals, numwant: u32) -> String {
+        announce_url(announce, &AnnounceParams {
+            info_hash: &self.torrent.info_hash,
+            peer_id: &self.peer_id,
+            port: self.port,
+            uploaded: totals.uploaded,
+            downloaded: totals.downloaded,
+            left: totals.left,
+            event,
+            numwant: Some(numwant),
+            key: Some(&self.key),
+            tracker_id,
+            ip: self.ip,
+            ipv6: self.ipv6,
+        })
+    }
+
+    // announces to one of the torrent's trackers and returns its response
+    pub async fn connect(&self, announce: &str, event: Option<AnnounceEvent>, tracker_id: Option<&str>, totals: Totals, numwant: u32) -> Result<TrackerResponse, BtError> {
+        let url = self.url(announce, event, tracker_id, totals, numwant);
         
         // get response from the tracker
         let res = self.http_client
@@ -162,21 +648,276 @@ impl Tracker {
         
         // if the response was successful, build a TrackerResponse from it
         if res.status().is_success() {
-            match TrackerResponse::new(res).await {
-                Ok(tracker_res) => tracker_res.print(),
-                Err(e) => println!("couldn't create tracker response: {}", e),
-            }
-        // if not, print error to console
+            TrackerResponse::new(res).await
+        // if not, pass the error on along with whatever details the tracker gave
         } else {
-            println!("error response from tracker: {} {}", res.status(), res.status().as_str());
-            
-            match res.text().await {
-                Ok(error_text) => println!("error: {}", error_text),
-                Err(_) => println!("couldn't get error details")
-            }
+            let status = res.status();
+            let details = match read_body(res).await {
+                Ok(body) => String::from_utf8_lossy(&body).to_string(),
+                Err(_) => "couldn't get error details".to_string(),
+            };
+            Err(BtError::Tracker(format!("error response from tracker: {} {}", status, details)))
         }
-        
-        Ok(())
     }
     
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_vectors;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    // answers every announce with no peers, passing on the request lines
+    async fn fake_tracker() -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let (requests, received) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut data = vec![0u8; 4096];
+                let n = stream.read(&mut data).await.unwrap();
+                let request = String::from_utf8_lossy(&data[..n]).to_string();
+                let _ = requests.send(request.lines().next().unwrap_or_default().to_string());
+
+                let body = b"d8:intervali1800e5:peers0:e";
+                let head = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n", body.len());
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(body).await.unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn tiered(tiers: Vec<Vec<String>>) -> Tracker {
+        let torrent = Torrent {
+            info_hash: InfoHash::new([3; 20]),
+            announce: tiers[0][0].clone(),
+            announce_list: tiers,
+            ..Default::default()
+        };
+        Tracker::new(Arc::new(torrent), "-BC0001-", 6881)
+    }
+
+    #[tokio::test]
+    async fn test_tier_failover() {
+        let (working, mut requests) = fake_tracker().await;
+        // nothing listens on port 1
+        let dead = "http://127.0.0.1:1/announce".to_string();
+        let tracker = tiered(vec![vec![dead.clone()], vec![working.clone()]]);
+        assert_eq!(tracker.states()[1], TrackerState::unused(&working));
+
+        let (response, wait) = tracker.announce(None, Totals::default(), 50).await;
+        assert!(response.is_ok());
+        assert_eq!(wait, Duration::from_secs(1800));
+        assert_eq!(tracker.state().url, working);
+        // the second tier hadn't heard from us, so it gets started
+        assert!(requests.recv().await.unwrap().contains("&event=started"));
+
+        let states = tracker.states();
+        assert_eq!((states[0].status, states[0].total_failures), (TrackerStatus::Error, 1));
+        assert_eq!((states[1].status, states[1].successes), (TrackerStatus::Working, 1));
+
+        // every announce starts from the first tier again
+        tracker.announce(None, Totals::default(), 50).await.0.unwrap();
+        assert!(!requests.recv().await.unwrap().contains("&event="));
+        assert_eq!(tracker.states()[0].total_failures, 2);
+
+        // and stopped goes to the tracker that has us
+        tracker.stop(Totals::default()).await.unwrap();
+        assert!(requests.recv().await.unwrap().contains("&event=stopped"));
+    }
+
+    #[tokio::test]
+    async fn test_tracker_that_answers_moves_to_the_front_of_its_tier() {
+        let (working, mut requests) = fake_tracker().await;
+        let dead = "http://127.0.0.1:1/announce".to_string();
+        let tracker = tiered(vec![vec![dead.clone(), working.clone()]]);
+
+        tracker.announce(None, Totals::default(), 50).await.0.unwrap();
+        requests.recv().await.unwrap();
+        let failures = tracker.states().iter().map(|t| t.total_failures).sum::<u64>();
+        assert_eq!(tracker.states()[0].url, working);
+
+        // so the dead one isn't tried again while it keeps answering
+        tracker.announce(None, Totals::default(), 50).await.0.unwrap();
+        assert_eq!(tracker.states().iter().map(|t| t.total_failures).sum::<u64>(), failures);
+
+        // with nobody answering the wait is the shortest of the retries
+        let tracker = tiered(vec![vec![dead.clone()], vec!["http://127.0.0.1:2/announce".to_string()]]);
+        let (response, wait) = tracker.announce(None, Totals::default(), 50).await;
+        assert!(response.is_err());
+        assert_eq!(wait, RETRY_BASE);
+        assert_eq!(tracker.state().url, dead);
+        // and nobody to tell we are going
+        tracker.stop(Totals::default()).await.unwrap();
+    }
+
+    #[test]
+    fn test_announce_url_vectors() {
+        for v in test_vectors::announce_urls() {
+            let url = announce_url(v.announce, &AnnounceParams {
+                info_hash: &InfoHash::new(v.info_hash),
+                peer_id: v.peer_id,
+                port: v.port,
+                uploaded: v.uploaded,
+                downloaded: v.downloaded,
+                left: v.left,
+                event: v.event,
+                numwant: v.numwant,
+                key: v.key,
+                tracker_id: v.tracker_id,
+                ip: v.ip.map(|ip| ip.parse().unwrap()),
+                ipv6: v.ipv6.map(|ip| ip.parse().unwrap()),
+            });
+            assert_eq!(url, v.expected, "{}", v.name);
+        }
+    }
+
+    #[test]
+    fn test_peer_id_prefix() {
+        let peer_id = calculate_peer_id("-MY6969-");
+        assert_eq!(peer_id.len(), 20);
+        assert!(peer_id.starts_with("-MY6969-"));
+        assert!(peer_id[8..].bytes().all(|b| b.is_ascii_digit()));
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let response = TrackerResponse::parse(b"\r\nd8:intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe1e").unwrap();
+        assert_eq!(response.interval, 900);
+        assert_eq!(response.peers, vec![("10.0.0.1".to_string(), 6881)]);
+
+        // ipv6 peers only
+        let response = TrackerResponse::parse(
+            b"d8:intervali900e6:peers618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe1e",
+        ).unwrap();
+        assert_eq!(response.peers, vec![("2001:db8::1".to_string(), 6881)]);
+
+        let response = TrackerResponse::parse(b"d8:intervali900e5:peers6:\x0a\x00\x00\x01\x1a\xe16:peers60:e").unwrap();
+        assert_eq!(response.peers.len(), 1);
+
+        assert!(TrackerResponse::parse(b"d8:intervali900e6:peers63:abce").is_err());
+        assert!(TrackerResponse::parse(b"d8:intervali900ee").is_err());
+
+        let response = TrackerResponse::parse(b"d11:external ip4:\xcb\x00\x71\x078:intervali900e5:peers0:e").unwrap();
+        assert_eq!(response.external_ip, Some("203.0.113.7".parse().unwrap()));
+        let response = TrackerResponse::parse(b"d11:external ip3:abc8:intervali900e5:peers0:e").unwrap();
+        assert_eq!(response.external_ip, None);
+
+        assert!(TrackerResponse::parse(b"").is_err());
+        assert!(TrackerResponse::parse(b"<html>not found</html>").is_err());
+    }
+
+    #[test]
+    fn test_numwant() {
+        assert_eq!(numwant(false, 3, 40), NUMWANT_NEEDED);
+        assert_eq!(numwant(false, 40, 40), DEFAULT_NUMWANT);
+        assert_eq!(numwant(true, 3, 40), DEFAULT_NUMWANT);
+        assert_eq!(numwant(true, 40, 40), 0);
+    }
+
+    #[test]
+    fn test_tracker_id_is_kept() {
+        let mut state = TrackerState::new("http://tracker/announce");
+        let with_id = TrackerResponse::parse(b"d8:intervali900e10:tracker id3:abc5:peers0:e").unwrap();
+        state.record_success(&with_id, UNIX_EPOCH);
+        assert_eq!(state.tracker_id.as_deref(), Some("abc"));
+
+        let without = TrackerResponse::parse(b"d8:intervali900e5:peers0:e").unwrap();
+        state.record_success(&without, UNIX_EPOCH);
+        assert_eq!(state.tracker_id.as_deref(), Some("abc"));
+
+        let key = random_key();
+        assert_eq!(key.len(), 8);
+        assert!(key.bytes().all(|b| b.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(retry_delay(1), Duration::from_secs(15));
+        assert_eq!(retry_delay(2), Duration::from_secs(30));
+        assert_eq!(retry_delay(4), Duration::from_secs(120));
+        assert_eq!(retry_delay(10), MAX_RETRY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY);
+    }
+
+    #[test]
+    fn test_state() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut state = TrackerState::new("http://tracker/announce");
+        assert_eq!(state.status, TrackerStatus::Waiting);
+
+        assert_eq!(state.record_failure("timed out".to_string(), now), Duration::from_secs(15));
+        assert_eq!(state.record_failure("timed out".to_string(), now), Duration::from_secs(30));
+        assert_eq!(state.status, TrackerStatus::Error);
+        assert_eq!(state.error.as_deref(), Some("timed out"));
+        assert_eq!(state.next_announce, Some(1030));
+
+        let response = TrackerResponse::parse(b"d8:completei3e10:incompletei7e8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe1e").unwrap();
+        assert_eq!(state.record_success(&response, now), Duration::from_secs(1800));
+        assert_eq!(state.status, TrackerStatus::Working);
+        assert_eq!(state.error, None);
+        assert_eq!(state.next_announce, Some(2800));
+        assert_eq!((state.consecutive_failures, state.total_failures, state.successes), (0, 2, 1));
+        assert_eq!(state.last_response, Some(1000));
+        assert_eq!((state.last_peers, state.seeders, state.leechers), (1, 3, 7));
+
+        // the backoff starts over after an announce that worked
+        assert_eq!(state.record_failure("refused".to_string(), now), Duration::from_secs(15));
+    }
+
+    #[test]
+    fn test_failure_reason_and_bad_intervals() {
+        let refused = TrackerResponse::parse(b"d14:failure reason17:torrent not founde");
+        assert!(matches!(refused, Err(BtError::Tracker(e)) if e.contains("torrent not found")));
+        // even with the rest of a response alongside it
+        assert!(TrackerResponse::parse(b"d14:failure reason3:no!8:intervali900e5:peers0:e").is_err());
+
+        for interval in ["i0e", "i-1e", "i5e"] {
+            let data = format!("d8:interval{}5:peers0:e", interval);
+            assert_eq!(TrackerResponse::parse(data.as_bytes()).unwrap().interval, MIN_INTERVAL);
+        }
+        let huge = TrackerResponse::parse(b"d8:intervali99999999999e5:peers0:e").unwrap();
+        assert_eq!(huge.interval, u32::MAX);
+    }
+
+    #[test]
+    fn test_min_interval_and_warning() {
+        let response = TrackerResponse::parse(
+            b"d8:intervali30e12:min intervali300e15:warning message12:update soon!5:peers0:e",
+        ).unwrap();
+        assert_eq!(response.min_interval, Some(300));
+        assert_eq!(response.warning.as_deref(), Some("update soon!"));
+
+        let now = UNIX_EPOCH;
+        let mut state = TrackerState::new("http://tracker/announce");
+        assert_eq!(state.record_success(&response, now), Duration::from_secs(300));
+        assert_eq!(state.warning.as_deref(), Some("update soon!"));
+
+        // failing doesn't get us an earlier announce either
+        assert_eq!(state.record_failure("timed out".to_string(), now), Duration::from_secs(300));
+
+        let response = TrackerResponse::parse(b"d8:intervali900e5:peers0:e").unwrap();
+        assert_eq!(response.min_interval, None);
+        state.record_success(&response, now);
+        assert_eq!(state.warning, None);
+        assert_eq!(state.record_failure("timed out".to_string(), now), Duration::from_secs(15));
+    }
+
+    #[test]
+    fn test_compact_peer_vectors() {
+        for v in test_vectors::compact_peers() {
+            let parsed = TrackerResponse::parse_peers(&v.encoded).ok();
+            let expected = v.peers.map(|peers| {
+                peers.iter().map(|&(ip, port)| (ip.to_string(), port)).collect::<Vec<_>>()
+            });
+            assert_eq!(parsed, expected, "{}", v.name);
+        }
+    }
+}
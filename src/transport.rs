@@ -0,0 +1,435 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use log::warn;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::{duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{lookup_host, TcpSocket, TcpStream},
+    sync::mpsc,
+};
+
+// anything the peer protocol can be spoken over
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerStream for T {}
+
+pub type BoxedStream = Box<dyn PeerStream>;
+
+pub type ConnectFuture<'a> = Pin<Box<dyn Future<Output = io::Result<BoxedStream>> + Send + 'a>>;
+
+// dials peers. PeerConnection only ever sees the stream handed back,
+// so the protocol logic doesn't care whether that's tcp, utp, a proxied
+// connection or an in-memory pipe.
+pub trait PeerTransport: Send + Sync {
+    fn connect<'a>(&'a self, ip: &'a str, port: u16) -> ConnectFuture<'a>;
+}
+
+// where our end of a connection is, for a machine whose peer traffic
+// has to stay on one network (a vpn's tun device, say) whatever the
+// default route is
+#[derive(Debug, Clone, PartialEq)]
+pub enum BindTo {
+    // sockets only use this device, with whatever address it has. linux
+    // only.
+    Interface(String),
+    // sockets go out from this address, so only carry traffic of its
+    // family
+    Address(IpAddr),
+}
+
+impl BindTo {
+    // an address if it reads as one, otherwise the name of an interface
+    pub fn parse(s: &str) -> Result<BindTo, String> {
+        if let Ok(ip) = s.parse() {
+            return Ok(BindTo::Address(ip));
+        }
+        // linux keeps names to 15 bytes
+        if s.is_empty() || s.len() > 15 || !s.bytes().all(|b| b.is_ascii_graphic() && b != b'/') {
+            return Err(format!("not an address or an interface name: {:?}", s));
+        }
+        Ok(BindTo::Interface(s.to_string()))
+    }
+
+    // binds the socket, which is for talking to or listening on addr's
+    // family, before it connects or listens
+    pub fn apply(&self, socket: &Socket, addr: SocketAddr) -> io::Result<()> {
+        match self {
+            BindTo::Interface(name) => {
+                bind_device(socket, name)?;
+                socket.bind(&addr.into())
+            }
+            BindTo::Address(ip) if ip.is_ipv4() == addr.is_ipv4() => socket.bind(&SocketAddr::new(*ip, addr.port()).into()),
+            BindTo::Address(ip) => Err(io::Error::new(ErrorKind::AddrNotAvailable, format!("{} can't carry traffic for {}", ip, addr))),
+        }
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, name: &str) -> io::Result<()> {
+    socket
+        .bind_device(Some(name.as_bytes()))
+        .map_err(|e| io::Error::new(e.kind(), format!("couldn't bind to interface {}: {}", name, e)))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_: &Socket, name: &str) -> io::Result<()> {
+    Err(io::Error::new(ErrorKind::Unsupported, format!("binding to interface {} by name only works on linux, give its address instead", name)))
+}
+
+#[derive(Default)]
+pub struct TcpTransport {
+    bind: Option<BindTo>,
+}
+
+impl TcpTransport {
+    pub fn new() -> TcpTransport {
+        TcpTransport::default()
+    }
+
+    pub fn bound(bind: BindTo) -> TcpTransport {
+        TcpTransport { bind: Some(bind) }
+    }
+}
+
+impl PeerTransport for TcpTransport {
+    fn connect<'a>(&'a self, ip: &'a str, port: u16) -> ConnectFuture<'a> {
+        Box::pin(async move {
+            let stream = dial(ip, port, self.bind.as_ref()).await?;
+            stream.set_nodelay(true)?;
+            Ok(Box::new(stream) as BoxedStream)
+        })
+    }
+}
+
+// a tcp connection to ip:port, from where bind says when it is given.
+// a name is tried at each of its addresses that bind can reach.
+async fn dial(ip: &str, port: u16, bind: Option<&BindTo>) -> io::Result<TcpStream> {
+    let Some(bind) = bind else { return TcpStream::connect((ip, port)).await };
+    let mut error = io::Error::new(ErrorKind::NotFound, format!("{} has no addresses", ip));
+    for addr in lookup_host((ip, port)).await? {
+        let local = match addr {
+            SocketAddr::V4(_) => SocketAddr::from(([0; 4], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0; 16], 0)),
+        };
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        if let Err(e) = bind.apply(&socket, local) {
+            error = e;
+            continue;
+        }
+        match TcpSocket::from_std_stream(socket.into()).connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
+}
+
+// dials peers through a socks5 proxy (rfc 1928), without a password.
+// with remote_dns a peer given by name is looked up by the proxy, the
+// way socks5h:// works, so the name never goes to our own resolver. a
+// strict transport has no fallback and fails when the proxy can't be
+// reached, rather than connecting to the peer directly and giving our
+// address away.
+pub struct Socks5Transport {
+    host: String,
+    port: u16,
+    remote_dns: bool,
+    // for the connection to the proxy
+    bind: Option<BindTo>,
+    fallback: Option<Arc<dyn PeerTransport>>,
+}
+
+impl Socks5Transport {
+    pub fn new(host: &str, port: u16, remote_dns: bool) -> Socks5Transport {
+        Socks5Transport { host: host.to_string(), port, remote_dns, bind: None, fallback: None }
+    }
+
+    pub fn bind(mut self, bind: BindTo) -> Socks5Transport {
+        self.bind = Some(bind);
+        self
+    }
+
+    // what to dial peers with while the proxy is down
+    pub fn fallback(mut self, direct: Arc<dyn PeerTransport>) -> Socks5Transport {
+        self.fallback = Some(direct);
+        self
+    }
+
+    async fn tunnel(&self, mut stream: TcpStream, ip: &str, port: u16) -> io::Result<BoxedStream> {
+        let address = match ip.parse::<IpAddr>() {
+            Ok(ip) => Socks5Address::Ip(ip),
+            Err(_) if self.remote_dns => Socks5Address::Name(ip),
+            Err(_) => {
+                let addr = lookup_host((ip, port)).await?.next();
+                Socks5Address::Ip(addr.ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("{} has no addresses", ip)))?.ip())
+            }
+        };
+
+        // no authentication is the only method we offer
+        stream.write_all(&[5, 1, 0]).await?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply != [5, 0] {
+            return Err(socks_error("the proxy wants a password or doesn't speak socks5".to_string()));
+        }
+
+        stream.write_all(&connect_request(&address, port)?).await?;
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != 5 {
+            return Err(socks_error("the proxy doesn't speak socks5".to_string()));
+        }
+        if reply[1] != 0 {
+            return Err(socks_error(format!("the proxy couldn't connect to {}:{}: {}", ip, port, reply_message(reply[1]))));
+        }
+        // the address the proxy connected from, which we have no use for
+        let bound = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => stream.read_u8().await? as usize,
+            kind => return Err(socks_error(format!("address type {} in the proxy's reply", kind))),
+        };
+        let mut skip = vec![0u8; bound + 2];
+        stream.read_exact(&mut skip).await?;
+
+        Ok(Box::new(stream) as BoxedStream)
+    }
+}
+
+impl PeerTransport for Socks5Transport {
+    fn connect<'a>(&'a self, ip: &'a str, port: u16) -> ConnectFuture<'a> {
+        Box::pin(async move {
+            let stream = match dial(&self.host, self.port, self.bind.as_ref()).await {
+                Ok(stream) => stream,
+                Err(e) => match &self.fallback {
+                    Some(direct) => {
+                        warn!("proxy {}:{} is down ({}), connecting to {}:{} directly", self.host, self.port, e, ip, port);
+                        return direct.connect(ip, port).await;
+                    }
+                    None => return Err(io::Error::new(e.kind(), format!("proxy {}:{} is down: {}", self.host, self.port, e))),
+                },
+            };
+            stream.set_nodelay(true)?;
+            self.tunnel(stream, ip, port).await
+        })
+    }
+}
+
+enum Socks5Address<'a> {
+    Ip(IpAddr),
+    Name(&'a str),
+}
+
+fn connect_request(address: &Socks5Address, port: u16) -> io::Result<Vec<u8>> {
+    let mut request = vec![5, 1, 0];
+    match address {
+        Socks5Address::Ip(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend(ip.octets());
+        }
+        Socks5Address::Ip(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend(ip.octets());
+        }
+        Socks5Address::Name(name) => {
+            let len = u8::try_from(name.len()).map_err(|_| socks_error(format!("{} is too long a name for socks5", name)))?;
+            request.push(3);
+            request.push(len);
+            request.extend(name.as_bytes());
+        }
+    }
+    request.extend(port.to_be_bytes());
+    Ok(request)
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "not allowed by its rules",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "ttl expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn socks_error(message: String) -> io::Error {
+    io::Error::new(ErrorKind::ConnectionRefused, message)
+}
+
+// size of the in-memory pipe buffer in each direction
+const MEMORY_PIPE_SIZE: usize = 64 * 1024;
+
+// in-process transport, mostly for tests. dialing an address that
+// something is listening on hands one end of a pipe to the dialer
+// and the other end to the listener.
+#[derive(Default)]
+pub struct MemoryTransport {
+    listeners: Mutex<HashMap<(String, u16), mpsc::UnboundedSender<BoxedStream>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+
+    // every connection made to ip:port shows up on the returned receiver
+    pub fn listen(&self, ip: &str, port: u16) -> mpsc::UnboundedReceiver<BoxedStream> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.listeners.lock().unwrap().insert((ip.to_string(), port), tx);
+        rx
+    }
+}
+
+impl PeerTransport for MemoryTransport {
+    fn connect<'a>(&'a self, ip: &'a str, port: u16) -> ConnectFuture<'a> {
+        Box::pin(async move {
+            let listeners = self.listeners.lock().unwrap();
+            let listener = listeners
+                .get(&(ip.to_string(), port))
+                .ok_or_else(|| io::Error::new(ErrorKind::ConnectionRefused, format!("nothing listening on {}:{}", ip, port)))?;
+
+            let (local, remote) = duplex(MEMORY_PIPE_SIZE);
+            listener
+                .send(Box::new(remote))
+                .map_err(|_| io::Error::new(ErrorKind::ConnectionRefused, "listener has gone away"))?;
+
+            Ok(Box::new(local) as BoxedStream)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    // a proxy that takes one connection, checks the request is for
+    // expected and answers with reply. once connected it echoes.
+    async fn proxy(expected: &'static [u8], reply: u8) -> (String, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [5, 1, 0]);
+            stream.write_all(&[5, 0]).await.unwrap();
+
+            let mut request = vec![0u8; expected.len()];
+            stream.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, expected);
+            stream.write_all(&[5, reply, 0, 1, 127, 0, 0, 1, 0x1a, 0xe1]).await.unwrap();
+
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+        (addr.ip().to_string(), addr.port())
+    }
+
+    #[test]
+    fn test_bind_to() {
+        assert_eq!(BindTo::parse("10.8.0.2").unwrap(), BindTo::Address("10.8.0.2".parse().unwrap()));
+        assert_eq!(BindTo::parse("fd00::2").unwrap(), BindTo::Address("fd00::2".parse().unwrap()));
+        assert_eq!(BindTo::parse("tun0").unwrap(), BindTo::Interface("tun0".to_string()));
+        assert!(BindTo::parse("").is_err());
+        assert!(BindTo::parse("a very long name").is_err());
+        assert!(BindTo::parse("../eth0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bound_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let bound = TcpTransport::bound(BindTo::Address("127.0.0.1".parse().unwrap()));
+        let stream = bound.connect("127.0.0.1", port).await.unwrap();
+        let (_, from) = listener.accept().await.unwrap();
+        assert_eq!(from.ip().to_string(), "127.0.0.1");
+        drop(stream);
+
+        // an ipv6 address can't reach an ipv4 peer
+        let v6 = TcpTransport::bound(BindTo::Address("::1".parse().unwrap()));
+        let err = v6.connect("127.0.0.1", port).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::AddrNotAvailable);
+    }
+
+    async fn unused_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn test_socks5() {
+        // by name, port 6881
+        let (host, port) = proxy(b"\x05\x01\x00\x03\x0cpeer.example\x1a\xe1", 0).await;
+        let transport = Socks5Transport::new(&host, port, true);
+        let mut stream = transport.connect("peer.example", 6881).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let (host, port) = proxy(b"\x05\x01\x00\x01\x0a\x00\x00\x01\x1a\xe1", 0).await;
+        assert!(Socks5Transport::new(&host, port, true).connect("10.0.0.1", 6881).await.is_ok());
+        let (host, port) = proxy(b"\x05\x01\x00\x04\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe1", 0).await;
+        assert!(Socks5Transport::new(&host, port, false).connect("2001:db8::1", 6881).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_socks5_refused() {
+        // the proxy is up, so a peer it can't reach isn't a reason to
+        // go around it
+        let (host, port) = proxy(b"\x05\x01\x00\x01\x0a\x00\x00\x01\x1a\xe1", 5).await;
+        let direct = Arc::new(MemoryTransport::new());
+        let _listener = direct.listen("10.0.0.1", 6881);
+        let transport = Socks5Transport::new(&host, port, true).fallback(direct);
+        let err = transport.connect("10.0.0.1", 6881).await.err().unwrap();
+        assert!(err.to_string().contains("connection refused"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_socks5_down() {
+        let port = unused_port().await;
+        let strict = Socks5Transport::new("127.0.0.1", port, true);
+        let err = strict.connect("10.0.0.1", 6881).await.err().unwrap();
+        assert!(err.to_string().contains("is down"), "{}", err);
+
+        let direct = Arc::new(MemoryTransport::new());
+        let mut listener = direct.listen("10.0.0.1", 6881);
+        let lenient = Socks5Transport::new("127.0.0.1", port, true).fallback(direct);
+        assert!(lenient.connect("10.0.0.1", 6881).await.is_ok());
+        assert!(listener.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_memory_transport_pipe() {
+        let transport = MemoryTransport::new();
+        let mut listener = transport.listen("127.0.0.1", 6881);
+
+        let mut local = transport.connect("127.0.0.1", 6881).await.unwrap();
+        let mut remote = listener.recv().await.unwrap();
+
+        local.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_memory_transport_refuses_unknown_address() {
+        let transport = MemoryTransport::new();
+        let err = transport.connect("127.0.0.1", 6881).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::{self, File},
+    io,
+    os::unix::fs::FileExt as _,
+    path::{Path, PathBuf},
+};
+
+use rand::seq::index;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    filemap::{self, FileMap},
+    torrent::Torrent,
+};
+
+// checks for data that is already on disk, e.g. when adding a torrent
+// for files we downloaded some other way.
+
+// what other clients call a file they haven't finished, tried after its
+// real name: ours and transmission's, then qbittorrent's and utorrent's
+pub const PARTIAL_SUFFIXES: &[&str] = &[filemap::PART_SUFFIX, ".!qB", ".!ut"];
+
+// a file of the torrent mapped to where it lives on disk
+#[derive(Debug)]
+pub struct MappedFile {
+    pub path: PathBuf,
+    pub length: u64,
+}
+
+// finds every file of the torrent inside dir by name and makes sure
+// its size matches what the torrent says it should be
+pub fn map_existing_files(torrent: &Torrent, dir: &Path) -> Result<Vec<MappedFile>, String> {
+    let mut mapped = Vec::new();
+
+    for file in &torrent.files {
+        let path = dir.join(&file.name);
+        let metadata = fs::metadata(&path).map_err(|e| format!("couldn't find {}: {}", path.display(), e))?;
+
+        if !metadata.is_file() {
+            return Err(format!("{} is not a file", path.display()));
+        }
+
+        if metadata.len() != file.length() {
+            return Err(format!(
+                "{} is {} bytes but the torrent expects {}",
+                path.display(),
+                metadata.len(),
+                file.length()
+            ));
+        }
+
+        mapped.push(MappedFile { path, length: file.length() });
+    }
+
+    Ok(mapped)
+}
+
+// picks which pieces to spot check: the first and the last (the
+// ones most likely to be wrong if a file was truncated or padded)
+// plus a random selection of the rest
+pub fn sample_pieces(num_pieces: usize, sample: usize) -> Vec<usize> {
+    if sample >= num_pieces {
+        return (0..num_pieces).collect();
+    }
+
+    let mut pieces = vec![0, num_pieces - 1];
+    if sample > 2 {
+        let mut rng = rand::rng();
+        let rest = index::sample(&mut rng, num_pieces - 2, sample - 2);
+        pieces.extend(rest.iter().map(|i| i + 1));
+    }
+
+    pieces.truncate(sample);
+    pieces.sort_unstable();
+    pieces
+}
+
+// hashes each requested piece and returns the indices that don't match
+pub fn verify_pieces(torrent: &Torrent, files: &[MappedFile], pieces: &[usize]) -> io::Result<Vec<usize>> {
+    let mut failed = Vec::new();
+
+    let map = torrent.piece_map();
+    for &index in pieces {
+        let range = map.piece(index as u32).ok_or_else(|| io::Error::other(format!("there is no piece {}", index)))?;
+        let data = read_range(files, range.start, range.end - range.start)?;
+
+        if Sha1::digest(&data)[..] != torrent.pieces[index] {
+            failed.push(index);
+        }
+    }
+
+    Ok(failed)
+}
+
+// where a download some other client didn't finish has each of the
+// torrent's files, indexed like FileMap::files. None for padding files
+// and files it never got to. output_file must already be where the
+// data is.
+pub fn find_partial_files(torrent: &Torrent) -> Vec<Option<PathBuf>> {
+    FileMap::new(torrent)
+        .files()
+        .iter()
+        .map(|file| {
+            if file.padding {
+                return None;
+            }
+            if file.path.is_file() {
+                return Some(file.path.clone());
+            }
+            PARTIAL_SUFFIXES.iter().map(|suffix| {
+                let mut path = file.path.as_os_str().to_owned();
+                path.push(suffix);
+                PathBuf::from(path)
+            }).find(|path| path.is_file())
+        })
+        .collect()
+}
+
+// hashes every piece of a partial download, with the files found by
+// find_partial_files. one byte per piece, like a peer bitfield. a piece
+// that runs into a missing or short file is one we don't have.
+pub fn check_partial(torrent: &Torrent, found: &[Option<PathBuf>]) -> Vec<u8> {
+    let map = FileMap::new(torrent);
+    let pieces = torrent.piece_map();
+    let mut handles = HashMap::new();
+
+    (0..pieces.num_pieces())
+        .map(|index| {
+            let length = pieces.piece_size(index).unwrap_or(0);
+            let Ok(spans) = pieces.spans(&map, index, 0, length) else { return 0 };
+
+            let mut data = vec![0u8; length as usize];
+            for span in spans {
+                // padding reads back as zeros
+                if map.files()[span.file].padding {
+                    continue;
+                }
+                let Some(path) = &found[span.file] else { return 0 };
+                let file = match handles.entry(span.file) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => match File::open(path) {
+                        Ok(file) => entry.insert(file),
+                        Err(_) => return 0,
+                    },
+                };
+                if file.read_exact_at(&mut data[span.range], span.file_offset).is_err() {
+                    return 0;
+                }
+            }
+            (Sha1::digest(&data)[..] == torrent.pieces[index as usize]) as u8
+        })
+        .collect()
+}
+
+// reads a range of the torrent's data, which may span several files
+fn read_range(files: &[MappedFile], mut offset: u64, length: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(length as usize);
+
+    for file in files {
+        if data.len() as u64 == length {
+            break;
+        }
+
+        if offset >= file.length {
+            offset -= file.length;
+            continue;
+        }
+
+        let wanted = std::cmp::min(length - data.len() as u64, file.length - offset);
+        let mut buf = vec![0u8; wanted as usize];
+        File::open(&file.path)?.read_exact_at(&mut buf, offset)?;
+        data.extend_from_slice(&buf);
+        offset = 0;
+    }
+
+    if data.len() as u64 != length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "range goes past the end of the torrent's data"));
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{info_hash::InfoHash, torrent::File as TorrentFile};
+
+    fn write_temp(name: &str, data: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("data.bin"), data).unwrap();
+        dir
+    }
+
+    fn test_torrent(data: &[u8], piece_length: u32) -> Torrent {
+        let pieces = data
+            .chunks(piece_length as usize)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+
+        Torrent {
+            info_hash: InfoHash::default(),
+            announce: String::new(),
+            multi_file: false,
+            piece_length,
+            total_size: data.len() as u64,
+            pieces,
+            output_file: "data.bin".to_string(),
+            files: vec![TorrentFile::new("data.bin".to_string(), data.len() as u64)],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sample_pieces() {
+        assert_eq!(sample_pieces(3, 10), vec![0, 1, 2]);
+        assert_eq!(sample_pieces(100, 2), vec![0, 99]);
+
+        let sample = sample_pieces(100, 10);
+        assert_eq!(sample.len(), 10);
+        assert_eq!(sample[0], 0);
+        assert_eq!(sample[9], 99);
+    }
+
+    #[test]
+    fn test_map_and_verify() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let dir = write_temp("bt-c-verify-ok", &data);
+        let torrent = test_torrent(&data, 32);
+
+        let files = map_existing_files(&torrent, &dir).unwrap();
+        let failed = verify_pieces(&torrent, &files, &[0, 1, 2, 3]).unwrap();
+        assert!(failed.is_empty());
+    }
+
+    #[test]
+    fn test_verify_detects_corruption() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let torrent = test_torrent(&data, 32);
+
+        let mut corrupt = data.clone();
+        corrupt[40] ^= 0xFF;
+        let dir = write_temp("bt-c-verify-corrupt", &corrupt);
+
+        let files = map_existing_files(&torrent, &dir).unwrap();
+        let failed = verify_pieces(&torrent, &files, &[0, 1, 2, 3]).unwrap();
+        assert_eq!(failed, vec![1]);
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let dir = write_temp("bt-c-verify-size", &data[..99]);
+        let torrent = test_torrent(&data, 32);
+
+        assert!(map_existing_files(&torrent, &dir).is_err());
+    }
+
+    #[test]
+    fn test_read_range_spans_files() {
+        let dir = std::env::temp_dir().join("bt-c-verify-span");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), b"hello").unwrap();
+        fs::write(dir.join("b"), b"world").unwrap();
+
+        let files = vec![
+            MappedFile { path: dir.join("a"), length: 5 },
+            MappedFile { path: dir.join("b"), length: 5 },
+        ];
+
+        assert_eq!(read_range(&files, 3, 4).unwrap(), b"lowo");
+        assert!(read_range(&files, 8, 4).is_err());
+    }
+
+    #[test]
+    fn test_check_partial() {
+        let dir = std::env::temp_dir().join("bt-c-verify-partial");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..100u8).collect();
+        let torrent = Torrent {
+            multi_file: true,
+            output_file: dir.to_string_lossy().to_string(),
+            files: vec![
+                TorrentFile::new("a".to_string(), 40),
+                TorrentFile::new("b".to_string(), 40),
+                TorrentFile::new("c".to_string(), 20),
+            ],
+            ..test_torrent(&data, 32)
+        };
+
+        // qbittorrent got part of a and the start of b, and never
+        // started on c
+        let mut a = data[..40].to_vec();
+        a[35] ^= 0xFF;
+        fs::write(dir.join("a.!qB"), &a).unwrap();
+        fs::write(dir.join("b"), &data[40..70]).unwrap();
+
+        let found = find_partial_files(&torrent);
+        assert_eq!(found, vec![Some(dir.join("a.!qB")), Some(dir.join("b")), None]);
+        assert_eq!(check_partial(&torrent, &found), vec![1, 0, 0, 0]);
+
+        fs::write(dir.join("a.!qB"), &data[..40]).unwrap();
+        assert_eq!(check_partial(&torrent, &found), vec![1, 1, 0, 0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
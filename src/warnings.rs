@@ -0,0 +1,119 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::warn as log_warn;
+
+// in a busy swarm the same warning ("peer not found", "piece not
+// ongoing", ...) can fire thousands of times a second. each kind of
+// warning is only logged once per window, with a note of how many
+// were swallowed in between, and every occurrence is counted so the
+// totals can be shown in the session stats.
+
+const WINDOW: Duration = Duration::from_secs(10);
+
+static WARNINGS: LazyLock<Mutex<WarningLimiter>> = LazyLock::new(|| Mutex::new(WarningLimiter::new(WINDOW)));
+
+struct Entry {
+    last_logged: Instant,
+    suppressed: u64,
+    total: u64,
+}
+
+pub struct WarningLimiter {
+    window: Duration,
+    entries: HashMap<&'static str, Entry>,
+}
+
+impl WarningLimiter {
+    pub fn new(window: Duration) -> WarningLimiter {
+        WarningLimiter {
+            window,
+            entries: HashMap::new(),
+        }
+    }
+
+    // counts the warning and decides whether it should be logged.
+    // returns the number of warnings of this kind suppressed since
+    // the last one that was logged, or None if this one should be
+    // suppressed too.
+    pub fn record(&mut self, kind: &'static str, now: Instant) -> Option<u64> {
+        match self.entries.get_mut(kind) {
+            Some(entry) => {
+                entry.total += 1;
+                if now.duration_since(entry.last_logged) < self.window {
+                    entry.suppressed += 1;
+                    return None;
+                }
+
+                let suppressed = entry.suppressed;
+                entry.suppressed = 0;
+                entry.last_logged = now;
+                Some(suppressed)
+            }
+            None => {
+                self.entries.insert(kind, Entry { last_logged: now, suppressed: 0, total: 1 });
+                Some(0)
+            }
+        }
+    }
+
+    pub fn counts(&self) -> BTreeMap<String, u64> {
+        self.entries
+            .iter()
+            .map(|(&kind, entry)| (kind.to_string(), entry.total))
+            .collect()
+    }
+}
+
+// logs a warning unless one of the same kind was logged recently.
+// the message is only built if it is actually going to be logged.
+pub fn warn<F: FnOnce() -> String>(kind: &'static str, message: F) {
+    let suppressed = WARNINGS.lock().unwrap().record(kind, Instant::now());
+
+    match suppressed {
+        Some(0) => log_warn!("{}", message()),
+        Some(n) => log_warn!("{} ({} similar warnings suppressed)", message(), n),
+        None => {}
+    }
+}
+
+// how many times each kind of warning has fired since startup
+pub fn counts() -> BTreeMap<String, u64> {
+    WARNINGS.lock().unwrap().counts()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_are_suppressed_within_window() {
+        let mut limiter = WarningLimiter::new(Duration::from_secs(10));
+        let start = Instant::now();
+
+        assert_eq!(limiter.record("peer not found", start), Some(0));
+        assert_eq!(limiter.record("peer not found", start + Duration::from_secs(1)), None);
+        assert_eq!(limiter.record("peer not found", start + Duration::from_secs(2)), None);
+
+        // once the window has passed the next one is logged along with the count
+        assert_eq!(limiter.record("peer not found", start + Duration::from_secs(11)), Some(2));
+        assert_eq!(limiter.counts()["peer not found"], 4);
+    }
+
+    #[test]
+    fn test_kinds_are_independent() {
+        let mut limiter = WarningLimiter::new(Duration::from_secs(10));
+        let now = Instant::now();
+
+        assert_eq!(limiter.record("a", now), Some(0));
+        assert_eq!(limiter.record("b", now), Some(0));
+        assert_eq!(limiter.record("a", now), None);
+
+        let counts = limiter.counts();
+        assert_eq!(counts["a"], 2);
+        assert_eq!(counts["b"], 1);
+    }
+}